@@ -0,0 +1,392 @@
+//! Human-readable output of the state of the daemon, in the style of
+//! `chronyc sources` and `chronyc tracking`
+
+use std::fmt::Write;
+
+use ntp_daemon::{
+    config::dynamic::ConfigResponse, metrics::format_reference_id, ObservablePeerState,
+    ObservableState,
+};
+use ntp_proto::{ClockState, NtpLeapIndicator, PeerSelection, PollInterval};
+
+/// Format a duration in seconds with the unit that fits its magnitude
+fn duration(seconds: f64) -> String {
+    // Compare against the value rounded to three decimals, so that e.g.
+    // 0.9999996ms is shown as 1.000ms rather than 1000.000us
+    let magnitude = seconds.abs();
+    let (value, unit) = if magnitude >= 0.9995 || magnitude == 0.0 || !magnitude.is_finite() {
+        (seconds, "s")
+    } else if magnitude >= 0.9995e-3 {
+        (seconds * 1e3, "ms")
+    } else if magnitude >= 0.9995e-6 {
+        (seconds * 1e6, "us")
+    } else {
+        (seconds * 1e9, "ns")
+    };
+
+    format!("{:.3}{}", value, unit)
+}
+
+/// Format a duration that can be negative, always with its sign
+fn signed_duration(seconds: f64) -> String {
+    if seconds.is_sign_negative() {
+        duration(seconds)
+    } else {
+        format!("+{}", duration(seconds))
+    }
+}
+
+fn poll_interval(poll_interval: PollInterval) -> String {
+    format!("{}s", 2f64.powi(poll_interval.as_log() as i32))
+}
+
+fn selection(selection: PeerSelection) -> &'static str {
+    match selection {
+        PeerSelection::Unusable => "unusable",
+        PeerSelection::Falseticker => "falseticker",
+        PeerSelection::Outlier => "outlier",
+        PeerSelection::Candidate => "candidate",
+        PeerSelection::Selected => "selected",
+    }
+}
+
+fn clock_state(clock_state: ClockState) -> &'static str {
+    match clock_state {
+        ClockState::StartupBlank => "starting up",
+        ClockState::StartupFreq => "starting up with known frequency",
+        ClockState::MeasureFreq => "measuring frequency",
+        ClockState::Spike => "ignoring spike",
+        ClockState::Sync => "synchronized",
+        ClockState::Slewing => "slewing",
+    }
+}
+
+fn leap_indicator(leap_indicator: NtpLeapIndicator) -> &'static str {
+    match leap_indicator {
+        NtpLeapIndicator::NoWarning => "normal",
+        NtpLeapIndicator::Leap61 => "insert second",
+        NtpLeapIndicator::Leap59 => "delete second",
+        NtpLeapIndicator::Unknown => "not synchronized",
+    }
+}
+
+/// Lay out rows in columns, with the first column aligned left and the
+/// others aligned right
+fn table<const N: usize>(header: [&str; N], rows: &[[String; N]]) -> String {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut output = String::new();
+    let header = header.map(String::from);
+    for row in std::iter::once(&header).chain(rows) {
+        let mut line = String::new();
+        for (i, (cell, width)) in row.iter().zip(widths).enumerate() {
+            // Writing to a string is infallible
+            let _ = match i {
+                0 => write!(line, "{:<width$}", cell, width = width),
+                _ => write!(line, "  {:>width$}", cell, width = width),
+            };
+        }
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+
+    output
+}
+
+/// A table with a line per peer
+pub fn peers(state: &ObservableState) -> String {
+    let rows: Vec<_> = state
+        .peers
+        .iter()
+        .filter_map(|peer| match peer {
+            ObservablePeerState::Nothing => None,
+            ObservablePeerState::Observable {
+                timedata,
+                reachability,
+                poll_interval: interval,
+                address,
+                stratum,
+                ..
+            } => Some([
+                address.clone(),
+                stratum.to_string(),
+                format!("{:o}", reachability.as_bits()),
+                poll_interval(*interval),
+                format!(
+                    "{} +/- {}",
+                    signed_duration(timedata.offset.to_seconds()),
+                    duration(timedata.uncertainty.to_seconds())
+                ),
+                duration(timedata.delay.to_seconds()),
+                selection(timedata.selection).to_string(),
+            ]),
+        })
+        .collect();
+
+    let unobservable = state.peers.len() - rows.len();
+
+    let mut output = table(
+        [
+            "Address", "Stratum", "Reach", "Poll", "Offset", "Delay", "State",
+        ],
+        &rows,
+    );
+    if unobservable > 0 {
+        let _ = writeln!(output, "{} peers without measurements yet", unobservable);
+    }
+
+    output
+}
+
+/// An overview of the state of the system clock
+pub fn system(state: &ObservableState) -> String {
+    let system = &state.system;
+    let time = &system.time_snapshot;
+
+    let system_peer = state.peers.iter().find_map(|peer| match peer {
+        ObservablePeerState::Observable {
+            timedata, address, ..
+        } if timedata.selection == PeerSelection::Selected => Some(address.as_str()),
+        _ => None,
+    });
+    let reference_id = format_reference_id(system.reference_id, system.stratum);
+
+    let mut lines = vec![
+        (
+            "Reference ID",
+            match system_peer {
+                Some(address) => format!("{} ({})", reference_id, address),
+                None => reference_id,
+            },
+        ),
+        ("Stratum", system.stratum.to_string()),
+        (
+            "Leap status",
+            leap_indicator(time.leap_indicator).to_string(),
+        ),
+        ("Clock state", clock_state(system.clock_state).to_string()),
+        ("System offset", signed_duration(time.offset.to_seconds())),
+        ("Jitter", duration(time.jitter.to_seconds())),
+        ("Frequency", format!("{:+.3} ppm", time.frequency * 1e6)),
+        ("Root delay", duration(time.root_delay.to_seconds())),
+        (
+            "Root dispersion",
+            duration(time.root_dispersion.to_seconds()),
+        ),
+        ("Poll interval", poll_interval(time.poll_interval)),
+        ("Precision", duration(time.precision.to_seconds())),
+        (
+            "Accumulated steps",
+            duration(time.accumulated_steps.to_seconds()),
+        ),
+    ];
+    if let Some(threshold) = system.accumulated_steps_threshold {
+        lines.push(("Step threshold", duration(threshold.to_seconds())));
+    }
+    if let Some(remaining) = time.slew_remaining {
+        lines.push(("Slew remaining", duration(remaining.to_seconds())));
+    }
+
+    overview(&lines)
+}
+
+/// Lines of a name and a value, with the values aligned
+fn overview(lines: &[(&str, String)]) -> String {
+    let width = lines.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let mut output = String::new();
+    for (name, value) in lines {
+        let _ = writeln!(output, "{:<width$} : {}", name, value, width = width);
+    }
+
+    output
+}
+
+fn panic_threshold(forward: Option<f64>, backward: Option<f64>) -> String {
+    let threshold = |seconds: Option<f64>| seconds.map_or("none".to_string(), duration);
+    if forward == backward {
+        threshold(forward)
+    } else {
+        format!(
+            "{} forward, {} backward",
+            threshold(forward),
+            threshold(backward)
+        )
+    }
+}
+
+/// A line per requested change with whether it was applied, followed by the
+/// configuration in effect afterwards
+pub fn config_response(response: &ConfigResponse) -> String {
+    let field_width = response.results.iter().map(|r| r.field.len()).max();
+    let value_width = response.results.iter().map(|r| r.value.len()).max();
+
+    let mut output = String::new();
+    for result in &response.results {
+        let outcome = match &result.error {
+            None => "applied".to_string(),
+            Some(error) => format!("failed: {}", error),
+        };
+        let _ = writeln!(
+            output,
+            "{:<field_width$}  {:<value_width$}  {}",
+            result.field,
+            result.value,
+            outcome,
+            field_width = field_width.unwrap_or(0),
+            value_width = value_width.unwrap_or(0),
+        );
+    }
+
+    if let Some(config) = &response.config {
+        let list = |items: Vec<String>| match items.is_empty() {
+            true => "none".to_string(),
+            false => items.join(", "),
+        };
+        let pools = config
+            .pools
+            .iter()
+            .map(|pool| format!("{} (max {} peers)", pool.addr, pool.max_peers))
+            .collect();
+
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(&overview(&[
+            ("Log filter", config.log_filter.clone()),
+            (
+                "Panic threshold",
+                panic_threshold(
+                    config.panic_threshold_forward,
+                    config.panic_threshold_backward,
+                ),
+            ),
+            ("Peers", list(config.peers.clone())),
+            ("Pools", list(pools)),
+        ]));
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{NtpDuration, ReferenceId, SystemSnapshot};
+
+    use super::*;
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(duration(0.0), "0.000s");
+        assert_eq!(duration(2.5), "2.500s");
+        assert_eq!(duration(0.0125), "12.500ms");
+        assert_eq!(duration(-0.000125), "-125.000us");
+        assert_eq!(duration(0.000000125), "125.000ns");
+        assert_eq!(duration(0.0009999996), "1.000ms");
+        assert_eq!(signed_duration(0.0125), "+12.500ms");
+        assert_eq!(signed_duration(-0.0125), "-12.500ms");
+    }
+
+    fn peer(address: &str, offset: f64, selection: PeerSelection) -> ObservablePeerState {
+        ObservablePeerState::Observable {
+            timedata: ntp_proto::ObservablePeerTimedata {
+                offset: NtpDuration::from_seconds(offset),
+                uncertainty: NtpDuration::from_seconds(0.0005),
+                delay: NtpDuration::from_seconds(0.02),
+                selection,
+                ..Default::default()
+            },
+            reachability: serde_json::from_str("255").unwrap(),
+            poll_interval: ntp_proto::PollIntervalLimits::default().min,
+            peer_id: ReferenceId::NONE,
+            address: address.into(),
+            stratum: 2,
+            reference_id: ReferenceId::NONE,
+            ignored: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_peers() {
+        let state = ObservableState {
+            system: SystemSnapshot::default(),
+            peers: vec![
+                peer("192.0.2.1:123", 0.001, PeerSelection::Selected),
+                peer("time.example.com:123", -0.25, PeerSelection::Falseticker),
+                ObservablePeerState::Nothing,
+            ],
+            servers: vec![],
+        };
+
+        let output = peers(&state);
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Address               Stratum  Reach  Poll                    Offset     Delay        State",
+                "192.0.2.1:123               2    377   16s    +1.000ms +/- 500.000us  20.000ms     selected",
+                "time.example.com:123        2    377   16s  -250.000ms +/- 500.000us  20.000ms  falseticker",
+                "1 peers without measurements yet",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_system() {
+        let mut state = ObservableState {
+            system: SystemSnapshot::default(),
+            peers: vec![peer("192.0.2.1:123", 0.001, PeerSelection::Selected)],
+            servers: vec![],
+        };
+        state.system.stratum = 3;
+        state.system.reference_id = ReferenceId::from_ip("192.0.2.1".parse().unwrap());
+        state.system.clock_state = ClockState::Sync;
+        state.system.time_snapshot.frequency = -12.5e-6;
+
+        let output = system(&state);
+        assert!(output.contains("Reference ID      : 192.0.2.1 (192.0.2.1:123)\n"));
+        assert!(output.contains("Clock state       : synchronized\n"));
+        assert!(output.contains("Frequency         : -12.500 ppm\n"));
+    }
+
+    #[test]
+    fn test_config_response() {
+        let response: ConfigResponse = serde_json::from_str(
+            r#"{
+                "version": 1,
+                "error": null,
+                "results": [
+                    {"field": "log_filter", "value": "debug", "error": null},
+                    {"field": "remove_peers", "value": "ntp.example.com", "error": "no peer or pool with this address"}
+                ],
+                "config": {
+                    "log_filter": "debug",
+                    "panic_threshold_forward": 1000.0,
+                    "panic_threshold_backward": null,
+                    "peers": ["0.pool.ntp.org:123", "192.0.2.1:123"],
+                    "pools": [{"addr": "pool.ntp.org:123", "max_peers": 4}]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let output = config_response(&response);
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "log_filter    debug            applied",
+                "remove_peers  ntp.example.com  failed: no peer or pool with this address",
+                "",
+                "Log filter      : debug",
+                "Panic threshold : 1000.000s forward, none backward",
+                "Peers           : 0.pool.ntp.org:123, 192.0.2.1:123",
+                "Pools           : pool.ntp.org:123 (max 4 peers)",
+            ]
+        );
+    }
+}
@@ -1,9 +1,19 @@
 #![forbid(unsafe_code)]
 
-use std::path::PathBuf;
+mod format;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
 
 use clap::{Parser, Subcommand};
-use ntp_daemon::{Config, ConfigUpdate, ObservableState};
+use ntp_daemon::{
+    config::dynamic::{ConfigRequest, ConfigResponse, PoolUpdate},
+    observer::{ObserveRequest, ObserveUpdate, PeerHistory},
+    remote::{RemoteClient, RemoteOperation, RemoteResponse},
+    Config, ConfigUpdate, ObservableState,
+};
 use ntp_metrics_exporter::Metrics;
 
 #[derive(Parser)]
@@ -24,102 +34,352 @@ struct Cli {
     /// Path of the configuration socket
     #[arg(short = 's', long)]
     configuration_socket: Option<PathBuf>,
+
+    /// Address (host:port) of a remote management endpoint to use instead of the sockets
+    #[arg(long, requires = "remote_ca")]
+    remote: Option<String>,
+
+    /// Name in the certificate of the remote endpoint, by default the host in --remote
+    #[arg(long)]
+    remote_name: Option<String>,
+
+    /// File with the certificates of the authorities that issue the certificate of the remote endpoint
+    #[arg(long)]
+    remote_ca: Option<PathBuf>,
+
+    /// Certificate to authenticate to the remote endpoint with
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// Private key of the client certificate
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Token to authenticate to the remote endpoint with
+    #[arg(long, env = "NTP_CTL_TOKEN", hide_env_values = true)]
+    token: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Command {
     #[command(about = "Information about the peers the daemon is currently connected with")]
-    Peers,
+    Peers {
+        /// Print the full state of the peers as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
     #[command(about = "Information about the state of the daemon itself")]
-    System,
+    System {
+        /// Print the full state of the daemon as JSON instead of an overview
+        #[arg(long)]
+        json: bool,
+    },
     #[command(
         about = "Information about the state of the daemon and peers in the prometheus export format"
     )]
     Prometheus,
+    #[command(
+        about = "Follow changes to the state of the daemon, printing one JSON object per line"
+    )]
+    Watch {
+        /// Only print the parts of the state that changed
+        #[arg(long)]
+        deltas: bool,
+    },
+    #[command(about = "Recent measurements of a peer, oldest first")]
+    History {
+        /// Address of the peer, as configured, the port may be left out
+        peer: String,
+    },
     #[command(about = "Adjust configuration (e.g. loglevel) of the daemon")]
-    Config(ConfigUpdate),
+    Config {
+        #[command(flatten)]
+        update: ConfigUpdate,
+
+        /// Print the response of the daemon as JSON instead of a list of changes
+        #[arg(long)]
+        json: bool,
+    },
+    #[command(about = "Make the daemon read its configuration file again, like on SIGHUP")]
+    Reload {
+        /// Print the response of the daemon as JSON instead of a list of changes
+        #[arg(long)]
+        json: bool,
+    },
+    #[command(subcommand, about = "Add or remove peers of the running daemon")]
+    Peer(PeerCommand),
+    #[command(subcommand, about = "Add pools to the running daemon")]
+    Pool(PoolCommand),
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum PeerCommand {
+    #[command(about = "Start synchronizing with an additional peer")]
+    Add {
+        /// Address of the peer, the port defaults to 123
+        address: String,
 
-    let config = Config::from_args(cli.config, vec![], vec![]).await;
+        /// Print the response of the daemon as JSON instead of a list of changes
+        #[arg(long)]
+        json: bool,
+    },
+    #[command(about = "Stop using the peers or pools with the given address")]
+    Remove {
+        /// Address of the peer or pool, as configured
+        address: String,
 
-    if let Err(ref e) = config {
-        println!("Warning: Unable to load configuration file: {}", e);
+        /// Print the response of the daemon as JSON instead of a list of changes
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum PoolCommand {
+    #[command(about = "Start using peers from an additional pool")]
+    Add {
+        /// Address of the pool, the port defaults to 123
+        address: String,
+
+        /// The maximum number of peers to use from the pool
+        #[arg(long)]
+        max_peers: usize,
+
+        /// Print the response of the daemon as JSON instead of a list of changes
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// How to reach the daemon: through its sockets, or over the network
+enum Connection {
+    Local {
+        observation: PathBuf,
+        configuration: PathBuf,
+    },
+    Remote(RemoteClient),
+}
+
+impl Connection {
+    async fn observe(&self) -> Result<ObservableState, String> {
+        match self {
+            Connection::Local { observation, .. } => {
+                let mut stream = connect(observation).await?;
+                let mut msg = Vec::with_capacity(16 * 1024);
+                ntp_daemon::sockets::read_json(&mut stream, &mut msg)
+                    .await
+                    .map_err(|e| format!("Failed to read state from observation socket: {}", e))
+            }
+            Connection::Remote(client) => match client.request(RemoteOperation::Observe).await {
+                Ok(RemoteResponse::Observe(state)) => Ok(state),
+                other => Err(remote_error(other)),
+            },
+        }
     }
 
-    let config = config.unwrap_or_default();
+    /// Print the state and then every update, until the daemon closes the connection
+    async fn watch(&self, deltas: bool) -> Result<(), String> {
+        let observation = match self {
+            Connection::Local { observation, .. } => observation,
+            Connection::Remote(_) => {
+                return Err("Watching is not supported over remote management".to_string())
+            }
+        };
 
-    let observation = match cli.observation_socket {
-        Some(path) => path,
-        None => match config.observe.path {
-            Some(path) => path,
-            None => "/run/ntpd-rs/observe".into(),
-        },
-    };
+        let mut stream = connect(observation).await?;
+        ntp_daemon::sockets::write_json(&mut stream, &ObserveRequest::Subscribe { deltas })
+            .await
+            .map_err(|e| format!("Failed to subscribe to updates: {}", e))?;
 
-    let configuration = match cli.configuration_socket {
-        Some(path) => path,
-        None => match config.configure.path {
-            Some(path) => path,
-            None => "/run/ntpd-rs/configure".into(),
-        },
-    };
+        let mut lines = BufReader::new(stream).lines();
 
-    let socket_path = match cli.command {
-        Command::Peers | Command::System | Command::Prometheus => &observation,
-        Command::Config(_) => &configuration,
-    };
+        // The current state comes first, in the same format as without subscribing
+        let state: ObservableState = serde_json::from_str(&next_line(&mut lines).await?)
+            .map_err(|e| format!("Failed to read state from observation socket: {}", e))?;
+        // Unwrap here is fine as our serializer is infallible.
+        let mut line = serde_json::to_string(&ObserveUpdate::Snapshot(state)).unwrap();
 
-    let mut stream = match tokio::net::UnixStream::connect(socket_path).await {
-        Ok(stream) => stream,
-        Err(e) => {
-            eprintln!("Could not open socket at {}: {}", socket_path.display(), e);
-            std::process::exit(1);
+        let mut stdout = std::io::stdout().lock();
+        loop {
+            match writeln!(stdout, "{}", line) {
+                Ok(()) => {}
+                // Stop quietly when the output is closed, e.g. when piped into head
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+                Err(e) => return Err(format!("Failed to write update: {}", e)),
+            }
+
+            line = next_line(&mut lines).await?;
         }
-    };
+    }
 
-    let exit_code = match cli.command {
-        Command::Peers => {
-            let mut msg = Vec::with_capacity(16 * 1024);
-            match ntp_daemon::sockets::read_json::<ObservableState>(&mut stream, &mut msg).await {
-                Ok(output) => {
-                    // Unwrap here is fine as our serializer is infallible.
-                    println!("{}", serde_json::to_string_pretty(&output.peers).unwrap());
-
-                    0
-                }
-                Err(e) => {
-                    eprintln!("Failed to read state from observation socket: {}", e);
+    async fn history(&self, peer: String) -> Result<Vec<PeerHistory>, String> {
+        let observation = match self {
+            Connection::Local { observation, .. } => observation,
+            Connection::Remote(_) => {
+                return Err("History is not supported over remote management".to_string())
+            }
+        };
+
+        let mut stream = connect(observation).await?;
+        ntp_daemon::sockets::write_json(&mut stream, &ObserveRequest::History { peer })
+            .await
+            .map_err(|e| format!("Failed to request history: {}", e))?;
+
+        // The current state comes first, the history follows it
+        let mut lines = BufReader::new(stream).lines();
+        next_line(&mut lines).await?;
+        serde_json::from_str(&next_line(&mut lines).await?)
+            .map_err(|e| format!("Failed to read history from observation socket: {}", e))
+    }
+
+    async fn configure(&self, config_update: ConfigUpdate) -> Result<ConfigResponse, String> {
+        let request = ConfigRequest::new(config_update);
 
-                    1
+        match self {
+            Connection::Local { configuration, .. } => {
+                let mut stream = connect(configuration).await?;
+                ntp_daemon::sockets::write_json(&mut stream, &request)
+                    .await
+                    .map_err(|e| format!("Failed to update configuration: {}", e))?;
+
+                let mut msg = Vec::with_capacity(16 * 1024);
+                ntp_daemon::sockets::read_json(&mut stream, &mut msg)
+                    .await
+                    .map_err(|e| {
+                        format!("Failed to read response from configuration socket: {}", e)
+                    })
+            }
+            Connection::Remote(client) => {
+                match client.request(RemoteOperation::Configure(request)).await {
+                    Ok(RemoteResponse::Configure(response)) => Ok(response),
+                    other => Err(remote_error(other)),
                 }
             }
         }
-        Command::System => {
-            let mut msg = Vec::with_capacity(16 * 1024);
-            match ntp_daemon::sockets::read_json::<ObservableState>(&mut stream, &mut msg).await {
-                Ok(output) => {
-                    // Unwrap here is fine as our serializer is infallible.
-                    println!("{}", serde_json::to_string_pretty(&output.system).unwrap());
-
-                    0
-                }
-                Err(e) => {
-                    eprintln!("Failed to read state from observation socket: {}", e);
+    }
+}
+
+async fn connect(path: &Path) -> Result<tokio::net::UnixStream, String> {
+    tokio::net::UnixStream::connect(path)
+        .await
+        .map_err(|e| format!("Could not open socket at {}: {}", path.display(), e))
+}
 
-                    1
+async fn next_line(lines: &mut Lines<BufReader<tokio::net::UnixStream>>) -> Result<String, String> {
+    match lines.next_line().await {
+        Ok(Some(line)) => Ok(line),
+        Ok(None) => Err("The daemon closed the connection".to_string()),
+        Err(e) => Err(format!("Failed to read from observation socket: {}", e)),
+    }
+}
+
+fn remote_error(response: std::io::Result<RemoteResponse>) -> String {
+    match response {
+        Ok(RemoteResponse::Error(e)) => format!("Request refused by remote endpoint: {}", e),
+        Ok(_) => "Unexpected response from remote endpoint".to_string(),
+        Err(e) => format!("Failed to communicate with remote endpoint: {}", e),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    let connection = match (cli.remote, cli.remote_ca) {
+        (Some(address), Some(ca)) => {
+            let client_certificate = cli.client_cert.as_deref().zip(cli.client_key.as_deref());
+            let client = RemoteClient::new(
+                &address,
+                cli.remote_name.as_deref(),
+                &ca,
+                client_certificate,
+                cli.token,
+            );
+            match client {
+                Ok(client) => Connection::Remote(client),
+                Err(e) => {
+                    eprintln!("Could not set up connection to {}: {}", address, e);
+                    std::process::exit(1);
                 }
             }
         }
-        Command::Prometheus => {
-            let mut stream = tokio::net::UnixStream::connect(observation).await?;
+        _ => {
+            let config = Config::from_args(cli.config, vec![], vec![]).await;
+
+            if let Err(ref e) = config {
+                println!("Warning: Unable to load configuration file: {}", e);
+            }
+
+            let config = config.unwrap_or_default();
+
+            let observation = match cli.observation_socket {
+                Some(path) => path,
+                None => match config.observe.path {
+                    Some(path) => path,
+                    None => "/run/ntpd-rs/observe".into(),
+                },
+            };
+
+            let configuration = match cli.configuration_socket {
+                Some(path) => path,
+                None => match config.configure.path {
+                    Some(path) => path,
+                    None => "/run/ntpd-rs/configure".into(),
+                },
+            };
+
+            Connection::Local {
+                observation,
+                configuration,
+            }
+        }
+    };
+
+    let exit_code = match cli.command {
+        Command::Peers { json } => match connection.observe().await {
+            Ok(output) if json => {
+                // Unwrap here is fine as our serializer is infallible.
+                println!("{}", serde_json::to_string_pretty(&output.peers).unwrap());
+
+                0
+            }
+            Ok(output) => {
+                print!("{}", format::peers(&output));
+
+                0
+            }
+            Err(e) => {
+                eprintln!("{}", e);
 
-            let mut msg = Vec::with_capacity(16 * 1024);
-            let output: ObservableState =
-                ntp_daemon::sockets::read_json(&mut stream, &mut msg).await?;
+                1
+            }
+        },
+        Command::System { json } => match connection.observe().await {
+            Ok(output) if json => {
+                // Unwrap here is fine as our serializer is infallible.
+                println!("{}", serde_json::to_string_pretty(&output.system).unwrap());
+
+                0
+            }
+            Ok(output) => {
+                print!("{}", format::system(&output));
+
+                0
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+
+                1
+            }
+        },
+        Command::Prometheus => {
+            let output = match connection.observe().await {
+                Ok(output) => output,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
 
             let metrics = Metrics::default();
             metrics.fill(&output);
@@ -131,17 +391,105 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             0
         }
-        Command::Config(config_update) => {
-            match ntp_daemon::sockets::write_json(&mut stream, &config_update).await {
-                Ok(_) => 0,
-                Err(e) => {
-                    eprintln!("Failed to update configuration: {}", e);
+        Command::Watch { deltas } => match connection.watch(deltas).await {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{}", e);
 
-                    1
-                }
+                1
             }
+        },
+        Command::History { peer } => match connection.history(peer.clone()).await {
+            Ok(history) if history.is_empty() => {
+                eprintln!("No peer with address {}", peer);
+
+                1
+            }
+            Ok(history) => {
+                // Unwrap here is fine as our serializer is infallible.
+                println!("{}", serde_json::to_string_pretty(&history).unwrap());
+
+                0
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+
+                1
+            }
+        },
+        Command::Config { update, json } => update_config(&connection, update, json).await,
+        Command::Reload { json } => {
+            let config_update = ConfigUpdate {
+                reload: true,
+                ..Default::default()
+            };
+            update_config(&connection, config_update, json).await
+        }
+        Command::Peer(PeerCommand::Add { address, json }) => {
+            let config_update = ConfigUpdate {
+                add_peers: vec![address],
+                ..Default::default()
+            };
+            update_config(&connection, config_update, json).await
+        }
+        Command::Peer(PeerCommand::Remove { address, json }) => {
+            let config_update = ConfigUpdate {
+                remove_peers: vec![address],
+                ..Default::default()
+            };
+            update_config(&connection, config_update, json).await
+        }
+        Command::Pool(PoolCommand::Add {
+            address,
+            max_peers,
+            json,
+        }) => {
+            let config_update = ConfigUpdate {
+                add_pools: vec![PoolUpdate {
+                    addr: address,
+                    max_peers,
+                }],
+                ..Default::default()
+            };
+            update_config(&connection, config_update, json).await
         }
     };
 
     std::process::exit(exit_code);
 }
+
+async fn update_config(connection: &Connection, config_update: ConfigUpdate, json: bool) -> i32 {
+    let response = match connection.configure(config_update).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    if json {
+        // Unwrap here is fine as our serializer is infallible.
+        println!("{}", serde_json::to_string_pretty(&response).unwrap());
+
+        for result in &response.results {
+            if let Some(error) = &result.error {
+                eprintln!(
+                    "Failed to change {} to {}: {}",
+                    result.field, result.value, error
+                );
+            }
+        }
+    } else {
+        print!("{}", format::config_response(&response));
+    }
+
+    if let Some(error) = &response.error {
+        eprintln!("Configuration request rejected: {}", error);
+    }
+
+    if response.is_success() {
+        0
+    } else {
+        1
+    }
+}
@@ -0,0 +1,245 @@
+//! Authorization of connections to the control sockets, based on the
+//! credentials of the connecting process (`SO_PEERCRED`). This comes on top of
+//! the file permissions of the sockets, which can't distinguish between
+//! individual users without changing the ownership of the socket.
+
+use serde::Deserialize;
+use tokio::net::UnixStream;
+use tracing::warn;
+
+use crate::config::{ConfigureConfig, ObserveConfig};
+
+/// What a connection is used for: reading the state of the daemon through
+/// the observe socket, or changing it through the configure socket. Roles are
+/// ordered, those that may write may also read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Write,
+}
+
+/// Users and groups allowed to take a role. An empty list puts no
+/// restrictions beyond the permissions of the socket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowList {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+}
+
+impl AllowList {
+    fn is_restricted(&self) -> bool {
+        !self.uids.is_empty() || !self.gids.is_empty()
+    }
+
+    fn contains(&self, uid: u32, gids: &[u32]) -> bool {
+        self.uids.contains(&uid) || gids.iter().any(|gid| self.gids.contains(gid))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Access {
+    read: AllowList,
+    write: AllowList,
+    /// The user the daemon runs as, which always has access, like root
+    own_uid: u32,
+}
+
+impl Access {
+    /// Must be created after privileges are dropped, see [`crate::privileges`]
+    pub fn new(read: AllowList, write: AllowList) -> Self {
+        // Safety: geteuid has no memory safety requirements
+        let own_uid = unsafe { libc::geteuid() };
+
+        Access {
+            read,
+            write,
+            own_uid,
+        }
+    }
+
+    pub fn from_config(observe: &ObserveConfig, configure: &ConfigureConfig) -> Self {
+        Access::new(
+            AllowList {
+                uids: observe.allowed_uids.clone(),
+                gids: observe.allowed_gids.clone(),
+            },
+            AllowList {
+                uids: configure.allowed_uids.clone(),
+                gids: configure.allowed_gids.clone(),
+            },
+        )
+    }
+
+    /// Whether the process at the other end of `stream` may take `role`,
+    /// logs a warning when it may not
+    pub fn check(&self, role: Role, stream: &UnixStream) -> bool {
+        if !self.is_restricted(role) {
+            return true;
+        }
+
+        let credentials = match stream.peer_cred() {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                warn!(?role, "could not get credentials of connection: {}", e);
+                return false;
+            }
+        };
+
+        let uid = credentials.uid();
+        let mut gids = vec![credentials.gid()];
+        if let Some(pid) = credentials.pid() {
+            gids.extend(supplementary_groups(pid));
+        }
+
+        let allowed = self.allows(role, uid, &gids);
+        if !allowed {
+            warn!(
+                ?role,
+                uid,
+                gid = credentials.gid(),
+                pid = credentials.pid(),
+                "refused connection to control socket"
+            );
+        }
+        allowed
+    }
+
+    fn is_restricted(&self, role: Role) -> bool {
+        match role {
+            Role::Read => self.read.is_restricted(),
+            Role::Write => self.write.is_restricted(),
+        }
+    }
+
+    fn allows(&self, role: Role, uid: u32, gids: &[u32]) -> bool {
+        if uid == 0 || uid == self.own_uid {
+            return true;
+        }
+
+        match role {
+            // Those that may change the configuration may also read the state
+            Role::Read => {
+                !self.read.is_restricted()
+                    || self.read.contains(uid, gids)
+                    || (self.write.is_restricted() && self.write.contains(uid, gids))
+            }
+            Role::Write => !self.write.is_restricted() || self.write.contains(uid, gids),
+        }
+    }
+}
+
+/// The supplementary groups of a process, which `SO_PEERCRED` does not include
+fn supplementary_groups(pid: libc::pid_t) -> Vec<u32> {
+    let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        Err(_) => return vec![],
+    };
+
+    parse_groups(&status)
+}
+
+fn parse_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(read: AllowList, write: AllowList) -> Access {
+        Access {
+            read,
+            write,
+            own_uid: 100,
+        }
+    }
+
+    #[test]
+    fn test_unrestricted() {
+        let access = access(AllowList::default(), AllowList::default());
+
+        assert!(access.allows(Role::Read, 1000, &[1000]));
+        assert!(access.allows(Role::Write, 1000, &[1000]));
+    }
+
+    #[test]
+    fn test_roles() {
+        let access = access(
+            AllowList {
+                uids: vec![1000],
+                gids: vec![2000],
+            },
+            AllowList {
+                uids: vec![1001],
+                gids: vec![],
+            },
+        );
+
+        // root and the daemon itself are always allowed
+        assert!(access.allows(Role::Write, 0, &[0]));
+        assert!(access.allows(Role::Write, 100, &[100]));
+
+        // readers
+        assert!(access.allows(Role::Read, 1000, &[1000]));
+        assert!(!access.allows(Role::Write, 1000, &[1000]));
+        assert!(access.allows(Role::Read, 1002, &[1002, 2000]));
+        assert!(!access.allows(Role::Read, 1002, &[1002]));
+
+        // writers may also read
+        assert!(access.allows(Role::Write, 1001, &[1001]));
+        assert!(access.allows(Role::Read, 1001, &[1001]));
+    }
+
+    #[test]
+    fn test_only_write_restricted() {
+        let access = access(
+            AllowList::default(),
+            AllowList {
+                uids: vec![],
+                gids: vec![2000],
+            },
+        );
+
+        assert!(access.allows(Role::Read, 1000, &[1000]));
+        assert!(!access.allows(Role::Write, 1000, &[1000]));
+        assert!(access.allows(Role::Write, 1000, &[1000, 2000]));
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let status =
+            "Name:\tntp-ctl\nUid:\t1000\t1000\t1000\t1000\nGroups:\t4 24 1000 \nVmPeak:\t0 kB\n";
+        assert_eq!(parse_groups(status), vec![4, 24, 1000]);
+        assert_eq!(parse_groups("Groups:\t\n"), Vec::<u32>::new());
+        assert_eq!(parse_groups(""), Vec::<u32>::new());
+    }
+
+    #[tokio::test]
+    async fn test_check_own_connection() {
+        let (a, _b) = UnixStream::pair().unwrap();
+
+        // we are allowed as the user the daemon runs as
+        let access = Access::new(
+            AllowList {
+                uids: vec![u32::MAX - 1],
+                gids: vec![],
+            },
+            AllowList {
+                uids: vec![u32::MAX - 1],
+                gids: vec![],
+            },
+        );
+        assert!(access.check(Role::Read, &a));
+        assert!(access.check(Role::Write, &a));
+    }
+}
@@ -1,16 +1,19 @@
-use crate::sockets::create_unix_socket;
+use crate::access::{Access, Role};
+use crate::config::{ConfigError, ConfigSource, PeerConfig, PoolPeerConfig, StandardPeerConfig};
+use crate::sockets::into_tokio_listener;
+use crate::system::{CommandReply, SystemCommand};
 use crate::tracing::ReloadHandle;
 use ntp_proto::{NtpDuration, StepThreshold, SystemConfig};
-use std::os::unix::fs::PermissionsExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task::JoinHandle;
-use tracing::error;
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
 use clap::Args;
 use serde::{Deserialize, Serialize};
 
-use super::ConfigureConfig;
-
 fn parse_env_filter(input: &str) -> Result<String, tracing_subscriber::filter::ParseError> {
     // run the parser to error on any invalid input
     let _ = EnvFilter::builder().with_regex(false).parse(input)?;
@@ -19,7 +22,7 @@ fn parse_env_filter(input: &str) -> Result<String, tracing_subscriber::filter::P
     Ok(input.to_string())
 }
 
-#[derive(Debug, Args, Serialize, Deserialize)]
+#[derive(Debug, Default, Args, Serialize, Deserialize)]
 pub struct ConfigUpdate {
     /// Change the log filter
     #[arg(long, value_parser = parse_env_filter)]
@@ -34,26 +37,156 @@ pub struct ConfigUpdate {
     /// during startup, use startup_panic_threshold
     #[arg(long)]
     pub panic_threshold: Option<f64>,
+
+    /// Read the configuration file again and apply it, like on SIGHUP
+    #[arg(skip)]
+    #[serde(default)]
+    pub reload: bool,
+
+    /// Addresses of peers to add
+    #[arg(skip)]
+    #[serde(default)]
+    pub add_peers: Vec<String>,
+
+    /// Pools to add
+    #[arg(skip)]
+    #[serde(default)]
+    pub add_pools: Vec<PoolUpdate>,
+
+    /// Addresses of peers and pools to remove
+    #[arg(skip)]
+    #[serde(default)]
+    pub remove_peers: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolUpdate {
+    pub addr: String,
+    pub max_peers: usize,
+}
+
+/// Version of the request and response format of the configure socket
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A request on the configure socket
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigRequest {
+    pub version: u32,
+    #[serde(flatten)]
+    pub update: ConfigUpdate,
+}
+
+impl ConfigRequest {
+    pub fn new(update: ConfigUpdate) -> Self {
+        ConfigRequest {
+            version: PROTOCOL_VERSION,
+            update,
+        }
+    }
+}
+
+/// The reply to a [`ConfigRequest`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigResponse {
+    pub version: u32,
+    /// Why the request as a whole was rejected, in which case nothing changed
+    pub error: Option<String>,
+    /// Outcome of each requested change, in the order they were applied
+    pub results: Vec<ChangeResult>,
+    /// The configuration after handling the request, absent when the daemon
+    /// is shutting down or the request was not allowed
+    pub config: Option<EffectiveConfig>,
+}
+
+impl ConfigResponse {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && self.results.iter().all(|result| result.error.is_none())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeResult {
+    /// The field of [`ConfigUpdate`] this change was requested through
+    pub field: String,
+    pub value: String,
+    /// Why the change was not applied, absent when it was
+    pub error: Option<String>,
+}
+
+impl ChangeResult {
+    fn new(field: &str, value: impl ToString, result: Result<(), String>) -> Self {
+        ChangeResult {
+            field: field.to_string(),
+            value: value.to_string(),
+            error: result.err(),
+        }
+    }
+}
+
+/// The parts of the configuration that can be changed at runtime, as they are
+/// currently in effect
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EffectiveConfig {
+    pub log_filter: String,
+    /// In seconds, absent when there is no limit
+    pub panic_threshold_forward: Option<f64>,
+    /// In seconds, absent when there is no limit
+    pub panic_threshold_backward: Option<f64>,
+    pub peers: Vec<String>,
+    pub pools: Vec<PoolUpdate>,
 }
 
 // Deal with reloading not being possible during testing.
 pub trait LogReloader {
     fn update_log(&self, f: EnvFilter);
+
+    fn current_log(&self) -> String;
 }
 
 impl LogReloader for ReloadHandle {
     fn update_log(&self, f: EnvFilter) {
         self.modify(|l| *l.filter_mut() = f).unwrap();
     }
+
+    fn current_log(&self) -> String {
+        self.with_current(|l| l.filter().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Seconds of a duration rounded to 9 significant digits, so that values are
+/// reported as they were set. Converting to and from `NtpDuration` is off by
+/// a factor of about 1 + 2^-32, which shows as e.g. 1000.0000002328306.
+fn rounded_seconds(duration: NtpDuration) -> f64 {
+    let seconds = duration.to_seconds();
+    format!("{:.8e}", seconds).parse().unwrap_or(seconds)
 }
 
+/// Accept configuration updates on `listener`, see
+/// [`crate::sockets::listen_unix_socket`], and from the remote management
+/// endpoint through `remote_receiver`, see [`crate::remote`]. Reloads the
+/// configuration from `config_source` on SIGHUP.
 pub async fn spawn<H: LogReloader + Send + 'static>(
-    config: ConfigureConfig,
-    system_config_sender: tokio::sync::watch::Sender<SystemConfig>,
+    listener: Option<std::os::unix::net::UnixListener>,
+    access: Access,
+    remote_receiver: mpsc::Receiver<(ConfigRequest, oneshot::Sender<ConfigResponse>)>,
+    config_source: ConfigSource,
+    system_config_sender: watch::Sender<SystemConfig>,
+    command_sender: mpsc::Sender<(SystemCommand, oneshot::Sender<CommandReply>)>,
     log_reload_handle: H,
 ) -> JoinHandle<std::io::Result<()>> {
+    // Install the signal handler right away, so that SIGHUP never terminates the daemon
+    let hangup = signal(SignalKind::hangup());
+
     tokio::spawn(async move {
-        let result = dynamic_configuration(config, system_config_sender, log_reload_handle).await;
+        let configurator = DynamicConfigurator {
+            access,
+            config_source,
+            system_config_sender,
+            command_sender,
+            log_reload_handle,
+        };
+        let result = configurator.run(listener, remote_receiver, hangup).await;
         if let Err(ref e) = result {
             error!("Abnormal termination of dynamic configurator: {}", e);
         }
@@ -61,59 +194,335 @@ pub async fn spawn<H: LogReloader + Send + 'static>(
     })
 }
 
-async fn dynamic_configuration<H: LogReloader>(
-    config: ConfigureConfig,
-    system_config_sender: tokio::sync::watch::Sender<SystemConfig>,
+struct DynamicConfigurator<H> {
+    access: Access,
+    config_source: ConfigSource,
+    system_config_sender: watch::Sender<SystemConfig>,
+    command_sender: mpsc::Sender<(SystemCommand, oneshot::Sender<CommandReply>)>,
     log_reload_handle: H,
-) -> std::io::Result<()> {
-    let path = match config.path {
-        Some(path) => path,
-        None => return Ok(()),
-    };
+}
+
+impl<H: LogReloader> DynamicConfigurator<H> {
+    async fn run(
+        mut self,
+        listener: Option<std::os::unix::net::UnixListener>,
+        mut remote_receiver: mpsc::Receiver<(ConfigRequest, oneshot::Sender<ConfigResponse>)>,
+        hangup: std::io::Result<Signal>,
+    ) -> std::io::Result<()> {
+        let listener = listener.map(into_tokio_listener).transpose()?;
+        let mut hangup = hangup?;
 
-    let peers_listener = create_unix_socket(&path)?;
+        let mut msg = Vec::with_capacity(16 * 1024);
 
-    // this binary needs to run as root to be able to adjust the system clock.
-    // by default, the socket inherits root permissions, but the client should not need
-    // elevated permissions to read from the socket. So we explicitly set the permissions
-    let permissions: std::fs::Permissions = PermissionsExt::from_mode(config.mode);
-    std::fs::set_permissions(&path, permissions)?;
+        loop {
+            tokio::select! {
+                accepted = accept(listener.as_ref()) => {
+                    let (mut stream, _addr) = accepted?;
 
-    let mut msg = Vec::with_capacity(16 * 1024);
+                    // Read the request either way, so that the client gets to see the response
+                    let request = crate::sockets::read_json(&mut stream, &mut msg).await;
+                    let response = if self.access.check(Role::Write, &stream) {
+                        self.handle_request(request).await
+                    } else {
+                        ConfigResponse {
+                            version: PROTOCOL_VERSION,
+                            error: Some("permission denied".into()),
+                            results: vec![],
+                            config: None,
+                        }
+                    };
 
-    loop {
-        let (mut stream, _addr) = peers_listener.accept().await?;
+                    if let Err(e) = crate::sockets::write_json(&mut stream, &response).await {
+                        warn!("Could not send configuration response: {}", e);
+                    }
+                }
+                // Already authorized by the remote endpoint
+                Some((request, reply)) = remote_receiver.recv() => {
+                    let _ = reply.send(self.handle_request(Ok(request)).await);
+                }
+                _ = hangup.recv() => {
+                    info!("received SIGHUP");
+                    let _ = self.reload().await;
+                }
+            }
+        }
+    }
 
-        let operation: ConfigUpdate = crate::sockets::read_json(&mut stream, &mut msg).await?;
+    async fn handle_request(&mut self, request: std::io::Result<ConfigRequest>) -> ConfigResponse {
+        let mut results = vec![];
 
+        let error = match request {
+            Err(e) => Some(format!("invalid request: {}", e)),
+            Ok(request) if request.version != PROTOCOL_VERSION => Some(format!(
+                "unsupported protocol version {}, expected {}",
+                request.version, PROTOCOL_VERSION
+            )),
+            Ok(request) => {
+                results = self.handle_update(request.update).await;
+                None
+            }
+        };
+        if let Some(error) = &error {
+            warn!("Rejected configuration request: {}", error);
+        }
+
+        ConfigResponse {
+            version: PROTOCOL_VERSION,
+            error,
+            results,
+            config: self.effective_config().await,
+        }
+    }
+
+    async fn handle_update(&mut self, operation: ConfigUpdate) -> Vec<ChangeResult> {
         tracing::info!(?operation, "dynamic config update");
 
-        if let Some(filter) = operation.log_filter {
-            log_reload_handle.update_log(EnvFilter::new(filter));
+        let mut results = vec![];
+
+        // Reload first, so that the other changes are applied on top of the new configuration
+        if operation.reload {
+            results.push(ChangeResult::new("reload", true, self.reload().await));
         }
 
-        if let Some(panic_threshold) = operation.panic_threshold {
-            system_config_sender.send_modify(|config| {
-                config.panic_threshold = StepThreshold {
-                    forward: Some(NtpDuration::from_seconds(panic_threshold)),
-                    backward: Some(NtpDuration::from_seconds(panic_threshold)),
+        let mut remove_peers = vec![];
+        for addr in operation.remove_peers {
+            match parse_peer(&addr) {
+                Ok(config) => remove_peers.push((addr, config.addr)),
+                Err(e) => results.push(ChangeResult::new("remove_peers", addr, Err(e))),
+            }
+        }
+        if !remove_peers.is_empty() {
+            let addresses = remove_peers.iter().map(|(_, address)| address.clone());
+            let reply = self
+                .send_command(SystemCommand::RemovePeers(addresses.collect()))
+                .await;
+            for (addr, address) in remove_peers {
+                let result = match &reply {
+                    Ok(reply) if reply.not_found.contains(&address) => {
+                        Err("no peer or pool with this address".to_string())
+                    }
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.clone()),
                 };
+                results.push(ChangeResult::new("remove_peers", addr, result));
+            }
+        }
+
+        let mut add_peers = vec![];
+        for addr in operation.add_peers {
+            match parse_peer(&addr) {
+                Ok(config) => add_peers.push(("add_peers", addr, PeerConfig::Standard(config))),
+                Err(e) => results.push(ChangeResult::new("add_peers", addr, Err(e))),
+            }
+        }
+        for pool in operation.add_pools {
+            let config = parse_peer(&pool.addr).and_then(|config| {
+                if pool.max_peers == 0 {
+                    return Err("max_peers must be at least 1".to_string());
+                }
+                Ok(PeerConfig::Pool(PoolPeerConfig {
+                    addr: config.addr,
+                    max_peers: pool.max_peers,
+                    options: config.options,
+                }))
             });
+            match config {
+                Ok(config) => add_peers.push(("add_pools", pool.addr, config)),
+                Err(e) => results.push(ChangeResult::new("add_pools", pool.addr, Err(e))),
+            }
+        }
+        if !add_peers.is_empty() {
+            let configs = add_peers.iter().map(|(_, _, config)| config.clone());
+            let reply = self
+                .send_command(SystemCommand::AddPeers(configs.collect()))
+                .await;
+            for (field, addr, _) in add_peers {
+                let result = reply.as_ref().map(|_| ()).map_err(Clone::clone);
+                results.push(ChangeResult::new(field, addr, result));
+            }
+        }
+
+        if let Some(filter) = operation.log_filter {
+            let result = match EnvFilter::builder().with_regex(false).parse(&filter) {
+                Ok(env_filter) => {
+                    self.log_reload_handle.update_log(env_filter);
+                    Ok(())
+                }
+                Err(e) => Err(e.to_string()),
+            };
+            results.push(ChangeResult::new("log_filter", filter, result));
+        }
+
+        if let Some(panic_threshold) = operation.panic_threshold {
+            let result = if panic_threshold.is_finite() && panic_threshold >= 0.0 {
+                self.system_config_sender.send_modify(|config| {
+                    config.panic_threshold = StepThreshold {
+                        forward: Some(NtpDuration::from_seconds(panic_threshold)),
+                        backward: Some(NtpDuration::from_seconds(panic_threshold)),
+                    };
+                });
+                Ok(())
+            } else {
+                Err("must be a non-negative number of seconds".to_string())
+            };
+            results.push(ChangeResult::new(
+                "panic_threshold",
+                panic_threshold,
+                result,
+            ));
+        }
+
+        results
+    }
+
+    /// Read the configuration again and apply it. When it cannot be loaded
+    /// nothing changes, and the daemon keeps running with what it has.
+    async fn reload(&mut self) -> Result<(), String> {
+        match self.try_reload().await {
+            Ok(()) => {
+                info!("reloaded configuration");
+                Ok(())
+            }
+            Err(e) => {
+                error!(
+                    "Could not reload configuration, keeping the current one: {}",
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+
+    async fn try_reload(&mut self) -> Result<(), String> {
+        let config = self
+            .config_source
+            .load()
+            .await
+            .map_err(|e: ConfigError| e.to_string())?;
+        config.check();
+
+        self.system_config_sender
+            .send_modify(|system| *system = config.system);
+
+        self.send_command(SystemCommand::Reload {
+            peers: config.peers,
+            servers: config.servers,
+            refclocks: config.refclocks,
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Have the main loop handle a command, and wait until it has
+    async fn send_command(&mut self, command: SystemCommand) -> Result<CommandReply, String> {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let not_running = || "the daemon is shutting down".to_string();
+
+        self.command_sender
+            .send((command, reply_sender))
+            .await
+            .map_err(|_| not_running())?;
+        reply_receiver.await.map_err(|_| not_running())
+    }
+
+    async fn effective_config(&mut self) -> Option<EffectiveConfig> {
+        let reply = self.send_command(SystemCommand::ListPeers).await.ok()?;
+        let panic_threshold = self.system_config_sender.borrow().panic_threshold;
+
+        let mut peers = vec![];
+        let mut pools = vec![];
+        for config in reply.peers {
+            match config {
+                PeerConfig::Standard(config) => peers.push(config.addr.as_str().to_string()),
+                PeerConfig::Pool(config) => pools.push(PoolUpdate {
+                    addr: config.addr.as_str().to_string(),
+                    max_peers: config.max_peers,
+                }),
+            }
         }
+
+        Some(EffectiveConfig {
+            log_filter: self.log_reload_handle.current_log(),
+            panic_threshold_forward: panic_threshold.forward.map(rounded_seconds),
+            panic_threshold_backward: panic_threshold.backward.map(rounded_seconds),
+            peers,
+            pools,
+        })
+    }
+}
+
+fn parse_peer(addr: &str) -> Result<StandardPeerConfig, String> {
+    StandardPeerConfig::try_from(addr).map_err(|e| format!("invalid address: {}", e))
+}
+
+/// Accept a connection, never completes when there is no listener
+async fn accept(
+    listener: Option<&UnixListener>,
+) -> std::io::Result<(UnixStream, tokio::net::unix::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::path::Path;
 
-    use crate::sockets::write_json;
+    use crate::sockets::{read_json, write_json};
 
     use super::*;
 
     struct TestLogReloader {}
     impl LogReloader for TestLogReloader {
         fn update_log(&self, _f: EnvFilter) {}
+
+        fn current_log(&self) -> String {
+            "info".into()
+        }
+    }
+
+    /// Stand-in for the main loop, that knows no peers to remove and keeps track of added ones
+    fn spawn_system() -> mpsc::Sender<(SystemCommand, oneshot::Sender<CommandReply>)> {
+        let (command_sender, mut command_receiver) =
+            mpsc::channel::<(SystemCommand, oneshot::Sender<CommandReply>)>(1);
+
+        tokio::spawn(async move {
+            let mut peers = vec![];
+            while let Some((command, reply)) = command_receiver.recv().await {
+                let not_found = match command {
+                    SystemCommand::RemovePeers(addresses) => addresses,
+                    SystemCommand::AddPeers(configs) => {
+                        peers.extend(configs);
+                        vec![]
+                    }
+                    _ => vec![],
+                };
+                let _ = reply.send(CommandReply {
+                    not_found,
+                    peers: peers.clone(),
+                });
+            }
+        });
+
+        command_sender
+    }
+
+    async fn request(path: &Path, request: &impl Serialize) -> ConfigResponse {
+        let mut stream = tokio::net::UnixStream::connect(path).await.unwrap();
+        write_json(&mut stream, request).await.unwrap();
+
+        let mut msg = Vec::with_capacity(16 * 1024);
+        read_json(&mut stream, &mut msg).await.unwrap()
+    }
+
+    #[test]
+    fn test_rounded_seconds() {
+        assert_eq!(rounded_seconds(NtpDuration::from_seconds(1000.)), 1000.);
+        assert_eq!(rounded_seconds(NtpDuration::from_seconds(0.125)), 0.125);
+        assert_eq!(rounded_seconds(NtpDuration::from_seconds(-2.5)), -2.5);
+        assert_eq!(rounded_seconds(NtpDuration::from_seconds(86400.)), 86400.);
     }
 
     #[tokio::test]
@@ -122,31 +531,50 @@ mod tests {
             tokio::sync::watch::channel(SystemConfig::default());
 
         let path = std::env::temp_dir().join("ntp-test-stream-4");
-        let config = ConfigureConfig {
-            path: Some(path.clone()),
-            mode: 0o700,
-        };
-
-        let handle = spawn(config, system_config_sender, TestLogReloader {}).await;
+        let listener = crate::sockets::create_unix_socket(&path).unwrap();
 
-        // Ensure client has started.
-        tokio::time::sleep(Duration::from_millis(10)).await;
-
-        let mut stream = tokio::net::UnixStream::connect(path).await.unwrap();
+        let handle = spawn(
+            Some(listener),
+            Access::new(Default::default(), Default::default()),
+            mpsc::channel(1).1,
+            ConfigSource::default(),
+            system_config_sender,
+            spawn_system(),
+            TestLogReloader {},
+        )
+        .await;
 
-        write_json(
-            &mut stream,
-            &ConfigUpdate {
+        let response = request(
+            &path,
+            &ConfigRequest::new(ConfigUpdate {
                 log_filter: Some("info".into()),
                 panic_threshold: Some(600.),
-            },
+                ..Default::default()
+            }),
         )
-        .await
-        .unwrap();
+        .await;
+
+        assert!(response.is_success());
+        assert_eq!(response.results.len(), 2);
+        let forward = response.config.unwrap().panic_threshold_forward.unwrap();
+        assert_eq!(forward, 600.);
+        assert_eq!(
+            system_config_receiver.borrow().panic_threshold.forward,
+            Some(NtpDuration::from_seconds(600.))
+        );
 
-        // Ensure message is handled.
-        tokio::time::sleep(Duration::from_millis(10)).await;
+        let response = request(
+            &path,
+            &ConfigRequest::new(ConfigUpdate {
+                log_filter: Some("[".into()),
+                panic_threshold: Some(-1.),
+                ..Default::default()
+            }),
+        )
+        .await;
 
+        assert!(!response.is_success());
+        assert!(response.results.iter().all(|r| r.error.is_some()));
         assert_eq!(
             system_config_receiver.borrow().panic_threshold.forward,
             Some(NtpDuration::from_seconds(600.))
@@ -154,4 +582,109 @@ mod tests {
 
         handle.abort();
     }
+
+    #[tokio::test]
+    async fn test_dynamic_peer_change() {
+        let (system_config_sender, _system_config_receiver) =
+            tokio::sync::watch::channel(SystemConfig::default());
+
+        let path = std::env::temp_dir().join("ntp-test-stream-9");
+        let listener = crate::sockets::create_unix_socket(&path).unwrap();
+
+        let handle = spawn(
+            Some(listener),
+            Access::new(Default::default(), Default::default()),
+            mpsc::channel(1).1,
+            ConfigSource::default(),
+            system_config_sender,
+            spawn_system(),
+            TestLogReloader {},
+        )
+        .await;
+
+        let response = request(
+            &path,
+            &ConfigRequest::new(ConfigUpdate {
+                add_peers: vec!["127.0.0.1".into(), "invalid:port".into()],
+                add_pools: vec![
+                    PoolUpdate {
+                        addr: "pool.example.com".into(),
+                        max_peers: 2,
+                    },
+                    PoolUpdate {
+                        addr: "empty.example.com".into(),
+                        max_peers: 0,
+                    },
+                ],
+                remove_peers: vec!["127.0.0.2:456".into()],
+                ..Default::default()
+            }),
+        )
+        .await;
+
+        assert!(!response.is_success());
+        let failed: Vec<_> = response
+            .results
+            .iter()
+            .filter(|result| result.error.is_some())
+            .map(|result| (result.field.as_str(), result.value.as_str()))
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                ("remove_peers", "127.0.0.2:456"),
+                ("add_peers", "invalid:port"),
+                ("add_pools", "empty.example.com"),
+            ]
+        );
+
+        let config = response.config.unwrap();
+        assert_eq!(config.peers, vec!["127.0.0.1:123"]);
+        assert_eq!(
+            config.pools,
+            vec![PoolUpdate {
+                addr: "pool.example.com:123".into(),
+                max_peers: 2
+            }]
+        );
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request() {
+        let (system_config_sender, _system_config_receiver) =
+            tokio::sync::watch::channel(SystemConfig::default());
+
+        let path = std::env::temp_dir().join("ntp-test-stream-10");
+        let listener = crate::sockets::create_unix_socket(&path).unwrap();
+
+        let handle = spawn(
+            Some(listener),
+            Access::new(Default::default(), Default::default()),
+            mpsc::channel(1).1,
+            ConfigSource::default(),
+            system_config_sender,
+            spawn_system(),
+            TestLogReloader {},
+        )
+        .await;
+
+        let response = request(&path, &"garbage").await;
+        assert!(response.error.is_some());
+
+        // requests in an unknown format are rejected as a whole
+        let mut request_v2 = ConfigRequest::new(ConfigUpdate {
+            panic_threshold: Some(600.),
+            ..Default::default()
+        });
+        request_v2.version = PROTOCOL_VERSION + 1;
+        let response = request(&path, &request_v2).await;
+        assert!(response.error.is_some());
+        assert!(response.results.is_empty());
+        let forward = response.config.unwrap().panic_threshold_forward.unwrap();
+        assert!((forward - 600.).abs() > 1.);
+
+        handle.abort();
+    }
 }
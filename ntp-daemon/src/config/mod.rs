@@ -1,10 +1,12 @@
 pub mod dynamic;
 pub mod format;
 mod peer;
+mod refclock;
 mod server;
 pub mod subnet;
 
 pub use peer::*;
+pub use refclock::*;
 pub use server::*;
 
 use clap::Parser;
@@ -12,6 +14,7 @@ use ntp_proto::SystemConfig;
 use serde::{de, Deserialize, Deserializer};
 use std::{
     io::ErrorKind,
+    net::SocketAddr,
     path::{Path, PathBuf},
     sync::Arc,
 };
@@ -21,6 +24,7 @@ use tracing::{info, warn};
 use tracing_subscriber::filter::EnvFilter;
 
 use self::format::LogFormat;
+use crate::access::Role;
 
 fn deserialize_option_env_filter<'de, D>(deserializer: D) -> Result<Option<EnvFilter>, D::Error>
 where
@@ -97,6 +101,11 @@ pub struct CmdArgs {
         help = "Override the servers to run from the configuration file"
     )]
     pub servers: Vec<ServerConfig>,
+    #[arg(
+        long,
+        help = "Synchronize the clock once with the configured peers and exit"
+    )]
+    pub once: bool,
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -106,6 +115,8 @@ pub struct Config {
     pub peers: Vec<PeerConfig>,
     #[serde(alias = "server", default)]
     pub servers: Vec<ServerConfig>,
+    #[serde(alias = "refclock", default)]
+    pub refclocks: Vec<RefClockConfig>,
     #[serde(default)]
     pub system: SystemConfig,
     #[serde(deserialize_with = "deserialize_option_env_filter", default)]
@@ -119,6 +130,17 @@ pub struct Config {
     pub observe: ObserveConfig,
     #[serde(default)]
     pub configure: ConfigureConfig,
+    #[serde(default)]
+    pub systemd: SystemdConfig,
+    #[serde(default)]
+    pub privileges: PrivilegesConfig,
+    #[serde(default)]
+    pub remote: Option<RemoteConfig>,
+    #[serde(default)]
+    pub statistics: StatisticsConfig,
+    #[cfg(feature = "metrics-endpoint")]
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
 }
 
 const fn default_observe_permissions() -> u32 {
@@ -131,6 +153,20 @@ pub struct ObserveConfig {
     pub path: Option<PathBuf>,
     #[serde(default = "default_observe_permissions")]
     pub mode: u32,
+    /// Name of the socket passed on by the service manager to use instead
+    /// of creating one at `path`
+    #[serde(rename = "fd-name", default)]
+    pub fd_name: Option<String>,
+    /// User and group to own the socket, by default those the daemon is started as
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    /// When either is non-empty, only these users and groups may connect
+    #[serde(rename = "allowed-uids", default)]
+    pub allowed_uids: Vec<u32>,
+    #[serde(rename = "allowed-gids", default)]
+    pub allowed_gids: Vec<u32>,
 }
 
 const fn default_configure_permissions() -> u32 {
@@ -142,6 +178,11 @@ impl Default for ObserveConfig {
         Self {
             path: None,
             mode: default_observe_permissions(),
+            fd_name: None,
+            owner: None,
+            group: None,
+            allowed_uids: vec![],
+            allowed_gids: vec![],
         }
     }
 }
@@ -152,6 +193,20 @@ pub struct ConfigureConfig {
     pub path: Option<std::path::PathBuf>,
     #[serde(default = "default_configure_permissions")]
     pub mode: u32,
+    /// Name of the socket passed on by the service manager to use instead
+    /// of creating one at `path`
+    #[serde(rename = "fd-name", default)]
+    pub fd_name: Option<String>,
+    /// User and group to own the socket, by default those the daemon is started as
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    /// When either is non-empty, only these users and groups may connect
+    #[serde(rename = "allowed-uids", default)]
+    pub allowed_uids: Vec<u32>,
+    #[serde(rename = "allowed-gids", default)]
+    pub allowed_gids: Vec<u32>,
 }
 
 impl Default for ConfigureConfig {
@@ -159,10 +214,110 @@ impl Default for ConfigureConfig {
         Self {
             path: None,
             mode: default_configure_permissions(),
+            fd_name: None,
+            owner: None,
+            group: None,
+            allowed_uids: vec![],
+            allowed_gids: vec![],
         }
     }
 }
 
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct SystemdConfig {
+    /// Only report readiness to the service manager after the first
+    /// successful clock update, instead of once startup is complete
+    #[serde(default)]
+    pub ready_after_sync: bool,
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct PrivilegesConfig {
+    /// User to switch to once setup is complete. The daemon keeps running
+    /// as the user it was started as when this is not set.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Group to switch to, the primary group of `user` when not set
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Restrict the daemon to the system calls it needs once setup is complete
+    #[serde(default)]
+    pub seccomp: bool,
+}
+
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct StatisticsConfig {
+    /// Directory to write the statistics files to, none are written when not set
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    /// Number of days to keep the files of past days, all are kept when not set
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+}
+
+const fn default_client_role() -> Role {
+    Role::Read
+}
+
+/// The management endpoint for use over the network, see [`crate::remote`]
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct RemoteConfig {
+    /// Address to accept connections on
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+    /// Name of the socket passed on by the service manager to use instead
+    /// of binding to `listen`
+    #[serde(default)]
+    pub fd_name: Option<String>,
+    /// PEM file with the certificate chain of the endpoint
+    pub certificate: PathBuf,
+    /// PEM file with the private key of the certificate
+    pub private_key: PathBuf,
+    /// PEM file with the authorities that issue client certificates. Clients
+    /// can only authenticate with a certificate when this is set.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Role of clients that authenticate with a certificate
+    #[serde(default = "default_client_role")]
+    pub client_role: Role,
+    #[serde(alias = "token", default)]
+    pub tokens: Vec<RemoteToken>,
+}
+
+#[derive(Clone, Deserialize, PartialEq, Eq)]
+pub struct RemoteToken {
+    pub token: String,
+    pub role: Role,
+}
+
+// Keep tokens out of the logs
+impl std::fmt::Debug for RemoteToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteToken")
+            .field("token", &"<redacted>")
+            .field("role", &self.role)
+            .finish()
+    }
+}
+
+/// The endpoint serving metrics over HTTP, see [`crate::metrics`]
+#[cfg(feature = "metrics-endpoint")]
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    /// Address to serve the metrics on
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+    /// Name of the socket passed on by the service manager to use instead
+    /// of binding to `listen`
+    #[serde(default)]
+    pub fd_name: Option<String>,
+}
+
 #[cfg(feature = "sentry")]
 #[derive(Deserialize, Debug, Default)]
 #[serde(rename_all = "kebab-case")]
@@ -177,6 +332,29 @@ fn default_sample_rate() -> f32 {
     0.0
 }
 
+/// Where the configuration comes from, so that it can be loaded again when
+/// the daemon is asked to reload it
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+    pub file: Option<PathBuf>,
+    pub peers: Vec<PeerConfig>,
+    pub servers: Vec<ServerConfig>,
+}
+
+impl ConfigSource {
+    /// Load the configuration. Once a config file has been found, later loads
+    /// use that same file and fail when it can no longer be read, rather than
+    /// falling back to another location or the default configuration.
+    pub async fn load(&mut self) -> Result<Config, ConfigError> {
+        let (config, file) = Config::from_first_file(self.file.as_ref()).await?;
+        if self.file.is_none() {
+            self.file = file;
+        }
+
+        Ok(config.with_overrides(self.peers.clone(), self.servers.clone()))
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("io error while reading config: {0}")]
@@ -191,12 +369,16 @@ impl Config {
         Ok(toml::de::from_str(&contents)?)
     }
 
-    async fn from_first_file(file: Option<impl AsRef<Path>>) -> Result<Config, ConfigError> {
+    /// Load the first config file that exists, returning its path if any
+    async fn from_first_file(
+        file: Option<impl AsRef<Path>>,
+    ) -> Result<(Config, Option<PathBuf>), ConfigError> {
         // if an explicit file is given, always use that one
         if let Some(f) = file {
             let path: &Path = f.as_ref();
             info!(?path, "using config file");
-            return Config::from_file(f).await;
+            let config = Config::from_file(path).await?;
+            return Ok((config, Some(path.to_path_buf())));
         }
 
         // try ntp.toml in working directory or skip if file doesn't exist
@@ -204,7 +386,7 @@ impl Config {
             Err(ConfigError::Io(e)) if e.kind() == ErrorKind::NotFound => {}
             other => {
                 info!("using local config file at default location `./ntp.toml`");
-                return other;
+                return other.map(|config| (config, Some("./ntp.toml".into())));
             }
         }
 
@@ -214,11 +396,11 @@ impl Config {
                 if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::PermissionDenied => {}
             other => {
                 info!("using global config file at default location `/etc/ntp.toml`");
-                return other;
+                return other.map(|config| (config, Some("/etc/ntp.toml".into())));
             }
         }
 
-        Ok(Config::default())
+        Ok((Config::default(), None))
     }
 
     pub async fn from_args(
@@ -226,7 +408,14 @@ impl Config {
         peers: Vec<PeerConfig>,
         servers: Vec<ServerConfig>,
     ) -> Result<Config, ConfigError> {
-        let mut config = Config::from_first_file(file).await?;
+        let (config, _) = Config::from_first_file(file).await?;
+
+        Ok(config.with_overrides(peers, servers))
+    }
+
+    /// Replace the peers and servers with those given on the command line, if any
+    fn with_overrides(mut self, peers: Vec<PeerConfig>, servers: Vec<ServerConfig>) -> Config {
+        let config = &mut self;
 
         if !peers.is_empty() {
             if !config.peers.is_empty() {
@@ -242,7 +431,7 @@ impl Config {
             config.servers = servers;
         }
 
-        Ok(config)
+        self
     }
 
     /// Check that the config is reasonable. This function may panic if the
@@ -252,13 +441,17 @@ impl Config {
         // using those fields should always work. This is also
         // probably a good policy in general (config should always work
         // but we may panic here to protect the user from themselves)
-        if self.peers.is_empty() {
-            warn!("No peers configured. Daemon will not do anything.");
+        if self.peers.is_empty() && self.refclocks.is_empty() {
+            warn!("No peers or reference clocks configured. Daemon will not do anything.");
         }
 
-        if self.peers.len() < self.system.min_intersection_survivors {
+        if self.peers.len() + self.refclocks.len() < self.system.min_intersection_survivors {
             warn!("Fewer peers configured than are required to agree on the current time. Daemon will not do anything.");
         }
+
+        if self.privileges.user.is_none() && self.privileges.group.is_some() {
+            warn!("A group to switch to is configured without a user. Daemon will keep running with its current privileges.");
+        }
     }
 }
 
@@ -266,7 +459,7 @@ impl Config {
 mod tests {
     use std::{env, ffi::OsString};
 
-    use ntp_proto::NtpDuration;
+    use ntp_proto::{FrequencyTolerance, NtpDuration};
 
     use super::*;
 
@@ -277,6 +470,7 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                options: Default::default(),
             })]
         );
 
@@ -287,6 +481,7 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                options: Default::default(),
             })]
         );
 
@@ -297,6 +492,7 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                options: Default::default(),
             })]
         );
 
@@ -307,6 +503,7 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                options: Default::default(),
             })]
         );
         assert_eq!(
@@ -326,6 +523,7 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                options: Default::default(),
             })]
         );
         assert!(config.system.panic_threshold.forward.is_none());
@@ -339,31 +537,134 @@ mod tests {
             [observe]
             path = "/foo/bar/observe"
             mode = 0o567
+            allowed-gids = [1000]
             [configure]
             path = "/foo/bar/configure"
             mode = 0o123
+            owner = "root"
+            group = "ntpd-rs"
+            allowed-uids = [1001, 1002]
+            [privileges]
+            user = "ntpd-rs"
+            seccomp = true
             "#,
         )
         .unwrap();
         assert!(config.log_filter.is_some());
 
+        assert_eq!(config.privileges.user.as_deref(), Some("ntpd-rs"));
+        assert!(config.privileges.group.is_none());
+        assert!(config.privileges.seccomp);
+
         assert_eq!(config.observe.path, Some(PathBuf::from("/foo/bar/observe")));
         assert_eq!(config.observe.mode, 0o567);
+        assert!(config.observe.allowed_uids.is_empty());
+        assert_eq!(config.observe.allowed_gids, vec![1000]);
+        assert!(config.observe.owner.is_none());
 
         assert_eq!(
             config.configure.path,
             Some(PathBuf::from("/foo/bar/configure"))
         );
         assert_eq!(config.configure.mode, 0o123);
+        assert_eq!(config.configure.owner.as_deref(), Some("root"));
+        assert_eq!(config.configure.group.as_deref(), Some("ntpd-rs"));
+        assert_eq!(config.configure.allowed_uids, vec![1001, 1002]);
+        assert!(config.remote.is_none());
 
         assert_eq!(
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                options: Default::default(),
             })]
         );
     }
 
+    #[test]
+    fn test_remote_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [[peers]]
+            addr = "example.com"
+            [remote]
+            listen = "[::]:9123"
+            certificate = "/etc/ntpd-rs/remote.pem"
+            private-key = "/etc/ntpd-rs/remote.key"
+            [[remote.tokens]]
+            token = "secret"
+            role = "write"
+            "#,
+        )
+        .unwrap();
+
+        let remote = config.remote.unwrap();
+        assert_eq!(remote.listen, Some("[::]:9123".parse().unwrap()));
+        assert_eq!(remote.private_key, PathBuf::from("/etc/ntpd-rs/remote.key"));
+        assert!(remote.client_ca.is_none());
+        assert_eq!(remote.client_role, Role::Read);
+        assert_eq!(
+            remote.tokens,
+            vec![RemoteToken {
+                token: "secret".into(),
+                role: Role::Write
+            }]
+        );
+        assert!(!format!("{:?}", remote.tokens).contains("secret"));
+
+        // a certificate is required
+        assert!(toml::from_str::<Config>(
+            "[[peers]]\naddr = \"example.com\"\n[remote]\nlisten = \"[::]:9123\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_slew_config() {
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[system]\nslew-only = true\nmax-slew-rate = 200",
+        )
+        .unwrap();
+        assert!(config.system.slew_only);
+        assert_eq!(
+            NtpDuration::from_seconds(1.0) * config.system.max_slew_rate,
+            NtpDuration::from_seconds(1.0) * FrequencyTolerance::ppm(200)
+        );
+
+        // the kernel does not slew faster than 500 ppm
+        for rate in [0, 501] {
+            assert!(toml::from_str::<Config>(&format!(
+                "[[peers]]\naddr = \"example.com\"\n[system]\nmax-slew-rate = {}",
+                rate
+            ))
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_statistics_config() {
+        let config: Config = toml::from_str("[[peers]]\naddr = \"example.com\"").unwrap();
+        assert_eq!(config.statistics, StatisticsConfig::default());
+
+        let config: Config = toml::from_str(
+            r#"
+            [[peers]]
+            addr = "example.com"
+            [statistics]
+            directory = "/var/log/ntpd-rs"
+            retention-days = 14
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.statistics,
+            StatisticsConfig {
+                directory: Some("/var/log/ntpd-rs".into()),
+                retention_days: Some(14),
+            }
+        );
+    }
+
     #[cfg(feature = "sentry")]
     #[test]
     fn test_sentry_config() {
@@ -382,6 +683,25 @@ mod tests {
         assert!((config.sentry.sample_rate - 0.5).abs() < 1e-9);
     }
 
+    #[cfg(feature = "metrics-endpoint")]
+    #[test]
+    fn test_metrics_config() {
+        let config: Config = toml::from_str("[[peers]]\naddr = \"example.com\"").unwrap();
+        assert!(config.metrics.is_none());
+
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[metrics]\nlisten = \"127.0.0.1:9975\"",
+        )
+        .unwrap();
+        assert_eq!(
+            config.metrics,
+            Some(MetricsConfig {
+                listen: Some("127.0.0.1:9975".parse().unwrap()),
+                fd_name: None,
+            })
+        );
+    }
+
     #[tokio::test]
     async fn test_file_config() {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -425,6 +745,22 @@ mod tests {
         .unwrap();
         assert_eq!(config.system.min_intersection_survivors, 3);
         assert_eq!(config.peers.len(), 2);
+
+        // the file that was found is used again on reload
+        let mut source = ConfigSource {
+            servers: vec![ServerConfig::try_from("127.0.0.1:123").unwrap()],
+            ..Default::default()
+        };
+        let config = source.load().await.unwrap();
+        assert_eq!(source.file, Some(PathBuf::from("./ntp.toml")));
+        assert_eq!(config.system.min_intersection_survivors, 2);
+        assert_eq!(config.servers.len(), 1);
+
+        let mut source = ConfigSource {
+            file: Some("missing.toml".into()),
+            ..Default::default()
+        };
+        assert!(source.load().await.is_err());
     }
 
     #[test]
@@ -492,6 +828,7 @@ mod tests {
             parsed_empty.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("foo.nl:123"),
+                options: Default::default(),
             })]
         );
         assert!(parsed_empty.config.is_none());
@@ -505,9 +842,11 @@ mod tests {
             vec![
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("foo.rs:123"),
+                    options: Default::default(),
                 }),
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("spam.nl:123"),
+                    options: Default::default(),
                 }),
             ]
         );
@@ -1,5 +1,6 @@
 use std::{fmt, net::SocketAddr};
 
+use ntp_proto::{PeerOptions, PollIntervalLimits};
 use serde::{
     de::{self, MapAccess, Visitor},
     Deserialize, Deserializer,
@@ -22,12 +23,16 @@ impl Default for PeerHostMode {
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct StandardPeerConfig {
     pub addr: NormalizedAddress,
+    #[serde(default)]
+    pub options: PeerOptions,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct PoolPeerConfig {
     pub addr: NormalizedAddress,
     pub max_peers: usize,
+    #[serde(default)]
+    pub options: PeerOptions,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -144,6 +149,7 @@ impl TryFrom<&str> for StandardPeerConfig {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         Ok(Self {
             addr: NormalizedAddress::from_string(value.to_string())?,
+            options: PeerOptions::default(),
         })
     }
 }
@@ -180,6 +186,12 @@ impl<'de> Deserialize<'de> for PeerConfig {
                 let mut addr = None;
                 let mut mode = None;
                 let mut max_peers = None;
+                let mut prefer = None;
+                let mut noselect = None;
+                let mut trust = None;
+                let mut iburst = None;
+                let mut burst = None;
+                let mut poll_limits: Option<PollIntervalLimits> = None;
                 while let Some(key) = map.next_key::<&str>()? {
                     match key {
                         "addr" => {
@@ -206,10 +218,62 @@ impl<'de> Deserialize<'de> for PeerConfig {
                             }
                             max_peers = Some(map.next_value()?);
                         }
+                        "prefer" => {
+                            if prefer.is_some() {
+                                return Err(de::Error::duplicate_field("prefer"));
+                            }
+                            prefer = Some(map.next_value()?);
+                        }
+                        "noselect" => {
+                            if noselect.is_some() {
+                                return Err(de::Error::duplicate_field("noselect"));
+                            }
+                            noselect = Some(map.next_value()?);
+                        }
+                        "trust" => {
+                            if trust.is_some() {
+                                return Err(de::Error::duplicate_field("trust"));
+                            }
+                            trust = Some(map.next_value()?);
+                        }
+                        "iburst" => {
+                            if iburst.is_some() {
+                                return Err(de::Error::duplicate_field("iburst"));
+                            }
+                            iburst = Some(map.next_value()?);
+                        }
+                        "burst" => {
+                            if burst.is_some() {
+                                return Err(de::Error::duplicate_field("burst"));
+                            }
+                            burst = Some(map.next_value()?);
+                        }
+                        "poll_limits" => {
+                            if poll_limits.is_some() {
+                                return Err(de::Error::duplicate_field("poll_limits"));
+                            }
+                            let limits: PollIntervalLimits = map.next_value()?;
+                            if limits.min > limits.max {
+                                return Err(de::Error::custom(
+                                    "poll_limits.min must not be larger than poll_limits.max",
+                                ));
+                            }
+                            poll_limits = Some(limits);
+                        }
                         _ => {
                             return Err(de::Error::unknown_field(
                                 key,
-                                &["addr", "mode", "max_peers"],
+                                &[
+                                    "addr",
+                                    "mode",
+                                    "max_peers",
+                                    "prefer",
+                                    "noselect",
+                                    "trust",
+                                    "poll_limits",
+                                    "iburst",
+                                    "burst",
+                                ],
                             ));
                         }
                     }
@@ -217,19 +281,43 @@ impl<'de> Deserialize<'de> for PeerConfig {
 
                 let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
                 let mode = mode.unwrap_or_default();
+                let options = PeerOptions {
+                    prefer: prefer.unwrap_or_default(),
+                    noselect: noselect.unwrap_or_default(),
+                    trust: trust.unwrap_or_default(),
+                    poll_limits,
+                    iburst: iburst.unwrap_or_default(),
+                    burst: burst.unwrap_or_default(),
+                };
 
                 match mode {
                     PeerHostMode::Server => {
                         if max_peers.is_some() {
-                            Err(de::Error::unknown_field("max_peers", &["addr", "mode"]))
+                            Err(de::Error::unknown_field(
+                                "max_peers",
+                                &[
+                                    "addr",
+                                    "mode",
+                                    "prefer",
+                                    "noselect",
+                                    "trust",
+                                    "poll_limits",
+                                    "iburst",
+                                    "burst",
+                                ],
+                            ))
                         } else {
-                            Ok(PeerConfig::Standard(StandardPeerConfig { addr }))
+                            Ok(PeerConfig::Standard(StandardPeerConfig { addr, options }))
                         }
                     }
                     PeerHostMode::Pool => {
                         let max_peers = max_peers.unwrap_or(1);
 
-                        Ok(PeerConfig::Pool(PoolPeerConfig { addr, max_peers }))
+                        Ok(PeerConfig::Pool(PoolPeerConfig {
+                            addr,
+                            max_peers,
+                            options,
+                        }))
                     }
                 }
             }
@@ -314,6 +402,71 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_deserialize_peer_options() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig = toml::from_str("peer = \"example.com\"").unwrap();
+        if let PeerConfig::Standard(config) = test.peer {
+            assert_eq!(config.options, PeerOptions::default());
+        } else {
+            panic!("expected a standard peer");
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "gps.local"
+            prefer = true
+            trust = true
+            poll_limits = { min = 3, max = 5 }
+            iburst = true
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Standard(config) = test.peer {
+            assert!(config.options.prefer);
+            assert!(config.options.trust);
+            assert!(!config.options.noselect);
+            assert!(config.options.iburst);
+            assert!(!config.options.burst);
+            let limits = config.options.poll_limits.unwrap();
+            assert_eq!(limits.min.as_log(), 3);
+            assert_eq!(limits.max.as_log(), 5);
+        } else {
+            panic!("expected a standard peer");
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "pool.example.com"
+            mode = "Pool"
+            max_peers = 4
+            noselect = true
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Pool(config) = test.peer {
+            assert_eq!(config.max_peers, 4);
+            assert!(config.options.noselect);
+        } else {
+            panic!("expected a pool");
+        }
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            poll_limits = { min = 6, max = 5 }
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
     #[test]
     fn test_peer_from_string() {
         let peer = PeerConfig::try_from("example.com").unwrap();
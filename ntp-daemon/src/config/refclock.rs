@@ -0,0 +1,209 @@
+use std::{fmt, path::PathBuf};
+
+use ntp_proto::{PollInterval, ReferenceId};
+use serde::{de, Deserialize, Deserializer};
+
+fn deserialize_refid<'de, D>(deserializer: D) -> Result<ReferenceId, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let code: String = Deserialize::deserialize(deserializer)?;
+
+    ReferenceId::from_code(&code)
+        .ok_or_else(|| de::Error::custom("refid must be at most 4 ascii characters"))
+}
+
+fn default_refclock_precision() -> i8 {
+    -20
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct RefClockConfig {
+    #[serde(flatten)]
+    pub driver: RefClockDriverConfig,
+    /// Reference id announced to our clients when synchronized to this clock
+    #[serde(deserialize_with = "deserialize_refid")]
+    pub refid: ReferenceId,
+    /// Interval at which the samples of the clock are combined into a measurement
+    #[serde(default)]
+    pub poll: PollInterval,
+    /// Precision of the clock (log2 seconds)
+    #[serde(default = "default_refclock_precision")]
+    pub precision: i8,
+    /// Constant offset (in seconds) added to every sample, to correct for
+    /// delays in the connection to the clock
+    #[serde(default)]
+    pub offset: f64,
+}
+
+/// Driver specific configuration of a reference clock, selected by the `driver` key
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "driver", rename_all = "kebab-case")]
+pub enum RefClockDriverConfig {
+    Shm(ShmRefClockConfig),
+    Sock(SockRefClockConfig),
+    Nmea(NmeaRefClockConfig),
+}
+
+impl fmt::Display for RefClockDriverConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefClockDriverConfig::Shm(config) => write!(f, "SHM({})", config.unit),
+            RefClockDriverConfig::Sock(config) => write!(f, "SOCK({})", config.path.display()),
+            RefClockDriverConfig::Nmea(config) => write!(f, "NMEA({})", config.path.display()),
+        }
+    }
+}
+
+fn deserialize_shm_unit<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let unit: u32 = Deserialize::deserialize(deserializer)?;
+    if unit <= 255 {
+        Ok(unit)
+    } else {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(unit as u64),
+            &"a unit between 0 and 255",
+        ))
+    }
+}
+
+/// Shared memory segment as used by ntpd and written to by gpsd
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ShmRefClockConfig {
+    /// Unit number, selecting the segment with key `0x4e545030 + unit`
+    #[serde(default, deserialize_with = "deserialize_shm_unit")]
+    pub unit: u32,
+}
+
+/// Unix datagram socket receiving samples in the format of chrony's SOCK refclock
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct SockRefClockConfig {
+    /// Path at which the socket is created
+    pub path: PathBuf,
+}
+
+fn default_baud_rate() -> u32 {
+    9600
+}
+
+fn deserialize_baud_rate<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    const SUPPORTED: &[u32] = &[4800, 9600, 19200, 38400, 57600, 115200];
+
+    let baud_rate: u32 = Deserialize::deserialize(deserializer)?;
+    if SUPPORTED.contains(&baud_rate) {
+        Ok(baud_rate)
+    } else {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(baud_rate as u64),
+            &"one of 4800, 9600, 19200, 38400, 57600 or 115200",
+        ))
+    }
+}
+
+/// Serial device (or pty) on which a receiver sends NMEA sentences
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct NmeaRefClockConfig {
+    pub path: PathBuf,
+    #[serde(
+        default = "default_baud_rate",
+        deserialize_with = "deserialize_baud_rate"
+    )]
+    pub baud_rate: u32,
+    /// Delay (in seconds) between the start of a second and the receiver
+    /// sending the sentence describing it. Not used for pulse aligned samples.
+    #[serde(default)]
+    pub sentence_delay: f64,
+    /// PPS device marking the start of every second
+    #[serde(default)]
+    pub pps: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shm_driver() {
+        let config: RefClockConfig = toml::from_str(
+            "driver = \"shm\"\nunit = 2\nrefid = \"GPS\"\nprecision = -10\noffset = 0.5",
+        )
+        .unwrap();
+        assert_eq!(
+            config.driver,
+            RefClockDriverConfig::Shm(ShmRefClockConfig { unit: 2 })
+        );
+        assert_eq!(config.refid, ReferenceId::from_code("GPS").unwrap());
+        assert_eq!(config.poll, PollInterval::default());
+        assert_eq!(config.precision, -10);
+        assert_eq!(config.offset, 0.5);
+
+        let config: RefClockConfig = toml::from_str("driver = \"shm\"\nrefid = \"GPS\"").unwrap();
+        assert_eq!(
+            config.driver,
+            RefClockDriverConfig::Shm(ShmRefClockConfig { unit: 0 })
+        );
+        assert_eq!(config.precision, -20);
+        assert_eq!(config.offset, 0.0);
+
+        let result: Result<RefClockConfig, _> =
+            toml::from_str("driver = \"shm\"\nrefid = \"TOOLONG\"");
+        assert!(result.is_err());
+
+        let result: Result<RefClockConfig, _> =
+            toml::from_str("driver = \"shm\"\nunit = 256\nrefid = \"GPS\"");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn sock_driver() {
+        let config: RefClockConfig =
+            toml::from_str("driver = \"sock\"\npath = \"/run/ntpd-rs/ptp.sock\"\nrefid = \"PTP\"")
+                .unwrap();
+        assert_eq!(
+            config.driver,
+            RefClockDriverConfig::Sock(SockRefClockConfig {
+                path: PathBuf::from("/run/ntpd-rs/ptp.sock")
+            })
+        );
+
+        let result: Result<RefClockConfig, _> =
+            toml::from_str("driver = \"sock\"\nrefid = \"PTP\"");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn nmea_driver() {
+        let config: RefClockConfig = toml::from_str(
+            "driver = \"nmea\"\npath = \"/dev/ttyS0\"\nrefid = \"GPS\"\nsentence-delay = 0.1\npps = \"/dev/pps0\"",
+        )
+        .unwrap();
+        assert_eq!(
+            config.driver,
+            RefClockDriverConfig::Nmea(NmeaRefClockConfig {
+                path: PathBuf::from("/dev/ttyS0"),
+                baud_rate: 9600,
+                sentence_delay: 0.1,
+                pps: Some(PathBuf::from("/dev/pps0")),
+            })
+        );
+
+        let result: Result<RefClockConfig, _> = toml::from_str(
+            "driver = \"nmea\"\npath = \"/dev/ttyS0\"\nrefid = \"GPS\"\nbaud-rate = 1234",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unknown_driver() {
+        let result: Result<RefClockConfig, _> = toml::from_str("driver = \"foo\"\nrefid = \"GPS\"");
+        assert!(result.is_err());
+    }
+}
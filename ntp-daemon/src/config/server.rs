@@ -27,6 +27,9 @@ pub struct ServerConfig {
     pub allowlist_action: FilterAction,
     pub rate_limiting_cache_size: usize,
     pub rate_limiting_cutoff: Duration,
+    /// Name of the socket passed on by the service manager to use instead
+    /// of binding to `addr`
+    pub fd_name: Option<String>,
 }
 
 impl ServerConfig {
@@ -47,6 +50,7 @@ impl TryFrom<&str> for ServerConfig {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cache_size: Default::default(),
             rate_limiting_cutoff: Default::default(),
+            fd_name: None,
         })
     }
 }
@@ -79,6 +83,7 @@ impl<'de> Deserialize<'de> for ServerConfig {
                 let mut allowlist_action = None;
                 let mut denylist = None;
                 let mut denylist_action = None;
+                let mut fd_name = None;
                 while let Some(key) = map.next_key::<&str>()? {
                     match key {
                         "addr" => {
@@ -127,6 +132,13 @@ impl<'de> Deserialize<'de> for ServerConfig {
 
                             rate_limiting_cutoff = Some(Duration::from_millis(map.next_value()?));
                         }
+                        "fd-name" => {
+                            if fd_name.is_some() {
+                                return Err(de::Error::duplicate_field("fd-name"));
+                            }
+
+                            fd_name = Some(map.next_value()?);
+                        }
                         _ => {
                             return Err(de::Error::unknown_field(
                                 key,
@@ -138,6 +150,7 @@ impl<'de> Deserialize<'de> for ServerConfig {
                                     "denylist-action",
                                     "rate-limiting-cache-size",
                                     "rate-limiting-cutoff-ms",
+                                    "fd-name",
                                 ],
                             ));
                         }
@@ -173,6 +186,7 @@ impl<'de> Deserialize<'de> for ServerConfig {
                     denylist_action,
                     rate_limiting_cache_size,
                     rate_limiting_cutoff,
+                    fd_name,
                 })
             }
         }
@@ -216,5 +230,16 @@ mod tests {
             test.server.rate_limiting_cutoff,
             Duration::from_millis(1000)
         );
+        assert_eq!(test.server.fd_name, None);
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "0.0.0.0:123"
+            fd-name = "ntp"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.server.fd_name.as_deref(), Some("ntp"));
     }
 }
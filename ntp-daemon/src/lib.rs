@@ -1,17 +1,27 @@
 //#![forbid(unsafe_code)]
 
+pub mod access;
 pub mod config;
 mod ipfilter;
+pub mod metrics;
 pub mod observer;
+pub mod once;
 mod peer;
+pub mod privileges;
+mod refclock;
+pub mod remote;
+pub mod seccomp;
 mod server;
 pub mod sockets;
+mod statistics;
 mod system;
+pub mod systemd;
 pub mod tracing;
 
 pub use config::dynamic::ConfigUpdate;
 pub use config::Config;
 pub use observer::{ObservablePeerState, ObservableState};
+pub use refclock::RefClockDevices;
 pub use system::spawn;
 //#[cfg(fuzz)]
 pub use ipfilter::fuzz::fuzz_ipfilter;
@@ -1,16 +1,27 @@
 #![forbid(unsafe_code)]
 
 use clap::Parser;
-use ntp_daemon::config::{CmdArgs, Config};
-use std::{error::Error, sync::Arc};
-use tracing::debug;
+#[cfg(feature = "metrics-endpoint")]
+use ntp_daemon::metrics::MetricsListener;
+use ntp_daemon::{
+    access::Access,
+    config::{CmdArgs, Config, ConfigSource},
+    remote::RemoteListener,
+    sockets::listen_unix_socket,
+    systemd::ListenFds,
+    tracing::TracingState,
+    RefClockDevices,
+};
+use std::{error::Error, os::unix::net::UnixListener, sync::Arc};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
 use tracing_subscriber::EnvFilter;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     let args = CmdArgs::parse();
     let has_log_override = args.log_filter.is_some();
     let has_format_override = args.log_format.is_some();
+    let once = args.once;
     let log_filter = args
         .log_filter
         // asserts that the arc is not shared. There is no reason it would be,
@@ -23,7 +34,21 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let finish_tracing_init =
         ntp_daemon::tracing::init(log_filter, args.log_format.unwrap_or_default());
 
-    let mut config = match Config::from_args(args.config, args.peers, args.servers).await {
+    // Setup runs on a single thread: capabilities are per thread, so the ones
+    // kept when dropping privileges only carry over to threads started later.
+    // Dropping the runtime also stops the threads it used for reading files.
+    let setup_runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let mut config_source = ConfigSource {
+        file: args.config,
+        peers: args.peers,
+        servers: args.servers,
+    };
+    let config = setup_runtime.block_on(config_source.load());
+    drop(setup_runtime);
+
+    let mut config = match config {
         Ok(c) => c,
         Err(e) => {
             // print to stderr because tracing is not yet setup
@@ -48,24 +73,170 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // tracing setup to ensure logging is fully configured.
     config.check();
 
+    let runtime = || {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+    };
+
+    if once {
+        debug!("Configuration loaded, synchronizing once");
+        let result = runtime()?.block_on(ntp_daemon::once::run(config.system, &config.peers));
+        let exit_code = match result {
+            Ok(result) => {
+                println!("{}", result);
+                exitcode::OK
+            }
+            Err(e) => {
+                eprintln!("Synchronization failed: {}", e);
+                e.exit_code()
+            }
+        };
+        std::process::exit(exit_code);
+    }
+
+    let mut listen_fds = ListenFds::from_env();
+
+    // Create the sockets in the filesystem while we may still be root
+    let observe_listener = listen_unix_socket(
+        config.observe.fd_name.as_deref(),
+        config.observe.path.as_deref(),
+        config.observe.mode,
+        config.observe.owner.as_deref(),
+        config.observe.group.as_deref(),
+        &mut listen_fds,
+    )
+    .unwrap_or_else(|e| {
+        error!("Could not set up observe socket: {}", e);
+        None
+    });
+    let configure_listener = listen_unix_socket(
+        config.configure.fd_name.as_deref(),
+        config.configure.path.as_deref(),
+        config.configure.mode,
+        config.configure.owner.as_deref(),
+        config.configure.group.as_deref(),
+        &mut listen_fds,
+    )
+    .unwrap_or_else(|e| {
+        error!("Could not set up configure socket: {}", e);
+        None
+    });
+    let remote_listener = ntp_daemon::remote::listen(config.remote.as_ref(), &mut listen_fds)
+        .unwrap_or_else(|e| {
+            error!("Could not set up remote management endpoint: {}", e);
+            None
+        });
+    #[cfg(feature = "metrics-endpoint")]
+    let metrics_listener = ntp_daemon::metrics::listen(config.metrics.as_ref(), &mut listen_fds)
+        .unwrap_or_else(|e| {
+            error!("Could not set up metrics endpoint: {}", e);
+            None
+        });
+
+    // Devices of reference clocks are often only accessible to root
+    let refclock_devices = RefClockDevices::open(&config.refclocks);
+
+    if let Err(e) = ntp_daemon::privileges::drop_privileges(&config.privileges) {
+        error!("Could not drop privileges: {}", e);
+        std::process::exit(e.exit_code());
+    }
+
+    runtime()?.block_on(run(
+        config,
+        config_source,
+        tracing_state,
+        listen_fds,
+        refclock_devices,
+        observe_listener,
+        configure_listener,
+        remote_listener,
+        #[cfg(feature = "metrics-endpoint")]
+        metrics_listener,
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run(
+    config: Config,
+    config_source: ConfigSource,
+    tracing_state: TracingState,
+    mut listen_fds: ListenFds,
+    refclock_devices: RefClockDevices,
+    observe_listener: Option<UnixListener>,
+    configure_listener: Option<UnixListener>,
+    remote_listener: Option<RemoteListener>,
+    #[cfg(feature = "metrics-endpoint")] metrics_listener: Option<MetricsListener>,
+) -> Result<(), Box<dyn Error>> {
     debug!("Configuration loaded, spawning daemon jobs");
-    let (main_loop_handle, channels) =
-        ntp_daemon::spawn(config.system, &config.peers, &config.servers).await?;
+    let access = Access::from_config(&config.observe, &config.configure);
+
+    let (main_loop_handle, channels) = ntp_daemon::spawn(
+        config.system,
+        &config.peers,
+        &config.servers,
+        &config.refclocks,
+        &config.systemd,
+        &config.statistics,
+        &mut listen_fds,
+        refclock_devices,
+    )
+    .await?;
+
+    let (remote_config_sender, remote_config_receiver) = mpsc::channel(1);
+    ntp_daemon::remote::spawn(
+        remote_listener,
+        channels.peer_snapshots_receiver.clone(),
+        channels.server_data_receiver.clone(),
+        channels.system_snapshot_receiver.clone(),
+        remote_config_sender,
+    )
+    .await;
+
+    #[cfg(feature = "metrics-endpoint")]
+    ntp_daemon::metrics::spawn(
+        metrics_listener,
+        channels.peer_snapshots_receiver.clone(),
+        channels.server_data_receiver.clone(),
+        channels.system_snapshot_receiver.clone(),
+    )
+    .await;
 
     ntp_daemon::observer::spawn(
-        &config.observe,
+        observe_listener,
+        access.clone(),
         channels.peer_snapshots_receiver,
+        channels.peer_history_receiver,
         channels.server_data_receiver,
         channels.system_snapshot_receiver,
     )
     .await;
 
     ntp_daemon::config::dynamic::spawn(
-        config.configure,
+        configure_listener,
+        access,
+        remote_config_receiver,
+        config_source,
         channels.config_sender,
+        channels.command_sender,
         tracing_state.reload_handle,
     )
     .await;
 
+    // Close any sockets passed on by the service manager that are not used
+    drop(listen_fds);
+
+    // Setup is complete, from here on only what is needed to keep running is allowed
+    if config.privileges.seccomp {
+        if let Err(e) = ntp_daemon::seccomp::enable() {
+            error!("Could not enable seccomp filter: {}", e);
+            std::process::exit(exitcode::OSERR);
+        }
+    }
+
+    if !config.systemd.ready_after_sync {
+        ntp_daemon::systemd::notify("READY=1");
+    }
+
     Ok(main_loop_handle.await??)
 }
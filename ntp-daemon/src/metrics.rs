@@ -0,0 +1,778 @@
+//! Metrics of the daemon in the OpenMetrics format, as used by `ntp-ctl prometheus`
+//! and the metrics exporter. With the `metrics-endpoint` feature, the daemon
+//! can also serve them itself.
+
+use crate::{observer::WrappedSocketAddr, ObservablePeerState, ObservableState};
+use ntp_os_clock::UnixNtpClock;
+use ntp_proto::{ClockState, NtpClock, PeerSelection, ReferenceId};
+use prometheus_client::{
+    encoding::text::{Encode, SendSyncEncodeMetric},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::{Atomic, Gauge},
+    },
+    registry::{Registry, Unit},
+};
+
+#[derive(Clone, PartialEq, Eq, Hash, Encode)]
+struct PeerLabels {
+    address: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Encode)]
+struct ClockStateLabels {
+    state: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Encode)]
+struct PeerReferenceLabels {
+    address: String,
+    reference_id: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Encode)]
+struct PeerSelectionLabels {
+    address: String,
+    selection: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Encode)]
+struct PeerIgnoredLabels {
+    address: String,
+    reason: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Encode)]
+struct ServerLabels {
+    listen_address: WrappedSocketAddr,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    system_poll_interval: Gauge<f64>,
+    system_poll_interval_exp: Gauge<f64>,
+    system_precision: Gauge<f64>,
+    system_accumulated_steps: Gauge<f64>,
+    system_accumulated_steps_threshold: Gauge<f64>,
+    system_leap_indicator: Gauge,
+    system_offset: Gauge<f64>,
+    system_jitter: Gauge<f64>,
+    system_frequency: Gauge<f64>,
+    system_root_delay: Gauge<f64>,
+    system_root_dispersion: Gauge<f64>,
+    system_stratum: Gauge,
+    system_clock_state: Family<ClockStateLabels, Gauge>,
+    system_peer: Family<PeerLabels, Gauge>,
+    peer_last_update: Family<PeerLabels, Gauge<f64>>,
+    peer_poll_interval: Family<PeerLabels, Gauge<f64>>,
+    peer_poll_interval_exp: Family<PeerLabels, Gauge<f64>>,
+    peer_reachability_status: Family<PeerLabels, Gauge>,
+    peer_offset: Family<PeerLabels, Gauge<f64>>,
+    peer_uncertainty: Family<PeerLabels, Gauge<f64>>,
+    peer_delay: Family<PeerLabels, Gauge<f64>>,
+    peer_jitter: Family<PeerLabels, Gauge<f64>>,
+    peer_stratum: Family<PeerLabels, Gauge>,
+    peer_reference_id: Family<PeerReferenceLabels, Gauge>,
+    peer_selection: Family<PeerSelectionLabels, Gauge>,
+    peer_ignored_packets: Family<PeerIgnoredLabels, Counter>,
+    server_received_packets: Family<ServerLabels, Counter>,
+    server_accepted_packets: Family<ServerLabels, Counter>,
+    server_denied_packets: Family<ServerLabels, Counter>,
+    server_ignored_packets: Family<ServerLabels, Counter>,
+    server_rate_limited_packets: Family<ServerLabels, Counter>,
+    server_response_send_errors: Family<ServerLabels, Counter>,
+}
+
+const CLOCK_STATES: [(ClockState, &str); 6] = [
+    (ClockState::StartupBlank, "startup_blank"),
+    (ClockState::StartupFreq, "startup_freq"),
+    (ClockState::MeasureFreq, "measure_freq"),
+    (ClockState::Spike, "spike"),
+    (ClockState::Sync, "sync"),
+    (ClockState::Slewing, "slewing"),
+];
+
+const PEER_SELECTIONS: [(PeerSelection, &str); 5] = [
+    (PeerSelection::Unusable, "unusable"),
+    (PeerSelection::Falseticker, "falseticker"),
+    (PeerSelection::Outlier, "outlier"),
+    (PeerSelection::Candidate, "candidate"),
+    (PeerSelection::Selected, "selected"),
+];
+
+/// Format a reference id the way ntpq does: as a code of ascii characters
+/// for reference clocks (stratum 0 and 1) and unsynchronized servers (stratum 16),
+/// and as an IPv4 address otherwise
+pub fn format_reference_id(reference_id: ReferenceId, stratum: u8) -> String {
+    let bytes = reference_id.to_bytes();
+    if stratum <= 1 || stratum >= 16 {
+        bytes
+            .iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| match byte {
+                b' '..=b'~' => *byte as char,
+                _ => '?',
+            })
+            .collect()
+    } else {
+        std::net::Ipv4Addr::from(bytes).to_string()
+    }
+}
+
+impl Metrics {
+    pub fn fill(&self, data: &ObservableState) {
+        let clock = UnixNtpClock::new();
+
+        self.system_poll_interval.set(
+            data.system
+                .time_snapshot
+                .poll_interval
+                .as_duration()
+                .to_seconds(),
+        );
+        self.system_poll_interval_exp
+            .set(data.system.time_snapshot.poll_interval.as_log() as f64);
+        self.system_precision
+            .set(data.system.time_snapshot.precision.to_seconds());
+        self.system_accumulated_steps
+            .set(data.system.time_snapshot.accumulated_steps.to_seconds());
+        self.system_accumulated_steps_threshold.set(
+            data.system
+                .accumulated_steps_threshold
+                .map(|v| v.to_seconds())
+                .unwrap_or(-1.0),
+        );
+        self.system_leap_indicator
+            .set(data.system.time_snapshot.leap_indicator as u64);
+        self.system_offset
+            .set(data.system.time_snapshot.offset.to_seconds());
+        self.system_jitter
+            .set(data.system.time_snapshot.jitter.to_seconds());
+        self.system_frequency
+            .set(data.system.time_snapshot.frequency * 1e6);
+        self.system_root_delay
+            .set(data.system.time_snapshot.root_delay.to_seconds());
+        self.system_root_dispersion
+            .set(data.system.time_snapshot.root_dispersion.to_seconds());
+        self.system_stratum.set(data.system.stratum as u64);
+        for (state, name) in CLOCK_STATES {
+            self.system_clock_state
+                .get_or_create(&ClockStateLabels {
+                    state: name.to_string(),
+                })
+                .set((state == data.system.clock_state) as u64);
+        }
+
+        for peer in &data.peers {
+            if let ObservablePeerState::Observable {
+                timedata,
+                reachability,
+                poll_interval,
+                address,
+                stratum,
+                reference_id,
+                ignored,
+                ..
+            } = peer
+            {
+                let labels = PeerLabels {
+                    address: address.clone(),
+                };
+                self.peer_last_update.get_or_create(&labels).set(
+                    (timedata.last_update
+                        - clock.now().expect("Unable to get current system time"))
+                    .to_seconds(),
+                );
+                self.peer_poll_interval
+                    .get_or_create(&labels)
+                    .set(poll_interval.as_duration().to_seconds());
+                self.peer_poll_interval_exp
+                    .get_or_create(&labels)
+                    .set(poll_interval.as_log() as f64);
+                self.peer_reachability_status
+                    .get_or_create(&labels)
+                    .set(reachability.reachability_score() as u64);
+                self.peer_offset
+                    .get_or_create(&labels)
+                    .set(timedata.offset.to_seconds());
+                self.peer_delay
+                    .get_or_create(&labels)
+                    .set(timedata.delay.to_seconds());
+                self.peer_uncertainty
+                    .get_or_create(&labels)
+                    .set(timedata.uncertainty.to_seconds());
+                self.peer_jitter
+                    .get_or_create(&labels)
+                    .set(timedata.jitter.to_seconds());
+                self.peer_stratum
+                    .get_or_create(&labels)
+                    .set(*stratum as u64);
+                self.peer_reference_id
+                    .get_or_create(&PeerReferenceLabels {
+                        address: address.clone(),
+                        reference_id: format_reference_id(*reference_id, *stratum),
+                    })
+                    .set(1);
+
+                for (selection, name) in PEER_SELECTIONS {
+                    self.peer_selection
+                        .get_or_create(&PeerSelectionLabels {
+                            address: address.clone(),
+                            selection: name.to_string(),
+                        })
+                        .set((selection == timedata.selection) as u64);
+                }
+                if timedata.selection == PeerSelection::Selected {
+                    self.system_peer.get_or_create(&labels).set(1);
+                }
+
+                for (reason, count) in ignored.by_reason() {
+                    self.peer_ignored_packets
+                        .get_or_create(&PeerIgnoredLabels {
+                            address: address.clone(),
+                            reason: reason.to_string(),
+                        })
+                        .inner()
+                        .set(count);
+                }
+            }
+        }
+
+        for server in &data.servers {
+            let labels = ServerLabels {
+                listen_address: server.address,
+            };
+
+            self.server_received_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.received_packets.get());
+            self.server_accepted_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.accepted_packets.get());
+            self.server_denied_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.denied_packets.get());
+            self.server_ignored_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.ignored_packets.get());
+            self.server_rate_limited_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.rate_limited_packets.get());
+            self.server_response_send_errors
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.response_send_errors.get());
+        }
+    }
+
+    pub fn registry(&self) -> Registry<Box<dyn SendSyncEncodeMetric>> {
+        let mut registry = <Registry>::with_prefix("ntp");
+
+        let system = registry.sub_registry_with_prefix("system");
+
+        system.register_with_unit(
+            "poll_interval",
+            "Time between polls of the system",
+            Unit::Seconds,
+            Box::new(self.system_poll_interval.clone()),
+        );
+        system.register(
+            "poll_interval",
+            "Exponent of time between poll intervals",
+            Box::new(self.system_poll_interval_exp.clone()),
+        );
+        system.register_with_unit(
+            "precision",
+            "Precision of the local clock",
+            Unit::Seconds,
+            Box::new(self.system_precision.clone()),
+        );
+        system.register_with_unit(
+            "accumulated_steps",
+            "Accumulated amount of seconds that the system needed to jump the time",
+            Unit::Seconds,
+            Box::new(self.system_accumulated_steps.clone()),
+        );
+        system.register_with_unit(
+            "accumulated_steps_threshold",
+            "Threshold for the accumulated step amount at which the NTP daemon will exit (or -1 if no threshold was set)",
+            Unit::Seconds,
+            Box::new(self.system_accumulated_steps_threshold.clone()),
+        );
+        system.register(
+            "leap_indicator",
+            "Indicates that a leap second will take place",
+            Box::new(self.system_leap_indicator.clone()),
+        );
+        system.register_with_unit(
+            "offset",
+            "Most recent offset between the system time and the combined upstream servers",
+            Unit::Seconds,
+            Box::new(self.system_offset.clone()),
+        );
+        system.register_with_unit(
+            "jitter",
+            "Estimated jitter of the system offset",
+            Unit::Seconds,
+            Box::new(self.system_jitter.clone()),
+        );
+        system.register_with_unit(
+            "frequency",
+            "Frequency correction applied to the system clock",
+            Unit::Other("ppm".into()),
+            Box::new(self.system_frequency.clone()),
+        );
+        system.register_with_unit(
+            "root_delay",
+            "Total round-trip delay to the primary reference clock",
+            Unit::Seconds,
+            Box::new(self.system_root_delay.clone()),
+        );
+        system.register_with_unit(
+            "root_dispersion",
+            "Total dispersion to the primary reference clock",
+            Unit::Seconds,
+            Box::new(self.system_root_dispersion.clone()),
+        );
+        system.register(
+            "stratum",
+            "Stratum of the system",
+            Box::new(self.system_stratum.clone()),
+        );
+        system.register(
+            "clock_state",
+            "State of the clock discipline, 1 for the current state and 0 for all others",
+            Box::new(self.system_clock_state.clone()),
+        );
+        system.register(
+            "peer",
+            "The peer currently used as system peer, which is always 1",
+            Box::new(self.system_peer.clone()),
+        );
+
+        let peer = registry.sub_registry_with_prefix("peer");
+
+        peer.register_with_unit(
+            "uptime",
+            "Time since the peer was started",
+            Unit::Seconds,
+            Box::new(self.peer_last_update.clone()),
+        );
+
+        peer.register_with_unit(
+            "poll_interval",
+            "Time between polls of the peer",
+            Unit::Seconds,
+            Box::new(self.peer_poll_interval.clone()),
+        );
+
+        peer.register(
+            "poll_interval",
+            "Exponent of time between polls of the peer",
+            Box::new(self.peer_poll_interval_exp.clone()),
+        );
+
+        peer.register(
+            "reachability_status",
+            "Number of polls until the upstream server is unreachable, zero if it is",
+            Box::new(self.peer_reachability_status.clone()),
+        );
+
+        peer.register_with_unit(
+            "offset",
+            "Offset between the upstream server and system time",
+            Unit::Seconds,
+            Box::new(self.peer_offset.clone()),
+        );
+
+        peer.register_with_unit(
+            "delay",
+            "Current round-trip delay to the upstream server",
+            Unit::Seconds,
+            Box::new(self.peer_delay.clone()),
+        );
+
+        peer.register_with_unit(
+            "uncertainty",
+            "Estimated error of the clock",
+            Unit::Seconds,
+            Box::new(self.peer_uncertainty.clone()),
+        );
+
+        peer.register_with_unit(
+            "jitter",
+            "Estimated jitter of the offset measurements of the upstream server",
+            Unit::Seconds,
+            Box::new(self.peer_jitter.clone()),
+        );
+
+        peer.register(
+            "stratum",
+            "Stratum of the upstream server",
+            Box::new(self.peer_stratum.clone()),
+        );
+
+        peer.register(
+            "reference_id",
+            "Reference id of the upstream server, which is always 1",
+            Box::new(self.peer_reference_id.clone()),
+        );
+
+        peer.register(
+            "selection",
+            "Outcome of clock selection for the upstream server, 1 for the current outcome and 0 for all others",
+            Box::new(self.peer_selection.clone()),
+        );
+
+        peer.register(
+            "ignored_packets",
+            "Number of packets from the upstream server that were ignored",
+            Box::new(self.peer_ignored_packets.clone()),
+        );
+
+        let server = registry.sub_registry_with_prefix("server");
+
+        server.register(
+            "received_packets",
+            "Number of incoming received packets",
+            Box::new(self.server_received_packets.clone()),
+        );
+
+        server.register(
+            "accepted_packets",
+            "Number of packets accepted",
+            Box::new(self.server_accepted_packets.clone()),
+        );
+
+        server.register(
+            "denied_packets",
+            "Number of denied packets",
+            Box::new(self.server_denied_packets.clone()),
+        );
+
+        server.register(
+            "ignored_packets",
+            "Number of packets ignored",
+            Box::new(self.server_ignored_packets.clone()),
+        );
+
+        server.register(
+            "rate_limited_packets",
+            "Number of rate limited packets",
+            Box::new(self.server_rate_limited_packets.clone()),
+        );
+
+        server.register(
+            "response_send_errors",
+            "Number of packets where there was an error responding",
+            Box::new(self.server_response_send_errors.clone()),
+        );
+
+        registry
+    }
+}
+
+#[cfg(feature = "metrics-endpoint")]
+pub use endpoint::{listen, spawn, MetricsListener};
+
+/// Serving the metrics over HTTP from the daemon itself, instead of through
+/// the separate metrics exporter
+#[cfg(feature = "metrics-endpoint")]
+mod endpoint {
+    use std::{
+        convert::Infallible,
+        io::{Error, ErrorKind},
+    };
+
+    use hyper::{
+        header::{ACCEPT, CONTENT_TYPE},
+        service::{make_service_fn, service_fn},
+        Body, Method, Request, Response, StatusCode,
+    };
+    use ntp_proto::SystemSnapshot;
+    use tokio::{sync::watch, task::JoinHandle};
+    use tracing::error;
+
+    use super::Metrics;
+    use crate::{
+        config::MetricsConfig,
+        observer::{observe_state, ObservablePeerState},
+        system::ServerData,
+        systemd::ListenFds,
+    };
+
+    const OPENMETRICS_CONTENT_TYPE: &str =
+        "application/openmetrics-text; version=1.0.0; charset=utf-8";
+    const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+    /// The metrics endpoint, set up but not yet accepting connections
+    pub struct MetricsListener {
+        listener: std::net::TcpListener,
+    }
+
+    /// Set up the metrics endpoint, if configured. It listens on the socket named
+    /// `fd_name` passed on by the service manager if there is one, or otherwise
+    /// on a newly bound socket. It is meant to be called before privileges are
+    /// dropped, see [`crate::privileges`].
+    pub fn listen(
+        config: Option<&MetricsConfig>,
+        listen_fds: &mut ListenFds,
+    ) -> std::io::Result<Option<MetricsListener>> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(None),
+        };
+
+        let from_service_manager = config
+            .fd_name
+            .as_deref()
+            .and_then(|name| listen_fds.take_tcp_listener(name));
+
+        let listener = match (from_service_manager, config.listen) {
+            (Some(listener), _) => listener,
+            (None, Some(address)) => std::net::TcpListener::bind(address).map_err(|e| {
+                Error::new(e.kind(), format!("Could not listen on {}: {}", address, e))
+            })?,
+            (None, None) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "no address to serve metrics on",
+                ))
+            }
+        };
+
+        Ok(Some(MetricsListener { listener }))
+    }
+
+    /// Serve the metrics endpoint set up by [`listen`]. Does nothing when there is none.
+    pub async fn spawn(
+        listener: Option<MetricsListener>,
+        peers_reader: watch::Receiver<Vec<ObservablePeerState>>,
+        server_reader: watch::Receiver<Vec<ServerData>>,
+        system_reader: watch::Receiver<SystemSnapshot>,
+    ) -> JoinHandle<std::io::Result<()>> {
+        tokio::spawn(async move {
+            let listener = match listener {
+                Some(listener) => listener,
+                None => return Ok(()),
+            };
+            let result = serve(listener, peers_reader, server_reader, system_reader).await;
+            if let Err(ref e) = result {
+                error!("Abnormal termination of metrics endpoint: {}", e);
+            }
+            result
+        })
+    }
+
+    async fn serve(
+        listener: MetricsListener,
+        peers_reader: watch::Receiver<Vec<ObservablePeerState>>,
+        server_reader: watch::Receiver<Vec<ServerData>>,
+        system_reader: watch::Receiver<SystemSnapshot>,
+    ) -> std::io::Result<()> {
+        listener.listener.set_nonblocking(true)?;
+
+        let make_service = make_service_fn(move |_| {
+            let peers_reader = peers_reader.clone();
+            let server_reader = server_reader.clone();
+            let system_reader = system_reader.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let metrics = Metrics::default();
+                    metrics.fill(&observe_state(
+                        &peers_reader,
+                        &server_reader,
+                        &system_reader,
+                    ));
+                    async move { Ok::<_, Infallible>(respond(&request, &metrics)) }
+                }))
+            }
+        });
+
+        hyper::Server::from_tcp(listener.listener)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?
+            .serve(make_service)
+            .await
+            .map_err(|e| Error::new(ErrorKind::Other, e))
+    }
+
+    fn respond(request: &Request<Body>, metrics: &Metrics) -> Response<Body> {
+        let status = if request.uri().path() != "/metrics" {
+            StatusCode::NOT_FOUND
+        } else if request.method() != Method::GET && request.method() != Method::HEAD {
+            StatusCode::METHOD_NOT_ALLOWED
+        } else {
+            StatusCode::OK
+        };
+
+        let mut buf = vec![];
+        if status == StatusCode::OK {
+            if let Err(e) = prometheus_client::encoding::text::encode(&mut buf, &metrics.registry())
+            {
+                error!("Could not encode metrics: {}", e);
+                return response(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", vec![]);
+            }
+        }
+
+        // The OpenMetrics text format is also understood by older Prometheus
+        // versions, which do not ask for it explicitly
+        let openmetrics = request
+            .headers()
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.contains("application/openmetrics-text"));
+        let content_type = match openmetrics {
+            true => OPENMETRICS_CONTENT_TYPE,
+            false => PROMETHEUS_CONTENT_TYPE,
+        };
+
+        response(status, content_type, buf)
+    }
+
+    fn response(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .expect("response with valid status and headers")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use super::*;
+
+        async fn get(address: std::net::SocketAddr, request: &str) -> String {
+            let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+
+        #[tokio::test]
+        async fn test_metrics_endpoint() {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let (_, peers_reader) = watch::channel(vec![]);
+            let (_, server_reader) = watch::channel(vec![]);
+            let (_, system_reader) = watch::channel(SystemSnapshot::default());
+            let handle = spawn(
+                Some(MetricsListener { listener }),
+                peers_reader,
+                server_reader,
+                system_reader,
+            )
+            .await;
+
+            let response = get(address, "GET /metrics HTTP/1.0\r\n\r\n").await;
+            assert!(response.starts_with("HTTP/1.0 200 OK"));
+            assert!(response.contains(PROMETHEUS_CONTENT_TYPE));
+            assert!(response.contains("\nntp_system_stratum 16\n"));
+
+            let response = get(
+                address,
+                "GET /metrics HTTP/1.0\r\nAccept: application/openmetrics-text; version=1.0.0\r\n\r\n",
+            )
+            .await;
+            assert!(response.contains(OPENMETRICS_CONTENT_TYPE));
+            assert!(response.ends_with("# EOF\n"));
+
+            let response = get(address, "GET /other HTTP/1.0\r\n\r\n").await;
+            assert!(response.starts_with("HTTP/1.0 404 Not Found"));
+
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_reference_id() {
+        let reference_id = ReferenceId::from_code("GPS").unwrap();
+        assert_eq!(format_reference_id(reference_id, 1), "GPS");
+
+        let reference_id = ReferenceId::from_ip("192.0.2.1".parse().unwrap());
+        assert_eq!(format_reference_id(reference_id, 2), "192.0.2.1");
+
+        assert_eq!(format_reference_id(ReferenceId::NONE, 16), "XNON");
+    }
+
+    #[test]
+    fn test_encode_metrics() {
+        let mut state: ObservableState = serde_json::from_str(
+            r#"{
+                "system": {
+                    "stratum": 2,
+                    "reference_id": 3221225985,
+                    "accumulated_steps_threshold": null,
+                    "poll_interval": 4,
+                    "precision": 1e-6,
+                    "root_delay": 0.25,
+                    "root_dispersion": 0.125,
+                    "leap_indicator": "NoWarning",
+                    "offset": 0.0,
+                    "jitter": 0.001,
+                    "frequency": 1e-5,
+                    "accumulated_steps": 0.0,
+                    "clock_state": "sync"
+                },
+                "peers": [],
+                "servers": []
+            }"#,
+        )
+        .unwrap();
+        state.peers.push(ObservablePeerState::Observable {
+            timedata: ntp_proto::ObservablePeerTimedata {
+                selection: PeerSelection::Selected,
+                ..Default::default()
+            },
+            reachability: Default::default(),
+            poll_interval: ntp_proto::PollIntervalLimits::default().min,
+            peer_id: ReferenceId::from_ip("192.0.2.1".parse().unwrap()),
+            address: "192.0.2.1:123".into(),
+            stratum: 1,
+            reference_id: ReferenceId::from_code("GPS").unwrap(),
+            ignored: Default::default(),
+        });
+
+        let metrics = Metrics::default();
+        metrics.fill(&state);
+        let mut buf = vec![];
+        prometheus_client::encoding::text::encode(&mut buf, &metrics.registry()).unwrap();
+        let output = String::from_utf8(buf).unwrap();
+
+        for line in [
+            "ntp_system_offset_seconds 0.0",
+            "ntp_system_frequency_ppm 10.0",
+            "ntp_system_stratum 2",
+            "ntp_system_clock_state{state=\"sync\"} 1",
+            "ntp_system_clock_state{state=\"spike\"} 0",
+            "ntp_system_peer{address=\"192.0.2.1:123\"} 1",
+            "ntp_peer_stratum{address=\"192.0.2.1:123\"} 1",
+            "ntp_peer_reference_id{address=\"192.0.2.1:123\",reference_id=\"GPS\"} 1",
+            "ntp_peer_selection{address=\"192.0.2.1:123\",selection=\"selected\"} 1",
+            "ntp_peer_selection{address=\"192.0.2.1:123\",selection=\"outlier\"} 0",
+            "ntp_peer_ignored_packets_total{address=\"192.0.2.1:123\",reason=\"kiss_ignore\"} 0",
+        ] {
+            assert!(
+                output.lines().any(|l| l == line),
+                "{} not in {}",
+                line,
+                output
+            );
+        }
+    }
+}
@@ -1,23 +1,30 @@
+use crate::access::{Access, Role};
 use crate::server::ServerStats;
-use crate::{sockets::create_unix_socket, system::ServerData};
-use ntp_proto::{ObservablePeerTimedata, PollInterval, Reach, ReferenceId, SystemSnapshot};
+use crate::{sockets::into_tokio_listener, system::ServerData};
+use ntp_proto::{
+    IgnoreCounts, MeasurementRecord, NtpDuration, NtpLeapIndicator, ObservablePeerTimedata,
+    PollInterval, Reach, ReferenceId, SystemSnapshot,
+};
 use prometheus_client::encoding::text::Encode;
 use std::io::Write;
 use std::net::SocketAddr;
-use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
-use tracing::error;
+use tracing::{debug, error};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ObservableState {
     pub system: SystemSnapshot,
     pub peers: Vec<ObservablePeerState>,
     pub servers: Vec<ObservableServerState>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ObservableServerState {
     pub address: WrappedSocketAddr,
     pub stats: ServerStats,
@@ -57,18 +64,111 @@ pub enum ObservablePeerState {
         poll_interval: PollInterval,
         peer_id: ReferenceId,
         address: String,
+        #[serde(default)]
+        stratum: u8,
+        #[serde(default = "unknown_reference_id")]
+        reference_id: ReferenceId,
+        #[serde(default)]
+        ignored: IgnoreCounts,
     },
 }
 
+fn unknown_reference_id() -> ReferenceId {
+    ReferenceId::NONE
+}
+
+/// The most recent measurements of a peer, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerHistory {
+    pub address: String,
+    pub peer_id: ReferenceId,
+    pub measurements: Vec<MeasurementRecord>,
+}
+
+impl PeerHistory {
+    /// Whether this is the history of `peer`, given as configured or without the port
+    pub fn matches(&self, peer: &str) -> bool {
+        self.address == peer
+            || matches!(self.address.strip_prefix(peer), Some(rest) if rest.starts_with(':'))
+    }
+}
+
+/// Time a client gets to send a request after receiving the current state
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Sent by a client right after connecting to the observe socket, to get more
+/// than the current state
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ObserveRequest {
+    /// Keep receiving updates after the current state
+    Subscribe {
+        /// Only send the parts of the state that changed, instead of all of it
+        #[serde(default)]
+        deltas: bool,
+    },
+    /// Get the recent measurements of the peers with the given address, see
+    /// [`PeerHistory::matches`]
+    History { peer: String },
+}
+
+/// An update sent to subscribed clients, one per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ObserveUpdate {
+    Snapshot(ObservableState),
+    Delta(ObservableDelta),
+    Event(ObservableEvent),
+}
+
+/// The parts of the state that changed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ObservableDelta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<SystemSnapshot>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peers: Option<Vec<ObservablePeerState>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub servers: Option<Vec<ObservableServerState>>,
+}
+
+/// Something that happened between two states, sent before the update that
+/// contains the new state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ObservableEvent {
+    /// The clock was stepped, by `amount` seconds in either direction
+    Step { amount: f64 },
+    /// A peer that could be reached no longer answers
+    PeerUnreachable { address: String },
+    /// A leap second will be inserted or deleted at the end of the day
+    LeapArmed { leap_indicator: NtpLeapIndicator },
+}
+
+/// Serve the observable state on `listener`, see
+/// [`crate::sockets::listen_unix_socket`]. Does nothing when there is none.
 pub async fn spawn(
-    config: &crate::config::ObserveConfig,
+    listener: Option<std::os::unix::net::UnixListener>,
+    access: Access,
     peers_reader: tokio::sync::watch::Receiver<Vec<ObservablePeerState>>,
+    history_reader: tokio::sync::watch::Receiver<Vec<PeerHistory>>,
     server_reader: tokio::sync::watch::Receiver<Vec<ServerData>>,
     system_reader: tokio::sync::watch::Receiver<SystemSnapshot>,
 ) -> JoinHandle<std::io::Result<()>> {
-    let config = config.clone();
     tokio::spawn(async move {
-        let result = observer(config, peers_reader, server_reader, system_reader).await;
+        let listener = match listener {
+            Some(listener) => listener,
+            None => return Ok(()),
+        };
+        let result = observer(
+            listener,
+            access,
+            peers_reader,
+            history_reader,
+            server_reader,
+            system_reader,
+        )
+        .await;
         if let Err(ref e) = result {
             error!("Abnormal termination of state observer: {}", e);
         }
@@ -77,34 +177,213 @@ pub async fn spawn(
 }
 
 async fn observer(
-    config: crate::config::ObserveConfig,
+    listener: std::os::unix::net::UnixListener,
+    access: Access,
     peers_reader: tokio::sync::watch::Receiver<Vec<ObservablePeerState>>,
+    history_reader: tokio::sync::watch::Receiver<Vec<PeerHistory>>,
     server_reader: tokio::sync::watch::Receiver<Vec<ServerData>>,
     system_reader: tokio::sync::watch::Receiver<SystemSnapshot>,
 ) -> std::io::Result<()> {
-    let path = match config.path {
-        Some(path) => path,
-        None => return Ok(()),
-    };
+    let peers_listener = into_tokio_listener(listener)?;
+
+    loop {
+        let (stream, _addr) = peers_listener.accept().await?;
 
-    let peers_listener = create_unix_socket(&path)?;
+        if !access.check(Role::Read, &stream) {
+            continue;
+        }
 
-    // this binary needs to run as root to be able to adjust the system clock.
-    // by default, the socket inherits root permissions, but the client should not need
-    // elevated permissions to read from the socket. So we explicitly set the permissions
-    let permissions: std::fs::Permissions = PermissionsExt::from_mode(config.mode);
-    std::fs::set_permissions(&path, permissions)?;
+        let mut connection = ObserveConnection {
+            peers_reader: peers_reader.clone(),
+            history_reader: history_reader.clone(),
+            server_reader: server_reader.clone(),
+            system_reader: system_reader.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = connection.serve(stream).await {
+                debug!("Observe connection closed: {}", e);
+            }
+        });
+    }
+}
 
-    loop {
-        let (mut stream, _addr) = peers_listener.accept().await?;
+/// A connection to the observe socket. It receives the current state, after
+/// which the client may ask for more, see [`ObserveRequest`].
+struct ObserveConnection {
+    peers_reader: watch::Receiver<Vec<ObservablePeerState>>,
+    history_reader: watch::Receiver<Vec<PeerHistory>>,
+    server_reader: watch::Receiver<Vec<ServerData>>,
+    system_reader: watch::Receiver<SystemSnapshot>,
+}
 
-        let observe = ObservableState {
-            peers: peers_reader.borrow().to_owned(),
-            system: *system_reader.borrow(),
-            servers: server_reader.borrow().iter().map(|s| s.into()).collect(),
+impl ObserveConnection {
+    async fn serve(&mut self, mut stream: UnixStream) -> std::io::Result<()> {
+        let mut state = ObservableState {
+            peers: self.peers_reader.borrow_and_update().to_owned(),
+            system: *self.system_reader.borrow_and_update(),
+            servers: observe_servers(&self.server_reader.borrow_and_update()),
         };
+        write_line(&mut stream, &state).await?;
+
+        // Clients that only want the current state send nothing, and usually close the connection
+        let mut msg = Vec::with_capacity(1024);
+        let read = crate::sockets::read_json(&mut stream, &mut msg);
+        let request: ObserveRequest = match tokio::time::timeout(REQUEST_TIMEOUT, read).await {
+            Ok(Ok(request)) => request,
+            _ => return Ok(()),
+        };
+
+        let deltas = match request {
+            ObserveRequest::Subscribe { deltas } => deltas,
+            ObserveRequest::History { peer } => {
+                let history: Vec<PeerHistory> = self
+                    .history_reader
+                    .borrow()
+                    .iter()
+                    .filter(|history| history.matches(&peer))
+                    .cloned()
+                    .collect();
+                return write_line(&mut stream, &history).await;
+            }
+        };
+
+        loop {
+            let delta = match self.changes().await {
+                Some(delta) => delta,
+                // the daemon is shutting down
+                None => return Ok(()),
+            };
+
+            let previous = state.clone();
+            if let Some(system) = delta.system {
+                state.system = system;
+            }
+            if let Some(peers) = &delta.peers {
+                state.peers = peers.clone();
+            }
+            if let Some(servers) = &delta.servers {
+                state.servers = servers.clone();
+            }
 
-        crate::sockets::write_json(&mut stream, &observe).await?;
+            for event in events(&previous, &state) {
+                write_line(&mut stream, &ObserveUpdate::Event(event)).await?;
+            }
+
+            let update = if deltas {
+                ObserveUpdate::Delta(delta)
+            } else {
+                ObserveUpdate::Snapshot(state.clone())
+            };
+            write_line(&mut stream, &update).await?;
+        }
+    }
+
+    /// Wait for the state to change, `None` when it no longer can
+    async fn changes(&mut self) -> Option<ObservableDelta> {
+        let mut delta = ObservableDelta::default();
+
+        // waiting marks the change as seen, so take the new value right away
+        let result = tokio::select! {
+            result = self.peers_reader.changed() => {
+                delta.peers = Some(self.peers_reader.borrow().to_owned());
+                result
+            }
+            result = self.server_reader.changed() => {
+                delta.servers = Some(observe_servers(&self.server_reader.borrow()));
+                result
+            }
+            result = self.system_reader.changed() => {
+                delta.system = Some(*self.system_reader.borrow());
+                result
+            }
+        };
+        result.ok()?;
+
+        // a single clock update usually changes more than one part
+        if delta.peers.is_none() && self.peers_reader.has_changed().unwrap_or(false) {
+            delta.peers = Some(self.peers_reader.borrow_and_update().to_owned());
+        }
+        if delta.servers.is_none() && self.server_reader.has_changed().unwrap_or(false) {
+            delta.servers = Some(observe_servers(&self.server_reader.borrow_and_update()));
+        }
+        if delta.system.is_none() && self.system_reader.has_changed().unwrap_or(false) {
+            delta.system = Some(*self.system_reader.borrow_and_update());
+        }
+
+        Some(delta)
+    }
+}
+
+/// The events that explain the change from `previous` to `current`
+fn events(previous: &ObservableState, current: &ObservableState) -> Vec<ObservableEvent> {
+    let mut events = vec![];
+
+    let steps = current.system.time_snapshot.accumulated_steps
+        - previous.system.time_snapshot.accumulated_steps;
+    if steps > NtpDuration::ZERO {
+        events.push(ObservableEvent::Step {
+            amount: steps.to_seconds(),
+        });
+    }
+
+    for peer in &current.peers {
+        if let ObservablePeerState::Observable {
+            reachability,
+            address,
+            ..
+        } = peer
+        {
+            let was_reachable = previous.peers.iter().any(|previous| {
+                matches!(previous, ObservablePeerState::Observable {
+                    reachability,
+                    address: previous_address,
+                    ..
+                } if previous_address == address && reachability.is_reachable())
+            });
+            if was_reachable && !reachability.is_reachable() {
+                events.push(ObservableEvent::PeerUnreachable {
+                    address: address.clone(),
+                });
+            }
+        }
+    }
+
+    let is_armed = |leap_indicator| {
+        matches!(
+            leap_indicator,
+            NtpLeapIndicator::Leap61 | NtpLeapIndicator::Leap59
+        )
+    };
+    let leap_indicator = current.system.time_snapshot.leap_indicator;
+    if is_armed(leap_indicator) && !is_armed(previous.system.time_snapshot.leap_indicator) {
+        events.push(ObservableEvent::LeapArmed { leap_indicator });
+    }
+
+    events
+}
+
+/// Write `value` as a single line, which clients that read a single value
+/// ignore as trailing whitespace
+async fn write_line(stream: &mut UnixStream, value: &impl Serialize) -> std::io::Result<()> {
+    let mut bytes = serde_json::to_vec(value).unwrap();
+    bytes.push(b'\n');
+    stream.write_all(&bytes).await
+}
+
+fn observe_servers(servers: &[ServerData]) -> Vec<ObservableServerState> {
+    servers.iter().map(|s| s.into()).collect()
+}
+
+/// The current state, without keeping any of the channels borrowed
+pub(crate) fn observe_state(
+    peers_reader: &tokio::sync::watch::Receiver<Vec<ObservablePeerState>>,
+    server_reader: &tokio::sync::watch::Receiver<Vec<ServerData>>,
+    system_reader: &tokio::sync::watch::Receiver<SystemSnapshot>,
+) -> ObservableState {
+    ObservableState {
+        peers: peers_reader.borrow().to_owned(),
+        system: *system_reader.borrow(),
+        servers: observe_servers(&server_reader.borrow()),
     }
 }
 
@@ -116,7 +395,7 @@ mod tests {
         NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval, PollIntervalLimits,
         Reach, ReferenceId, TimeSnapshot,
     };
-    use tokio::{io::AsyncReadExt, net::UnixStream};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
 
     use super::*;
 
@@ -134,6 +413,10 @@ mod tests {
             Ok(())
         }
 
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            Ok(0.0)
+        }
+
         fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
             Ok(())
         }
@@ -154,10 +437,7 @@ mod tests {
     async fn test_observation() {
         // be careful with copying: tests run concurrently and should use a unique socket name!
         let path = std::env::temp_dir().join("ntp-test-stream-2");
-        let config = crate::config::ObserveConfig {
-            path: Some(path.clone()),
-            mode: 0o700,
-        };
+        let listener = crate::sockets::create_unix_socket(&path).unwrap();
 
         let (_, peers_reader) = tokio::sync::watch::channel(vec![
             ObservablePeerState::Nothing,
@@ -168,6 +448,9 @@ mod tests {
                 poll_interval: PollIntervalLimits::default().min,
                 peer_id: ReferenceId::from_ip("127.0.0.1".parse().unwrap()),
                 address: "127.0.0.3:123".into(),
+                stratum: 2,
+                reference_id: ReferenceId::NONE,
+                ignored: Default::default(),
             },
         ]);
 
@@ -183,14 +466,26 @@ mod tests {
                 root_delay: NtpDuration::ZERO,
                 root_dispersion: NtpDuration::ZERO,
                 leap_indicator: NtpLeapIndicator::Leap59,
+                offset: NtpDuration::ZERO,
+                jitter: NtpDuration::ZERO,
+                frequency: 0.0,
                 accumulated_steps: NtpDuration::ZERO,
+                slew_remaining: None,
             },
+            clock_state: Default::default(),
         });
 
         let handle = tokio::spawn(async move {
-            observer(config, peers_reader, servers_reader, system_reader)
-                .await
-                .unwrap();
+            observer(
+                listener,
+                Access::new(Default::default(), Default::default()),
+                peers_reader,
+                tokio::sync::watch::channel(vec![]).1,
+                servers_reader,
+                system_reader,
+            )
+            .await
+            .unwrap();
         });
 
         tokio::time::sleep(Duration::from_millis(10)).await;
@@ -217,10 +512,7 @@ mod tests {
     async fn test_block_during_read() {
         // be careful with copying: tests run concurrently and should use a unique socket name!
         let path = std::env::temp_dir().join("ntp-test-stream-3");
-        let config = crate::config::ObserveConfig {
-            path: Some(path.clone()),
-            mode: 0o700,
-        };
+        let listener = crate::sockets::create_unix_socket(&path).unwrap();
 
         let (mut peers_writer, peers_reader) = tokio::sync::watch::channel(vec![
             ObservablePeerState::Nothing,
@@ -231,6 +523,9 @@ mod tests {
                 poll_interval: PollIntervalLimits::default().min,
                 peer_id: ReferenceId::from_ip("127.0.0.1".parse().unwrap()),
                 address: "127.0.0.3:123".into(),
+                stratum: 2,
+                reference_id: ReferenceId::NONE,
+                ignored: Default::default(),
             },
         ]);
 
@@ -246,14 +541,26 @@ mod tests {
                 root_delay: NtpDuration::ZERO,
                 root_dispersion: NtpDuration::ZERO,
                 leap_indicator: NtpLeapIndicator::Leap59,
+                offset: NtpDuration::ZERO,
+                jitter: NtpDuration::ZERO,
+                frequency: 0.0,
                 accumulated_steps: NtpDuration::ZERO,
+                slew_remaining: None,
             },
+            clock_state: Default::default(),
         });
 
         let handle = tokio::spawn(async move {
-            observer(config, peers_reader, servers_reader, system_reader)
-                .await
-                .unwrap();
+            observer(
+                listener,
+                Access::new(Default::default(), Default::default()),
+                peers_reader,
+                tokio::sync::watch::channel(vec![]).1,
+                servers_reader,
+                system_reader,
+            )
+            .await
+            .unwrap();
         });
 
         tokio::time::sleep(Duration::from_millis(10)).await;
@@ -274,4 +581,209 @@ mod tests {
 
         handle.abort();
     }
+
+    fn observable_peer(address: &str, reach: u8) -> ObservablePeerState {
+        ObservablePeerState::Observable {
+            timedata: Default::default(),
+            reachability: serde_json::from_str(&reach.to_string()).unwrap(),
+            poll_interval: PollIntervalLimits::default().min,
+            peer_id: ReferenceId::from_ip("127.0.0.1".parse().unwrap()),
+            address: address.into(),
+            stratum: 2,
+            reference_id: ReferenceId::NONE,
+            ignored: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let previous = ObservableState {
+            system: SystemSnapshot::default(),
+            peers: vec![
+                observable_peer("127.0.0.2:123", 1),
+                observable_peer("127.0.0.3:123", 0),
+                observable_peer("127.0.0.4:123", 1),
+            ],
+            servers: vec![],
+        };
+        assert!(events(&previous, &previous).is_empty());
+
+        let mut current = previous.clone();
+        current.system.time_snapshot.accumulated_steps = NtpDuration::from_seconds(2.0);
+        current.system.time_snapshot.leap_indicator = NtpLeapIndicator::Leap61;
+        current.peers = vec![
+            observable_peer("127.0.0.2:123", 0),
+            observable_peer("127.0.0.3:123", 0),
+            observable_peer("127.0.0.4:123", 2),
+        ];
+
+        let events = events(&previous, &current);
+        assert_eq!(events.len(), 3);
+        assert!(
+            matches!(events[0], ObservableEvent::Step { amount } if (amount - 2.0).abs() < 1e-6)
+        );
+        assert_eq!(
+            events[1],
+            ObservableEvent::PeerUnreachable {
+                address: "127.0.0.2:123".into()
+            }
+        );
+        assert_eq!(
+            events[2],
+            ObservableEvent::LeapArmed {
+                leap_indicator: NtpLeapIndicator::Leap61
+            }
+        );
+
+        // a leap second that stays armed is not reported again
+        let mut next = current.clone();
+        next.system.time_snapshot.leap_indicator = NtpLeapIndicator::Leap59;
+        assert!(super::events(&current, &next).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscription() {
+        // be careful with copying: tests run concurrently and should use a unique socket name!
+        let path = std::env::temp_dir().join("ntp-test-stream-11");
+        let listener = crate::sockets::create_unix_socket(&path).unwrap();
+
+        let (peers_writer, peers_reader) =
+            tokio::sync::watch::channel(vec![observable_peer("127.0.0.2:123", 1)]);
+        let (_server_writer, servers_reader) = tokio::sync::watch::channel(vec![]);
+        let (system_writer, system_reader) = tokio::sync::watch::channel(SystemSnapshot::default());
+
+        let handle = tokio::spawn(async move {
+            observer(
+                listener,
+                Access::new(Default::default(), Default::default()),
+                peers_reader,
+                tokio::sync::watch::channel(vec![]).1,
+                servers_reader,
+                system_reader,
+            )
+            .await
+            .unwrap();
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        crate::sockets::write_json(&mut stream, &ObserveRequest::Subscribe { deltas: true })
+            .await
+            .unwrap();
+        let mut lines = tokio::io::BufReader::new(stream).lines();
+
+        let state: ObservableState =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(state.peers.len(), 1);
+
+        // give the observer time to handle the subscription
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        peers_writer
+            .send(vec![observable_peer("127.0.0.2:123", 0)])
+            .unwrap();
+        let update: ObserveUpdate =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(
+            update,
+            ObserveUpdate::Event(ObservableEvent::PeerUnreachable { .. })
+        ));
+        let update: ObserveUpdate =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        match update {
+            ObserveUpdate::Delta(delta) => {
+                assert_eq!(delta.peers.unwrap().len(), 1);
+                assert!(delta.system.is_none());
+                assert!(delta.servers.is_none());
+            }
+            other => panic!("unexpected update {:?}", other),
+        }
+
+        system_writer.send_modify(|system| system.stratum = 2);
+        let update: ObserveUpdate =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        match update {
+            ObserveUpdate::Delta(delta) => assert_eq!(delta.system.unwrap().stratum, 2),
+            other => panic!("unexpected update {:?}", other),
+        }
+
+        handle.abort();
+    }
+
+    #[test]
+    fn test_history_matches() {
+        let history = PeerHistory {
+            address: "ntp.example.com:123".into(),
+            peer_id: ReferenceId::NONE,
+            measurements: vec![],
+        };
+
+        assert!(history.matches("ntp.example.com:123"));
+        assert!(history.matches("ntp.example.com"));
+        assert!(!history.matches("ntp.example"));
+        assert!(!history.matches("ntp.example.com:1234"));
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        // be careful with copying: tests run concurrently and should use a unique socket name!
+        let path = std::env::temp_dir().join("ntp-test-stream-12");
+        let listener = crate::sockets::create_unix_socket(&path).unwrap();
+
+        // durations are sent in seconds, pick some that survive the round trip exactly
+        let measurement = MeasurementRecord {
+            offset: NtpDuration::from_seconds(0.5),
+            delay: NtpDuration::from_seconds(0.25),
+            dispersion: NtpDuration::from_seconds(0.125),
+            timestamp: Default::default(),
+            accepted: true,
+        };
+        let history = |address: &str| PeerHistory {
+            address: address.into(),
+            peer_id: ReferenceId::NONE,
+            measurements: vec![measurement],
+        };
+        let (_history_writer, history_reader) =
+            tokio::sync::watch::channel(vec![history("127.0.0.2:123"), history("127.0.0.3:123")]);
+
+        let handle = tokio::spawn(async move {
+            observer(
+                listener,
+                Access::new(Default::default(), Default::default()),
+                tokio::sync::watch::channel(vec![]).1,
+                history_reader,
+                tokio::sync::watch::channel(vec![]).1,
+                tokio::sync::watch::channel(SystemSnapshot::default()).1,
+            )
+            .await
+            .unwrap();
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        let request = ObserveRequest::History {
+            peer: "127.0.0.3".into(),
+        };
+        crate::sockets::write_json(&mut stream, &request)
+            .await
+            .unwrap();
+        let mut lines = tokio::io::BufReader::new(stream).lines();
+
+        // the state comes first, as always
+        let _: ObservableState =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+
+        let history: Vec<PeerHistory> =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].address, "127.0.0.3:123");
+        assert_eq!(history[0].measurements, vec![measurement]);
+
+        // after which the connection is closed
+        assert!(lines.next_line().await.unwrap().is_none());
+
+        handle.abort();
+    }
 }
@@ -0,0 +1,550 @@
+//! One-shot synchronization, as a replacement for `ntpdate` or `chronyd -q`.
+//!
+//! Every configured peer is queried a few times, after which the regular
+//! clock selection and combining algorithms determine the offset of the local
+//! clock. The clock is then corrected once: large offsets are stepped, small
+//! offsets are handed to the kernel to be slewed away. With `slew_only`,
+//! offsets beyond what the kernel slews away are refused.
+
+use std::{fmt::Display, io::Cursor, net::SocketAddr};
+
+use ntp_os_clock::UnixNtpClock;
+use ntp_proto::{
+    FilterAndCombine, IgnoreReason, NtpClock, NtpDuration, NtpInstant, Peer, PeerOptions,
+    PeerSnapshot, PeerTimeSnapshot, PeerTimeState, ReferenceId, SystemConfig, SystemSnapshot,
+    Update,
+};
+use ntp_udp::UdpSocket;
+use thiserror::Error;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::PeerConfig,
+    peer::{accept_packet, unspecified_for, AcceptResult, BURST_INTERVAL},
+};
+
+/// Number of requests sent to every peer
+const SAMPLES: usize = 4;
+
+/// Time to wait for a response to a request
+const RESPONSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum OnceError {
+    #[error("no peers are configured")]
+    NoPeers,
+    #[error("no usable time sources, {0} peer(s) queried")]
+    NoSources(usize),
+    #[error("offset of {0:+.6}s exceeds the startup panic threshold, clock not adjusted")]
+    Panic(f64),
+    #[error("offset of {0:+.6}s is too large to slew away with slew-only, clock not adjusted")]
+    SlewTooLarge(f64),
+    #[error("could not adjust the clock: {0}")]
+    Clock(String),
+}
+
+impl OnceError {
+    /// Exit code of the process for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            OnceError::NoPeers => exitcode::CONFIG,
+            OnceError::NoSources(_) => exitcode::UNAVAILABLE,
+            OnceError::Panic(_) | OnceError::SlewTooLarge(_) => exitcode::SOFTWARE,
+            OnceError::Clock(_) => exitcode::NOPERM,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Adjustment {
+    Step,
+    Slew,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OnceResult {
+    pub adjustment: Adjustment,
+    pub offset: NtpDuration,
+    pub jitter: NtpDuration,
+    /// Address of the peer selected as system peer
+    pub system_peer: SocketAddr,
+    pub usable_peers: usize,
+    pub queried_peers: usize,
+}
+
+impl Display for OnceResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.adjustment {
+            Adjustment::Step => "stepped",
+            Adjustment::Slew => "slewing",
+        };
+
+        write!(
+            f,
+            "{} clock by {:+.6}s (jitter {:.6}s), system peer {}, {} of {} peer(s) usable",
+            action,
+            self.offset.to_seconds(),
+            self.jitter.to_seconds(),
+            self.system_peer,
+            self.usable_peers,
+            self.queried_peers,
+        )
+    }
+}
+
+/// Synchronize the system clock once with the given peers
+pub async fn run(
+    config: SystemConfig,
+    peer_configs: &[PeerConfig],
+) -> Result<OnceResult, OnceError> {
+    sync_once(UnixNtpClock::new(), config, peer_configs).await
+}
+
+async fn sync_once<C: NtpClock>(
+    clock: C,
+    config: SystemConfig,
+    peer_configs: &[PeerConfig],
+) -> Result<OnceResult, OnceError> {
+    let addresses = resolve(peer_configs).await;
+    if addresses.is_empty() {
+        return Err(OnceError::NoPeers);
+    }
+
+    let handles: Vec<_> = addresses
+        .iter()
+        .map(|&(address, options)| {
+            let clock = clock.clone();
+            tokio::spawn(async move { query_peer(clock, address, options, config).await })
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(handles.len());
+    for (&(address, _), handle) in addresses.iter().zip(handles) {
+        if let Ok(Some(result)) = handle.await {
+            results.push((address, result));
+        }
+    }
+
+    let now = NtpInstant::now();
+    let system = SystemSnapshot::default();
+    let candidates: Vec<(SocketAddr, PeerTimeSnapshot)> = results
+        .into_iter()
+        .filter(|(address, (snapshot, timedata))| {
+            let accepted = snapshot
+                .accept_synchronization(config.local_stratum)
+                .and_then(|_| {
+                    timedata.accept_synchronization(
+                        now,
+                        config.frequency_tolerance,
+                        config.distance_threshold,
+                        system.time_snapshot.poll_interval,
+                    )
+                });
+            if let Err(error) = &accepted {
+                info!(%address, ?error, "peer is not usable");
+            }
+            accepted.is_ok()
+        })
+        .map(|(address, (_, timedata))| (address, timedata))
+        .collect();
+
+    let selection = FilterAndCombine::run(
+        &config,
+        &candidates,
+        now,
+        system.time_snapshot.poll_interval,
+    )
+    .ok_or(OnceError::NoSources(addresses.len()))?;
+
+    let offset = selection.system_offset;
+    if !config.startup_panic_threshold.is_within(offset) {
+        return Err(OnceError::Panic(offset.to_seconds()));
+    }
+
+    // The kernel clamps the offset it slews away to half a second, so with
+    // slew-only anything beyond that would mostly remain
+    if config.slew_only && offset.abs() > NtpDuration::from_exponent(-1) {
+        return Err(OnceError::SlewTooLarge(offset.to_seconds()));
+    }
+
+    let adjustment = if offset.abs() > NtpDuration::STEP_THRESHOLD && !config.slew_only {
+        clock
+            .step_clock(offset)
+            .map_err(|e| OnceError::Clock(e.to_string()))?;
+        Adjustment::Step
+    } else {
+        clock
+            .update_clock(
+                offset,
+                selection.system_jitter,
+                selection.system_root_delay / 2 + selection.system_root_dispersion,
+                config.initial_poll,
+                selection.system_peer_snapshot.1.leap_indicator,
+            )
+            .map_err(|e| OnceError::Clock(e.to_string()))?;
+        Adjustment::Slew
+    };
+
+    Ok(OnceResult {
+        adjustment,
+        offset,
+        jitter: selection.system_jitter,
+        system_peer: selection.system_peer_snapshot.0,
+        usable_peers: candidates.len(),
+        queried_peers: addresses.len(),
+    })
+}
+
+/// Resolve the configured peers to the addresses to query
+async fn resolve(peer_configs: &[PeerConfig]) -> Vec<(SocketAddr, PeerOptions)> {
+    let mut addresses = vec![];
+
+    for peer_config in peer_configs {
+        let (addr, max_peers, options) = match peer_config {
+            PeerConfig::Standard(config) => (&config.addr, 1, config.options),
+            PeerConfig::Pool(config) => (&config.addr, config.max_peers, config.options),
+        };
+
+        match addr.lookup_host().await {
+            Ok(resolved) => {
+                let before = addresses.len();
+                addresses.extend(resolved.take(max_peers).map(|address| (address, options)));
+                if addresses.len() == before {
+                    warn!(addr = addr.as_str(), "could not resolve peer address");
+                }
+            }
+            Err(error) => {
+                warn!(
+                    addr = addr.as_str(),
+                    ?error,
+                    "could not resolve peer address"
+                );
+            }
+        }
+    }
+
+    addresses
+}
+
+/// Query a single peer a number of times, returning its state afterwards
+async fn query_peer<C: NtpClock>(
+    clock: C,
+    address: SocketAddr,
+    options: PeerOptions,
+    config: SystemConfig,
+) -> Option<(PeerSnapshot, PeerTimeSnapshot)> {
+    let mut socket = match UdpSocket::client(unspecified_for(address), address).await {
+        Ok(socket) => socket,
+        Err(error) => {
+            warn!(%address, ?error, "could not open socket");
+            return None;
+        }
+    };
+
+    // Unwraps should be safe because we know the socket was bound and connected just before
+    let our_id = ReferenceId::from_ip(socket.as_ref().local_addr().unwrap().ip());
+    let peer_id = ReferenceId::from_ip(socket.as_ref().peer_addr().unwrap().ip());
+
+    let system = SystemSnapshot::default();
+    let mut peer = Peer::new(our_id, peer_id, NtpInstant::now(), config, options);
+    let mut timestate = PeerTimeState::new(NtpInstant::now());
+
+    for sample in 0..SAMPLES {
+        if sample > 0 {
+            tokio::time::sleep(BURST_INTERVAL).await;
+        }
+
+        let packet = peer.generate_poll_message(system, &config);
+        let mut send_timestamp = match clock.now() {
+            Ok(ts) => ts,
+            Err(error) => {
+                warn!(%error, "there was an error retrieving the current time");
+                return None;
+            }
+        };
+
+        let mut buf = Cursor::new([0; 48]);
+        if let Err(error) = packet.serialize(&mut buf) {
+            warn!(?error, "poll message could not be serialized");
+            continue;
+        }
+        match socket.send(&buf.get_ref()[..buf.position() as usize]).await {
+            Ok((_, opt_send_timestamp)) => {
+                send_timestamp = opt_send_timestamp.unwrap_or(send_timestamp);
+            }
+            Err(error) => {
+                warn!(%address, ?error, "poll message could not be sent");
+                continue;
+            }
+        }
+
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        loop {
+            let mut buf = [0_u8; 48];
+            let result = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    debug!(%address, "no response received");
+                    break;
+                }
+            };
+
+            // The kernel does not timestamp packets that arrive right after
+            // timestamping was enabled, read the clock instead like for the
+            // send timestamp
+            let result = result.map(|(size, addr, recv_timestamp)| {
+                (size, addr, recv_timestamp.or_else(|| clock.now().ok()))
+            });
+
+            let (packet, recv_timestamp) = match accept_packet(result, &buf) {
+                AcceptResult::Accept(packet, recv_timestamp) => (packet, recv_timestamp),
+                AcceptResult::NetworkGone => return None,
+                _ => continue,
+            };
+
+            match peer.handle_incoming(
+                system,
+                packet,
+                NtpInstant::now(),
+                send_timestamp,
+                recv_timestamp,
+            ) {
+                Ok(Update::NewMeasurement(_, measurement, packet)) => {
+                    timestate.update(measurement, packet, system.time_snapshot, &config);
+                    break;
+                }
+                Ok(Update::BareUpdate(_)) => break,
+                Err(IgnoreReason::KissDemobilize) => {
+                    warn!(%address, "peer denied service");
+                    return None;
+                }
+                Err(ignore_reason) => {
+                    debug!(?ignore_reason, "packet ignored");
+                }
+            }
+        }
+    }
+
+    Some((
+        PeerSnapshot::from_peer(&peer),
+        PeerTimeSnapshot::from_timestate(&timestate, options),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{Arc, Mutex},
+    };
+
+    use ntp_proto::{
+        NtpLeapIndicator, NtpPacket, NtpTimestamp, PollInterval, StepThreshold, TimeSnapshot,
+    };
+
+    use super::*;
+
+    const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
+
+    fn system_time(offset: NtpDuration) -> NtpTimestamp {
+        let cur = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        NtpTimestamp::from_seconds_nanos_since_ntp_era(
+            EPOCH_OFFSET.wrapping_add(cur.as_secs() as u32),
+            cur.subsec_nanos(),
+        ) + offset
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct TestClock {
+        offset: NtpDuration,
+        steps: Arc<Mutex<Vec<NtpDuration>>>,
+        updates: Arc<Mutex<Vec<NtpDuration>>>,
+    }
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(system_time(self.offset))
+        }
+
+        fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by one-shot synchronization");
+        }
+
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            panic!("Shouldn't be called by one-shot synchronization");
+        }
+
+        fn step_clock(&self, offset: NtpDuration) -> Result<(), Self::Error> {
+            self.steps.lock().unwrap().push(offset);
+            Ok(())
+        }
+
+        fn update_clock(
+            &self,
+            offset: NtpDuration,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+            _poll_interval: PollInterval,
+            _leap_status: NtpLeapIndicator,
+        ) -> Result<(), Self::Error> {
+            self.updates.lock().unwrap().push(offset);
+            Ok(())
+        }
+    }
+
+    /// Run a server on an ephemeral port whose clock is ahead by the given
+    /// offset. It answers until the returned handle is aborted.
+    async fn serve(offset: NtpDuration) -> (u16, tokio::task::JoinHandle<()>) {
+        let socket = UdpSocket::server(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let port = socket.as_ref().local_addr().unwrap().port();
+        let clock = TestClock {
+            offset,
+            ..Default::default()
+        };
+        let system = SystemSnapshot {
+            stratum: 1,
+            time_snapshot: TimeSnapshot {
+                leap_indicator: NtpLeapIndicator::NoWarning,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let server = tokio::spawn(async move {
+            loop {
+                let mut buf = [0; 48];
+                let (size, addr, timestamp) = match socket.recv(&mut buf).await {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                };
+                if size < 48 {
+                    continue;
+                }
+
+                // The kernel does not always provide a receive timestamp,
+                // fall back to reading the clock like a server would
+                let timestamp = match timestamp {
+                    Some(timestamp) => timestamp + offset,
+                    None => clock.now().unwrap(),
+                };
+
+                let request = NtpPacket::deserialize(&buf).unwrap();
+                let response = NtpPacket::timestamp_response(&system, request, timestamp, &clock);
+                let mut data = vec![];
+                response.serialize(&mut data).unwrap();
+                let _ = socket.send_to(&data, addr).await;
+            }
+        });
+
+        (port, server)
+    }
+
+    fn test_config() -> SystemConfig {
+        SystemConfig {
+            min_intersection_survivors: 1,
+            ..Default::default()
+        }
+    }
+
+    fn test_peer(port: u16) -> PeerConfig {
+        PeerConfig::try_from(format!("127.0.0.1:{}", port).as_str()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_once_steps_large_offset() {
+        let (port, server) = serve(NtpDuration::from_seconds(10.0)).await;
+        let clock = TestClock::default();
+
+        let result = sync_once(clock.clone(), test_config(), &[test_peer(port)])
+            .await
+            .unwrap();
+        server.abort();
+
+        assert_eq!(result.adjustment, Adjustment::Step);
+        assert_eq!(result.usable_peers, 1);
+        assert!((result.offset.to_seconds() - 10.0).abs() < 0.1);
+
+        let steps = clock.steps.lock().unwrap();
+        assert_eq!(steps.len(), 1);
+        assert!((steps[0].to_seconds() - 10.0).abs() < 0.1);
+        assert!(clock.updates.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_once_slews_small_offset() {
+        let (port, server) = serve(NtpDuration::from_seconds(0.01)).await;
+        let clock = TestClock::default();
+
+        let result = sync_once(clock.clone(), test_config(), &[test_peer(port)])
+            .await
+            .unwrap();
+        server.abort();
+
+        assert_eq!(result.adjustment, Adjustment::Slew);
+        assert!(clock.steps.lock().unwrap().is_empty());
+        assert_eq!(clock.updates.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_once_respects_panic_threshold() {
+        let (port, server) = serve(NtpDuration::from_seconds(-3600.0)).await;
+        let clock = TestClock::default();
+
+        let config = SystemConfig {
+            startup_panic_threshold: StepThreshold {
+                forward: None,
+                backward: Some(NtpDuration::from_seconds(1800.0)),
+            },
+            ..test_config()
+        };
+
+        let result = sync_once(clock.clone(), config, &[test_peer(port)]).await;
+        server.abort();
+
+        let error = result.unwrap_err();
+        assert!(matches!(error, OnceError::Panic(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), exitcode::SOFTWARE);
+        assert!(clock.steps.lock().unwrap().is_empty());
+        assert!(clock.updates.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_once_slew_only_refuses_large_offset() {
+        let (port, server) = serve(NtpDuration::from_seconds(10.0)).await;
+        let clock = TestClock::default();
+
+        let config = SystemConfig {
+            slew_only: true,
+            ..test_config()
+        };
+
+        let result = sync_once(clock.clone(), config, &[test_peer(port)]).await;
+        server.abort();
+
+        let error = result.unwrap_err();
+        assert!(matches!(error, OnceError::SlewTooLarge(_)), "{:?}", error);
+        assert_eq!(error.exit_code(), exitcode::SOFTWARE);
+        assert!(clock.steps.lock().unwrap().is_empty());
+        assert!(clock.updates.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_once_without_sources() {
+        let clock = TestClock::default();
+
+        // The socket is never read from, so no responses will arrive
+        let silent = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = silent.local_addr().unwrap().port();
+        let result = sync_once(clock.clone(), test_config(), &[test_peer(port)]).await;
+
+        assert!(matches!(result, Err(OnceError::NoSources(1))));
+    }
+}
@@ -7,8 +7,8 @@ use std::{
 };
 
 use ntp_proto::{
-    IgnoreReason, Measurement, NtpClock, NtpInstant, NtpPacket, NtpTimestamp, Peer, PeerSnapshot,
-    ReferenceId, SystemConfig, SystemSnapshot, Update,
+    IgnoreReason, Measurement, NtpClock, NtpInstant, NtpPacket, NtpTimestamp, Peer, PeerOptions,
+    PeerSnapshot, ReferenceId, SystemConfig, SystemSnapshot, Update,
 };
 use ntp_udp::UdpSocket;
 use rand::{thread_rng, Rng};
@@ -18,6 +18,15 @@ use tokio::time::{Instant, Sleep};
 
 use crate::system::PeerIndex;
 
+/// Number of requests sent in quick succession when iburst is enabled
+const IBURST_COUNT: u32 = 4;
+
+/// Number of requests sent per poll when burst is enabled
+const BURST_COUNT: u32 = 4;
+
+/// Spacing of the requests of an (i)burst
+pub(crate) const BURST_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
 /// Trait needed to allow injecting of futures other than tokio::time::Sleep for testing
 pub trait Wait: Future<Output = ()> {
     fn reset(self: Pin<&mut Self>, deadline: Instant);
@@ -33,7 +42,7 @@ impl Wait for Sleep {
 pub enum MsgForSystem {
     /// Received a Kiss-o'-Death and must demobilize
     MustDemobilize(PeerIndex),
-    /// Experienced a network issue and must be restarted
+    /// Experienced a network (or device) issue and must be restarted
     NetworkIssue(PeerIndex),
     /// Received an acceptable packet and made a new peer snapshot
     /// A new measurement should try to trigger a clock select
@@ -68,6 +77,17 @@ pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
 
     /// Instant last poll message was sent (used for timing the wait)
     last_poll_sent: Instant,
+
+    /// Whether the peer was reachable after the last poll, to start an
+    /// iburst when it becomes unreachable
+    reachable: bool,
+    /// Remaining polls of the current iburst
+    iburst_remaining: u32,
+    /// Remaining requests of the burst of the current poll
+    burst_remaining: u32,
+    /// Best measurement of the current burst, sent to the system once the
+    /// burst is complete
+    burst_best: Option<(PeerSnapshot, Measurement, NtpPacket<'static>)>,
 }
 
 #[derive(Debug)]
@@ -89,10 +109,13 @@ where
 {
     /// Set the next deadline for the poll interval based on current state
     fn update_poll_wait(&self, poll_wait: &mut Pin<&mut T>, system_snapshot: SystemSnapshot) {
-        let poll_interval = self
-            .peer
-            .current_poll_interval(system_snapshot)
-            .as_system_duration();
+        let poll_interval = if self.iburst_remaining > 0 || self.burst_remaining > 0 {
+            BURST_INTERVAL
+        } else {
+            self.peer
+                .current_poll_interval(system_snapshot)
+                .as_system_duration()
+        };
 
         // randomize the poll interval a little to make it harder to predict poll requests
         let poll_interval = poll_interval.mul_f64(thread_rng().gen_range(1.01..=1.05));
@@ -104,20 +127,61 @@ where
 
     async fn handle_poll(&mut self, poll_wait: &mut Pin<&mut T>) -> PollResult {
         let system_snapshot = *self.channels.system_snapshot_receiver.borrow();
+
+        if self.burst_remaining > 0 {
+            // Additional request within the current poll
+            self.burst_remaining -= 1;
+            let packet = self.peer.generate_burst_message(system_snapshot);
+
+            self.last_poll_sent = Instant::now();
+            self.update_poll_wait(poll_wait, system_snapshot);
+
+            return self.send_packet(packet).await;
+        }
+
+        // A response to the last request of the previous burst may never have arrived
+        self.flush_burst().await;
+
         let config_snapshot = *self.channels.system_config_receiver.borrow_and_update();
         let packet = self
             .peer
             .generate_poll_message(system_snapshot, &config_snapshot);
 
+        let options = self.peer.options();
+        let snapshot = PeerSnapshot::from_peer(&self.peer);
+        if options.iburst && self.reachable && !snapshot.reach.is_reachable() {
+            debug!("peer became unreachable, starting iburst");
+            self.iburst_remaining = IBURST_COUNT;
+        }
+        self.reachable = snapshot.reach.is_reachable();
+
+        if self.iburst_remaining > 0 {
+            // this poll is part of the iburst
+            self.iburst_remaining -= 1;
+        } else if options.burst {
+            self.burst_remaining = BURST_COUNT - 1;
+        }
+
         // Sent a poll, so update waiting to match deadline of next
         self.last_poll_sent = Instant::now();
         self.update_poll_wait(poll_wait, system_snapshot);
 
         // NOTE: fitness check is not performed here, but by System
-        let snapshot = PeerSnapshot::from_peer(&self.peer);
         let msg = MsgForSystem::UpdatedSnapshot(self.index, snapshot);
         self.channels.msg_for_system_sender.send(msg).await.ok();
 
+        self.send_packet(packet).await
+    }
+
+    /// Send the best measurement of the last burst to the system
+    async fn flush_burst(&mut self) {
+        if let Some((snapshot, measurement, packet)) = self.burst_best.take() {
+            let msg = MsgForSystem::NewMeasurement(self.index, snapshot, measurement, packet);
+            self.channels.msg_for_system_sender.send(msg).await.ok();
+        }
+    }
+
+    async fn send_packet(&mut self, packet: NtpPacket<'static>) -> PollResult {
         match self.clock.now() {
             Err(e) => {
                 // we cannot determine the origin_timestamp
@@ -191,6 +255,33 @@ where
 
                 let msg = match update {
                     Update::BareUpdate(update) => MsgForSystem::UpdatedSnapshot(self.index, update),
+                    Update::NewMeasurement(update, measurement, packet)
+                        if self.peer.options().burst && self.iburst_remaining == 0 =>
+                    {
+                        // Keep the measurement with the lowest delay, which is least
+                        // affected by network congestion
+                        let is_better = match &self.burst_best {
+                            Some((_, best, _)) => measurement.delay < best.delay,
+                            None => true,
+                        };
+                        if is_better {
+                            self.burst_best = Some((update, measurement, packet));
+                        }
+
+                        if self.burst_remaining > 0 {
+                            return PacketResult::Ok;
+                        }
+
+                        match self.burst_best.take() {
+                            Some((update, measurement, packet)) => MsgForSystem::NewMeasurement(
+                                self.index,
+                                update,
+                                measurement,
+                                packet,
+                            ),
+                            None => return PacketResult::Ok,
+                        }
+                    }
                     Update::NewMeasurement(update, measurement, packet) => {
                         MsgForSystem::NewMeasurement(self.index, update, measurement, packet)
                     }
@@ -267,6 +358,7 @@ where
     pub fn spawn(
         index: PeerIndex,
         addr: SocketAddr,
+        options: PeerOptions,
         clock: C,
         network_wait_period: std::time::Duration,
         mut channels: PeerChannels,
@@ -294,7 +386,7 @@ where
 
                 let local_clock_time = NtpInstant::now();
                 let config_snapshot = *channels.system_config_receiver.borrow_and_update();
-                let peer = Peer::new(our_id, peer_id, local_clock_time, config_snapshot);
+                let peer = Peer::new(our_id, peer_id, local_clock_time, config_snapshot, options);
 
                 let poll_wait = tokio::time::sleep(std::time::Duration::default());
                 tokio::pin!(poll_wait);
@@ -308,6 +400,10 @@ where
                     peer,
                     last_send_timestamp: None,
                     last_poll_sent: Instant::now(),
+                    reachable: false,
+                    iburst_remaining: if options.iburst { IBURST_COUNT } else { 0 },
+                    burst_remaining: 0,
+                    burst_best: None,
                 };
 
                 process.run(poll_wait).await
@@ -318,20 +414,20 @@ where
 }
 
 #[derive(Debug)]
-enum AcceptResult<'a> {
+pub(crate) enum AcceptResult<'a> {
     Accept(NtpPacket<'a>, NtpTimestamp),
     Ignore,
     NetworkGone,
 }
 
-fn unspecified_for(addr: SocketAddr) -> SocketAddr {
+pub(crate) fn unspecified_for(addr: SocketAddr) -> SocketAddr {
     match addr {
         SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
         SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
     }
 }
 
-fn accept_packet(
+pub(crate) fn accept_packet(
     result: Result<(usize, SocketAddr, Option<NtpTimestamp>), std::io::Error>,
     buf: &[u8; 48],
 ) -> AcceptResult {
@@ -375,7 +471,7 @@ fn accept_packet(
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::{sync::Arc, time::Duration};
 
     use ntp_proto::{NtpDuration, NtpLeapIndicator, PollInterval, TimeSnapshot};
@@ -383,27 +479,33 @@ mod tests {
 
     use super::*;
 
-    struct TestWaitSender {
+    pub(crate) struct TestWaitSender {
         state: Arc<std::sync::Mutex<TestWaitState>>,
     }
 
     impl TestWaitSender {
-        fn notify(&self) {
+        pub(crate) fn notify(&self) {
             let mut state = self.state.lock().unwrap();
             state.pending = true;
             if let Some(waker) = state.waker.take() {
                 waker.wake();
             }
         }
+
+        /// The deadline the wait was last reset to
+        pub(crate) fn deadline(&self) -> Option<Instant> {
+            self.state.lock().unwrap().deadline
+        }
     }
 
-    struct TestWait {
+    pub(crate) struct TestWait {
         state: Arc<std::sync::Mutex<TestWaitState>>,
     }
 
     struct TestWaitState {
         waker: Option<std::task::Waker>,
         pending: bool,
+        deadline: Option<Instant>,
     }
 
     impl Future for TestWait {
@@ -427,7 +529,9 @@ mod tests {
     }
 
     impl Wait for TestWait {
-        fn reset(self: Pin<&mut Self>, _deadline: Instant) {}
+        fn reset(self: Pin<&mut Self>, deadline: Instant) {
+            self.state.lock().unwrap().deadline = Some(deadline);
+        }
     }
 
     impl Drop for TestWait {
@@ -437,10 +541,11 @@ mod tests {
     }
 
     impl TestWait {
-        fn new() -> (TestWait, TestWaitSender) {
+        pub(crate) fn new() -> (TestWait, TestWaitSender) {
             let state = Arc::new(std::sync::Mutex::new(TestWaitState {
                 waker: None,
                 pending: false,
+                deadline: None,
             }));
 
             (
@@ -474,6 +579,10 @@ mod tests {
             panic!("Shouldn't be called by peer");
         }
 
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            panic!("Shouldn't be called by peer");
+        }
+
         fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
             panic!("Shouldn't be called by peer");
         }
@@ -496,6 +605,17 @@ mod tests {
         PeerTask<TestClock, T>,
         UdpSocket,
         mpsc::Receiver<MsgForSystem>,
+    ) {
+        test_startup_with_options(port_base, PeerOptions::default()).await
+    }
+
+    async fn test_startup_with_options<T: Wait>(
+        port_base: u16,
+        options: PeerOptions,
+    ) -> (
+        PeerTask<TestClock, T>,
+        UdpSocket,
+        mpsc::Receiver<MsgForSystem>,
     ) {
         // Note: Ports must be unique among tests to deal with parallelism, hence
         // port_base
@@ -524,6 +644,7 @@ mod tests {
             peer_id,
             local_clock_time,
             *system_config_receiver.borrow_and_update(),
+            options,
         );
 
         let process = PeerTask {
@@ -539,6 +660,10 @@ mod tests {
             peer,
             last_send_timestamp: None,
             last_poll_sent: Instant::now(),
+            reachable: false,
+            iburst_remaining: if options.iburst { IBURST_COUNT } else { 0 },
+            burst_remaining: 0,
+            burst_best: None,
         };
 
         (process, test_socket, msg_for_system_receiver)
@@ -612,6 +737,104 @@ mod tests {
         handle.abort();
     }
 
+    /// Answer the request received on the socket with a timestamped response
+    async fn respond(socket: &mut UdpSocket) {
+        let system = SystemSnapshot {
+            time_snapshot: TimeSnapshot {
+                leap_indicator: NtpLeapIndicator::NoWarning,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut buf = [0; 48];
+        let (size, _, timestamp) = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+
+        let rec_packet = NtpPacket::deserialize(&buf).unwrap();
+        let send_packet =
+            NtpPacket::timestamp_response(&system, rec_packet, timestamp.unwrap(), &TestClock {});
+        let mut pdata = vec![];
+        send_packet.serialize(&mut pdata).unwrap();
+
+        socket.send(&pdata).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_iburst_polls_quickly() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let options = PeerOptions {
+            iburst: true,
+            ..Default::default()
+        };
+        let (mut process, mut socket, mut msg_recv) =
+            test_startup_with_options(8012, options).await;
+
+        let (poll_wait, poll_send) = TestWait::new();
+        let deadline = |poll_send: &TestWaitSender| poll_send.deadline().unwrap() - Instant::now();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        for i in 1..=IBURST_COUNT {
+            poll_send.notify();
+
+            let msg = msg_recv.recv().await.unwrap();
+            assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _)));
+
+            // after the iburst, polling continues at the normal interval
+            let quick = deadline(&poll_send) <= BURST_INTERVAL.mul_f64(1.05);
+            assert_eq!(quick, i < IBURST_COUNT);
+
+            // every response of an iburst is a measurement
+            respond(&mut socket).await;
+            let msg = msg_recv.recv().await.unwrap();
+            assert!(matches!(msg, MsgForSystem::NewMeasurement(_, _, _, _)));
+        }
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_burst_sends_single_measurement() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let options = PeerOptions {
+            burst: true,
+            ..Default::default()
+        };
+        let (mut process, mut socket, mut msg_recv) =
+            test_startup_with_options(8014, options).await;
+
+        let (poll_wait, poll_send) = TestWait::new();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        poll_send.notify();
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _)));
+        respond(&mut socket).await;
+
+        for _ in 1..BURST_COUNT {
+            poll_send.notify();
+            respond(&mut socket).await;
+        }
+
+        // only the best response of the burst is passed on
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::NewMeasurement(_, _, _, _)));
+
+        poll_send.notify();
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _)));
+
+        handle.abort();
+    }
+
     #[tokio::test]
     async fn test_deny_stops_poll() {
         // Note: Ports must be unique among tests to deal with parallelism
@@ -0,0 +1,306 @@
+//! Dropping root privileges once the daemon is set up. The daemon switches to
+//! an unprivileged user and group, keeping only the capabilities it needs
+//! while running: `CAP_SYS_TIME` to adjust the clock, and `CAP_NET_BIND_SERVICE`
+//! for servers that (re)bind sockets on privileged ports, also after a reload.
+//!
+//! The C library switches the user and group of all threads, but capabilities
+//! are per thread. The capabilities are only kept for the thread that drops
+//! privileges, so this must happen while the process has only one thread,
+//! i.e. before the tokio runtime is started.
+
+use std::ffi::CString;
+use std::io::Error as IoError;
+
+use thiserror::Error;
+use tracing::info;
+
+use crate::config::PrivilegesConfig;
+
+#[derive(Debug, Error)]
+pub enum PrivilegeError {
+    #[error("unknown user {0:?}")]
+    UnknownUser(String),
+    #[error("unknown group {0:?}")]
+    UnknownGroup(String),
+    #[error("missing capability {0}, is the daemon started as root?")]
+    MissingCapability(&'static str),
+    #[error("could not {0}: {1}")]
+    Os(&'static str, IoError),
+}
+
+impl PrivilegeError {
+    /// Exit code of the process for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PrivilegeError::UnknownUser(_) | PrivilegeError::UnknownGroup(_) => exitcode::NOUSER,
+            PrivilegeError::MissingCapability(_) => exitcode::NOPERM,
+            PrivilegeError::Os(_, _) => exitcode::OSERR,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Capability {
+    name: &'static str,
+    number: u32,
+}
+
+impl Capability {
+    const fn mask(self) -> u64 {
+        1 << self.number
+    }
+}
+
+// Capability numbers from linux/capability.h
+const CAP_SETGID: Capability = Capability {
+    name: "CAP_SETGID",
+    number: 6,
+};
+const CAP_SETUID: Capability = Capability {
+    name: "CAP_SETUID",
+    number: 7,
+};
+const CAP_NET_BIND_SERVICE: Capability = Capability {
+    name: "CAP_NET_BIND_SERVICE",
+    number: 10,
+};
+const CAP_SYS_TIME: Capability = Capability {
+    name: "CAP_SYS_TIME",
+    number: 25,
+};
+
+/// Capabilities kept after switching user. Servers on privileged ports can be
+/// added by a reload, so binding to those is always allowed.
+const RETAINED_CAPABILITIES: [Capability; 2] = [CAP_SYS_TIME, CAP_NET_BIND_SERVICE];
+
+/// Switch to the configured user and group, keeping only the capabilities
+/// needed while running. Does nothing when no user is configured.
+///
+/// Must be called while the process has a single thread.
+pub fn drop_privileges(config: &PrivilegesConfig) -> Result<(), PrivilegeError> {
+    let user = match &config.user {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+
+    let (uid, primary_gid) = lookup_user(user)?;
+    let gid = match &config.group {
+        Some(group) => lookup_group(group)?,
+        None => primary_gid,
+    };
+
+    let retained = RETAINED_CAPABILITIES;
+    let retained_mask = retained.iter().fold(0, |mask, cap| mask | cap.mask());
+
+    // Check up front, so that nothing changes when we can't complete
+    let permitted = get_permitted_capabilities()?;
+    for capability in [CAP_SETGID, CAP_SETUID].iter().chain(&retained) {
+        if permitted & capability.mask() == 0 {
+            return Err(PrivilegeError::MissingCapability(capability.name));
+        }
+    }
+
+    // Keep our permitted capabilities when switching away from root
+    // Safety: prctl has no memory safety requirements
+    check("keep capabilities", unsafe {
+        libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0)
+    })?;
+
+    // Also sets the supplementary groups, so the user can access e.g. serial devices
+    let c_user = CString::new(user.as_str()).expect("user names were checked by lookup");
+    // Safety: c_user is a valid nul-terminated string
+    check("set groups", unsafe {
+        libc::initgroups(c_user.as_ptr(), gid)
+    })?;
+    // Safety: setresgid and setresuid have no memory safety requirements
+    check("set group id", unsafe { libc::setresgid(gid, gid, gid) })?;
+    check("set user id", unsafe { libc::setresuid(uid, uid, uid) })?;
+
+    set_capabilities(retained_mask)?;
+    // Safety: prctl has no memory safety requirements
+    check("reset keeping capabilities", unsafe {
+        libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0)
+    })?;
+
+    let capabilities: Vec<_> = retained.iter().map(|cap| cap.name).collect();
+    info!(user, uid, gid, ?capabilities, "dropped privileges");
+
+    Ok(())
+}
+
+fn check(action: &'static str, result: libc::c_int) -> Result<(), PrivilegeError> {
+    if result < 0 {
+        Err(PrivilegeError::Os(action, IoError::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Call one of the reentrant user database functions, growing the buffer as needed
+fn lookup<T>(
+    action: &'static str,
+    mut call: impl FnMut(&mut T, &mut Vec<libc::c_char>, &mut *mut T) -> libc::c_int,
+) -> Result<Option<T>, PrivilegeError> {
+    // Safety: passwd and group are plain old data, for which all zeroes is valid
+    let mut entry: T = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; 1024];
+    let mut result = std::ptr::null_mut();
+
+    loop {
+        match call(&mut entry, &mut buffer, &mut result) {
+            0 => break,
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            error => {
+                return Err(PrivilegeError::Os(
+                    action,
+                    IoError::from_raw_os_error(error),
+                ))
+            }
+        }
+    }
+
+    Ok((!result.is_null()).then_some(entry))
+}
+
+/// Uid and primary gid of a user
+pub(crate) fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), PrivilegeError> {
+    let unknown = || PrivilegeError::UnknownUser(name.to_string());
+    let c_name = CString::new(name).map_err(|_| unknown())?;
+
+    let passwd = lookup(
+        "look up user",
+        |passwd: &mut libc::passwd, buffer, result| {
+            // Safety: all pointers are valid, and the buffer length is correct
+            unsafe {
+                libc::getpwnam_r(
+                    c_name.as_ptr(),
+                    passwd,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    result,
+                )
+            }
+        },
+    )?
+    .ok_or_else(unknown)?;
+
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+pub(crate) fn lookup_group(name: &str) -> Result<libc::gid_t, PrivilegeError> {
+    let unknown = || PrivilegeError::UnknownGroup(name.to_string());
+    let c_name = CString::new(name).map_err(|_| unknown())?;
+
+    let group = lookup(
+        "look up group",
+        |group: &mut libc::group, buffer, result| {
+            // Safety: all pointers are valid, and the buffer length is correct
+            unsafe {
+                libc::getgrnam_r(
+                    c_name.as_ptr(),
+                    group,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    result,
+                )
+            }
+        },
+    )?
+    .ok_or_else(unknown)?;
+
+    Ok(group.gr_gid)
+}
+
+// Kernel interface of capget/capset, see capget(2)
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn get_permitted_capabilities() -> Result<u64, PrivilegeError> {
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+
+    // Safety: header and data have the layout the kernel expects for version 3
+    let result = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+    check("get capabilities", result as libc::c_int)?;
+
+    Ok(u64::from(data[0].permitted) | u64::from(data[1].permitted) << 32)
+}
+
+/// Limit both the effective and permitted capabilities to `mask`
+fn set_capabilities(mask: u64) -> Result<(), PrivilegeError> {
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [mask as u32, (mask >> 32) as u32].map(|half| CapUserData {
+        effective: half,
+        permitted: half,
+        inheritable: 0,
+    });
+
+    // Safety: header and data have the layout the kernel expects for version 3
+    let result = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    check("set capabilities", result as libc::c_int)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+
+        assert!(matches!(
+            lookup_user("ntpd-rs-no-such-user"),
+            Err(PrivilegeError::UnknownUser(_))
+        ));
+        assert!(matches!(
+            lookup_group("ntpd-rs-no-such-group"),
+            Err(PrivilegeError::UnknownGroup(_))
+        ));
+        assert!(matches!(
+            lookup_user("nul\0byte"),
+            Err(PrivilegeError::UnknownUser(_))
+        ));
+    }
+
+    #[test]
+    fn test_retained_capabilities() {
+        assert_eq!(RETAINED_CAPABILITIES, [CAP_SYS_TIME, CAP_NET_BIND_SERVICE]);
+        assert_eq!(CAP_SYS_TIME.mask(), 1 << 25);
+    }
+
+    #[test]
+    fn test_no_user_is_noop() {
+        let config = PrivilegesConfig {
+            user: None,
+            group: Some("root".into()),
+            seccomp: false,
+        };
+        drop_privileges(&config).unwrap();
+    }
+
+    #[test]
+    fn test_get_capabilities() {
+        // whatever we run as, reading our own capabilities should work
+        get_permitted_capabilities().unwrap();
+    }
+}
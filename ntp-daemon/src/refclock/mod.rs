@@ -0,0 +1,356 @@
+mod nmea;
+mod pps;
+mod shm;
+mod sock;
+
+use std::{future::Future, marker::PhantomData, pin::Pin};
+
+use ntp_proto::{NtpClock, NtpDuration, NtpTimestamp, RefClockSample, RefClockSource, Update};
+use tokio::time::{Instant, Sleep};
+use tracing::{debug, instrument, warn, Instrument, Span};
+
+use crate::{
+    config::{RefClockConfig, RefClockDriverConfig},
+    peer::{MsgForSystem, PeerChannels, Wait},
+    system::PeerIndex,
+};
+
+pub type SampleFuture<'a> =
+    Pin<Box<dyn Future<Output = std::io::Result<RefClockSample>> + Send + 'a>>;
+
+/// A driver for a reference clock, a local source of time such as a GPS
+/// receiver or an atomic clock.
+pub trait RefClock: Send {
+    /// Wait for the next sample of the clock. The returned future may be
+    /// dropped before completing, so it must not lose state when cancelled.
+    ///
+    /// An error indicates the clock (or the connection to it) has failed.
+    /// The driver is then reopened after a short wait.
+    fn next_sample(&mut self) -> SampleFuture<'_>;
+}
+
+/// Convert a time given in seconds and nanoseconds since the unix epoch
+fn unix_timestamp(seconds: impl Into<i64>, nanos: u32) -> NtpTimestamp {
+    // Unix uses an epoch located at 1/1/1970-00:00h (UTC) and NTP uses 1/1/1900-00:00h.
+    // This leads to an offset equivalent to 70 years in seconds
+    // there are 17 leap years between the two dates so the offset is
+    const EPOCH_OFFSET: u32 = (70 * 365 + 17) * 86400;
+
+    // truncates the higher bits of the i64
+    let seconds = (seconds.into() as u32).wrapping_add(EPOCH_OFFSET);
+
+    NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, nanos)
+}
+
+/// What a driver reads from: a shared memory segment, socket or device.
+/// Opening these does not need a runtime, so it can be done before the
+/// daemon drops its privileges.
+enum Device {
+    Shm(shm::ShmSegment),
+    Sock(std::os::unix::net::UnixDatagram),
+    Nmea(nmea::NmeaDevice),
+}
+
+impl Device {
+    fn open(config: &RefClockDriverConfig) -> std::io::Result<Self> {
+        match config {
+            RefClockDriverConfig::Shm(config) => {
+                Ok(Device::Shm(shm::ShmSegment::attach(config.unit)?))
+            }
+            RefClockDriverConfig::Sock(config) => {
+                Ok(Device::Sock(sock::SockRefClock::bind(&config.path)?))
+            }
+            RefClockDriverConfig::Nmea(config) => Ok(Device::Nmea(nmea::NmeaDevice::open(config)?)),
+        }
+    }
+}
+
+/// Devices of the configured reference clocks, opened before the daemon
+/// drops its privileges. Each is used the first time its clock is started.
+#[derive(Default)]
+pub struct RefClockDevices {
+    devices: Vec<(RefClockDriverConfig, Device)>,
+}
+
+impl RefClockDevices {
+    pub fn open(configs: &[RefClockConfig]) -> Self {
+        let mut devices = vec![];
+        for config in configs {
+            match Device::open(&config.driver) {
+                Ok(device) => devices.push((config.driver.clone(), device)),
+                // Opening is tried again when the clock is started
+                Err(error) => {
+                    warn!(driver = %config.driver, ?error, "Could not open reference clock")
+                }
+            }
+        }
+
+        Self { devices }
+    }
+
+    fn take(&mut self, config: &RefClockDriverConfig) -> Option<Device> {
+        let index = self
+            .devices
+            .iter()
+            .position(|(driver, _)| driver == config)?;
+        Some(self.devices.remove(index).1)
+    }
+}
+
+/// Open the driver selected by the configuration, using the device that was
+/// opened before if there is one
+fn open_driver<C: NtpClock + Send + 'static>(
+    config: &RefClockDriverConfig,
+    device: Option<Device>,
+    clock: C,
+) -> std::io::Result<Box<dyn RefClock>> {
+    let device = match device {
+        Some(device) => device,
+        None => Device::open(config)?,
+    };
+
+    match (config, device) {
+        (_, Device::Shm(segment)) => Ok(Box::new(shm::ShmRefClock::from_segment(segment))),
+        (_, Device::Sock(socket)) => Ok(Box::new(sock::SockRefClock::from_socket(socket)?)),
+        (RefClockDriverConfig::Nmea(config), Device::Nmea(device)) => Ok(Box::new(
+            nmea::NmeaRefClock::from_device(device, config, clock)?,
+        )),
+        (_, Device::Nmea(_)) => {
+            unreachable!("devices are only used for the driver they were opened for")
+        }
+    }
+}
+
+pub(crate) struct RefClockTask<T: Wait> {
+    _wait: PhantomData<T>,
+    index: PeerIndex,
+    driver: Box<dyn RefClock>,
+    source: RefClockSource,
+    /// Correction applied to every sample of the driver
+    offset: NtpDuration,
+    channels: PeerChannels,
+
+    /// Instant of the last poll (used for timing the wait)
+    last_poll: Instant,
+}
+
+impl<T: Wait> RefClockTask<T> {
+    async fn handle_poll(&mut self, poll_wait: &mut Pin<&mut T>) {
+        self.last_poll = Instant::now();
+        poll_wait
+            .as_mut()
+            .reset(self.last_poll + self.source.poll_interval().as_system_duration());
+
+        // NOTE: fitness check is not performed here, but by System
+        let msg = match self.source.poll() {
+            Update::BareUpdate(snapshot) => MsgForSystem::UpdatedSnapshot(self.index, snapshot),
+            Update::NewMeasurement(snapshot, measurement, packet) => {
+                MsgForSystem::NewMeasurement(self.index, snapshot, measurement, packet)
+            }
+        };
+        self.channels.msg_for_system_sender.send(msg).await.ok();
+    }
+
+    async fn run(&mut self, mut poll_wait: Pin<&mut T>) {
+        loop {
+            tokio::select! {
+                () = &mut poll_wait => {
+                    self.handle_poll(&mut poll_wait).await;
+                },
+                result = self.driver.next_sample() => {
+                    match result {
+                        Ok(mut sample) => {
+                            debug!(offset = debug(sample.offset), "received refclock sample");
+                            sample.offset += self.offset;
+                            self.source.add_sample(sample);
+                        }
+                        Err(error) => {
+                            warn!(?error, "reference clock failed");
+                            self.channels.msg_for_system_sender.send(MsgForSystem::NetworkIssue(self.index)).await.ok();
+                            break;
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl RefClockTask<Sleep> {
+    #[instrument(skip(devices, clock, channels))]
+    pub fn spawn<C: NtpClock + Send + 'static>(
+        index: PeerIndex,
+        config: &RefClockConfig,
+        devices: &mut RefClockDevices,
+        clock: C,
+        wait_period: std::time::Duration,
+        channels: PeerChannels,
+    ) -> tokio::task::JoinHandle<()> {
+        let driver = open_driver(&config.driver, devices.take(&config.driver), clock);
+        let refid = config.refid;
+        let precision = config.precision;
+        let poll_interval = config.poll;
+        let offset = NtpDuration::from_seconds(config.offset);
+
+        tokio::spawn(
+            (async move {
+                let driver = match driver {
+                    Ok(driver) => driver,
+                    Err(error) => {
+                        warn!(?error, "Could not open reference clock");
+                        tokio::time::sleep(wait_period).await;
+                        channels
+                            .msg_for_system_sender
+                            .send(MsgForSystem::NetworkIssue(index))
+                            .await
+                            .ok();
+                        return;
+                    }
+                };
+
+                let poll_wait = tokio::time::sleep(poll_interval.as_system_duration());
+                tokio::pin!(poll_wait);
+
+                let mut process = RefClockTask {
+                    _wait: PhantomData,
+                    index,
+                    driver,
+                    source: RefClockSource::new(refid, precision, poll_interval),
+                    offset,
+                    channels,
+                    last_poll: Instant::now(),
+                };
+
+                process.run(poll_wait).await
+            })
+            .instrument(Span::current()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{
+        NtpInstant, NtpLeapIndicator, NtpTimestamp, PollInterval, ReferenceId, SystemConfig,
+        SystemSnapshot,
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::peer::tests::TestWait;
+
+    struct TestRefClock {
+        samples: mpsc::Receiver<std::io::Result<RefClockSample>>,
+    }
+
+    impl RefClock for TestRefClock {
+        fn next_sample(&mut self) -> SampleFuture<'_> {
+            Box::pin(async move {
+                match self.samples.recv().await {
+                    Some(sample) => sample,
+                    None => std::future::pending().await,
+                }
+            })
+        }
+    }
+
+    fn sample(offset: f64) -> RefClockSample {
+        RefClockSample {
+            offset: NtpDuration::from_seconds(offset),
+            dispersion: NtpDuration::from_seconds(1e-6),
+            localtime: NtpTimestamp::default(),
+            monotime: NtpInstant::now(),
+            leap: NtpLeapIndicator::NoWarning,
+        }
+    }
+
+    fn test_startup() -> (
+        RefClockTask<TestWait>,
+        mpsc::Sender<std::io::Result<RefClockSample>>,
+        mpsc::Receiver<MsgForSystem>,
+    ) {
+        let (sample_sender, samples) = mpsc::channel(32);
+        let (_, system_snapshot_receiver) = tokio::sync::watch::channel(SystemSnapshot::default());
+        let (_, system_config_receiver) = tokio::sync::watch::channel(SystemConfig::default());
+        let (msg_for_system_sender, msg_for_system_receiver) = mpsc::channel(32);
+
+        let process = RefClockTask {
+            _wait: PhantomData,
+            index: PeerIndex::from_inner(0),
+            driver: Box::new(TestRefClock { samples }),
+            source: RefClockSource::new(
+                ReferenceId::from_code("TEST").unwrap(),
+                -20,
+                PollInterval::default(),
+            ),
+            offset: NtpDuration::from_seconds(0.5),
+            channels: PeerChannels {
+                msg_for_system_sender,
+                system_snapshot_receiver,
+                system_config_receiver,
+            },
+            last_poll: Instant::now(),
+        };
+
+        (process, sample_sender, msg_for_system_receiver)
+    }
+
+    #[tokio::test]
+    async fn test_poll_sends_measurement() {
+        let (mut process, samples, mut msg_recv) = test_startup();
+        let (poll_wait, poll_send) = TestWait::new();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        // without samples, only the snapshot is updated
+        poll_send.notify();
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _)));
+
+        samples.send(Ok(sample(0.1))).await.unwrap();
+        samples.send(Ok(sample(0.2))).await.unwrap();
+        samples.send(Ok(sample(0.3))).await.unwrap();
+        // give the task time to process the samples
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        poll_send.notify();
+        let msg = msg_recv.recv().await.unwrap();
+        match msg {
+            MsgForSystem::NewMeasurement(_, snapshot, measurement, packet) => {
+                assert_eq!(snapshot.stratum, 0);
+                assert!(snapshot.reach.is_reachable());
+                assert_eq!(packet.stratum(), 0);
+                // median sample plus the configured offset
+                assert!((measurement.offset.to_seconds() - 0.7).abs() < 1e-6);
+            }
+            _ => panic!("expected a new measurement"),
+        }
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_failing_clock_is_restarted() {
+        let (mut process, samples, mut msg_recv) = test_startup();
+        let (poll_wait, _poll_send) = TestWait::new();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        samples
+            .send(Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe)))
+            .await
+            .unwrap();
+
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::NetworkIssue(_)));
+
+        // the task stops after a failure
+        handle.await.unwrap();
+    }
+}
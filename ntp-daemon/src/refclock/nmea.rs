@@ -0,0 +1,531 @@
+//! Driver for GPS receivers sending NMEA 0183 sentences over a serial line.
+//!
+//! The time is taken from the RMC and ZDA sentences. Without PPS, the local
+//! time at which the start of a sentence is received is used as the time at
+//! which the reported second started, corrected by the configured sentence
+//! delay. When a PPS device is configured, its pulses are used instead to
+//! mark the start of the second reported by the next sentence.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::Read,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+};
+
+use ntp_proto::{
+    NtpClock, NtpDuration, NtpInstant, NtpLeapIndicator, NtpTimestamp, RefClockSample,
+};
+use tokio::{io::unix::AsyncFd, sync::mpsc};
+use tracing::{debug, warn};
+
+use super::{pps::PpsSource, unix_timestamp, RefClock, SampleFuture};
+use crate::config::NmeaRefClockConfig;
+
+/// Maximum length of an NMEA sentence (including `$` and line ending) is 82
+/// characters, longer lines are discarded.
+const MAX_SENTENCE_LENGTH: usize = 82;
+
+/// Assumed error of samples that are not aligned to a PPS pulse, mostly due to
+/// variations in when the receiver sends its sentences.
+const NMEA_DISPERSION: f64 = 0.01;
+
+/// UTC time reported by a sentence, as seconds and nanoseconds since the
+/// unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NmeaTime {
+    seconds: i64,
+    nanos: u32,
+}
+
+impl NmeaTime {
+    fn timestamp(&self) -> NtpTimestamp {
+        unix_timestamp(self.seconds, self.nanos)
+    }
+}
+
+/// Number of days since 1970-01-01 of the given date in the gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+fn parse_number(field: &str, range: std::ops::RangeInclusive<i64>) -> Option<i64> {
+    if field.is_empty() || !field.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    field.parse().ok().filter(|v| range.contains(v))
+}
+
+/// Parse a `hhmmss.sss` time of day into seconds and nanoseconds
+fn parse_time_of_day(field: &str) -> Option<(i64, u32)> {
+    let (whole, fraction) = field.split_once('.').unwrap_or((field, ""));
+    if whole.len() != 6 || fraction.len() > 9 {
+        return None;
+    }
+
+    let hours = parse_number(&whole[0..2], 0..=23)?;
+    let minutes = parse_number(&whole[2..4], 0..=59)?;
+    // allow for leap seconds
+    let seconds = parse_number(&whole[4..6], 0..=60)?;
+
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        let digits = parse_number(fraction, 0..=999_999_999)?;
+        (digits * 10i64.pow(9 - fraction.len() as u32)) as u32
+    };
+
+    Some((hours * 3600 + minutes * 60 + seconds, nanos))
+}
+
+/// Parse a single NMEA sentence (without line ending), returning the time it
+/// reports if it is a valid RMC or ZDA sentence.
+fn parse_sentence(sentence: &str) -> Option<NmeaTime> {
+    let body = sentence.strip_prefix('$')?;
+    let (body, checksum) = body.split_once('*')?;
+
+    let expected = u8::from_str_radix(checksum, 16).ok()?;
+    if checksum.len() != 2 || body.bytes().fold(0, |acc, c| acc ^ c) != expected {
+        return None;
+    }
+
+    let mut fields = body.split(',');
+    let address = fields.next()?;
+    // the first two characters identify the talker (GP, GN, GL, ...)
+    if address.len() != 5 {
+        return None;
+    }
+
+    let (seconds, nanos, year, month, day) = match &address[2..] {
+        "RMC" => {
+            let (seconds, nanos) = parse_time_of_day(fields.next()?)?;
+            // status, A for valid data, V for a warning
+            if fields.next()? != "A" {
+                return None;
+            }
+            // skip latitude, longitude, speed and course
+            let date = fields.nth(6)?;
+            if date.len() != 6 {
+                return None;
+            }
+            let day = parse_number(&date[0..2], 1..=31)?;
+            let month = parse_number(&date[2..4], 1..=12)?;
+            // two digit years, interpreted like the gps week rollover in 1980
+            let year = parse_number(&date[4..6], 0..=99)?;
+            let year = if year < 80 { 2000 + year } else { 1900 + year };
+
+            (seconds, nanos, year, month, day)
+        }
+        "ZDA" => {
+            let (seconds, nanos) = parse_time_of_day(fields.next()?)?;
+            let day = parse_number(fields.next()?, 1..=31)?;
+            let month = parse_number(fields.next()?, 1..=12)?;
+            let year = parse_number(fields.next()?, 1980..=9999)?;
+
+            (seconds, nanos, year, month, day)
+        }
+        _ => return None,
+    };
+
+    Some(NmeaTime {
+        seconds: days_from_civil(year, month, day) * 86400 + seconds,
+        nanos,
+    })
+}
+
+/// Offset between the time reported by a sentence and the pulse marking the
+/// start of that second, if the pulse belongs to the sentence
+fn pps_offset(time: NmeaTime, received: NtpTimestamp, pulse: NtpTimestamp) -> Option<NtpDuration> {
+    let since_pulse = received - pulse;
+    if time.nanos != 0
+        || since_pulse < NtpDuration::ZERO
+        || since_pulse >= NtpDuration::from_seconds(1.0)
+    {
+        return None;
+    }
+
+    Some(time.timestamp() - pulse)
+}
+
+/// Set the line to raw mode at the given speed
+fn configure_serial(file: &File, baud_rate: libc::speed_t) -> std::io::Result<()> {
+    let fd = file.as_raw_fd();
+
+    // Safety: termios is plain old data, so may be zero initialized. We own
+    // file, so fd is a valid file descriptor for the duration of the calls,
+    // and all pointers point to the termios struct we own.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        if libc::cfsetspeed(&mut termios, baud_rate) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+fn baud_rate_constant(baud_rate: u32) -> Option<libc::speed_t> {
+    Some(match baud_rate {
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        _ => return None,
+    })
+}
+
+/// The serial line and PPS device of a receiver, opened but not yet in use
+pub(crate) struct NmeaDevice {
+    serial: File,
+    pps: Option<PpsSource>,
+}
+
+impl NmeaDevice {
+    pub(crate) fn open(config: &NmeaRefClockConfig) -> std::io::Result<Self> {
+        let baud_rate = baud_rate_constant(config.baud_rate).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "unsupported baud rate")
+        })?;
+
+        let serial = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&config.path)?;
+        configure_serial(&serial, baud_rate)?;
+
+        let pps = match &config.pps {
+            Some(path) => Some(PpsSource::open(path)?),
+            None => None,
+        };
+
+        Ok(Self { serial, pps })
+    }
+}
+
+pub(crate) struct NmeaRefClock<C: NtpClock> {
+    clock: C,
+    serial: AsyncFd<File>,
+    sentence_delay: NtpDuration,
+    pps: Option<mpsc::Receiver<NtpTimestamp>>,
+    last_pulse: Option<NtpTimestamp>,
+
+    /// Sentence currently being received, and the time its start was received
+    partial: Option<(Vec<u8>, NtpTimestamp)>,
+    /// Complete sentences that have not been processed yet
+    sentences: VecDeque<(Vec<u8>, NtpTimestamp)>,
+}
+
+impl<C: NtpClock + Send + 'static> NmeaRefClock<C> {
+    #[cfg(test)]
+    pub(crate) fn open(config: &NmeaRefClockConfig, clock: C) -> std::io::Result<Self> {
+        Self::from_device(NmeaDevice::open(config)?, config, clock)
+    }
+
+    pub(crate) fn from_device(
+        device: NmeaDevice,
+        config: &NmeaRefClockConfig,
+        clock: C,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            clock,
+            serial: AsyncFd::new(device.serial)?,
+            sentence_delay: NtpDuration::from_seconds(config.sentence_delay),
+            pps: device.pps.map(PpsSource::spawn),
+            last_pulse: None,
+            partial: None,
+            sentences: VecDeque::new(),
+        })
+    }
+
+    /// Split the data received on the serial line into sentences
+    fn receive(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if data.is_empty() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let now = self
+            .clock
+            .now()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        for &byte in data {
+            match byte {
+                b'$' => self.partial = Some((vec![byte], now)),
+                b'\r' | b'\n' => {
+                    if let Some(sentence) = self.partial.take() {
+                        self.sentences.push_back(sentence);
+                    }
+                }
+                _ => {
+                    if let Some((sentence, _)) = &mut self.partial {
+                        sentence.push(byte);
+                        if sentence.len() > MAX_SENTENCE_LENGTH {
+                            self.partial = None;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sample(&mut self, sentence: &[u8], received: NtpTimestamp) -> Option<RefClockSample> {
+        let sentence = std::str::from_utf8(sentence).ok()?;
+        let time = parse_sentence(sentence)?;
+
+        let pulse_offset = self
+            .last_pulse
+            .and_then(|pulse| pps_offset(time, received, pulse));
+
+        let (offset, dispersion) = match pulse_offset {
+            Some(offset) => (offset, NtpDuration::ZERO),
+            None => {
+                if self.pps.is_some() {
+                    debug!("no pulse matching sentence, using sentence timing");
+                }
+
+                (
+                    time.timestamp() - (received - self.sentence_delay),
+                    NtpDuration::from_seconds(NMEA_DISPERSION),
+                )
+            }
+        };
+
+        Some(RefClockSample {
+            offset,
+            dispersion,
+            localtime: received,
+            monotime: NtpInstant::now(),
+            leap: NtpLeapIndicator::NoWarning,
+        })
+    }
+}
+
+async fn next_pulse(pps: &mut Option<mpsc::Receiver<NtpTimestamp>>) -> Option<NtpTimestamp> {
+    match pps {
+        Some(pps) => pps.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl<C: NtpClock + Send + 'static> RefClock for NmeaRefClock<C> {
+    fn next_sample(&mut self) -> SampleFuture<'_> {
+        Box::pin(async move {
+            loop {
+                while let Some((sentence, received)) = self.sentences.pop_front() {
+                    if let Some(sample) = self.sample(&sentence, received) {
+                        return Ok(sample);
+                    }
+                }
+
+                let mut buf = [0; 256];
+
+                // all state is stored in self, and both branches are cancel safe
+                let n = tokio::select! {
+                    pulse = next_pulse(&mut self.pps) => match pulse {
+                        Some(pulse) => {
+                            self.last_pulse = Some(pulse);
+                            continue;
+                        }
+                        None => {
+                            warn!("PPS source failed");
+                            return Err(std::io::ErrorKind::BrokenPipe.into());
+                        }
+                    },
+                    guard = self.serial.readable() => {
+                        match guard?.try_io(|file| file.get_ref().read(&mut buf)) {
+                            Ok(result) => result?,
+                            Err(_would_block) => continue,
+                        }
+                    }
+                };
+
+                self.receive(&buf[..n])?;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::unix::io::FromRawFd, path::PathBuf};
+
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    struct TestClock {}
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(NmeaTime {
+                seconds: 1_000_000_000,
+                nanos: 0,
+            }
+            .timestamp())
+        }
+
+        fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by refclock");
+        }
+
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            panic!("Shouldn't be called by refclock");
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by refclock");
+        }
+
+        fn update_clock(
+            &self,
+            _offset: NtpDuration,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+            _poll_interval: ntp_proto::PollInterval,
+            _leap_status: NtpLeapIndicator,
+        ) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by refclock");
+        }
+    }
+
+    #[test]
+    fn test_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2001, 9, 9), 11574);
+    }
+
+    #[test]
+    fn test_parse_rmc() {
+        let time =
+            parse_sentence("$GPRMC,014640.00,A,5213.0000,N,00507.0000,E,0.0,0.0,090901,,,A*5F");
+        assert_eq!(
+            time,
+            Some(NmeaTime {
+                seconds: 1_000_000_000,
+                nanos: 0
+            })
+        );
+
+        // invalid checksum
+        assert_eq!(
+            parse_sentence("$GPRMC,014640.00,A,5213.0000,N,00507.0000,E,0.0,0.0,090901,,,A*60"),
+            None
+        );
+
+        // receiver has no fix
+        assert_eq!(
+            parse_sentence("$GPRMC,014640.00,V,,,,,,,090901,,,N*7B"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_zda() {
+        assert_eq!(
+            parse_sentence("$GNZDA,014640.50,09,09,2001,00,00*79"),
+            Some(NmeaTime {
+                seconds: 1_000_000_000,
+                nanos: 500_000_000
+            })
+        );
+
+        // other sentences are ignored
+        assert_eq!(
+            parse_sentence("$GPGSA,A,3,,,,,,,,,,,,,1.0,1.0,1.0*33"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_pps_offset() {
+        let time = NmeaTime {
+            seconds: 1_000_000_000,
+            nanos: 0,
+        };
+        let pulse = time.timestamp() + NtpDuration::from_seconds(0.002);
+
+        // the sentence is received some time after the pulse
+        let received = pulse + NtpDuration::from_seconds(0.3);
+        let offset = pps_offset(time, received, pulse).unwrap();
+        assert!((offset.to_seconds() + 0.002).abs() < 1e-9);
+
+        // a pulse from more than a second ago belongs to an earlier sentence
+        let received = pulse + NtpDuration::from_seconds(1.3);
+        assert_eq!(pps_offset(time, received, pulse), None);
+    }
+
+    /// Create a pseudo terminal, returning the writing end and the path of the
+    /// terminal end
+    fn open_pty() -> (File, PathBuf) {
+        let mut master = 0;
+        let mut slave = 0;
+
+        // Safety: we pass valid pointers for the file descriptors, and null
+        // for the optional arguments.
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(result, 0);
+
+        let path = std::fs::read_link(format!("/proc/self/fd/{slave}")).unwrap();
+
+        // Safety: openpty just gave us ownership of these file descriptors
+        let master = unsafe { File::from_raw_fd(master) };
+        let _slave = unsafe { File::from_raw_fd(slave) };
+
+        (master, path)
+    }
+
+    #[tokio::test]
+    async fn test_read_from_pty() {
+        let (mut master, path) = open_pty();
+
+        let config = NmeaRefClockConfig {
+            path,
+            baud_rate: 9600,
+            sentence_delay: 0.25,
+            pps: None,
+        };
+        let mut clock = NmeaRefClock::open(&config, TestClock {}).unwrap();
+
+        master
+            .write_all(b"garbage\r\n$GPGSA,A,3,,,,,,,,,,,,,1.0,1.0,1.0*33\r\n$GPRMC,014640.00,A,5213.0000,N,00507.0000,E,0.0,0.0,090901,,,A*5F\r\n")
+            .unwrap();
+
+        let sample = clock.next_sample().await.unwrap();
+        // received at the start of the second, corrected for the delay
+        assert!((sample.offset.to_seconds() - 0.25).abs() < 1e-9);
+        assert_eq!(
+            sample.dispersion,
+            NtpDuration::from_seconds(NMEA_DISPERSION)
+        );
+        assert_eq!(sample.localtime, TestClock {}.now().unwrap());
+    }
+}
@@ -0,0 +1,142 @@
+//! Access to pulse per second devices using the Linux PPS API
+//! (see https://www.kernel.org/doc/html/latest/driver-api/pps.html).
+
+use std::{fs::File, os::unix::io::AsRawFd, path::Path};
+
+use ntp_proto::NtpTimestamp;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use super::unix_timestamp;
+
+/// How long a single fetch waits for a pulse, before checking whether the
+/// pulses are still needed.
+const FETCH_TIMEOUT_SECONDS: i64 = 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct PpsKtime {
+    sec: i64,
+    nsec: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct PpsKinfo {
+    assert_sequence: u32,
+    clear_sequence: u32,
+    assert_tu: PpsKtime,
+    clear_tu: PpsKtime,
+    current_mode: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct PpsFdata {
+    info: PpsKinfo,
+    timeout: PpsKtime,
+}
+
+/// `_IOWR('p', 0xa4, struct pps_fdata *)`. Note that the kernel headers use
+/// the size of a pointer, rather than of the struct, for this ioctl.
+const PPS_FETCH: libc::c_ulong = (3 << 30)
+    | ((std::mem::size_of::<*mut PpsFdata>() as libc::c_ulong) << 16)
+    | ((b'p' as libc::c_ulong) << 8)
+    | 0xa4;
+
+pub(crate) struct PpsSource {
+    device: File,
+}
+
+impl PpsSource {
+    pub(crate) fn open(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            device: File::open(path)?,
+        })
+    }
+
+    /// Wait for the next assert edge, returning the local time at which it occurred.
+    /// Returns `None` when no pulse is seen within the timeout.
+    fn fetch(&self, last_sequence: &mut u32) -> std::io::Result<Option<NtpTimestamp>> {
+        let mut data = PpsFdata {
+            timeout: PpsKtime {
+                sec: FETCH_TIMEOUT_SECONDS,
+                nsec: 0,
+                // PPS_TIME_INVALID not set, so the timeout is used
+                flags: 0,
+            },
+            ..Default::default()
+        };
+
+        // Safety: we own the device, so the fd is valid for the duration of
+        // the call, and PPS_FETCH expects a pointer to a pps_fdata, which
+        // PpsFdata matches the layout of.
+        let result = unsafe {
+            libc::ioctl(
+                self.device.as_raw_fd(),
+                PPS_FETCH as _,
+                &mut data as *mut PpsFdata,
+            )
+        };
+
+        if result == -1 {
+            let error = std::io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(libc::ETIMEDOUT) | Some(libc::EINTR) => Ok(None),
+                _ => Err(error),
+            };
+        }
+
+        if data.info.assert_sequence == *last_sequence {
+            return Ok(None);
+        }
+        *last_sequence = data.info.assert_sequence;
+
+        Ok(Some(unix_timestamp(
+            data.info.assert_tu.sec,
+            data.info.assert_tu.nsec as u32,
+        )))
+    }
+
+    /// Read pulses on a separate thread, as fetching them blocks. The thread
+    /// stops when the receiver is dropped, or the device fails (which closes
+    /// the channel).
+    pub(crate) fn spawn(self) -> mpsc::Receiver<NtpTimestamp> {
+        let (sender, receiver) = mpsc::channel(4);
+
+        std::thread::spawn(move || {
+            let mut last_sequence = 0;
+
+            while !sender.is_closed() {
+                match self.fetch(&mut last_sequence) {
+                    Ok(Some(pulse)) => {
+                        if sender.blocking_send(pulse).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(error) => {
+                        warn!(?error, "could not fetch PPS pulse");
+                        break;
+                    }
+                }
+            }
+        });
+
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pps_fetch_request() {
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(PPS_FETCH, 0xc00870a4);
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(PPS_FETCH, 0xc00470a4);
+    }
+}
@@ -0,0 +1,326 @@
+//! Driver for the shared memory reference clock interface of ntpd, as written
+//! to by for example gpsd.
+//!
+//! Each unit uses a System V shared memory segment with key `0x4e545030 + unit`
+//! ("NTP0" + unit), containing a single `shmTime` structure. The writer fills in
+//! the time of the reference clock and the local time at which it was read, and
+//! sets `valid`. We poll the segment, and clear `valid` once a sample is consumed.
+
+use std::time::Duration;
+
+use ntp_proto::{NtpDuration, NtpInstant, NtpLeapIndicator, RefClockSample};
+use tokio::time::{Interval, MissedTickBehavior};
+use tracing::debug;
+
+use super::{unix_timestamp, RefClock, SampleFuture};
+
+use segment::ShmReading;
+pub(crate) use segment::ShmSegment;
+
+/// Interval at which the segment is checked for a new sample
+const POLL_PERIOD: Duration = Duration::from_secs(1);
+
+pub(crate) struct ShmRefClock {
+    segment: ShmSegment,
+    interval: Interval,
+}
+
+impl ShmRefClock {
+    #[cfg(test)]
+    pub(crate) fn open(unit: u32) -> std::io::Result<Self> {
+        Ok(Self::from_segment(ShmSegment::attach(unit)?))
+    }
+
+    /// Use a segment that was attached to before, e.g. while the daemon
+    /// still had the privileges to create it
+    pub(crate) fn from_segment(segment: ShmSegment) -> Self {
+        let mut interval = tokio::time::interval(POLL_PERIOD);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self { segment, interval }
+    }
+}
+
+impl RefClock for ShmRefClock {
+    fn next_sample(&mut self) -> SampleFuture<'_> {
+        Box::pin(async move {
+            loop {
+                // ticking the interval is cancel safe, and reading the
+                // segment happens synchronously, so no state is lost when
+                // this future is dropped.
+                self.interval.tick().await;
+
+                if let Some(reading) = self.segment.read() {
+                    debug!(?reading, "read sample from shared memory segment");
+                    return Ok(reading.sample());
+                }
+            }
+        })
+    }
+}
+
+impl ShmReading {
+    fn sample(&self) -> RefClockSample {
+        let clock = unix_timestamp(self.clock_seconds, self.clock_nanos);
+        let receive = unix_timestamp(self.receive_seconds, self.receive_nanos);
+
+        RefClockSample {
+            offset: clock - receive,
+            dispersion: NtpDuration::from_exponent(self.precision.clamp(-128, 127) as i8),
+            localtime: receive,
+            monotime: NtpInstant::now(),
+            leap: match self.leap {
+                0 => NtpLeapIndicator::NoWarning,
+                1 => NtpLeapIndicator::Leap61,
+                2 => NtpLeapIndicator::Leap59,
+                _ => NtpLeapIndicator::Unknown,
+            },
+        }
+    }
+}
+
+mod segment {
+    use std::{
+        ptr::{addr_of, addr_of_mut},
+        sync::atomic::{fence, Ordering},
+    };
+
+    use libc::{c_int, c_uint, time_t};
+
+    const SHM_KEY_BASE: libc::key_t = 0x4e545030;
+
+    /// Key of the segment of a unit, if it fits in a key_t
+    fn key(unit: u32) -> std::io::Result<libc::key_t> {
+        libc::key_t::try_from(unit)
+            .ok()
+            .and_then(|unit| SHM_KEY_BASE.checked_add(unit))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("SHM unit {} is out of range", unit),
+                )
+            })
+    }
+
+    /// Layout of the segment, matching `struct shmTime` of ntpd
+    #[repr(C)]
+    struct ShmTime {
+        mode: c_int,
+        count: c_int,
+        clock_timestamp_sec: time_t,
+        clock_timestamp_usec: c_int,
+        receive_timestamp_sec: time_t,
+        receive_timestamp_usec: c_int,
+        leap: c_int,
+        precision: c_int,
+        nsamples: c_int,
+        valid: c_int,
+        clock_timestamp_nsec: c_uint,
+        receive_timestamp_nsec: c_uint,
+        dummy: [c_int; 8],
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) struct ShmReading {
+        pub clock_seconds: time_t,
+        pub clock_nanos: u32,
+        pub receive_seconds: time_t,
+        pub receive_nanos: u32,
+        pub leap: c_int,
+        pub precision: c_int,
+    }
+
+    /// Older writers only fill in the microsecond fields. The nanosecond
+    /// fields are only used when they are consistent with those.
+    pub(super) fn nanos(usec: c_int, nsec: c_uint) -> u32 {
+        if nsec / 1000 == usec as c_uint {
+            nsec
+        } else {
+            (usec as u32).wrapping_mul(1000)
+        }
+    }
+
+    pub(crate) struct ShmSegment {
+        ptr: *mut ShmTime,
+    }
+
+    // Safety: the segment is owned by this struct, the pointer is only
+    // dereferenced through &mut self.
+    unsafe impl Send for ShmSegment {}
+
+    impl ShmSegment {
+        pub(crate) fn attach(unit: u32) -> std::io::Result<Self> {
+            // Like ntpd, only root may write to the first two units
+            let permissions = if unit < 2 { 0o600 } else { 0o666 };
+            let key = key(unit)?;
+
+            // Safety: shmget has no memory safety requirements
+            let id = unsafe {
+                libc::shmget(
+                    key,
+                    std::mem::size_of::<ShmTime>(),
+                    libc::IPC_CREAT | permissions,
+                )
+            };
+            if id == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            // Safety: we let the kernel choose the address to attach at, so no
+            // existing memory is affected. The segment is at least as large as
+            // ShmTime (otherwise shmget would have failed), so the returned
+            // pointer is valid for ShmTime until it is detached in drop.
+            let ptr = unsafe { libc::shmat(id, std::ptr::null(), 0) };
+            if ptr as isize == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(Self {
+                ptr: ptr as *mut ShmTime,
+            })
+        }
+
+        /// Read the sample in the segment, if there is a valid one
+        pub(crate) fn read(&mut self) -> Option<ShmReading> {
+            let ptr = self.ptr;
+
+            // Safety: ptr is valid for the lifetime of self (see attach). Other
+            // processes may write to the segment concurrently, so all
+            // accesses are volatile and torn reads are detected via count.
+            unsafe {
+                if std::ptr::read_volatile(addr_of!((*ptr).valid)) == 0 {
+                    return None;
+                }
+
+                let mode = std::ptr::read_volatile(addr_of!((*ptr).mode));
+                let count = std::ptr::read_volatile(addr_of!((*ptr).count));
+                fence(Ordering::SeqCst);
+
+                let reading = ShmReading {
+                    clock_seconds: std::ptr::read_volatile(addr_of!((*ptr).clock_timestamp_sec)),
+                    clock_nanos: nanos(
+                        std::ptr::read_volatile(addr_of!((*ptr).clock_timestamp_usec)),
+                        std::ptr::read_volatile(addr_of!((*ptr).clock_timestamp_nsec)),
+                    ),
+                    receive_seconds: std::ptr::read_volatile(addr_of!(
+                        (*ptr).receive_timestamp_sec
+                    )),
+                    receive_nanos: nanos(
+                        std::ptr::read_volatile(addr_of!((*ptr).receive_timestamp_usec)),
+                        std::ptr::read_volatile(addr_of!((*ptr).receive_timestamp_nsec)),
+                    ),
+                    leap: std::ptr::read_volatile(addr_of!((*ptr).leap)),
+                    precision: std::ptr::read_volatile(addr_of!((*ptr).precision)),
+                };
+
+                fence(Ordering::SeqCst);
+                let count_after = std::ptr::read_volatile(addr_of!((*ptr).count));
+                std::ptr::write_volatile(addr_of_mut!((*ptr).valid), 0);
+
+                // In mode 1, the writer increments count before and after
+                // writing, so a changed count means we read a partial update
+                if mode == 1 && count != count_after {
+                    return None;
+                }
+
+                Some(reading)
+            }
+        }
+
+        /// Write a sample to the segment, as a writer like gpsd would
+        #[cfg(test)]
+        pub(crate) fn write(&mut self, reading: ShmReading) {
+            let ptr = self.ptr;
+
+            // Safety: ptr is valid for the lifetime of self (see attach)
+            unsafe {
+                std::ptr::write_volatile(addr_of_mut!((*ptr).mode), 1);
+                let count = std::ptr::read_volatile(addr_of!((*ptr).count));
+                std::ptr::write_volatile(addr_of_mut!((*ptr).count), count.wrapping_add(1));
+                fence(Ordering::SeqCst);
+
+                (*ptr).clock_timestamp_sec = reading.clock_seconds;
+                (*ptr).clock_timestamp_usec = (reading.clock_nanos / 1000) as c_int;
+                (*ptr).clock_timestamp_nsec = reading.clock_nanos;
+                (*ptr).receive_timestamp_sec = reading.receive_seconds;
+                (*ptr).receive_timestamp_usec = (reading.receive_nanos / 1000) as c_int;
+                (*ptr).receive_timestamp_nsec = reading.receive_nanos;
+                (*ptr).leap = reading.leap;
+                (*ptr).precision = reading.precision;
+
+                fence(Ordering::SeqCst);
+                std::ptr::write_volatile(addr_of_mut!((*ptr).count), count.wrapping_add(2));
+                std::ptr::write_volatile(addr_of_mut!((*ptr).valid), 1);
+            }
+        }
+
+        /// Mark the segment of a unit for removal once it is no longer attached
+        #[cfg(test)]
+        pub(crate) fn remove(unit: u32) {
+            let key = key(unit).unwrap();
+            // Safety: shmget has no memory safety requirements, and IPC_RMID
+            // does not use the buffer argument
+            unsafe {
+                let id = libc::shmget(key, 0, 0);
+                libc::shmctl(id, libc::IPC_RMID, std::ptr::null_mut());
+            }
+        }
+    }
+
+    impl Drop for ShmSegment {
+        fn drop(&mut self) {
+            // Safety: ptr was returned by shmat and has not been detached yet.
+            // It is not used after this point.
+            unsafe { libc::shmdt(self.ptr as *const libc::c_void) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_sample() {
+        // Note: units must be unique among tests to deal with parallelism
+        let unit = 1000;
+
+        let mut clock = ShmRefClock::open(unit).unwrap();
+        let mut writer = ShmSegment::attach(unit).unwrap();
+        ShmSegment::remove(unit);
+
+        // nothing is read before a sample is written
+        assert_eq!(clock.segment.read(), None);
+
+        writer.write(ShmReading {
+            clock_seconds: 1_000_000_000,
+            clock_nanos: 500_000_000,
+            receive_seconds: 1_000_000_000,
+            receive_nanos: 250_000_000,
+            leap: 1,
+            precision: -10,
+        });
+
+        let sample = clock.next_sample().await.unwrap();
+        assert!((sample.offset.to_seconds() - 0.25).abs() < 1e-9);
+        assert_eq!(sample.leap, NtpLeapIndicator::Leap61);
+        assert_eq!(sample.dispersion, NtpDuration::from_exponent(-10));
+        assert_eq!(sample.localtime, unix_timestamp(1_000_000_000, 250_000_000));
+
+        // a sample is only used once
+        assert_eq!(clock.segment.read(), None);
+    }
+
+    #[test]
+    fn test_unit_out_of_range() {
+        // Keys are signed, so these would overflow or wrap around
+        assert!(ShmSegment::attach(0x40000000).is_err());
+        assert!(ShmSegment::attach(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn test_microsecond_writers() {
+        assert_eq!(segment::nanos(250, 0), 250_000);
+        assert_eq!(segment::nanos(250, 250_123), 250_123);
+    }
+}
@@ -0,0 +1,241 @@
+//! Driver for the SOCK reference clock interface of chrony.
+//!
+//! Samples are received as datagrams on a unix socket, each containing a
+//! native `struct sock_sample`:
+//!
+//! ```c
+//! struct sock_sample {
+//!   struct timeval tv;  /* system time at which the sample was taken */
+//!   double offset;      /* true time minus system time, in seconds */
+//!   int pulse;          /* non-zero for samples of a PPS signal */
+//!   int leap;           /* 0 - normal, 1 - insert leap second, 2 - delete leap second */
+//!   int _pad;
+//!   int magic;          /* 0x534f434b */
+//! };
+//! ```
+
+use std::path::Path;
+
+use ntp_proto::{NtpDuration, NtpInstant, NtpLeapIndicator, RefClockSample};
+use tokio::net::UnixDatagram;
+use tracing::{debug, warn};
+
+use super::{unix_timestamp, RefClock, SampleFuture};
+
+const SOCK_MAGIC: i32 = 0x534f434b;
+
+const TIME_T_SIZE: usize = std::mem::size_of::<libc::time_t>();
+const SUSECONDS_T_SIZE: usize = std::mem::size_of::<libc::suseconds_t>();
+const TIMEVAL_SIZE: usize = std::mem::size_of::<libc::timeval>();
+const SOCK_SAMPLE_SIZE: usize = TIMEVAL_SIZE + 8 + 4 * 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SockSample {
+    tv_sec: libc::time_t,
+    tv_usec: libc::suseconds_t,
+    offset: f64,
+    pulse: i32,
+    leap: i32,
+    magic: i32,
+}
+
+impl SockSample {
+    fn deserialize(data: &[u8]) -> Option<Self> {
+        if data.len() != SOCK_SAMPLE_SIZE {
+            return None;
+        }
+
+        let int = |offset: usize| i32::from_ne_bytes(data[offset..][..4].try_into().unwrap());
+        let rest = &data[TIMEVAL_SIZE..];
+
+        Some(Self {
+            tv_sec: libc::time_t::from_ne_bytes(data[..TIME_T_SIZE].try_into().unwrap()),
+            tv_usec: libc::suseconds_t::from_ne_bytes(
+                data[TIME_T_SIZE..][..SUSECONDS_T_SIZE].try_into().unwrap(),
+            ),
+            offset: f64::from_ne_bytes(rest[..8].try_into().unwrap()),
+            pulse: int(TIMEVAL_SIZE + 8),
+            leap: int(TIMEVAL_SIZE + 12),
+            magic: int(TIMEVAL_SIZE + 20),
+        })
+    }
+
+    #[cfg(test)]
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = vec![0; SOCK_SAMPLE_SIZE];
+        data[..TIME_T_SIZE].copy_from_slice(&self.tv_sec.to_ne_bytes());
+        data[TIME_T_SIZE..][..SUSECONDS_T_SIZE].copy_from_slice(&self.tv_usec.to_ne_bytes());
+
+        let rest = &mut data[TIMEVAL_SIZE..];
+        rest[..8].copy_from_slice(&self.offset.to_ne_bytes());
+        rest[8..12].copy_from_slice(&self.pulse.to_ne_bytes());
+        rest[12..16].copy_from_slice(&self.leap.to_ne_bytes());
+        rest[20..24].copy_from_slice(&self.magic.to_ne_bytes());
+
+        data
+    }
+
+    fn sample(&self) -> RefClockSample {
+        // A pulse only marks the start of a second, so only the offset to the
+        // nearest second is known. This assumes the local clock is already
+        // within half a second of the true time.
+        let offset = if self.pulse != 0 {
+            self.offset - self.offset.round()
+        } else {
+            self.offset
+        };
+
+        RefClockSample {
+            offset: NtpDuration::from_seconds(offset),
+            dispersion: NtpDuration::ZERO,
+            localtime: unix_timestamp(self.tv_sec, (self.tv_usec as u32).wrapping_mul(1000)),
+            monotime: NtpInstant::now(),
+            leap: match self.leap {
+                0 => NtpLeapIndicator::NoWarning,
+                1 => NtpLeapIndicator::Leap61,
+                2 => NtpLeapIndicator::Leap59,
+                _ => NtpLeapIndicator::Unknown,
+            },
+        }
+    }
+}
+
+pub(crate) struct SockRefClock {
+    socket: UnixDatagram,
+}
+
+impl SockRefClock {
+    #[cfg(test)]
+    pub(crate) fn open(path: &Path) -> std::io::Result<Self> {
+        Self::from_socket(Self::bind(path)?)
+    }
+
+    /// Create the socket, which does not need a runtime so it can be done
+    /// while the daemon still has the privileges to write to `path`
+    pub(crate) fn bind(path: &Path) -> std::io::Result<std::os::unix::net::UnixDatagram> {
+        // must unlink path before the bind below (otherwise we get "address already in use")
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+
+        std::os::unix::net::UnixDatagram::bind(path)
+    }
+
+    pub(crate) fn from_socket(socket: std::os::unix::net::UnixDatagram) -> std::io::Result<Self> {
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket: UnixDatagram::from_std(socket)?,
+        })
+    }
+}
+
+impl RefClock for SockRefClock {
+    fn next_sample(&mut self) -> SampleFuture<'_> {
+        Box::pin(async move {
+            // one byte extra to detect datagrams that are too long
+            let mut buf = [0; SOCK_SAMPLE_SIZE + 1];
+
+            loop {
+                // receiving a datagram is cancel safe
+                let n = self.socket.recv(&mut buf).await?;
+
+                match SockSample::deserialize(&buf[..n]) {
+                    Some(sample) if sample.magic == SOCK_MAGIC => {
+                        debug!(?sample, "received sample on refclock socket");
+                        return Ok(sample.sample());
+                    }
+                    Some(sample) => {
+                        warn!(magic = sample.magic, "sample with invalid magic, ignoring");
+                    }
+                    None => {
+                        warn!(
+                            expected = SOCK_SAMPLE_SIZE,
+                            actual = n,
+                            "sample of invalid size, ignoring"
+                        );
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::NtpTimestamp;
+
+    use super::*;
+
+    fn test_sample() -> SockSample {
+        SockSample {
+            tv_sec: 1_000_000_000,
+            tv_usec: 250_000,
+            offset: 0.125,
+            pulse: 0,
+            leap: 2,
+            magic: SOCK_MAGIC,
+        }
+    }
+
+    #[test]
+    fn test_serialization_roundtrip() {
+        let sample = test_sample();
+        let data = sample.serialize();
+        assert_eq!(data.len(), std::mem::size_of::<libc::timeval>() + 24);
+        assert_eq!(SockSample::deserialize(&data), Some(sample));
+        assert_eq!(SockSample::deserialize(&data[1..]), None);
+    }
+
+    #[test]
+    fn test_pulse_offset() {
+        let sample = SockSample {
+            offset: 3.001,
+            pulse: 1,
+            ..test_sample()
+        };
+        assert!((sample.sample().offset.to_seconds() - 0.001).abs() < 1e-9);
+
+        let sample = SockSample {
+            offset: -0.999,
+            pulse: 1,
+            ..test_sample()
+        };
+        assert!((sample.sample().offset.to_seconds() - 0.001).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_receive_sample() {
+        // be careful with copying: tests run concurrently and should use a unique socket name!
+        let path = std::env::temp_dir().join("ntp-test-refclock-sock-1");
+        let mut clock = SockRefClock::open(&path).unwrap();
+
+        let sender = UnixDatagram::unbound().unwrap();
+
+        // invalid messages are ignored
+        let invalid = SockSample {
+            magic: 0,
+            ..test_sample()
+        };
+        sender.send_to(&invalid.serialize(), &path).await.unwrap();
+        sender.send_to(&[0; 3], &path).await.unwrap();
+
+        sender
+            .send_to(&test_sample().serialize(), &path)
+            .await
+            .unwrap();
+
+        let sample = clock.next_sample().await.unwrap();
+        assert!((sample.offset.to_seconds() - 0.125).abs() < 1e-9);
+        assert_eq!(sample.leap, NtpLeapIndicator::Leap59);
+        assert_eq!(
+            sample.localtime,
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(
+                1_000_000_000 + (70 * 365 + 17) * 86400,
+                250_000_000
+            )
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
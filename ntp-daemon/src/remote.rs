@@ -0,0 +1,766 @@
+//! Management of the daemon over the network. The remote endpoint offers the
+//! same operations as the observe and configure sockets, over TLS. Clients
+//! authenticate with a certificate issued by the configured authority, or
+//! with one of the configured tokens, each of which grants a [`Role`].
+
+use std::{
+    io::{BufReader, Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use ntp_proto::SystemSnapshot;
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch, Semaphore},
+    task::JoinHandle,
+};
+use tokio_rustls::{
+    rustls::{
+        server::AllowAnyAnonymousOrAuthenticatedClient, Certificate, ClientConfig, PrivateKey,
+        RootCertStore, ServerConfig, ServerName,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use tracing::{debug, error, warn};
+
+use crate::{
+    access::Role,
+    config::{
+        dynamic::{ConfigRequest, ConfigResponse},
+        RemoteConfig, RemoteToken,
+    },
+    observer::{observe_state, ObservablePeerState, ObservableState},
+    system::ServerData,
+    systemd::ListenFds,
+};
+
+/// Requests are small, anything bigger is not worth reading
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+/// Time a client gets to complete the handshake, send its request and read the response
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections handled at the same time, further connections are closed
+/// right after they are accepted
+const MAX_CONNECTIONS: usize = 64;
+
+/// A request to the remote endpoint. The connection is closed for writing
+/// after the request, and the endpoint closes it after the response.
+#[derive(Serialize, Deserialize)]
+pub struct RemoteRequest {
+    /// One of the configured tokens, absent when authenticating with a
+    /// client certificate
+    pub token: Option<String>,
+    pub operation: RemoteOperation,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RemoteOperation {
+    /// Read the state, like on the observe socket. Needs the read role.
+    Observe,
+    /// Change the configuration, like on the configure socket. Needs the write role.
+    Configure(ConfigRequest),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RemoteResponse {
+    Observe(ObservableState),
+    Configure(ConfigResponse),
+    /// Why the request was not handled at all
+    Error(String),
+}
+
+/// The remote endpoint, set up but not yet accepting connections
+pub struct RemoteListener {
+    listener: std::net::TcpListener,
+    acceptor: TlsAcceptor,
+    /// Role of clients with a valid certificate, absent when clients cannot
+    /// authenticate that way
+    client_role: Option<Role>,
+    tokens: Vec<RemoteToken>,
+}
+
+/// Set up the remote endpoint, if configured. It listens on the socket named
+/// `fd_name` passed on by the service manager if there is one, or otherwise
+/// on a newly bound socket.
+///
+/// This reads the certificates and keys, so that they can be readable by root
+/// only, and does not need a runtime. It is meant to be called before
+/// privileges are dropped, see [`crate::privileges`].
+pub fn listen(
+    config: Option<&RemoteConfig>,
+    listen_fds: &mut ListenFds,
+) -> std::io::Result<Option<RemoteListener>> {
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(config)?));
+
+    if config.client_ca.is_none() && config.tokens.is_empty() {
+        warn!("No client can authenticate to the remote management endpoint, configure a client-ca or tokens");
+    }
+
+    let from_service_manager = config
+        .fd_name
+        .as_deref()
+        .and_then(|name| listen_fds.take_tcp_listener(name));
+    let listener = match (from_service_manager, config.listen) {
+        (Some(listener), _) => listener,
+        (None, Some(address)) => std::net::TcpListener::bind(address)
+            .map_err(|e| Error::new(e.kind(), format!("Could not listen on {}: {}", address, e)))?,
+        (None, None) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "no address to listen on for remote management",
+            ))
+        }
+    };
+
+    Ok(Some(RemoteListener {
+        listener,
+        acceptor,
+        client_role: config.client_ca.as_ref().map(|_| config.client_role),
+        tokens: config.tokens.clone(),
+    }))
+}
+
+fn server_config(config: &RemoteConfig) -> std::io::Result<ServerConfig> {
+    let certificates = load_certificates(&config.certificate)?;
+    let private_key = load_private_key(&config.private_key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    // Clients without a certificate may still authenticate with a token
+    let builder = match &config.client_ca {
+        Some(path) => builder.with_client_cert_verifier(
+            AllowAnyAnonymousOrAuthenticatedClient::new(load_root_store(path)?),
+        ),
+        None => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(certificates, private_key)
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid certificate: {}", e),
+            )
+        })
+}
+
+fn read_pem(path: &Path) -> std::io::Result<Vec<Item>> {
+    let annotate = |e: Error| Error::new(e.kind(), format!("Could not read {:?}: {}", path, e));
+
+    let file = std::fs::File::open(path).map_err(annotate)?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(annotate)
+}
+
+fn load_certificates(path: &Path) -> std::io::Result<Vec<Certificate>> {
+    let certificates: Vec<_> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(certificate) => Some(Certificate(certificate)),
+            _ => None,
+        })
+        .collect();
+
+    if certificates.is_empty() {
+        let msg = format!("No certificates found in {:?}", path);
+        return Err(Error::new(ErrorKind::InvalidData, msg));
+    }
+
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> std::io::Result<PrivateKey> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            let msg = format!("No private key found in {:?}", path);
+            Error::new(ErrorKind::InvalidData, msg)
+        })
+}
+
+fn load_root_store(path: &Path) -> std::io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(path)? {
+        roots.add(&certificate).map_err(|e| {
+            let msg = format!("Invalid certificate in {:?}: {}", path, e);
+            Error::new(ErrorKind::InvalidData, msg)
+        })?;
+    }
+    Ok(roots)
+}
+
+/// Serve the remote endpoint set up by [`listen`]. Does nothing when there is
+/// none. Configuration requests are handled by the dynamic configurator
+/// behind `config_sender`, see [`crate::config::dynamic::spawn`].
+pub async fn spawn(
+    listener: Option<RemoteListener>,
+    peers_reader: watch::Receiver<Vec<ObservablePeerState>>,
+    server_reader: watch::Receiver<Vec<ServerData>>,
+    system_reader: watch::Receiver<SystemSnapshot>,
+    config_sender: mpsc::Sender<(ConfigRequest, oneshot::Sender<ConfigResponse>)>,
+) -> JoinHandle<std::io::Result<()>> {
+    tokio::spawn(async move {
+        let listener = match listener {
+            Some(listener) => listener,
+            None => return Ok(()),
+        };
+        let result = serve(
+            listener,
+            peers_reader,
+            server_reader,
+            system_reader,
+            config_sender,
+        )
+        .await;
+        if let Err(ref e) = result {
+            error!("Abnormal termination of remote management endpoint: {}", e);
+        }
+        result
+    })
+}
+
+async fn serve(
+    listener: RemoteListener,
+    peers_reader: watch::Receiver<Vec<ObservablePeerState>>,
+    server_reader: watch::Receiver<Vec<ServerData>>,
+    system_reader: watch::Receiver<SystemSnapshot>,
+    config_sender: mpsc::Sender<(ConfigRequest, oneshot::Sender<ConfigResponse>)>,
+) -> std::io::Result<()> {
+    listener.listener.set_nonblocking(true)?;
+    let tcp_listener = TcpListener::from_std(listener.listener)?;
+
+    let remote = Arc::new(Remote {
+        acceptor: listener.acceptor,
+        client_role: listener.client_role,
+        tokens: listener.tokens,
+        peers_reader,
+        server_reader,
+        system_reader,
+        config_sender,
+    });
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+
+    loop {
+        let (stream, address) = match tcp_listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // e.g. running out of file descriptors, which may resolve itself
+                warn!("Could not accept remote management connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!(%address, "Too many remote management connections, closing connection");
+                continue;
+            }
+        };
+
+        let remote = remote.clone();
+        tokio::spawn(async move {
+            let connection = remote.handle_connection(stream, address);
+            match tokio::time::timeout(CONNECTION_TIMEOUT, connection).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!(%address, "remote management connection failed: {}", e),
+                Err(_) => debug!(%address, "remote management connection timed out"),
+            }
+            drop(permit);
+        });
+    }
+}
+
+struct Remote {
+    acceptor: TlsAcceptor,
+    client_role: Option<Role>,
+    tokens: Vec<RemoteToken>,
+    peers_reader: watch::Receiver<Vec<ObservablePeerState>>,
+    server_reader: watch::Receiver<Vec<ServerData>>,
+    system_reader: watch::Receiver<SystemSnapshot>,
+    config_sender: mpsc::Sender<(ConfigRequest, oneshot::Sender<ConfigResponse>)>,
+}
+
+impl Remote {
+    async fn handle_connection(
+        &self,
+        stream: TcpStream,
+        address: SocketAddr,
+    ) -> std::io::Result<()> {
+        let mut stream = self.acceptor.accept(stream).await?;
+        let has_certificate = stream.get_ref().1.peer_certificates().is_some();
+
+        let response = match read_message(&mut stream, MAX_REQUEST_SIZE).await? {
+            None => RemoteResponse::Error("request too large".into()),
+            Some(request) => match serde_json::from_slice::<RemoteRequest>(&request) {
+                Ok(request) => self.handle_request(request, has_certificate, address).await,
+                Err(e) => RemoteResponse::Error(format!("invalid request: {}", e)),
+            },
+        };
+
+        write_message(&mut stream, &response).await
+    }
+
+    async fn handle_request(
+        &self,
+        request: RemoteRequest,
+        has_certificate: bool,
+        address: SocketAddr,
+    ) -> RemoteResponse {
+        let required = match request.operation {
+            RemoteOperation::Observe => Role::Read,
+            RemoteOperation::Configure(_) => Role::Write,
+        };
+
+        let role = self.authenticate(has_certificate, request.token.as_deref());
+        if !matches!(role, Some(role) if role >= required) {
+            warn!(%address, ?role, ?required, "refused remote management request");
+            return RemoteResponse::Error("permission denied".into());
+        }
+
+        match request.operation {
+            RemoteOperation::Observe => RemoteResponse::Observe(observe_state(
+                &self.peers_reader,
+                &self.server_reader,
+                &self.system_reader,
+            )),
+            RemoteOperation::Configure(request) => {
+                let (reply_sender, reply_receiver) = oneshot::channel();
+                let not_running = || RemoteResponse::Error("the daemon is shutting down".into());
+
+                if self
+                    .config_sender
+                    .send((request, reply_sender))
+                    .await
+                    .is_err()
+                {
+                    return not_running();
+                }
+                match reply_receiver.await {
+                    Ok(response) => RemoteResponse::Configure(response),
+                    Err(_) => not_running(),
+                }
+            }
+        }
+    }
+
+    /// The role of a client, if any. A token that is given must be valid, and
+    /// when both a certificate and a token are given the client gets the
+    /// highest of their roles.
+    fn authenticate(&self, has_certificate: bool, token: Option<&str>) -> Option<Role> {
+        let certificate_role = if has_certificate {
+            self.client_role
+        } else {
+            None
+        };
+
+        match token {
+            None => certificate_role,
+            Some(token) => {
+                // Check all tokens, so that the time taken does not reveal which one matched
+                let token_role = self
+                    .tokens
+                    .iter()
+                    .filter(|known| constant_time_eq(known.token.as_bytes(), token.as_bytes()))
+                    .map(|known| known.role)
+                    .max();
+                token_role.map(|role| role.max(certificate_role.unwrap_or(role)))
+            }
+        }
+    }
+}
+
+/// Compare without returning early, so that the time taken does not reveal
+/// how much of a token was guessed correctly
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Read until the other side closes the connection for writing, or `None`
+/// when that takes more than `limit` bytes
+async fn read_message(
+    stream: &mut (impl AsyncRead + Unpin),
+    limit: u64,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut buffer = vec![];
+    stream.take(limit + 1).read_to_end(&mut buffer).await?;
+
+    Ok((buffer.len() as u64 <= limit).then_some(buffer))
+}
+
+/// Write a single message, and close the connection for writing
+async fn write_message(
+    stream: &mut (impl AsyncWrite + Unpin),
+    value: &impl Serialize,
+) -> std::io::Result<()> {
+    let bytes = serde_json::to_vec(value).unwrap();
+    stream.write_all(&bytes).await?;
+    stream.shutdown().await
+}
+
+/// A client of the remote endpoint, as used by `ntp-ctl`
+pub struct RemoteClient {
+    address: String,
+    server_name: ServerName,
+    connector: TlsConnector,
+    token: Option<String>,
+}
+
+impl RemoteClient {
+    /// Connect to `address`, a `host:port` pair, verifying the endpoint with
+    /// the authorities in `ca`. The certificate of the endpoint must be for
+    /// `server_name`, by default the host in `address`.
+    pub fn new(
+        address: &str,
+        server_name: Option<&str>,
+        ca: &Path,
+        client_certificate: Option<(&Path, &Path)>,
+        token: Option<String>,
+    ) -> std::io::Result<Self> {
+        let host = match address.rsplit_once(':') {
+            Some((host, _port)) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => {
+                let msg = format!("{:?} is not a host:port pair", address);
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
+        };
+        let server_name = server_name.unwrap_or(host);
+
+        // rustls only verifies certificates for host names
+        if server_name.parse::<IpAddr>().is_ok() {
+            let msg = format!(
+                "cannot verify a certificate for IP address {}, specify the host name in the certificate",
+                server_name
+            );
+            return Err(Error::new(ErrorKind::InvalidInput, msg));
+        }
+        let server_name = ServerName::try_from(server_name).map_err(|e| {
+            let msg = format!("invalid host name {:?}: {}", server_name, e);
+            Error::new(ErrorKind::InvalidInput, msg)
+        })?;
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_root_store(ca)?);
+        let config = match client_certificate {
+            Some((certificate, private_key)) => builder
+                .with_single_cert(
+                    load_certificates(certificate)?,
+                    load_private_key(private_key)?,
+                )
+                .map_err(|e| {
+                    let msg = format!("Invalid client certificate: {}", e);
+                    Error::new(ErrorKind::InvalidInput, msg)
+                })?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(RemoteClient {
+            address: address.to_string(),
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+            token,
+        })
+    }
+
+    pub async fn request(&self, operation: RemoteOperation) -> std::io::Result<RemoteResponse> {
+        let stream = TcpStream::connect(&self.address).await?;
+        let mut stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+
+        let request = RemoteRequest {
+            token: self.token.clone(),
+            operation,
+        };
+        write_message(&mut stream, &request).await?;
+
+        let mut buffer = vec![];
+        stream.read_to_end(&mut buffer).await?;
+
+        serde_json::from_slice(&buffer).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::config::dynamic::{ConfigUpdate, PROTOCOL_VERSION};
+
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("testdata/certificates");
+        path.push(name);
+        path
+    }
+
+    fn token(token: &str, role: Role) -> RemoteToken {
+        RemoteToken {
+            token: token.into(),
+            role,
+        }
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let config = RemoteConfig {
+            listen: Some("127.0.0.1:0".parse().unwrap()),
+            fd_name: None,
+            certificate: testdata("server.pem"),
+            private_key: testdata("server.key"),
+            client_ca: None,
+            client_role: Role::Read,
+            tokens: vec![token("reader", Role::Read), token("writer", Role::Write)],
+        };
+        let listener = listen(Some(&config), &mut ListenFds::default())
+            .unwrap()
+            .unwrap();
+
+        let remote = Remote {
+            acceptor: listener.acceptor,
+            client_role: Some(Role::Read),
+            tokens: listener.tokens,
+            peers_reader: watch::channel(vec![]).1,
+            server_reader: watch::channel(vec![]).1,
+            system_reader: watch::channel(SystemSnapshot::default()).1,
+            config_sender: mpsc::channel(1).0,
+        };
+
+        assert_eq!(remote.authenticate(false, None), None);
+        assert_eq!(remote.authenticate(true, None), Some(Role::Read));
+        assert_eq!(remote.authenticate(false, Some("reader")), Some(Role::Read));
+        assert_eq!(
+            remote.authenticate(false, Some("writer")),
+            Some(Role::Write)
+        );
+        assert_eq!(remote.authenticate(true, Some("writer")), Some(Role::Write));
+
+        // an invalid token is not ignored
+        assert_eq!(remote.authenticate(false, Some("write")), None);
+        assert_eq!(remote.authenticate(true, Some("write")), None);
+    }
+
+    #[test]
+    fn test_listen_errors() {
+        let mut config = RemoteConfig {
+            listen: None,
+            fd_name: None,
+            certificate: testdata("server.pem"),
+            private_key: testdata("server.key"),
+            client_ca: Some(testdata("ca.pem")),
+            client_role: Role::Read,
+            tokens: vec![],
+        };
+
+        assert!(listen(None, &mut ListenFds::default()).unwrap().is_none());
+        assert!(listen(Some(&config), &mut ListenFds::default()).is_err());
+
+        config.listen = Some("127.0.0.1:0".parse().unwrap());
+        config.private_key = testdata("server.pem");
+        assert!(listen(Some(&config), &mut ListenFds::default()).is_err());
+
+        config.private_key = testdata("does-not-exist.key");
+        assert!(listen(Some(&config), &mut ListenFds::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remote_requests() {
+        let config = RemoteConfig {
+            listen: Some("127.0.0.1:0".parse().unwrap()),
+            fd_name: None,
+            certificate: testdata("server.pem"),
+            private_key: testdata("server.key"),
+            client_ca: Some(testdata("ca.pem")),
+            client_role: Role::Read,
+            tokens: vec![token("writer", Role::Write)],
+        };
+        let listener = listen(Some(&config), &mut ListenFds::default())
+            .unwrap()
+            .unwrap();
+        let address = listener.listener.local_addr().unwrap().to_string();
+
+        // stand-in for the dynamic configurator
+        let (config_sender, mut config_receiver) =
+            mpsc::channel::<(ConfigRequest, oneshot::Sender<ConfigResponse>)>(1);
+        tokio::spawn(async move {
+            while let Some((request, reply)) = config_receiver.recv().await {
+                assert!(request.update.reload);
+                let _ = reply.send(ConfigResponse {
+                    version: PROTOCOL_VERSION,
+                    error: None,
+                    results: vec![],
+                    config: None,
+                });
+            }
+        });
+
+        let handle = spawn(
+            Some(listener),
+            watch::channel(vec![ObservablePeerState::Nothing]).1,
+            watch::channel(vec![]).1,
+            watch::channel(SystemSnapshot::default()).1,
+            config_sender,
+        )
+        .await;
+
+        let configure = || {
+            RemoteOperation::Configure(ConfigRequest::new(ConfigUpdate {
+                reload: true,
+                ..Default::default()
+            }))
+        };
+        let client = |certificate: bool, token: Option<&str>| {
+            let client_certificate = (testdata("client.pem"), testdata("client.key"));
+            RemoteClient::new(
+                &address,
+                Some("localhost"),
+                &testdata("ca.pem"),
+                certificate.then_some((&client_certificate.0, &client_certificate.1)),
+                token.map(String::from),
+            )
+            .unwrap()
+        };
+
+        // without credentials
+        let anonymous = client(false, None);
+        let response = anonymous.request(RemoteOperation::Observe).await.unwrap();
+        assert!(matches!(response, RemoteResponse::Error(_)));
+
+        // the certificate grants the read role
+        let reader = client(true, None);
+        let response = reader.request(RemoteOperation::Observe).await.unwrap();
+        match response {
+            RemoteResponse::Observe(state) => assert_eq!(state.peers.len(), 1),
+            other => panic!("unexpected response {:?}", other),
+        }
+        let response = reader.request(configure()).await.unwrap();
+        assert!(matches!(response, RemoteResponse::Error(_)));
+
+        // the token grants the write role
+        let writer = client(false, Some("writer"));
+        let response = writer.request(configure()).await.unwrap();
+        match response {
+            RemoteResponse::Configure(response) => assert!(response.is_success()),
+            other => panic!("unexpected response {:?}", other),
+        }
+        let response = writer.request(RemoteOperation::Observe).await.unwrap();
+        assert!(matches!(response, RemoteResponse::Observe(_)));
+
+        // the certificate of the endpoint is checked
+        let wrong_name = RemoteClient::new(
+            &address,
+            Some("example.com"),
+            &testdata("ca.pem"),
+            None,
+            Some("writer".into()),
+        )
+        .unwrap();
+        assert!(wrong_name.request(RemoteOperation::Observe).await.is_err());
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let config = RemoteConfig {
+            listen: Some("127.0.0.1:0".parse().unwrap()),
+            fd_name: None,
+            certificate: testdata("server.pem"),
+            private_key: testdata("server.key"),
+            client_ca: None,
+            client_role: Role::Read,
+            tokens: vec![token("reader", Role::Read)],
+        };
+        let listener = listen(Some(&config), &mut ListenFds::default())
+            .unwrap()
+            .unwrap();
+        let address = listener.listener.local_addr().unwrap();
+
+        let handle = spawn(
+            Some(listener),
+            watch::channel(vec![]).1,
+            watch::channel(vec![]).1,
+            watch::channel(SystemSnapshot::default()).1,
+            mpsc::channel(1).0,
+        )
+        .await;
+
+        // connections that never start the handshake use up the limit
+        let mut idle = vec![];
+        for _ in 0..MAX_CONNECTIONS {
+            idle.push(TcpStream::connect(address).await.unwrap());
+        }
+
+        // so the next one is closed without a response
+        let mut refused = TcpStream::connect(address).await.unwrap();
+        let mut buffer = vec![];
+        let read = tokio::time::timeout(Duration::from_secs(5), refused.read_to_end(&mut buffer))
+            .await
+            .unwrap();
+        assert!(read.is_err() || buffer.is_empty());
+
+        // closing one makes room again
+        drop(idle.pop());
+        let reader = RemoteClient::new(
+            &address.to_string(),
+            Some("localhost"),
+            &testdata("ca.pem"),
+            None,
+            Some("reader".into()),
+        )
+        .unwrap();
+        let mut response = reader.request(RemoteOperation::Observe).await;
+        for _ in 0..50 {
+            if response.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            response = reader.request(RemoteOperation::Observe).await;
+        }
+        assert!(matches!(response.unwrap(), RemoteResponse::Observe(_)));
+
+        handle.abort();
+    }
+
+    #[test]
+    fn test_client_server_name() {
+        let ca = testdata("ca.pem");
+        assert!(RemoteClient::new("localhost:9123", None, &ca, None, None).is_ok());
+        assert!(RemoteClient::new("[::1]:9123", Some("localhost"), &ca, None, None).is_ok());
+        assert!(RemoteClient::new("127.0.0.1:9123", None, &ca, None, None).is_err());
+        assert!(RemoteClient::new("localhost", None, &ca, None, None).is_err());
+    }
+}
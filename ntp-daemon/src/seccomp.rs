@@ -0,0 +1,371 @@
+//! Seccomp sandbox for the daemon. Once enabled, all threads of the daemon may
+//! only make the system calls it needs while running: those of tokio and the
+//! standard library, of socket handling and timestamping in `ntp-udp`, of
+//! adjusting the clock in `ntp-os-clock`, and of the reference clocks. This
+//! includes what is needed after a reload, when servers, peers and reference
+//! clocks are started again, and when statistics files are started. Any other
+//! system call is reported on stderr, after which the process is killed.
+
+use std::io::{Error, ErrorKind};
+
+use tracing::info;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscall numbers with this bit set are from the x32 ABI, which shares the
+/// architecture with x86_64 but has its own syscall table
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// System calls that are allowed once the filter is enabled
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // memory management
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    // threads and synchronization
+    libc::SYS_futex,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_restart_syscall,
+    // signals
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    // time and clock adjustment
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_clock_adjtime,
+    libc::SYS_adjtimex,
+    // files and devices, e.g. for name resolution, reference clocks and statistics
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_getdents64,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_pipe2,
+    libc::SYS_uname,
+    libc::SYS_getrandom,
+    // event notification and signal handling used by tokio
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    libc::SYS_socketpair,
+    // sockets, including timestamping in ntp-udp
+    libc::SYS_socket,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_connect,
+    libc::SYS_accept4,
+    libc::SYS_shutdown,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    // shared memory reference clocks
+    libc::SYS_shmget,
+    libc::SYS_shmat,
+    libc::SYS_shmdt,
+    libc::SYS_shmctl,
+    // legacy variants, which do not exist on newer architectures
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chmod,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_chown,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_getrlimit,
+];
+
+const fn statement(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+const fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+// Offsets in struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn filter() -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    let mut program = vec![
+        // system calls of other architectures have different numbers
+        statement(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR),
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    program.extend([
+        jump(BPF_JMP | libc::BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+        statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+    ]);
+
+    for syscall in ALLOWED_SYSCALLS {
+        program.extend([
+            jump(BPF_JMP | BPF_JEQ | BPF_K, *syscall as u32, 0, 1),
+            statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW),
+        ]);
+    }
+
+    // the SIGSYS handler reports the system call, then kills the process
+    program.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_TRAP));
+
+    program
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn filter() -> Vec<libc::sock_filter> {
+    vec![]
+}
+
+/// Restrict all threads of the process to the allowed system calls
+pub fn enable() -> std::io::Result<()> {
+    let mut program = filter();
+    if program.is_empty() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "seccomp filter is not available on this architecture",
+        ));
+    }
+
+    install_violation_handler()?;
+
+    // Required to install a filter without CAP_SYS_ADMIN
+    // Safety: prctl has no memory safety requirements
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+
+    // Safety: fprog points to a valid filter program of the given length
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &fprog as *const libc::sock_fprog,
+        )
+    };
+
+    match result {
+        0 => {
+            info!("enabled seccomp filter");
+            Ok(())
+        }
+        // with TSYNC, a positive result is the id of a thread that could not be synchronized
+        thread if thread > 0 => Err(Error::new(
+            ErrorKind::Other,
+            format!("could not apply seccomp filter to thread {}", thread),
+        )),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+/// Layout of siginfo_t for SIGSYS on 64-bit platforms, which libc does not expose
+#[allow(dead_code)]
+#[repr(C)]
+struct SigSysInfo {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    _padding: libc::c_int,
+    call_addr: *mut libc::c_void,
+    syscall: libc::c_int,
+    arch: libc::c_uint,
+}
+
+fn install_violation_handler() -> std::io::Result<()> {
+    // Safety: sigaction is plain old data, for which all zeroes is valid
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+        report_violation;
+    action.sa_sigaction = handler as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO;
+
+    // Safety: the handler only does async-signal-safe operations
+    if unsafe { libc::sigaction(libc::SIGSYS, &action, std::ptr::null_mut()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+extern "C" fn report_violation(
+    _signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _context: *mut libc::c_void,
+) {
+    // Safety: the kernel passes siginfo in the SIGSYS layout for this signal
+    let syscall = unsafe { (*(info as *const SigSysInfo)).syscall };
+
+    // No allocation or locking is allowed here, so the message is built on the stack
+    let mut message = [0u8; 128];
+    let length = violation_message(&mut message, syscall as u32);
+
+    // Safety: the message is valid for the given length, and these calls are
+    // async-signal-safe. The signal is raised again with the default action
+    // once this handler returns, killing the process.
+    unsafe {
+        libc::write(
+            libc::STDERR_FILENO,
+            message.as_ptr() as *const libc::c_void,
+            length,
+        );
+        libc::signal(libc::SIGSYS, libc::SIG_DFL);
+        libc::raise(libc::SIGSYS);
+    }
+}
+
+/// Write the message for a seccomp violation into `buffer`, returning its length
+fn violation_message(buffer: &mut [u8], syscall: u32) -> usize {
+    const PREFIX: &[u8] = b"seccomp: blocked system call ";
+    const SUFFIX: &[u8] = b" that is not allowed, terminating\n";
+
+    let mut digits = [0u8; 10];
+    let mut start = digits.len();
+    let mut remaining = syscall;
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (remaining % 10) as u8;
+        remaining /= 10;
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    let mut length = 0;
+    for part in [PREFIX, &digits[start..], SUFFIX] {
+        buffer[length..length + part.len()].copy_from_slice(part);
+        length += part.len();
+    }
+    length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_violation_message() {
+        let mut buffer = [0u8; 128];
+
+        let length = violation_message(&mut buffer, 0);
+        assert_eq!(
+            &buffer[..length],
+            b"seccomp: blocked system call 0 that is not allowed, terminating\n"
+        );
+
+        let length = violation_message(&mut buffer, u32::MAX);
+        assert_eq!(
+            &buffer[..length],
+            b"seccomp: blocked system call 4294967295 that is not allowed, terminating\n"
+        );
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn test_filter() {
+        let program = filter();
+
+        // the architecture is checked first
+        assert_eq!(program[0].k, SECCOMP_DATA_ARCH);
+        assert_eq!(program[1].k, AUDIT_ARCH);
+        assert_eq!(program[2].k, libc::SECCOMP_RET_KILL_PROCESS);
+
+        // every allowed syscall is compared against, and allowed on a match
+        for syscall in ALLOWED_SYSCALLS {
+            let position = program
+                .iter()
+                .position(|instruction| {
+                    instruction.code == (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16
+                        && instruction.k == *syscall as u32
+                })
+                .unwrap();
+            assert_eq!(program[position + 1].k, libc::SECCOMP_RET_ALLOW);
+        }
+
+        // anything else traps
+        assert_eq!(program.last().unwrap().k, libc::SECCOMP_RET_TRAP);
+        assert!(program.len() < libc::BPF_MAXINSNS as usize);
+    }
+}
@@ -81,6 +81,7 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
         mut system_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
         clock: C,
         network_wait_period: Duration,
+        socket: Option<std::net::UdpSocket>,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
             let rate_limiting_cutoff = config.rate_limiting_cutoff;
@@ -97,7 +98,19 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                 stats,
             };
 
-            process.serve(rate_limiting_cutoff).await
+            // A socket passed on by the service manager is used until it fails
+            let socket = socket.and_then(|socket| match UdpSocket::server_from_std(socket) {
+                Ok(socket) => Some(socket),
+                Err(error) => {
+                    warn!(
+                        ?error,
+                        "Could not use server socket passed by service manager"
+                    );
+                    None
+                }
+            });
+
+            process.serve(rate_limiting_cutoff, socket).await
         })
     }
 
@@ -113,11 +126,11 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
         }
     }
 
-    #[instrument(level = "debug", skip(self), fields(
+    #[instrument(level = "debug", skip(self, socket), fields(
         addr = debug(self.config.addr),
     ))]
-    async fn serve(&mut self, rate_limiting_cutoff: Duration) {
-        let mut cur_socket = None;
+    async fn serve(&mut self, rate_limiting_cutoff: Duration, socket: Option<UdpSocket>) {
+        let mut cur_socket = socket;
         loop {
             let socket = if let Some(ref socket) = cur_socket {
                 socket
@@ -425,6 +438,10 @@ mod tests {
             panic!("Shouldn't be called by peer");
         }
 
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            panic!("Shouldn't be called by peer");
+        }
+
         fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
             panic!("Shouldn't be called by peer");
         }
@@ -451,6 +468,7 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            fd_name: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -461,6 +479,7 @@ mod tests {
             system_snapshots,
             clock,
             Duration::from_secs(1),
+            None,
         );
 
         let mut socket = UdpSocket::client(
@@ -496,6 +515,7 @@ mod tests {
             allowlist_action: FilterAction::Deny,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            fd_name: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -506,6 +526,7 @@ mod tests {
             system_snapshots,
             clock,
             Duration::from_secs(1),
+            None,
         );
 
         let mut socket = UdpSocket::client(
@@ -542,6 +563,7 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            fd_name: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -552,6 +574,7 @@ mod tests {
             system_snapshots,
             clock,
             Duration::from_secs(1),
+            None,
         );
 
         let mut socket = UdpSocket::client(
@@ -582,6 +605,7 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            fd_name: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -592,6 +616,7 @@ mod tests {
             system_snapshots,
             clock,
             Duration::from_secs(1),
+            None,
         );
 
         let mut socket = UdpSocket::client(
@@ -627,6 +652,7 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            fd_name: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -637,6 +663,7 @@ mod tests {
             system_snapshots,
             clock,
             Duration::from_secs(1),
+            None,
         );
 
         let mut socket = UdpSocket::client(
@@ -673,6 +700,7 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            fd_name: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -683,6 +711,7 @@ mod tests {
             system_snapshots,
             clock,
             Duration::from_secs(1),
+            None,
         );
 
         let mut socket = UdpSocket::client(
@@ -713,6 +742,7 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_millis(100),
             rate_limiting_cache_size: 32,
+            fd_name: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -723,6 +753,7 @@ mod tests {
             system_snapshots,
             clock,
             Duration::from_secs(1),
+            None,
         );
 
         let mut socket = UdpSocket::client(
@@ -787,6 +818,7 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::default(),
             rate_limiting_cache_size: Default::default(),
+            fd_name: None,
         };
         let (_, system_snapshots) = tokio::sync::watch::channel(SystemSnapshot::default());
         let clock = TestClock {};
@@ -797,6 +829,7 @@ mod tests {
             system_snapshots,
             clock,
             Duration::from_secs(1),
+            None,
         );
 
         let mut socket = UdpSocket::client(
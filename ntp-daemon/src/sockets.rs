@@ -1,9 +1,14 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
 use std::path::Path;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixListener;
 use tokio::net::UnixStream;
 
+use crate::systemd::ListenFds;
+
 pub async fn write_json<T>(stream: &mut UnixStream, value: &T) -> std::io::Result<()>
 where
     T: serde::Serialize,
@@ -24,7 +29,8 @@ where
     let n = stream.read_buf(buffer).await?;
     buffer.truncate(n);
 
-    Ok(serde_json::from_slice(buffer).unwrap())
+    serde_json::from_slice(buffer)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
 }
 
 pub fn create_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
@@ -45,7 +51,7 @@ pub fn create_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
     if let Some(parent) = path.parent() {
         if !parent.exists() {
             let msg = format!(
-                r"Could not create observe socket at {:?} because its parent directory does not exist",
+                r"Could not create socket at {:?} because its parent directory does not exist",
                 &path
             );
             return Err(Error::new(ErrorKind::Other, msg));
@@ -53,13 +59,88 @@ pub fn create_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
     }
 
     // otherwise, just forward the OS error
-    let msg = format!(
-        "Could not create observe socket at {:?}: {:?}",
-        &path, error
-    );
+    let msg = format!("Could not create socket at {:?}: {:?}", &path, error);
     Err(Error::new(ErrorKind::Other, msg))
 }
 
+/// Listen on the socket named `fd_name` passed on by the service manager if
+/// there is one, or otherwise on a newly created socket at `path`, if given.
+///
+/// This does not need a runtime, so sockets can be created before
+/// privileges are dropped, see [`crate::privileges`].
+pub fn listen_unix_socket(
+    fd_name: Option<&str>,
+    path: Option<&Path>,
+    mode: u32,
+    owner: Option<&str>,
+    group: Option<&str>,
+    listen_fds: &mut ListenFds,
+) -> std::io::Result<Option<UnixListener>> {
+    if let Some(listener) = fd_name.and_then(|name| listen_fds.take_unix_listener(name)) {
+        // permissions of the socket are managed by the service manager
+        return Ok(Some(listener));
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let listener = create_unix_socket(path)?;
+
+    // this binary usually starts as root to be able to adjust the system clock.
+    // by default, the socket inherits root permissions, but the client should not need
+    // elevated permissions to read from the socket. So we explicitly set the permissions
+    let permissions: std::fs::Permissions = PermissionsExt::from_mode(mode);
+    std::fs::set_permissions(path, permissions)?;
+    set_ownership(path, owner, group)?;
+
+    Ok(Some(listener))
+}
+
+/// Change the owner and group of the file at `path`, as far as they are given
+fn set_ownership(path: &Path, owner: Option<&str>, group: Option<&str>) -> std::io::Result<()> {
+    use crate::privileges::{lookup_group, lookup_user};
+    use std::io::{Error, ErrorKind};
+
+    let lookup_error = |e| Error::new(ErrorKind::InvalidInput, format!("{}", e));
+    let uid = match owner {
+        Some(owner) => Some(lookup_user(owner).map_err(lookup_error)?.0),
+        None => None,
+    };
+    let gid = match group {
+        Some(group) => Some(lookup_group(group).map_err(lookup_error)?),
+        None => None,
+    };
+
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Safety: c_path is a valid nul-terminated string, -1 leaves the owner or group as is
+    let result = unsafe {
+        libc::chown(
+            c_path.as_ptr(),
+            uid.unwrap_or(libc::uid_t::MAX),
+            gid.unwrap_or(libc::gid_t::MAX),
+        )
+    };
+    if result < 0 {
+        let error = Error::last_os_error();
+        let msg = format!("Could not change ownership of {:?}: {}", path, error);
+        return Err(Error::new(error.kind(), msg));
+    }
+
+    Ok(())
+}
+
+/// Take over a listener created by [`listen_unix_socket`] in the tokio runtime
+pub fn into_tokio_listener(listener: UnixListener) -> std::io::Result<tokio::net::UnixListener> {
+    listener.set_nonblocking(true)?;
+    tokio::net::UnixListener::from_std(listener)
+}
+
 #[cfg(test)]
 mod tests {
     use tokio::net::UnixListener;
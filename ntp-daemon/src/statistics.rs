@@ -0,0 +1,441 @@
+//! Statistics files in the style of ntpd, for long-term analysis of the
+//! synchronization. Every kind of record goes to its own file, with the date
+//! appended to its name, so a new file is started every day (UTC):
+//!
+//! - `peerstats.YYYYMMDD`: every measurement of a peer
+//! - `clockstats.YYYYMMDD`: every measurement of a reference clock
+//! - `loopstats.YYYYMMDD`: every update of the clock
+//!
+//! Every line starts with the modified Julian day and the seconds since
+//! midnight, followed by columns separated by a single space. Durations are
+//! in seconds, frequencies in parts per million.
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use ntp_proto::{MeasurementRecord, NtpDuration, PollInterval};
+use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc};
+use tracing::{debug, warn};
+
+use crate::config::StatisticsConfig;
+
+/// Records waiting to be written, beyond which new ones are dropped
+const CHANNEL_SIZE: usize = 64;
+
+/// Modified Julian day of the unix epoch
+const MJD_UNIX_EPOCH: u64 = 40587;
+
+/// Weight of a new frequency change in the estimated wander, like ntpd
+const WANDER_AVG: f64 = 8.;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum StatisticsRecord {
+    /// A measurement of a peer or reference clock
+    Measurement {
+        time: SystemTime,
+        reference_clock: bool,
+        /// Address of the peer, or the driver of the reference clock
+        address: String,
+        measurement: MeasurementRecord,
+        /// Jitter of the peer after this measurement
+        jitter: NtpDuration,
+    },
+    /// An update of the clock
+    Loop {
+        time: SystemTime,
+        offset: NtpDuration,
+        /// In seconds per second
+        frequency: f64,
+        jitter: NtpDuration,
+        poll_interval: PollInterval,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Peer,
+    Clock,
+    Loop,
+}
+
+impl Kind {
+    const ALL: [Kind; 3] = [Kind::Peer, Kind::Clock, Kind::Loop];
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Peer => "peerstats",
+            Kind::Clock => "clockstats",
+            Kind::Loop => "loopstats",
+        }
+    }
+}
+
+/// Start writing statistics when a directory is configured. Records sent to
+/// the returned channel are written in the background.
+pub(crate) fn spawn(config: &StatisticsConfig) -> Option<mpsc::Sender<StatisticsRecord>> {
+    let directory = config.directory.clone()?;
+    let (sender, mut receiver) = mpsc::channel(CHANNEL_SIZE);
+
+    let mut writer = StatisticsWriter::new(directory, config.retention_days);
+    tokio::spawn(async move {
+        while let Some(record) = receiver.recv().await {
+            writer.handle(record).await;
+        }
+    });
+
+    Some(sender)
+}
+
+struct StatisticsWriter {
+    directory: PathBuf,
+    retention_days: Option<u32>,
+    /// The open file of every kind, with the day it is for
+    files: Vec<(Kind, u64, File)>,
+    /// Frequency at the previous clock update, and the wander estimated so far
+    frequency: Option<(f64, f64)>,
+    /// Whether the last write failed, to warn only once
+    failing: bool,
+}
+
+impl StatisticsWriter {
+    fn new(directory: PathBuf, retention_days: Option<u32>) -> Self {
+        StatisticsWriter {
+            directory,
+            retention_days,
+            files: vec![],
+            frequency: None,
+            failing: false,
+        }
+    }
+
+    async fn handle(&mut self, record: StatisticsRecord) {
+        match self.write(record).await {
+            Ok(()) => self.failing = false,
+            Err(e) if self.failing => debug!("could not write statistics: {}", e),
+            Err(e) => {
+                warn!(directory = %self.directory.display(), "could not write statistics: {}", e);
+                self.failing = true;
+            }
+        }
+    }
+
+    async fn write(&mut self, record: StatisticsRecord) -> std::io::Result<()> {
+        let (kind, time, line) = match record {
+            StatisticsRecord::Measurement {
+                time,
+                reference_clock,
+                address,
+                measurement,
+                jitter,
+            } => {
+                let kind = if reference_clock {
+                    Kind::Clock
+                } else {
+                    Kind::Peer
+                };
+                (kind, time, measurement_line(&address, &measurement, jitter))
+            }
+            StatisticsRecord::Loop {
+                time,
+                offset,
+                frequency,
+                jitter,
+                poll_interval,
+            } => {
+                let wander = self.wander(frequency);
+                let line = loop_line(offset, frequency, jitter, wander, poll_interval);
+                (Kind::Loop, time, line)
+            }
+        };
+
+        let (day, seconds) = day_and_seconds(time);
+        let file = self.file(kind, day).await?;
+        file.write_all(format!("{} {:.3} {}\n", day, seconds, line).as_bytes())
+            .await?;
+        // tokio writes in the background, make sure the line is written before the next
+        file.flush().await
+    }
+
+    /// Update the estimated wander, the root mean square of the frequency changes
+    fn wander(&mut self, frequency: f64) -> f64 {
+        let wander = match self.frequency {
+            Some((previous, wander)) => {
+                let change = (frequency - previous) * 1e6;
+                let squared = wander * wander;
+                (squared + (change * change - squared) / WANDER_AVG).sqrt()
+            }
+            None => 0.,
+        };
+        self.frequency = Some((frequency, wander));
+        wander
+    }
+
+    /// The file for `kind` on `day`, starting a new one when the day changed
+    async fn file(&mut self, kind: Kind, day: u64) -> std::io::Result<&mut File> {
+        let position = self
+            .files
+            .iter()
+            .position(|(k, d, _)| *k == kind && *d == day);
+        let position = match position {
+            Some(position) => position,
+            None => {
+                self.files.retain(|(k, _, _)| *k != kind);
+                tokio::fs::create_dir_all(&self.directory).await?;
+                let file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.directory.join(file_name(kind, day)))
+                    .await?;
+                self.files.push((kind, day, file));
+
+                if let Some(retention_days) = self.retention_days {
+                    let oldest = day.saturating_sub(retention_days.into());
+                    if let Err(e) = remove_old_files(&self.directory, oldest).await {
+                        warn!("could not remove old statistics files: {}", e);
+                    }
+                }
+
+                self.files.len() - 1
+            }
+        };
+
+        Ok(&mut self.files[position].2)
+    }
+}
+
+/// Remove the statistics files of the days before `oldest`
+async fn remove_old_files(directory: &Path, oldest: u64) -> std::io::Result<()> {
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        if Kind::ALL
+            .iter()
+            .any(|kind| is_file_before(name, *kind, oldest))
+        {
+            debug!(name, "removing old statistics file");
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `name` is the file of `kind` for a day before `oldest`
+fn is_file_before(name: &str, kind: Kind, oldest: u64) -> bool {
+    let date = match name
+        .strip_prefix(kind.name())
+        .and_then(|rest| rest.strip_prefix('.'))
+    {
+        Some(date) if date.len() == 8 && date.bytes().all(|b| b.is_ascii_digit()) => date,
+        _ => return false,
+    };
+
+    // dates in this format sort like the days they are on
+    date < date_string(oldest).as_str()
+}
+
+fn file_name(kind: Kind, day: u64) -> String {
+    format!("{}.{}", kind.name(), date_string(day))
+}
+
+/// The columns after the time of a peerstats or clockstats line
+fn measurement_line(address: &str, measurement: &MeasurementRecord, jitter: NtpDuration) -> String {
+    format!(
+        "{} {} {:.9} {:.9} {:.9} {:.9}",
+        address,
+        u8::from(measurement.accepted),
+        measurement.offset.to_seconds(),
+        measurement.delay.to_seconds(),
+        measurement.dispersion.to_seconds(),
+        jitter.to_seconds(),
+    )
+}
+
+/// The columns after the time of a loopstats line
+fn loop_line(
+    offset: NtpDuration,
+    frequency: f64,
+    jitter: NtpDuration,
+    wander: f64,
+    poll_interval: PollInterval,
+) -> String {
+    format!(
+        "{:.9} {:.3} {:.9} {:.6} {}",
+        offset.to_seconds(),
+        frequency * 1e6,
+        jitter.to_seconds(),
+        wander,
+        poll_interval.as_log(),
+    )
+}
+
+/// Modified Julian day and seconds since midnight of `time`
+fn day_and_seconds(time: SystemTime) -> (u64, f64) {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+
+    (
+        MJD_UNIX_EPOCH + seconds / 86400,
+        (seconds % 86400) as f64 + f64::from(since_epoch.subsec_millis()) / 1000.,
+    )
+}
+
+/// The date of a modified Julian day as YYYYMMDD
+fn date_string(day: u64) -> String {
+    // Conversion from days to the civil calendar, after
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = day as i64 - MJD_UNIX_EPOCH as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}{:02}{:02}", year, month, day_of_month)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ntp_proto::{NtpTimestamp, PollIntervalLimits};
+
+    use super::*;
+
+    /// 2023-02-25 12:00:01.5 UTC, modified Julian day 60000
+    fn time() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(1677326401500)
+    }
+
+    fn measurement(accepted: bool) -> MeasurementRecord {
+        MeasurementRecord {
+            offset: NtpDuration::from_seconds(0.5),
+            delay: NtpDuration::from_seconds(0.25),
+            dispersion: NtpDuration::from_seconds(0.125),
+            timestamp: NtpTimestamp::default(),
+            accepted,
+        }
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(day_and_seconds(time()), (60000, 43201.5));
+        assert_eq!(
+            day_and_seconds(SystemTime::UNIX_EPOCH),
+            (MJD_UNIX_EPOCH, 0.)
+        );
+
+        assert_eq!(date_string(MJD_UNIX_EPOCH), "19700101");
+        assert_eq!(date_string(60000), "20230225");
+        assert_eq!(date_string(60004), "20230301");
+        assert_eq!(date_string(51603), "20000229");
+    }
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(
+            measurement_line("127.0.0.1", &measurement(true), NtpDuration::ZERO),
+            "127.0.0.1 1 0.500000000 0.250000000 0.125000000 0.000000000"
+        );
+        assert_eq!(
+            loop_line(
+                NtpDuration::from_seconds(0.5),
+                12.5e-6,
+                NtpDuration::from_seconds(0.25),
+                0.5,
+                PollIntervalLimits::default().min
+            ),
+            "0.500000000 12.500 0.250000000 0.500000 4"
+        );
+    }
+
+    #[test]
+    fn test_is_file_before() {
+        assert!(is_file_before("peerstats.20230224", Kind::Peer, 60000));
+        assert!(!is_file_before("peerstats.20230225", Kind::Peer, 60000));
+        assert!(!is_file_before("peerstats.20230224", Kind::Loop, 60000));
+        assert!(!is_file_before("peerstats.2023022", Kind::Peer, 60000));
+        assert!(!is_file_before("peerstats.20230224.gz", Kind::Peer, 60000));
+        assert!(!is_file_before("ntp.conf", Kind::Peer, 60000));
+    }
+
+    #[test]
+    fn test_wander() {
+        let mut writer = StatisticsWriter::new(PathBuf::new(), None);
+        assert_eq!(writer.wander(1e-6), 0.);
+        assert_eq!(writer.wander(1e-6), 0.);
+        // a change of 1 ppm is weighed in for an eighth
+        assert!((writer.wander(2e-6) - 0.125f64.sqrt()).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_write_and_rotate() {
+        let directory = std::env::temp_dir().join("ntp-test-statistics");
+        if directory.exists() {
+            std::fs::remove_dir_all(&directory).unwrap();
+        }
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("peerstats.20230220"), "old\n").unwrap();
+        std::fs::write(directory.join("loopstats.20230223"), "recent\n").unwrap();
+        std::fs::write(directory.join("other.20230220"), "unrelated\n").unwrap();
+
+        let mut writer = StatisticsWriter::new(directory.clone(), Some(2));
+        let peer = |time| StatisticsRecord::Measurement {
+            time,
+            reference_clock: false,
+            address: "127.0.0.1".into(),
+            measurement: measurement(false),
+            jitter: NtpDuration::ZERO,
+        };
+        writer.handle(peer(time())).await;
+        writer.handle(peer(time())).await;
+        writer
+            .handle(StatisticsRecord::Measurement {
+                time: time(),
+                reference_clock: true,
+                address: "nmea".into(),
+                measurement: measurement(true),
+                jitter: NtpDuration::ZERO,
+            })
+            .await;
+        assert!(!writer.failing);
+
+        let peerstats = std::fs::read_to_string(directory.join("peerstats.20230225")).unwrap();
+        assert_eq!(peerstats.lines().count(), 2);
+        assert!(peerstats.starts_with("60000 43201.500 127.0.0.1 0 0.500000000 "));
+        let clockstats = std::fs::read_to_string(directory.join("clockstats.20230225")).unwrap();
+        assert!(clockstats.starts_with("60000 43201.500 nmea 1 "));
+
+        // files of more than two days ago are removed, others are kept
+        assert!(!directory.join("peerstats.20230220").exists());
+        assert!(directory.join("loopstats.20230223").exists());
+        assert!(directory.join("other.20230220").exists());
+
+        // the next day goes to a new file
+        writer
+            .handle(peer(time() + Duration::from_secs(86400)))
+            .await;
+        let peerstats = std::fs::read_to_string(directory.join("peerstats.20230226")).unwrap();
+        assert!(peerstats.starts_with("60001 43201.500 "));
+        assert!(directory.join("peerstats.20230225").exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
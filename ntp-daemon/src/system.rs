@@ -1,9 +1,16 @@
 use crate::{
     config::NormalizedAddress,
-    config::{PeerConfig, PoolPeerConfig, ServerConfig, StandardPeerConfig},
+    config::{
+        PeerConfig, PoolPeerConfig, RefClockConfig, ServerConfig, StandardPeerConfig,
+        StatisticsConfig, SystemdConfig,
+    },
+    observer::PeerHistory,
     peer::PeerTask,
     peer::{MsgForSystem, PeerChannels},
+    refclock::{RefClockDevices, RefClockTask},
     server::{ServerStats, ServerTask},
+    statistics::{self, StatisticsRecord},
+    systemd::{self, ListenFds, Watchdog},
     ObservablePeerState,
 };
 
@@ -11,11 +18,14 @@ use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use ntp_os_clock::UnixNtpClock;
 use ntp_proto::{
-    DefaultTimeSyncController, NtpClock, PeerSnapshot, SystemConfig, SystemSnapshot,
-    TimeSyncController,
+    DefaultTimeSyncController, NtpClock, NtpDuration, NtpTimestamp, PeerOptions, PeerSnapshot,
+    SystemConfig, SystemSnapshot, TimeSyncController,
 };
 use tokio::{
-    sync::mpsc::{self, Sender},
+    sync::{
+        mpsc::{self, Sender},
+        oneshot,
+    },
     task::JoinHandle,
 };
 use tracing::warn;
@@ -25,35 +35,75 @@ const NETWORK_WAIT_PERIOD: std::time::Duration = std::time::Duration::from_secs(
 pub struct DaemonChannels {
     pub config_receiver: tokio::sync::watch::Receiver<SystemConfig>,
     pub config_sender: tokio::sync::watch::Sender<SystemConfig>,
+    /// Commands for the main loop, each with where to send the reply
+    pub command_sender: Sender<(SystemCommand, oneshot::Sender<CommandReply>)>,
     pub peer_snapshots_receiver: tokio::sync::watch::Receiver<Vec<ObservablePeerState>>,
+    pub peer_history_receiver: tokio::sync::watch::Receiver<Vec<PeerHistory>>,
     pub server_data_receiver: tokio::sync::watch::Receiver<Vec<ServerData>>,
     pub system_snapshot_receiver: tokio::sync::watch::Receiver<SystemSnapshot>,
 }
 
+/// Changes to the running daemon, handled by its main loop
+#[derive(Debug)]
+pub enum SystemCommand {
+    /// Bring the peers, reference clocks and servers in line with a reloaded
+    /// configuration. Those that did not change keep running undisturbed.
+    Reload {
+        peers: Vec<PeerConfig>,
+        servers: Vec<ServerConfig>,
+        refclocks: Vec<RefClockConfig>,
+    },
+    /// Start additional peers and pools
+    AddPeers(Vec<PeerConfig>),
+    /// Stop all peers and pools with one of these addresses
+    RemovePeers(Vec<NormalizedAddress>),
+    /// Change nothing, only reply with the configured peers and pools
+    ListPeers,
+}
+
+/// Outcome of a `SystemCommand`
+#[derive(Debug)]
+pub struct CommandReply {
+    /// Addresses to remove that did not match any peer or pool
+    pub not_found: Vec<NormalizedAddress>,
+    /// The peers and pools configured once the command was handled
+    pub peers: Vec<PeerConfig>,
+}
+
 /// Spawn the NTP daemon
+#[allow(clippy::too_many_arguments)]
 pub async fn spawn(
     config: SystemConfig,
     peer_configs: &[PeerConfig],
     server_configs: &[ServerConfig],
+    refclock_configs: &[RefClockConfig],
+    systemd_config: &SystemdConfig,
+    statistics_config: &StatisticsConfig,
+    listen_fds: &mut ListenFds,
+    refclock_devices: RefClockDevices,
 ) -> std::io::Result<(JoinHandle<std::io::Result<()>>, DaemonChannels)> {
     let clock = UnixNtpClock::new();
     let (mut system, channels) = System::new(clock, config);
+    system.refclock_devices = refclock_devices;
+    system.notify_ready = systemd_config.ready_after_sync;
+    system.statistics_sender = statistics::spawn(statistics_config);
 
     for peer_config in peer_configs {
-        match peer_config {
-            PeerConfig::Standard(StandardPeerConfig { addr }) => {
-                system.add_peer(addr.clone()).await;
-            }
-            PeerConfig::Pool(PoolPeerConfig {
-                addr, max_peers, ..
-            }) => {
-                system.add_new_pool(addr.clone(), *max_peers).await;
-            }
-        }
+        system.add_peer_config(peer_config.clone()).await;
+    }
+
+    for refclock_config in refclock_configs {
+        system.add_refclock(refclock_config.clone());
     }
 
     for server_config in server_configs.iter() {
-        system.add_server(server_config.to_owned()).await;
+        if let Some(name) = &server_config.fd_name {
+            if let Some(socket) = listen_fds.take_udp(name) {
+                system.inherited_sockets.insert(name.clone(), socket);
+            }
+        }
+        let socket = system.inherited_socket(server_config);
+        system.add_server(server_config.to_owned(), socket).await;
     }
 
     let handle = tokio::spawn(async move { system.run().await });
@@ -68,21 +118,44 @@ struct System<C: NtpClock> {
     config_receiver: tokio::sync::watch::Receiver<SystemConfig>,
     system_snapshot_sender: tokio::sync::watch::Sender<SystemSnapshot>,
     peer_snapshots_sender: tokio::sync::watch::Sender<Vec<ObservablePeerState>>,
+    peer_history_sender: tokio::sync::watch::Sender<Vec<PeerHistory>>,
     server_data_sender: tokio::sync::watch::Sender<Vec<ServerData>>,
 
     msg_for_system_rx: mpsc::Receiver<MsgForSystem>,
     spawn_task_rx: mpsc::Receiver<SpawnTask>,
+    command_rx: mpsc::Receiver<(SystemCommand, oneshot::Sender<CommandReply>)>,
 
     peers: HashMap<PeerIndex, PeerState>,
     servers: Vec<ServerData>,
+    /// Tasks of the servers, in the same order as `servers`
+    server_tasks: Vec<JoinHandle<()>>,
+    /// Server sockets passed on by the service manager, by name, kept so
+    /// that servers started again on reload can use them
+    inherited_sockets: HashMap<String, std::net::UdpSocket>,
     spawner: Spawner,
     peer_indexer: PeerIndexIssuer,
     pool_indexer: PoolIndexIssuer,
 
+    // The configured sources, compared against on reload
+    peer_configs: Vec<StandardPeerConfig>,
+    pool_configs: Vec<(PoolIndex, PoolPeerConfig)>,
+    refclock_configs: Vec<RefClockConfig>,
+    /// Peers and pools added through the configure socket, which are kept
+    /// when the configuration file is reloaded
+    added_peers: Vec<PeerConfig>,
+    /// Reference clock devices opened before privileges were dropped
+    refclock_devices: RefClockDevices,
+
     peer_channels: PeerChannels,
 
     clock: C,
     controller: DefaultTimeSyncController<C, PeerIndex>,
+
+    /// Readiness still has to be reported to the service manager at the
+    /// first clock update
+    notify_ready: bool,
+    /// Where to send records for the statistics files, if they are written
+    statistics_sender: Option<mpsc::Sender<StatisticsRecord>>,
 }
 
 impl<C: NtpClock> System<C> {
@@ -100,11 +173,14 @@ impl<C: NtpClock> System<C> {
         let (system_snapshot_sender, system_snapshot_receiver) =
             tokio::sync::watch::channel(system);
         let (peer_snapshots_sender, peer_snapshots_receiver) = tokio::sync::watch::channel(vec![]);
+        let (peer_history_sender, peer_history_receiver) = tokio::sync::watch::channel(vec![]);
         let (server_data_sender, server_data_receiver) = tokio::sync::watch::channel(vec![]);
         let (spawn_task_sender, spawn_task_receiver) =
             tokio::sync::mpsc::channel(Self::MESSAGE_BUFFER_SIZE);
         let (msg_for_system_sender, msg_for_system_receiver) =
             tokio::sync::mpsc::channel(Self::MESSAGE_BUFFER_SIZE);
+        let (command_sender, command_receiver) =
+            tokio::sync::mpsc::channel(Self::MESSAGE_BUFFER_SIZE);
 
         // Build System and its channels
         (
@@ -115,19 +191,28 @@ impl<C: NtpClock> System<C> {
                 config_receiver: config_receiver.clone(),
                 system_snapshot_sender,
                 peer_snapshots_sender,
+                peer_history_sender,
                 server_data_sender,
 
                 msg_for_system_rx: msg_for_system_receiver,
                 spawn_task_rx: spawn_task_receiver,
+                command_rx: command_receiver,
 
                 peers: Default::default(),
                 servers: Default::default(),
+                server_tasks: Default::default(),
+                inherited_sockets: Default::default(),
                 spawner: Spawner {
                     pools: Default::default(),
                     sender: spawn_task_sender,
                 },
                 peer_indexer: Default::default(),
                 pool_indexer: Default::default(),
+                peer_configs: Default::default(),
+                pool_configs: Default::default(),
+                refclock_configs: Default::default(),
+                added_peers: Default::default(),
+                refclock_devices: Default::default(),
                 peer_channels: PeerChannels {
                     msg_for_system_sender,
                     system_snapshot_receiver: system_snapshot_receiver.clone(),
@@ -135,11 +220,15 @@ impl<C: NtpClock> System<C> {
                 },
                 clock: clock.clone(),
                 controller: DefaultTimeSyncController::new(clock, config),
+                notify_ready: false,
+                statistics_sender: None,
             },
             DaemonChannels {
                 config_receiver,
                 config_sender,
+                command_sender,
                 peer_snapshots_receiver,
+                peer_history_receiver,
                 server_data_receiver,
                 system_snapshot_receiver,
             },
@@ -149,6 +238,8 @@ impl<C: NtpClock> System<C> {
     async fn run(&mut self) -> std::io::Result<()> {
         //let mut snapshots = Vec::with_capacity(self.peers_rwlock.read().await.size());
 
+        let mut watchdog = Watchdog::from_env();
+
         loop {
             tokio::select! {
                 opt_msg_for_system = self.msg_for_system_rx.recv() => {
@@ -177,6 +268,14 @@ impl<C: NtpClock> System<C> {
                 _ = self.config_receiver.changed(), if self.config_receiver.has_changed().is_ok() => {
                     self.handle_config_update();
                 }
+                Some((command, reply)) = self.command_rx.recv() => {
+                    let result = self.handle_command(command).await;
+                    // Don't care if the requester is no longer waiting
+                    let _ = reply.send(result);
+                }
+                () = watchdog.tick() => {
+                    watchdog.notify();
+                }
             }
         }
 
@@ -190,9 +289,256 @@ impl<C: NtpClock> System<C> {
         self.config = config;
     }
 
+    async fn handle_command(&mut self, command: SystemCommand) -> CommandReply {
+        let mut not_found = vec![];
+
+        match command {
+            SystemCommand::Reload {
+                mut peers,
+                servers,
+                refclocks,
+            } => {
+                peers.extend(self.added_peers.iter().cloned());
+                self.reload_peers(peers).await;
+                self.reload_refclocks(refclocks);
+                self.reload_servers(servers).await;
+            }
+            SystemCommand::AddPeers(configs) => {
+                for config in configs {
+                    match &config {
+                        PeerConfig::Standard(config) => {
+                            tracing::info!(address = config.addr.as_str(), "adding peer")
+                        }
+                        PeerConfig::Pool(config) => {
+                            tracing::info!(address = config.addr.as_str(), "adding pool")
+                        }
+                    }
+                    self.added_peers.push(config.clone());
+                    self.add_peer_config(config).await;
+                }
+            }
+            SystemCommand::RemovePeers(addresses) => {
+                for address in addresses {
+                    self.added_peers.retain(|config| match config {
+                        PeerConfig::Standard(config) => config.addr != address,
+                        PeerConfig::Pool(config) => config.addr != address,
+                    });
+                    if !self.remove_peers(&address) {
+                        not_found.push(address);
+                    }
+                }
+            }
+            SystemCommand::ListPeers => {}
+        }
+
+        // Don't care if there is no receiver
+        let _ = self
+            .peer_snapshots_sender
+            .send(self.observe_peers().collect());
+        let _ = self.peer_history_sender.send(self.peer_history().collect());
+
+        CommandReply {
+            not_found,
+            peers: self.configured_peers(),
+        }
+    }
+
+    fn configured_peers(&self) -> Vec<PeerConfig> {
+        let peers = self.peer_configs.iter().cloned().map(PeerConfig::Standard);
+        let pools = self
+            .pool_configs
+            .iter()
+            .map(|(_, config)| PeerConfig::Pool(config.clone()));
+
+        peers.chain(pools).collect()
+    }
+
+    async fn reload_peers(&mut self, peer_configs: Vec<PeerConfig>) {
+        let mut new_peers = vec![];
+        let mut new_pools = vec![];
+        for config in peer_configs {
+            match config {
+                PeerConfig::Standard(config) => new_peers.push(config),
+                PeerConfig::Pool(config) => new_pools.push(config),
+            }
+        }
+
+        for config in std::mem::take(&mut self.peer_configs) {
+            if take_matching(&mut new_peers, &config).is_some() {
+                self.peer_configs.push(config);
+                continue;
+            }
+
+            tracing::info!(address = config.addr.as_str(), "removing peer");
+            self.stop_standard_peer(&config);
+        }
+
+        for (pool_index, config) in std::mem::take(&mut self.pool_configs) {
+            if take_matching(&mut new_pools, &config).is_some() {
+                self.pool_configs.push((pool_index, config));
+                continue;
+            }
+
+            tracing::info!(address = config.addr.as_str(), "removing pool");
+            self.stop_pool(pool_index);
+        }
+
+        for config in new_peers {
+            tracing::info!(address = config.addr.as_str(), "adding peer");
+            self.add_peer(config.addr, config.options).await;
+        }
+
+        for config in new_pools {
+            tracing::info!(address = config.addr.as_str(), "adding pool");
+            self.add_new_pool(config.addr, config.max_peers, config.options)
+                .await;
+        }
+    }
+
+    /// Remove the peers and pools with the given address from the configuration,
+    /// returns whether there were any
+    fn remove_peers(&mut self, address: &NormalizedAddress) -> bool {
+        let (removed_peers, peer_configs): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.peer_configs)
+                .into_iter()
+                .partition(|config| config.addr == *address);
+        self.peer_configs = peer_configs;
+
+        let (removed_pools, pool_configs): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.pool_configs)
+                .into_iter()
+                .partition(|(_, config)| config.addr == *address);
+        self.pool_configs = pool_configs;
+
+        if removed_peers.is_empty() && removed_pools.is_empty() {
+            warn!(address = address.as_str(), "no peer or pool to remove");
+            return false;
+        }
+
+        for config in removed_peers {
+            tracing::info!(address = config.addr.as_str(), "removing peer");
+            self.stop_standard_peer(&config);
+        }
+
+        for (pool_index, config) in removed_pools {
+            tracing::info!(address = config.addr.as_str(), "removing pool");
+            self.stop_pool(pool_index);
+        }
+
+        true
+    }
+
+    /// Stop a running peer after its configuration was removed. A peer that
+    /// is still being resolved is dropped once it is, see `handle_spawn`.
+    fn stop_standard_peer(&mut self, config: &StandardPeerConfig) {
+        let running = self
+            .peers
+            .iter()
+            .find_map(|(index, state)| match &state.peer_address {
+                PeerAddress::Peer { address, options }
+                    if *address == config.addr && *options == config.options =>
+                {
+                    Some(*index)
+                }
+                _ => None,
+            });
+        if let Some(index) = running {
+            self.stop_peer(index);
+        }
+    }
+
+    /// Stop the peers of a pool after its configuration was removed
+    fn stop_pool(&mut self, pool_index: PoolIndex) {
+        self.spawner.pools.remove(&pool_index);
+        let running: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| {
+                matches!(state.peer_address, PeerAddress::Pool { index, .. } if index == pool_index)
+            })
+            .map(|(index, _)| *index)
+            .collect();
+        for index in running {
+            self.stop_peer(index);
+        }
+    }
+
+    fn reload_refclocks(&mut self, mut new_refclocks: Vec<RefClockConfig>) {
+        for config in std::mem::take(&mut self.refclock_configs) {
+            if take_matching(&mut new_refclocks, &config).is_some() {
+                self.refclock_configs.push(config);
+                continue;
+            }
+
+            tracing::info!(driver = %config.driver, "removing reference clock");
+            let running = self
+                .peers
+                .iter()
+                .find_map(|(index, state)| match &state.peer_address {
+                    PeerAddress::RefClock { config: running } if *running == config => Some(*index),
+                    _ => None,
+                });
+            if let Some(index) = running {
+                self.stop_peer(index);
+            }
+        }
+
+        for config in new_refclocks {
+            tracing::info!(driver = %config.driver, "adding reference clock");
+            self.add_refclock(config);
+        }
+    }
+
+    async fn reload_servers(&mut self, mut new_servers: Vec<ServerConfig>) {
+        let servers = std::mem::take(&mut self.servers);
+        let tasks = std::mem::take(&mut self.server_tasks);
+
+        for (data, task) in servers.into_iter().zip(tasks) {
+            if take_matching(&mut new_servers, &data.config).is_some() {
+                self.servers.push(data);
+                self.server_tasks.push(task);
+                continue;
+            }
+
+            // Wait for the socket to be closed, as the server may be started again on the same address
+            tracing::info!(addr = %data.config.addr, "stopping server");
+            task.abort();
+            let _ = task.await;
+        }
+
+        for config in new_servers {
+            tracing::info!(addr = %config.addr, "starting server");
+            let socket = self.inherited_socket(&config);
+            self.add_server(config, socket).await;
+        }
+
+        let _ = self.server_data_sender.send(self.servers.clone());
+    }
+
+    /// Stop a running peer or reference clock
+    fn stop_peer(&mut self, index: PeerIndex) {
+        if let Some(state) = self.peers.remove(&index) {
+            if let Some(task) = state.task {
+                task.abort();
+            }
+        }
+        self.controller.peer_remove(index);
+    }
+
     async fn handle_peer_update(&mut self, msg: MsgForSystem) {
         tracing::debug!(?msg, "updating peer");
 
+        let index = match &msg {
+            MsgForSystem::MustDemobilize(index)
+            | MsgForSystem::NetworkIssue(index)
+            | MsgForSystem::NewMeasurement(index, ..)
+            | MsgForSystem::UpdatedSnapshot(index, _) => *index,
+        };
+        if !self.peers.contains_key(&index) {
+            // The peer was stopped while this message was underway
+            return;
+        }
+
         match msg {
             MsgForSystem::MustDemobilize(index) => {
                 self.handle_peer_demobilize(index);
@@ -213,22 +559,27 @@ impl<C: NtpClock> System<C> {
         let _ = self
             .peer_snapshots_sender
             .send(self.observe_peers().collect());
+        let _ = self.peer_history_sender.send(self.peer_history().collect());
     }
 
     async fn handle_peer_network_issue(&mut self, index: PeerIndex) {
         // Restart the peer reusing its configuration.
         let config = self.peers.remove(&index).unwrap().peer_address;
         match config {
-            PeerAddress::Peer { address } => {
-                self.add_peer_internal(address).await;
+            PeerAddress::Peer { address, options } => {
+                self.add_peer_internal(address, options).await;
             }
             PeerAddress::Pool {
                 index,
                 address,
                 max_peers,
+                options,
                 ..
             } => {
-                self.add_to_pool(index, address, max_peers).await;
+                self.add_to_pool(index, address, max_peers, options).await;
+            }
+            PeerAddress::RefClock { config } => {
+                self.spawn_refclock(config);
             }
         }
     }
@@ -251,7 +602,19 @@ impl<C: NtpClock> System<C> {
         packet: ntp_proto::NtpPacket<'static>,
     ) {
         self.handle_peer_snapshot(index, snapshot);
+        let localtime = measurement.localtime;
         let result = self.controller.peer_measurement(index, measurement, packet);
+        self.record_measurement(index, localtime);
+
+        // The clock state can change without the controller producing new timedata,
+        // e.g. when it starts ignoring a spike
+        let clock_state = self.controller.clock_state();
+        let clock_state_changed = clock_state != self.system.clock_state;
+        self.system.clock_state = clock_state;
+        if result.is_none() && clock_state_changed {
+            let _ = self.system_snapshot_sender.send(self.system);
+        }
+
         if let Some((used_peers, timedata)) = result {
             self.system.update(
                 used_peers.iter().map(|v| {
@@ -264,6 +627,76 @@ impl<C: NtpClock> System<C> {
             );
             // Don't care if there is no receiver.
             let _ = self.system_snapshot_sender.send(self.system);
+
+            self.notify_clock_update(used_peers.first().copied(), timedata.offset);
+            self.record_statistics(StatisticsRecord::Loop {
+                time: std::time::SystemTime::now(),
+                offset: timedata.offset,
+                frequency: timedata.frequency,
+                jitter: timedata.jitter,
+                poll_interval: timedata.poll_interval,
+            });
+        }
+    }
+
+    /// Write the measurement taken at `localtime` to the statistics files
+    fn record_measurement(&mut self, index: PeerIndex, localtime: NtpTimestamp) {
+        if self.statistics_sender.is_none() {
+            return;
+        }
+
+        // Measurements right after a clock step are not used, and not kept either
+        let measurement = match self.controller.peer_history(index).last() {
+            Some(measurement) if measurement.timestamp == localtime => *measurement,
+            _ => return,
+        };
+        let data = &self.peers[&index];
+        let (reference_clock, address) = match (&data.peer_address, data.addr) {
+            (PeerAddress::RefClock { config }, _) => (true, config.driver.to_string()),
+            (_, Some(addr)) => (false, addr.ip().to_string()),
+            (peer_address, None) => (false, peer_address.to_string()),
+        };
+        let jitter = self
+            .controller
+            .peer_snapshot(index)
+            .map(|timedata| timedata.jitter)
+            .unwrap_or_default();
+
+        self.record_statistics(StatisticsRecord::Measurement {
+            time: std::time::SystemTime::now(),
+            reference_clock,
+            address,
+            measurement,
+            jitter,
+        });
+    }
+
+    fn record_statistics(&self, record: StatisticsRecord) {
+        if let Some(sender) = &self.statistics_sender {
+            // Rather lose a record than hold up the daemon when writing falls behind
+            if sender.try_send(record).is_err() {
+                tracing::debug!("dropped statistics record");
+            }
+        }
+    }
+
+    /// Report the synchronization state to the service manager
+    fn notify_clock_update(&mut self, system_peer: Option<PeerIndex>, offset: NtpDuration) {
+        let address = system_peer
+            .and_then(|index| self.peers.get(&index))
+            .map(|data| data.peer_address.to_string())
+            .unwrap_or_default();
+        let status = format!(
+            "STATUS=Synchronized to {}, offset {:+.3} ms",
+            address,
+            offset.to_seconds() * 1e3,
+        );
+
+        if self.notify_ready {
+            self.notify_ready = false;
+            systemd::notify(&format!("READY=1\n{}", status));
+        } else {
+            systemd::notify(&status);
         }
     }
 
@@ -273,23 +706,32 @@ impl<C: NtpClock> System<C> {
     }
 
     fn handle_spawn(&mut self, peer_address: PeerAddress, addr: SocketAddr) {
+        if !self.is_configured(&peer_address) {
+            tracing::debug!(%peer_address, "not spawning peer removed from configuration");
+            return;
+        }
+
         let index = self.peer_indexer.get();
+        let options = peer_address.options();
 
+        let task = PeerTask::spawn(
+            index,
+            addr,
+            options,
+            self.clock.clone(),
+            NETWORK_WAIT_PERIOD,
+            self.peer_channels.clone(),
+        );
         self.peers.insert(
             index,
             PeerState {
                 snapshot: None,
                 peer_address,
+                addr: Some(addr),
+                task: Some(task),
             },
         );
-        self.controller.peer_add(index);
-        PeerTask::spawn(
-            index,
-            addr,
-            self.clock.clone(),
-            NETWORK_WAIT_PERIOD,
-            self.peer_channels.clone(),
-        );
+        self.controller.peer_add(index, options);
 
         // Don't care if there is no receiver
         let _ = self
@@ -297,6 +739,31 @@ impl<C: NtpClock> System<C> {
             .send(self.observe_peers().collect());
     }
 
+    /// Whether a peer that has been resolved is still part of the configuration
+    fn is_configured(&self, peer_address: &PeerAddress) -> bool {
+        match peer_address {
+            PeerAddress::Peer { address, options } => {
+                let matches = |other: &PeerAddress| matches!(other, PeerAddress::Peer { address: a, options: o } if a == address && o == options);
+                let configured = self
+                    .peer_configs
+                    .iter()
+                    .filter(|config| config.addr == *address && config.options == *options)
+                    .count();
+                let running = self
+                    .peers
+                    .values()
+                    .filter(|state| matches(&state.peer_address))
+                    .count();
+                running < configured
+            }
+            PeerAddress::Pool { index, .. } => self
+                .pool_configs
+                .iter()
+                .any(|(pool_index, _)| pool_index == index),
+            PeerAddress::RefClock { .. } => true,
+        }
+    }
+
     #[cfg(test)]
     fn create_test_peer(&mut self, addr: NormalizedAddress) -> PeerIndex {
         let index = self.peer_indexer.get();
@@ -305,31 +772,68 @@ impl<C: NtpClock> System<C> {
             index,
             PeerState {
                 snapshot: None,
-                peer_address: PeerAddress::Peer { address: addr },
+                peer_address: PeerAddress::Peer {
+                    address: addr,
+                    options: PeerOptions::default(),
+                },
+                addr: None,
+                task: None,
             },
         );
-        self.controller.peer_add(index);
+        self.controller.peer_add(index, PeerOptions::default());
 
         index
     }
 
     /// Add a single standard peer
-    async fn add_peer_internal(&mut self, address: NormalizedAddress) {
+    async fn add_peer_internal(&mut self, address: NormalizedAddress, options: PeerOptions) {
         let config = SpawnConfig::Standard {
-            config: StandardPeerConfig { addr: address },
+            config: StandardPeerConfig {
+                addr: address,
+                options,
+            },
         };
 
         self.spawner.spawn(config).await;
     }
 
+    /// Adds a configured peer or pool
+    async fn add_peer_config(&mut self, config: PeerConfig) {
+        match config {
+            PeerConfig::Standard(StandardPeerConfig { addr, options }) => {
+                self.add_peer(addr, options).await;
+            }
+            PeerConfig::Pool(PoolPeerConfig {
+                addr,
+                max_peers,
+                options,
+            }) => {
+                self.add_new_pool(addr, max_peers, options).await;
+            }
+        }
+    }
+
     /// Adds up to `max_peers` peers from a pool.
-    async fn add_new_pool(&mut self, address: NormalizedAddress, max_peers: usize) {
+    async fn add_new_pool(
+        &mut self,
+        address: NormalizedAddress,
+        max_peers: usize,
+        options: PeerOptions,
+    ) {
         // Each pool gets a unique index, because the `NormalizedAddress` may not be unique
         // Having two pools use the same address does not really do anything good, but we
         // want to make sure it does technically work.
         let index = self.pool_indexer.get();
+        self.pool_configs.push((
+            index,
+            PoolPeerConfig {
+                addr: address.clone(),
+                max_peers,
+                options,
+            },
+        ));
 
-        self.add_to_pool(index, address, max_peers).await
+        self.add_to_pool(index, address, max_peers, options).await
     }
 
     async fn add_to_pool(
@@ -337,12 +841,13 @@ impl<C: NtpClock> System<C> {
         index: PoolIndex,
         address: NormalizedAddress,
         max_peers: usize,
+        options: PeerOptions,
     ) {
         let in_use: Vec<_> = self
             .peers
             .values()
             .filter_map(|v| match &v.peer_address {
-                PeerAddress::Peer { .. } => None,
+                PeerAddress::Peer { .. } | PeerAddress::RefClock { .. } => None,
                 PeerAddress::Pool {
                     index: pool_index,
                     address: peer_address,
@@ -361,6 +866,7 @@ impl<C: NtpClock> System<C> {
             config: PoolPeerConfig {
                 addr: address,
                 max_peers,
+                options,
             },
             in_use,
         };
@@ -369,23 +875,73 @@ impl<C: NtpClock> System<C> {
     }
 
     /// Adds a single peer (that is not part of a pool!)
-    async fn add_peer(&mut self, address: NormalizedAddress) {
-        self.add_peer_internal(address).await
+    async fn add_peer(&mut self, address: NormalizedAddress, options: PeerOptions) {
+        self.peer_configs.push(StandardPeerConfig {
+            addr: address.clone(),
+            options,
+        });
+        self.add_peer_internal(address, options).await
     }
 
-    async fn add_server(&mut self, config: ServerConfig) {
+    /// Adds a reference clock as a (stratum 0) source of time
+    fn add_refclock(&mut self, config: RefClockConfig) {
+        self.refclock_configs.push(config.clone());
+        self.spawn_refclock(config);
+    }
+
+    fn spawn_refclock(&mut self, config: RefClockConfig) {
+        let index = self.peer_indexer.get();
+
+        let task = RefClockTask::spawn(
+            index,
+            &config,
+            &mut self.refclock_devices,
+            self.clock.clone(),
+            NETWORK_WAIT_PERIOD,
+            self.peer_channels.clone(),
+        );
+        self.controller.peer_add(index, PeerOptions::default());
+        self.peers.insert(
+            index,
+            PeerState {
+                snapshot: None,
+                peer_address: PeerAddress::RefClock { config },
+                addr: None,
+                task: Some(task),
+            },
+        );
+    }
+
+    /// A copy of the socket passed on by the service manager for this server, if any
+    fn inherited_socket(&self, config: &ServerConfig) -> Option<std::net::UdpSocket> {
+        let socket = self.inherited_sockets.get(config.fd_name.as_deref()?)?;
+        match socket.try_clone() {
+            Ok(socket) => Some(socket),
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "Could not use server socket passed by service manager"
+                );
+                None
+            }
+        }
+    }
+
+    async fn add_server(&mut self, config: ServerConfig, socket: Option<std::net::UdpSocket>) {
         let stats = ServerStats::default();
         self.servers.push(ServerData {
             stats: stats.clone(),
             config: config.clone(),
         });
-        ServerTask::spawn(
+        let task = ServerTask::spawn(
             config,
             stats,
             self.peer_channels.system_snapshot_receiver.clone(),
             self.clock.clone(),
             NETWORK_WAIT_PERIOD,
+            socket,
         );
+        self.server_tasks.push(task);
         let _ = self.server_data_sender.send(self.servers.clone());
     }
 
@@ -399,10 +955,10 @@ impl<C: NtpClock> System<C> {
                             reachability: snapshot.reach,
                             poll_interval: snapshot.poll_interval,
                             peer_id: snapshot.peer_id,
-                            address: match &data.peer_address {
-                                PeerAddress::Peer { address } => address.as_str().to_string(),
-                                PeerAddress::Pool { address, .. } => address.as_str().to_string(),
-                            },
+                            address: data.peer_address.to_string(),
+                            stratum: snapshot.stratum,
+                            reference_id: snapshot.reference_id,
+                            ignored: snapshot.ignored,
                         }
                     } else {
                         ObservablePeerState::Nothing
@@ -411,6 +967,16 @@ impl<C: NtpClock> System<C> {
                 .unwrap_or(ObservablePeerState::Nothing)
         })
     }
+
+    fn peer_history(&self) -> impl Iterator<Item = PeerHistory> + '_ {
+        self.peers.iter().filter_map(|(index, data)| {
+            data.snapshot.map(|snapshot| PeerHistory {
+                address: data.peer_address.to_string(),
+                peer_id: snapshot.peer_id,
+                measurements: self.controller.peer_history(*index),
+            })
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
@@ -460,19 +1026,53 @@ impl PoolIndexIssuer {
 enum PeerAddress {
     Peer {
         address: NormalizedAddress,
+        options: PeerOptions,
     },
     Pool {
         index: PoolIndex,
         address: NormalizedAddress,
         socket_address: std::net::SocketAddr,
         max_peers: usize,
+        options: PeerOptions,
     },
+    RefClock {
+        config: RefClockConfig,
+    },
+}
+
+impl PeerAddress {
+    fn options(&self) -> PeerOptions {
+        match self {
+            PeerAddress::Peer { options, .. } | PeerAddress::Pool { options, .. } => *options,
+            PeerAddress::RefClock { .. } => PeerOptions::default(),
+        }
+    }
+}
+
+impl std::fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddress::Peer { address, .. } | PeerAddress::Pool { address, .. } => {
+                f.write_str(address.as_str())
+            }
+            PeerAddress::RefClock { config } => write!(f, "{}", config.driver),
+        }
+    }
 }
 
 #[derive(Debug)]
 struct PeerState {
     snapshot: Option<PeerSnapshot>,
     peer_address: PeerAddress,
+    /// The address the peer was resolved to, none for reference clocks
+    addr: Option<SocketAddr>,
+    task: Option<JoinHandle<()>>,
+}
+
+/// Remove the first item equal to `item` from `items`, if any
+fn take_matching<T: PartialEq>(items: &mut Vec<T>, item: &T) -> Option<T> {
+    let position = items.iter().position(|other| other == item)?;
+    Some(items.remove(position))
 }
 
 #[derive(Debug, Clone)]
@@ -550,6 +1150,7 @@ impl Spawner {
         let spawn_task = SpawnTask {
             peer_address: PeerAddress::Peer {
                 address: config.addr,
+                options: config.options,
             },
             address: addr,
         };
@@ -570,6 +1171,11 @@ impl Spawner {
         let mut remaining;
 
         loop {
+            // The pool was removed from the configuration, stop filling it
+            if Arc::strong_count(&pool) == 1 {
+                return;
+            }
+
             let mut pool = pool.lock().await;
 
             remaining = config.max_peers - in_use.len();
@@ -601,6 +1207,7 @@ impl Spawner {
                         address: config.addr.clone(),
                         socket_address: addr,
                         max_peers: config.max_peers,
+                        options: config.options,
                     },
                     address: addr,
                 };
@@ -636,7 +1243,7 @@ impl Spawner {
 mod tests {
     use ntp_proto::{
         peer_snapshot, Measurement, NtpDuration, NtpInstant, NtpLeapIndicator, NtpPacket,
-        NtpTimestamp, PollInterval, SystemConfig,
+        NtpTimestamp, PollInterval, PollIntervalLimits, SystemConfig,
     };
 
     use crate::config::NormalizedAddress;
@@ -658,6 +1265,10 @@ mod tests {
             Ok(())
         }
 
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            Ok(0.0)
+        }
+
         fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
             Ok(())
         }
@@ -785,11 +1396,13 @@ mod tests {
         let (mut system, _) = System::new(TestClock {}, SystemConfig::default());
 
         let peer_address = NormalizedAddress::new_unchecked("127.0.0.2:123");
-        system.add_peer(peer_address).await;
+        system.add_peer(peer_address, PeerOptions::default()).await;
 
         let pool_address = NormalizedAddress::new_unchecked("127.0.0.1:123");
         let max_peers = 1;
-        system.add_new_pool(pool_address.clone(), max_peers).await;
+        system
+            .add_new_pool(pool_address.clone(), max_peers, PeerOptions::default())
+            .await;
 
         for _ in 0..2 {
             let task = system.spawn_task_rx.recv().await.unwrap();
@@ -818,14 +1431,16 @@ mod tests {
         let (mut system, _) = System::new(TestClock {}, SystemConfig::default());
 
         let peer_address = NormalizedAddress::new_unchecked("127.0.0.5:123");
-        system.add_peer(peer_address).await;
+        system.add_peer(peer_address, PeerOptions::default()).await;
 
         let pool_address = NormalizedAddress::with_hardcoded_dns(
             "tweedegolf.nl:123",
             vec!["127.0.0.1:123".parse().unwrap()],
         );
         let max_peers = 2;
-        system.add_new_pool(pool_address.clone(), max_peers).await;
+        system
+            .add_new_pool(pool_address.clone(), max_peers, PeerOptions::default())
+            .await;
 
         for _ in 0..2 {
             let task = system.spawn_task_rx.recv().await.unwrap();
@@ -858,7 +1473,7 @@ mod tests {
         let (mut system, _) = System::new(TestClock {}, SystemConfig::default());
 
         let peer_address = NormalizedAddress::new_unchecked("127.0.0.5:123");
-        system.add_peer(peer_address).await;
+        system.add_peer(peer_address, PeerOptions::default()).await;
 
         let pool_address = NormalizedAddress::with_hardcoded_dns(
             "tweedegolf.nl:123",
@@ -870,7 +1485,9 @@ mod tests {
             ],
         );
         let max_peers = 3;
-        system.add_new_pool(pool_address.clone(), max_peers).await;
+        system
+            .add_new_pool(pool_address.clone(), max_peers, PeerOptions::default())
+            .await;
 
         for _ in 0..4 {
             let task = system.spawn_task_rx.recv().await.unwrap();
@@ -891,4 +1508,227 @@ mod tests {
         // automatically selects another peer from the pool
         assert_eq!(system.peers.len(), 4);
     }
+
+    #[tokio::test]
+    async fn reload_peers() {
+        let (mut system, _) = System::new(TestClock {}, SystemConfig::default());
+
+        let peer = StandardPeerConfig {
+            addr: NormalizedAddress::new_unchecked("127.0.0.5:123"),
+            options: PeerOptions::default(),
+        };
+        let pool = PoolPeerConfig {
+            addr: NormalizedAddress::with_hardcoded_dns(
+                "tweedegolf.nl:123",
+                vec![
+                    "127.0.0.1:123".parse().unwrap(),
+                    "127.0.0.2:123".parse().unwrap(),
+                ],
+            ),
+            max_peers: 2,
+            options: PeerOptions::default(),
+        };
+        system
+            .add_peer_config(PeerConfig::Standard(peer.clone()))
+            .await;
+        system.add_peer_config(PeerConfig::Pool(pool.clone())).await;
+
+        for _ in 0..3 {
+            let task = system.spawn_task_rx.recv().await.unwrap();
+            system.handle_spawn(task.peer_address, task.address);
+        }
+        assert_eq!(system.peers.len(), 3);
+        let pool_peer = system
+            .peers
+            .iter()
+            .find(|(_, state)| matches!(state.peer_address, PeerAddress::Pool { .. }))
+            .map(|(index, _)| *index)
+            .unwrap();
+
+        // reloading the same configuration changes nothing
+        system
+            .handle_command(SystemCommand::Reload {
+                peers: vec![PeerConfig::Pool(pool), PeerConfig::Standard(peer.clone())],
+                servers: vec![],
+                refclocks: vec![],
+            })
+            .await;
+        assert_eq!(system.peers.len(), 3);
+        assert!(system.spawn_task_rx.try_recv().is_err());
+
+        // removing the pool stops its peers, a new peer is started
+        let new_peer = StandardPeerConfig {
+            addr: NormalizedAddress::new_unchecked("127.0.0.6:123"),
+            options: PeerOptions::default(),
+        };
+        system
+            .handle_command(SystemCommand::Reload {
+                peers: vec![
+                    PeerConfig::Standard(peer.clone()),
+                    PeerConfig::Standard(new_peer.clone()),
+                ],
+                servers: vec![],
+                refclocks: vec![],
+            })
+            .await;
+        assert_eq!(system.peers.len(), 1);
+        assert!(system.spawner.pools.is_empty());
+
+        let task = system.spawn_task_rx.recv().await.unwrap();
+        system.handle_spawn(task.peer_address, task.address);
+        assert_eq!(system.peers.len(), 2);
+
+        // messages from peers that were stopped are ignored
+        system
+            .handle_peer_update(MsgForSystem::NetworkIssue(pool_peer))
+            .await;
+        assert!(system.spawn_task_rx.try_recv().is_err());
+        assert_eq!(system.peers.len(), 2);
+
+        // a peer that is removed before it is resolved is never started
+        let pending_peer = StandardPeerConfig {
+            addr: NormalizedAddress::new_unchecked("127.0.0.7:123"),
+            options: PeerOptions::default(),
+        };
+        system
+            .add_peer_config(PeerConfig::Standard(pending_peer))
+            .await;
+        system
+            .handle_command(SystemCommand::Reload {
+                peers: vec![PeerConfig::Standard(new_peer)],
+                servers: vec![],
+                refclocks: vec![],
+            })
+            .await;
+        assert_eq!(system.peers.len(), 1);
+
+        let task = system.spawn_task_rx.recv().await.unwrap();
+        system.handle_spawn(task.peer_address, task.address);
+        assert_eq!(system.peers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reload_servers_keeps_inherited_socket() {
+        let (mut system, _) = System::new(TestClock {}, SystemConfig::default());
+
+        // stands in for a socket passed on by the service manager
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        system.inherited_sockets.insert("ntp".into(), socket);
+
+        let mut config = ServerConfig::try_from("127.0.0.1:1").unwrap();
+        config.fd_name = Some("ntp".into());
+        let socket = system.inherited_socket(&config);
+        system.add_server(config.clone(), socket).await;
+
+        // a changed server is started again on the same socket
+        config.rate_limiting_cache_size += 1;
+        system
+            .handle_command(SystemCommand::Reload {
+                peers: vec![],
+                servers: vec![config],
+                refclocks: vec![],
+            })
+            .await;
+        assert_eq!(system.servers.len(), 1);
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut pdata = vec![];
+        packet.serialize(&mut pdata).unwrap();
+
+        // The kernel may not timestamp the first packets after the server
+        // enabled timestamping, which the server then drops
+        let mut buf = [0; 48];
+        let mut answered = false;
+        for _ in 0..5 {
+            client.send_to(&pdata, address).await.unwrap();
+            let timeout = std::time::Duration::from_millis(200);
+            if let Ok(received) = tokio::time::timeout(timeout, client.recv(&mut buf)).await {
+                received.unwrap();
+                answered = true;
+                break;
+            }
+        }
+        assert!(answered);
+        let packet = NtpPacket::deserialize(&buf).unwrap();
+        assert!(packet.valid_server_response(id));
+    }
+
+    #[tokio::test]
+    async fn add_and_remove_peers() {
+        let (mut system, _) = System::new(TestClock {}, SystemConfig::default());
+
+        let peer_address = NormalizedAddress::new_unchecked("127.0.0.5:123");
+        let pool_address = NormalizedAddress::with_hardcoded_dns(
+            "tweedegolf.nl:123",
+            vec![
+                "127.0.0.1:123".parse().unwrap(),
+                "127.0.0.2:123".parse().unwrap(),
+            ],
+        );
+        system
+            .handle_command(SystemCommand::AddPeers(vec![
+                PeerConfig::Standard(StandardPeerConfig {
+                    addr: peer_address.clone(),
+                    options: PeerOptions::default(),
+                }),
+                PeerConfig::Pool(PoolPeerConfig {
+                    addr: pool_address.clone(),
+                    max_peers: 2,
+                    options: PeerOptions::default(),
+                }),
+            ]))
+            .await;
+
+        for _ in 0..3 {
+            let task = system.spawn_task_rx.recv().await.unwrap();
+            system.handle_spawn(task.peer_address, task.address);
+        }
+        assert_eq!(system.peers.len(), 3);
+
+        // peers that were added are kept on reload
+        system
+            .handle_command(SystemCommand::Reload {
+                peers: vec![],
+                servers: vec![],
+                refclocks: vec![],
+            })
+            .await;
+        assert_eq!(system.peers.len(), 3);
+        assert!(system.spawn_task_rx.try_recv().is_err());
+
+        let reply = system
+            .handle_command(SystemCommand::RemovePeers(vec![pool_address]))
+            .await;
+        assert_eq!(system.peers.len(), 1);
+        assert!(system.pool_configs.is_empty());
+        assert!(reply.not_found.is_empty());
+        assert_eq!(reply.peers.len(), 1);
+
+        // unknown addresses are reported
+        let unknown = NormalizedAddress::new_unchecked("127.0.0.6:123");
+        let reply = system
+            .handle_command(SystemCommand::RemovePeers(vec![unknown.clone()]))
+            .await;
+        assert_eq!(system.peers.len(), 1);
+        assert_eq!(reply.not_found, vec![unknown]);
+
+        system
+            .handle_command(SystemCommand::RemovePeers(vec![peer_address]))
+            .await;
+        assert!(system.peers.is_empty());
+        assert!(system.peer_configs.is_empty());
+
+        // but not once they are removed
+        system
+            .handle_command(SystemCommand::Reload {
+                peers: vec![],
+                servers: vec![],
+                refclocks: vec![],
+            })
+            .await;
+        assert!(system.peers.is_empty());
+        assert!(system.added_peers.is_empty());
+    }
 }
@@ -0,0 +1,351 @@
+//! Integration with the systemd service manager, through the `sd_notify`
+//! protocol and socket activation. All of this does nothing when the daemon
+//! was not started by a service manager.
+
+use std::{
+    ffi::OsStr,
+    io::{Error, ErrorKind},
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::{UnixDatagram, UnixListener},
+    },
+    time::Duration,
+};
+
+use tokio::time::Interval;
+use tracing::{debug, warn};
+
+/// Send a state update (see `sd_notify(3)`) to the service manager
+pub fn notify(state: &str) {
+    let address = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(address) => address,
+        None => return,
+    };
+
+    if let Err(error) = send(&address, state) {
+        warn!(?error, "could not notify service manager");
+    }
+}
+
+fn send(address: &OsStr, state: &str) -> std::io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    match address.as_bytes() {
+        [b'/', ..] => socket.send_to(state.as_bytes(), address).map(|_| ()),
+        [b'@', name @ ..] => send_abstract(&socket, name, state.as_bytes()),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "unsupported NOTIFY_SOCKET address",
+        )),
+    }
+}
+
+/// Send to a socket in the abstract namespace, which std does not support
+fn send_abstract(socket: &UnixDatagram, name: &[u8], message: &[u8]) -> std::io::Result<()> {
+    // Safety: sockaddr_un is plain old data, for which all zeroes is valid
+    let mut address: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    // The name starts after the leading zero byte that marks the abstract namespace
+    if name.len() >= address.sun_path.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "NOTIFY_SOCKET address too long",
+        ));
+    }
+    for (dst, src) in address.sun_path[1..].iter_mut().zip(name) {
+        *dst = *src as libc::c_char;
+    }
+    let length = std::mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+
+    // Safety: the message and address are valid for the given lengths
+    let result = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            message.as_ptr() as *const libc::c_void,
+            message.len(),
+            0,
+            &address as *const libc::sockaddr_un as *const libc::sockaddr,
+            length as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Periodic keep-alive for the service manager watchdog
+pub struct Watchdog {
+    interval: Option<Interval>,
+}
+
+impl Watchdog {
+    /// Set up the watchdog as requested by the service manager, if at all
+    pub fn from_env() -> Self {
+        let interval = watchdog_interval(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        );
+
+        if let Some(interval) = interval {
+            debug!(?interval, "sending watchdog keep-alives");
+        }
+
+        Watchdog {
+            interval: interval.map(tokio::time::interval),
+        }
+    }
+
+    /// Wait until the next keep-alive is due, never completes when the watchdog is disabled
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn notify(&self) {
+        notify("WATCHDOG=1");
+    }
+}
+
+/// Keep-alives are sent at half the timeout configured in the service manager
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    let usec: u64 = usec?.parse().ok()?;
+
+    // The watchdog may be meant for another process, e.g. our parent
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+
+    if usec == 0 {
+        return None;
+    }
+
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// First file descriptor passed by the service manager
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed on by the service manager through socket activation (see
+/// `sd_listen_fds(3)`), identified by their `FileDescriptorName=`. Sockets
+/// that are not taken are closed when this is dropped.
+#[derive(Debug, Default)]
+pub struct ListenFds {
+    fds: Vec<(String, RawFd)>,
+}
+
+impl ListenFds {
+    /// Take over the sockets passed to this process, if any
+    pub fn from_env() -> Self {
+        let fds = listen_fds(
+            std::env::var("LISTEN_PID").ok().as_deref(),
+            std::env::var("LISTEN_FDS").ok().as_deref(),
+            std::env::var("LISTEN_FDNAMES").ok().as_deref(),
+            std::process::id(),
+        );
+
+        // Make sure the sockets are not passed on to any child processes
+        for key in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(key);
+        }
+        for (name, fd) in &fds {
+            debug!(name, fd, "received socket from service manager");
+            // Safety: fcntl has no memory safety requirements
+            unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+
+        ListenFds { fds }
+    }
+
+    /// Take the socket with the given name, if it was passed and is of the given type
+    fn take(&mut self, name: &str, socket_type: libc::c_int) -> Option<RawFd> {
+        let position = match self.fds.iter().position(|(n, _)| n == name) {
+            Some(position) => position,
+            None => {
+                warn!(name, "no socket with this name passed by service manager");
+                return None;
+            }
+        };
+        let fd = self.fds[position].1;
+
+        if get_socket_type(fd) != Some(socket_type) {
+            warn!(
+                name,
+                "socket passed by service manager is of the wrong type"
+            );
+            return None;
+        }
+
+        self.fds.remove(position);
+        Some(fd)
+    }
+
+    pub fn take_udp(&mut self, name: &str) -> Option<std::net::UdpSocket> {
+        let fd = self.take(name, libc::SOCK_DGRAM)?;
+
+        // Safety: the fd was passed on to us and is no longer owned by self
+        Some(unsafe { std::net::UdpSocket::from_raw_fd(fd) })
+    }
+
+    pub fn take_unix_listener(&mut self, name: &str) -> Option<UnixListener> {
+        let fd = self.take(name, libc::SOCK_STREAM)?;
+
+        // Safety: the fd was passed on to us and is no longer owned by self
+        Some(unsafe { UnixListener::from_raw_fd(fd) })
+    }
+
+    pub fn take_tcp_listener(&mut self, name: &str) -> Option<std::net::TcpListener> {
+        let fd = self.take(name, libc::SOCK_STREAM)?;
+
+        // Safety: the fd was passed on to us and is no longer owned by self
+        Some(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+    }
+}
+
+impl Drop for ListenFds {
+    fn drop(&mut self) {
+        for (name, fd) in self.fds.drain(..) {
+            warn!(
+                name,
+                "socket passed by service manager is not used by the configuration"
+            );
+            // Safety: the fd was passed on to us and is not used anywhere else
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+fn get_socket_type(fd: RawFd) -> Option<libc::c_int> {
+    let mut socket_type: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    // Safety: socket_type is valid for writes of the given length
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut socket_type as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+
+    (result == 0).then_some(socket_type)
+}
+
+fn listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Vec<(String, RawFd)> {
+    // The sockets may be meant for another process, e.g. our parent
+    match pid.and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) if pid == own_pid => {}
+        _ => return vec![],
+    }
+
+    let count: RawFd = match fds.and_then(|fds| fds.parse().ok()) {
+        Some(count) => count,
+        None => return vec![],
+    };
+
+    // Sockets without a name are named "unknown", like sd_listen_fds_with_names does
+    let mut names = names.unwrap_or_default().split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            let name = names.next().filter(|name| !name.is_empty());
+            (name.unwrap_or("unknown").to_string(), fd)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_to_path() {
+        let path = std::env::temp_dir().join(format!("ntp-test-notify-{}", std::process::id()));
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        send(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_send_invalid_address() {
+        assert!(send(OsStr::new("relative/path"), "READY=1").is_err());
+        assert!(send(OsStr::new(""), "READY=1").is_err());
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(
+            watchdog_interval(Some("10000000"), None, 42),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            watchdog_interval(Some("10000000"), Some("42"), 42),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(watchdog_interval(Some("10000000"), Some("43"), 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(Some("invalid"), None, 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+    }
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(
+            listen_fds(Some("42"), Some("2"), Some("ntp:observe"), 42),
+            vec![("ntp".to_string(), 3), ("observe".to_string(), 4)]
+        );
+        assert_eq!(
+            listen_fds(Some("42"), Some("2"), None, 42),
+            vec![("unknown".to_string(), 3), ("unknown".to_string(), 4)]
+        );
+        assert_eq!(listen_fds(Some("43"), Some("2"), None, 42), vec![]);
+        assert_eq!(listen_fds(None, Some("2"), None, 42), vec![]);
+        assert_eq!(listen_fds(Some("42"), None, None, 42), vec![]);
+    }
+
+    #[test]
+    fn test_take_socket() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let fd = socket.as_raw_fd();
+        std::mem::forget(socket);
+
+        let mut listen_fds = ListenFds {
+            fds: vec![("ntp".to_string(), fd)],
+        };
+
+        // wrong type or name leaves the socket in place
+        assert!(listen_fds.take_unix_listener("ntp").is_none());
+        assert!(listen_fds.take_udp("observe").is_none());
+
+        let socket = listen_fds.take_udp("ntp").unwrap();
+        assert_eq!(socket.local_addr().unwrap(), address);
+        assert!(listen_fds.fds.is_empty());
+    }
+}
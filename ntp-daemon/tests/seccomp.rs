@@ -0,0 +1,140 @@
+//! Runs the daemon with its seccomp filter enabled through the paths that are
+//! only taken after startup: reloading the configuration, which starts new
+//! servers, peers and reference clocks, adding peers on the configure socket,
+//! and starting statistics files. A system call that is missing from the
+//! allowlist kills the daemon.
+
+use std::{
+    io::Read,
+    net::UdpSocket,
+    path::Path,
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+use ntp_daemon::config::dynamic::{ConfigRequest, ConfigResponse};
+use ntp_daemon::ConfigUpdate;
+use tokio::net::UnixStream;
+
+/// A port on localhost that was free a moment ago
+fn free_port() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().port()
+}
+
+fn write_config(directory: &Path, peer_port: u16, server_port: u16, refclock: &str) {
+    let config = format!(
+        r#"
+[[peers]]
+addr = "127.0.0.1:{peer_port}"
+
+[[server]]
+addr = "127.0.0.1:{server_port}"
+
+[[refclocks]]
+driver = "sock"
+path = "{refclock}"
+refid = "TEST"
+
+[configure]
+path = "{configure}"
+
+[statistics]
+directory = "{statistics}"
+retention-days = 1
+
+[privileges]
+seccomp = true
+"#,
+        refclock = directory.join(refclock).display(),
+        configure = directory.join("configure").display(),
+        statistics = directory.join("statistics").display(),
+    );
+    std::fs::write(directory.join("ntp.toml"), config).unwrap();
+}
+
+async fn configure(directory: &Path, update: ConfigUpdate) -> std::io::Result<ConfigResponse> {
+    let mut stream = UnixStream::connect(directory.join("configure")).await?;
+    ntp_daemon::sockets::write_json(&mut stream, &ConfigRequest::new(update)).await?;
+    let mut msg = Vec::with_capacity(16 * 1024);
+    ntp_daemon::sockets::read_json(&mut stream, &mut msg).await
+}
+
+/// Wait until the daemon accepts requests, after which the filter is enabled
+async fn wait_until_ready(directory: &Path, daemon: &mut Child) {
+    for _ in 0..100 {
+        if daemon.try_wait().unwrap().is_some() {
+            let mut stderr = String::new();
+            daemon
+                .stderr
+                .take()
+                .unwrap()
+                .read_to_string(&mut stderr)
+                .unwrap();
+            panic!("daemon stopped: {}", stderr);
+        }
+        if configure(directory, ConfigUpdate::default()).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("daemon did not start");
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[tokio::test]
+async fn test_reload_with_seccomp() {
+    let directory = std::env::temp_dir().join(format!("ntp-test-seccomp-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+
+    // The daemon measures its own server, so that statistics are written
+    let server_port = free_port();
+    write_config(&directory, server_port, server_port, "refclock-1");
+
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_ntp-daemon"))
+        .arg("--config")
+        .arg(directory.join("ntp.toml"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    wait_until_ready(&directory, &mut daemon).await;
+
+    // Replace the peer, server and reference clock
+    let new_server_port = free_port();
+    write_config(&directory, new_server_port, new_server_port, "refclock-2");
+    let response = configure(
+        &directory,
+        ConfigUpdate {
+            reload: true,
+            add_peers: vec![format!("127.0.0.1:{}", server_port)],
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.error, None);
+
+    // And back again through SIGHUP
+    write_config(&directory, server_port, server_port, "refclock-1");
+    // Safety: kill has no memory safety requirements
+    unsafe { libc::kill(daemon.id() as libc::pid_t, libc::SIGHUP) };
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let statistics = std::fs::read_dir(directory.join("statistics")).unwrap();
+    assert!(statistics.count() > 0);
+    configure(&directory, ConfigUpdate::default())
+        .await
+        .unwrap();
+
+    let running = daemon.try_wait().unwrap().is_none();
+    let _ = daemon.kill();
+    let output = daemon.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(running, "daemon stopped: {}", stderr);
+    assert!(!stderr.contains("seccomp"), "{}", stderr);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,131 @@
+//! Decide whether the daemon is healthy and ready to be relied upon, for use
+//! by liveness and readiness probes and load balancers.
+
+use ntp_daemon::{ObservablePeerState, ObservableState};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HealthCriteria {
+    /// Largest offset of the system clock, in seconds, at which the daemon is ready
+    pub max_offset: f64,
+    /// Number of peers that must be reachable for the daemon to be healthy
+    pub min_reachable_peers: usize,
+}
+
+/// Outcome of a health check, with the values it was based on
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    pub synchronized: bool,
+    pub offset: f64,
+    pub reachable_peers: usize,
+    /// Why the check failed, empty when it passed
+    pub problems: Vec<String>,
+}
+
+impl Health {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl HealthCriteria {
+    /// Check whether the daemon is alive: enough of its peers are reachable
+    pub fn liveness(&self, state: &ObservableState) -> Health {
+        let mut health = Health {
+            synchronized: state.system.time_snapshot.leap_indicator.is_synchronized(),
+            offset: state.system.time_snapshot.offset.to_seconds(),
+            reachable_peers: state
+                .peers
+                .iter()
+                .filter(|peer| {
+                    matches!(peer, ObservablePeerState::Observable { reachability, .. } if reachability.is_reachable())
+                })
+                .count(),
+            problems: vec![],
+        };
+
+        if health.reachable_peers < self.min_reachable_peers {
+            health.problems.push(format!(
+                "{} of at least {} peers reachable",
+                health.reachable_peers, self.min_reachable_peers
+            ));
+        }
+
+        health
+    }
+
+    /// Check whether the daemon is ready: it is alive, synchronized, and the
+    /// offset of the system clock is small enough
+    pub fn readiness(&self, state: &ObservableState) -> Health {
+        let mut health = self.liveness(state);
+
+        if !health.synchronized {
+            health.problems.push("not synchronized".into());
+        } else if health.offset.abs() > self.max_offset {
+            health.problems.push(format!(
+                "offset of {}s exceeds {}s",
+                health.offset, self.max_offset
+            ));
+        }
+
+        health
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{NtpDuration, NtpLeapIndicator, SystemSnapshot};
+
+    use super::*;
+
+    fn state(leap_indicator: NtpLeapIndicator, offset: f64, reach: &[u8]) -> ObservableState {
+        let mut system = SystemSnapshot::default();
+        system.time_snapshot.leap_indicator = leap_indicator;
+        system.time_snapshot.offset = NtpDuration::from_seconds(offset);
+
+        ObservableState {
+            system,
+            peers: reach
+                .iter()
+                .map(|reach| ObservablePeerState::Observable {
+                    timedata: Default::default(),
+                    reachability: serde_json::from_str(&reach.to_string()).unwrap(),
+                    poll_interval: ntp_proto::PollIntervalLimits::default().min,
+                    peer_id: ntp_proto::ReferenceId::NONE,
+                    address: "192.0.2.1:123".into(),
+                    stratum: 2,
+                    reference_id: ntp_proto::ReferenceId::NONE,
+                    ignored: Default::default(),
+                })
+                .chain(std::iter::once(ObservablePeerState::Nothing))
+                .collect(),
+            servers: vec![],
+        }
+    }
+
+    #[test]
+    fn test_health() {
+        let criteria = HealthCriteria {
+            max_offset: 0.125,
+            min_reachable_peers: 2,
+        };
+
+        let healthy = state(NtpLeapIndicator::NoWarning, -0.0625, &[1, 3, 0]);
+        assert!(criteria.liveness(&healthy).is_ok());
+        let readiness = criteria.readiness(&healthy);
+        assert!(readiness.is_ok());
+        assert_eq!(readiness.reachable_peers, 2);
+
+        let unsynchronized = state(NtpLeapIndicator::Unknown, 0.0, &[1, 1]);
+        assert!(criteria.liveness(&unsynchronized).is_ok());
+        assert!(!criteria.readiness(&unsynchronized).is_ok());
+
+        let large_offset = state(NtpLeapIndicator::NoWarning, -0.25, &[1, 1]);
+        assert!(criteria.liveness(&large_offset).is_ok());
+        assert!(!criteria.readiness(&large_offset).is_ok());
+
+        let unreachable = state(NtpLeapIndicator::NoWarning, 0.0, &[1, 0]);
+        assert!(!criteria.liveness(&unreachable).is_ok());
+        assert_eq!(criteria.readiness(&unreachable).problems.len(), 1);
+    }
+}
@@ -1,10 +1,14 @@
 #![forbid(unsafe_code)]
 
-use ntp_metrics_exporter::Metrics;
+use ntp_metrics_exporter::{
+    health::{Health, HealthCriteria},
+    otlp::OtlpExporter,
+    Metrics,
+};
 use serde_json::json;
 use thiserror::Error;
 
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::Path, path::PathBuf, time::Duration};
 
 use axum::{
     handler::Handler,
@@ -29,6 +33,40 @@ struct Cli {
 
     #[arg(short = 'l', long = "listen", default_value = "127.0.0.1:9975")]
     listen_socket: SocketAddr,
+
+    /// Also push the metrics to the OTLP/HTTP collector at this endpoint
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Seconds between pushes to the OTLP collector
+    #[arg(long, default_value_t = 60)]
+    otlp_interval: u64,
+
+    /// Attribute of the resource in pushed metrics, as key=value
+    #[arg(
+        long = "otlp-resource-attribute",
+        env = "OTEL_RESOURCE_ATTRIBUTES",
+        value_delimiter = ',',
+        value_parser = parse_attribute
+    )]
+    otlp_resource_attributes: Vec<(String, String)>,
+
+    /// Largest offset of the system clock, in seconds, for which /readyz reports ready
+    #[arg(long, default_value_t = 0.1)]
+    max_offset: f64,
+
+    /// Number of peers that must be reachable for /healthz and /readyz to succeed
+    #[arg(long, default_value_t = 1)]
+    min_reachable_peers: usize,
+}
+
+fn parse_attribute(attribute: &str) -> Result<(String, String), String> {
+    match attribute.split_once('=') {
+        Some((key, value)) if !key.is_empty() => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected key=value, got '{}'", attribute)),
+    }
 }
 
 #[derive(Debug, Error)]
@@ -49,6 +87,60 @@ impl IntoResponse for ServeError {
     }
 }
 
+async fn read_state(observation_socket_path: &Path) -> std::io::Result<ObservableState> {
+    let mut stream = tokio::net::UnixStream::connect(observation_socket_path).await?;
+    let mut msg = Vec::with_capacity(16 * 1024);
+    ntp_daemon::sockets::read_json(&mut stream, &mut msg).await
+}
+
+async fn push_metrics(
+    exporter: OtlpExporter,
+    observation_socket_path: PathBuf,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+
+        let output = match read_state(&observation_socket_path).await {
+            Ok(output) => output,
+            Err(e) => {
+                println!("Warning: Unable to read the state of the daemon: {}", e);
+                continue;
+            }
+        };
+        let metrics = Metrics::default();
+        metrics.fill(&output);
+        if let Err(e) = exporter.export(&metrics.registry()).await {
+            println!("Warning: Unable to push metrics: {}", e);
+        }
+    }
+}
+
+/// Respond to a probe with the outcome of `check`, or with service unavailable
+/// when the daemon cannot be reached
+async fn probe(
+    observation_socket_path: &Path,
+    check: impl FnOnce(&ObservableState) -> Health,
+) -> (StatusCode, Json<serde_json::Value>) {
+    match read_state(observation_socket_path).await {
+        Ok(state) => {
+            let health = check(&state);
+            let status = match health.is_ok() {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            (status, Json(json!(health)))
+        }
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "problems": [format!("unable to reach the daemon: {}", e)],
+            })),
+        ),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
@@ -69,14 +161,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         },
     };
 
+    if let Some(endpoint) = cli.otlp_endpoint {
+        let mut resource_attributes = cli.otlp_resource_attributes;
+        if !resource_attributes
+            .iter()
+            .any(|(key, _)| key == "service.name")
+        {
+            resource_attributes.push(("service.name".into(), "ntpd-rs".into()));
+        }
+        let exporter = OtlpExporter::new(&endpoint, resource_attributes)?;
+        tokio::spawn(push_metrics(
+            exporter,
+            observation_socket_path.clone(),
+            Duration::from_secs(cli.otlp_interval.max(1)),
+        ));
+    }
+
+    let criteria = HealthCriteria {
+        max_offset: cli.max_offset,
+        min_reachable_peers: cli.min_reachable_peers,
+    };
+
     let app = Router::new()
+        .route(
+            "/healthz",
+            get({
+                let observation_socket_path = observation_socket_path.clone();
+                move || async move {
+                    probe(&observation_socket_path, |state| criteria.liveness(state)).await
+                }
+            }),
+        )
+        .route(
+            "/readyz",
+            get({
+                let observation_socket_path = observation_socket_path.clone();
+                move || async move {
+                    probe(&observation_socket_path, |state| criteria.readiness(state)).await
+                }
+            }),
+        )
+        .route(
+            "/state",
+            get({
+                let observation_socket_path = observation_socket_path.clone();
+                move || async move {
+                    let output = read_state(&observation_socket_path).await?;
+                    Ok::<_, ServeError>(Json(output))
+                }
+            }),
+        )
         .route(
             "/metrics",
-            get(|| async {
-                let mut stream = tokio::net::UnixStream::connect(observation_socket_path).await?;
-                let mut msg = Vec::with_capacity(16 * 1024);
-                let output: ObservableState =
-                    ntp_daemon::sockets::read_json(&mut stream, &mut msg).await?;
+            get(|| async move {
+                let output = read_state(&observation_socket_path).await?;
                 let metrics = Metrics::default();
                 metrics.fill(&output);
                 let registry = metrics.registry();
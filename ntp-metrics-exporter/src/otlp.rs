@@ -0,0 +1,468 @@
+//! Push the metrics to an OpenTelemetry collector, using OTLP over HTTP with
+//! JSON encoding.
+//!
+//! The metrics are taken from the OpenMetrics text encoding of the registry, so
+//! exactly the same metric set is pushed as is served on the `/metrics` endpoint.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{client::HttpConnector, Body, Client, Method, Request, StatusCode, Uri};
+use prometheus_client::{encoding::text::SendSyncEncodeMetric, registry::Registry};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+/// Time the collector gets to respond to an export request
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum OtlpError {
+    #[error("invalid endpoint: {0}")]
+    InvalidEndpoint(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("http error: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("collector did not respond in time")]
+    Timeout,
+    #[error("collector responded with status {0}")]
+    Status(StatusCode),
+}
+
+pub struct OtlpExporter {
+    client: Client<HttpConnector>,
+    endpoint: Uri,
+    resource_attributes: Vec<(String, String)>,
+    start_time: SystemTime,
+}
+
+impl OtlpExporter {
+    /// Create an exporter for the collector at `endpoint`. When the endpoint has
+    /// no path, the default path `/v1/metrics` is used.
+    pub fn new(
+        endpoint: &str,
+        resource_attributes: Vec<(String, String)>,
+    ) -> Result<Self, OtlpError> {
+        let uri: Uri = endpoint
+            .parse()
+            .map_err(|_| OtlpError::InvalidEndpoint(endpoint.to_string()))?;
+
+        if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+            return Err(OtlpError::InvalidEndpoint(format!(
+                "{} (only http:// endpoints are supported)",
+                endpoint
+            )));
+        }
+
+        let endpoint = match uri.path() {
+            "" | "/" => format!("{}/v1/metrics", endpoint.trim_end_matches('/'))
+                .parse()
+                .map_err(|_| OtlpError::InvalidEndpoint(endpoint.to_string()))?,
+            _ => uri,
+        };
+
+        Ok(Self {
+            client: Client::new(),
+            endpoint,
+            resource_attributes,
+            start_time: SystemTime::now(),
+        })
+    }
+
+    /// Send the current values of the metrics in `registry` to the collector
+    pub async fn export(
+        &self,
+        registry: &Registry<Box<dyn SendSyncEncodeMetric>>,
+    ) -> Result<(), OtlpError> {
+        let mut buf = vec![];
+        prometheus_client::encoding::text::encode(&mut buf, registry)?;
+        let text = String::from_utf8_lossy(&buf);
+
+        let body = export_request(
+            &text,
+            &self.resource_attributes,
+            self.start_time,
+            SystemTime::now(),
+        );
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.endpoint.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .expect("request with valid uri and headers");
+
+        let response = tokio::time::timeout(EXPORT_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| OtlpError::Timeout)??;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(OtlpError::Status(response.status()))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum MetricType {
+    Gauge,
+    Counter,
+    Unknown,
+}
+
+#[derive(Debug)]
+struct MetricFamily<'a> {
+    name: &'a str,
+    help: String,
+    unit: Option<&'a str>,
+    metric_type: MetricType,
+    samples: Vec<(Vec<(&'a str, String)>, &'a str)>,
+}
+
+impl<'a> MetricFamily<'a> {
+    fn new(name: &'a str) -> Self {
+        Self {
+            name,
+            help: String::new(),
+            unit: None,
+            metric_type: MetricType::Unknown,
+            samples: vec![],
+        }
+    }
+}
+
+/// Build an `ExportMetricsServiceRequest` in the JSON encoding of OTLP from
+/// metrics in the OpenMetrics text format
+fn export_request(
+    text: &str,
+    resource_attributes: &[(String, String)],
+    start_time: SystemTime,
+    time: SystemTime,
+) -> Value {
+    let start_time = unix_nanos(start_time);
+    let time = unix_nanos(time);
+
+    let metrics: Vec<Value> = parse_families(text)
+        .iter()
+        .map(|family| {
+            let data_points: Vec<Value> = family
+                .samples
+                .iter()
+                .filter_map(|(labels, value)| {
+                    let (field, value) = value_field(value)?;
+                    let mut point = json!({
+                        "attributes": attributes(labels.iter().map(|(k, v)| (*k, v.as_str()))),
+                        "startTimeUnixNano": start_time,
+                        "timeUnixNano": time,
+                    });
+                    point[field] = value;
+                    Some(point)
+                })
+                .collect();
+
+            let mut metric = json!({
+                "name": family.name,
+                "description": family.help,
+                "unit": family.unit.map(ucum_unit).unwrap_or(""),
+            });
+            match family.metric_type {
+                MetricType::Counter => {
+                    metric["sum"] = json!({
+                        "dataPoints": data_points,
+                        // cumulative
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                    })
+                }
+                MetricType::Gauge | MetricType::Unknown => {
+                    metric["gauge"] = json!({ "dataPoints": data_points })
+                }
+            }
+            metric
+        })
+        .collect();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": attributes(resource_attributes.iter().map(|(k, v)| (k.as_str(), v.as_str()))),
+            },
+            "scopeMetrics": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn attributes<'a>(attributes: impl Iterator<Item = (&'a str, &'a str)>) -> Vec<Value> {
+    attributes
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect()
+}
+
+/// The field and value of a data point for a sample value. Integers are encoded
+/// without a decimal point, and values that are not finite are skipped.
+fn value_field(value: &str) -> Option<(&'static str, Value)> {
+    if let Ok(value) = value.parse::<i64>() {
+        Some(("asInt", json!(value.to_string())))
+    } else {
+        match value.parse::<f64>() {
+            Ok(value) if value.is_finite() => Some(("asDouble", json!(value))),
+            _ => None,
+        }
+    }
+}
+
+/// Units of OTLP metrics are given as UCUM codes
+fn ucum_unit(unit: &str) -> &str {
+    match unit {
+        "seconds" => "s",
+        "bytes" => "By",
+        "ratios" => "1",
+        "ppm" => "[ppm]",
+        other => other,
+    }
+}
+
+fn parse_families(text: &str) -> Vec<MetricFamily<'_>> {
+    let mut families: Vec<MetricFamily> = vec![];
+
+    for line in text.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (keyword, name, rest) = match (parts.next(), parts.next()) {
+                (Some(keyword), Some(name)) => (keyword, name, parts.next().unwrap_or("")),
+                _ => continue,
+            };
+            if !matches!(families.last(), Some(family) if family.name == name) {
+                families.push(MetricFamily::new(name));
+            }
+            let family = families.last_mut().unwrap();
+            match keyword {
+                "HELP" => family.help = unescape(rest),
+                "UNIT" => family.unit = Some(rest),
+                "TYPE" => {
+                    family.metric_type = match rest {
+                        "gauge" => MetricType::Gauge,
+                        "counter" => MetricType::Counter,
+                        _ => MetricType::Unknown,
+                    }
+                }
+                _ => {}
+            }
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let (Some(family), Some(sample)) = (families.last_mut(), parse_sample(line)) {
+                family.samples.push(sample);
+            }
+        }
+    }
+
+    families
+}
+
+/// Parse a line like `name{label="value",...} 1.0` into its labels and value
+fn parse_sample(line: &str) -> Option<(Vec<(&str, String)>, &str)> {
+    let (labels, rest) = match line.find('{') {
+        Some(start) => {
+            let mut labels = vec![];
+            let mut rest = &line[start + 1..];
+            while let Some(end_of_name) = rest.find("=\"") {
+                let name = rest[..end_of_name].trim_start_matches(',');
+                let (value, remaining) = split_quoted(&rest[end_of_name + 2..])?;
+                labels.push((name, value));
+                rest = remaining;
+            }
+            (labels, rest.strip_prefix('}')?)
+        }
+        None => (vec![], line.split_once(' ')?.1),
+    };
+
+    let value = rest.split_whitespace().next()?;
+    Some((labels, value))
+}
+
+/// Split an escaped label value from the text after its closing quote
+fn split_quoted(text: &str) -> Option<(String, &str)> {
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some((unescape(&text[..index]), &text[index + 1..])),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            },
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{routing::post, Router};
+    use prometheus_client::metrics::{counter::Counter, family::Family, gauge::Gauge};
+
+    use super::*;
+
+    const TEXT: &str = r#"# HELP ntp_system_offset_seconds Offset of the system.
+# TYPE ntp_system_offset_seconds gauge
+# UNIT ntp_system_offset_seconds seconds
+ntp_system_offset_seconds 0.5
+# HELP ntp_server_received_packets Number of incoming received packets.
+# TYPE ntp_server_received_packets counter
+ntp_server_received_packets_total{listen_address="127.0.0.1:123",name="a \"b\""} 7
+# EOF
+"#;
+
+    #[test]
+    fn test_parse_families() {
+        let families = parse_families(TEXT);
+        assert_eq!(families.len(), 2);
+
+        assert_eq!(families[0].name, "ntp_system_offset_seconds");
+        assert_eq!(families[0].help, "Offset of the system.");
+        assert_eq!(families[0].unit, Some("seconds"));
+        assert_eq!(families[0].metric_type, MetricType::Gauge);
+        assert_eq!(families[0].samples, vec![(vec![], "0.5")]);
+
+        assert_eq!(families[1].metric_type, MetricType::Counter);
+        assert_eq!(
+            families[1].samples,
+            vec![(
+                vec![
+                    ("listen_address", "127.0.0.1:123".to_string()),
+                    ("name", "a \"b\"".to_string())
+                ],
+                "7"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_export_request() {
+        let request = export_request(
+            TEXT,
+            &[("service.name".into(), "ntpd-rs".into())],
+            UNIX_EPOCH,
+            UNIX_EPOCH + Duration::from_secs(1),
+        );
+
+        let resource_metrics = &request["resourceMetrics"][0];
+        assert_eq!(
+            resource_metrics["resource"]["attributes"][0],
+            json!({ "key": "service.name", "value": { "stringValue": "ntpd-rs" } })
+        );
+
+        let metrics = &resource_metrics["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["unit"], "s");
+        assert_eq!(metrics[0]["gauge"]["dataPoints"][0]["asDouble"], 0.5);
+        assert_eq!(
+            metrics[0]["gauge"]["dataPoints"][0]["timeUnixNano"],
+            "1000000000"
+        );
+
+        let sum = &metrics[1]["sum"];
+        assert_eq!(sum["isMonotonic"], true);
+        assert_eq!(sum["dataPoints"][0]["asInt"], "7");
+        assert_eq!(
+            sum["dataPoints"][0]["attributes"][1]["value"]["stringValue"],
+            "a \"b\""
+        );
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let exporter = OtlpExporter::new("http://localhost:4318", vec![]).unwrap();
+        assert_eq!(exporter.endpoint, "http://localhost:4318/v1/metrics");
+
+        let exporter = OtlpExporter::new("http://localhost:4318/custom", vec![]).unwrap();
+        assert_eq!(exporter.endpoint, "http://localhost:4318/custom");
+
+        assert!(OtlpExporter::new("https://localhost:4318", vec![]).is_err());
+        assert!(OtlpExporter::new("localhost", vec![]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        // a stand-in for the collector, which keeps the requests it receives
+        let received = Arc::new(Mutex::new(vec![]));
+        let app = Router::new().route(
+            "/v1/metrics",
+            post({
+                let received = received.clone();
+                move |body: String| async move {
+                    received.lock().unwrap().push(body);
+                    "{}"
+                }
+            }),
+        );
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let address = server.local_addr();
+        let handle = tokio::spawn(server);
+
+        let mut registry = <Registry>::with_prefix("ntp");
+        let gauge: Gauge<f64> = Gauge::default();
+        gauge.set(0.25);
+        registry.register("offset", "Offset", Box::new(gauge));
+        let family: Family<Vec<(String, String)>, Counter> = Family::default();
+        family
+            .get_or_create(&vec![("address".into(), "192.0.2.1:123".into())])
+            .inc();
+        registry.register("packets", "Packets", Box::new(family));
+
+        let exporter = OtlpExporter::new(
+            &format!("http://{}", address),
+            vec![("service.name".into(), "ntpd-rs".into())],
+        )
+        .unwrap();
+        exporter.export(&registry).await.unwrap();
+
+        let received: Vec<String> = received.lock().unwrap().drain(..).collect();
+        assert_eq!(received.len(), 1);
+        let request: Value = serde_json::from_str(&received[0]).unwrap();
+        let metrics = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "ntp_offset");
+        assert_eq!(metrics[0]["gauge"]["dataPoints"][0]["asDouble"], 0.25);
+        assert_eq!(metrics[1]["name"], "ntp_packets");
+        assert_eq!(metrics[1]["sum"]["dataPoints"][0]["asInt"], "1");
+
+        // errors of the collector are reported
+        let exporter = OtlpExporter::new(&format!("http://{}/other", address), vec![]).unwrap();
+        assert!(matches!(
+            exporter.export(&registry).await,
+            Err(OtlpError::Status(StatusCode::NOT_FOUND))
+        ));
+
+        handle.abort();
+    }
+}
@@ -137,6 +137,16 @@ impl NtpClock for UnixNtpClock {
         }
     }
 
+    fn get_freq(&self) -> Result<f64, Self::Error> {
+        let mut ntp_kapi_timex = EMPTY_TIMEX;
+        if unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) } == -1 {
+            return Err(convert_errno());
+        }
+
+        // Convert back from units of 2^-16 ppm, see set_freq
+        Ok(ntp_kapi_timex.freq as f64 / 65536e6)
+    }
+
     fn step_clock(&self, offset: ntp_proto::NtpDuration) -> Result<(), Self::Error> {
         let mut tp = libc::timespec {
             tv_sec: 0,
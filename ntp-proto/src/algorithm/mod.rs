@@ -3,7 +3,8 @@ use std::{fmt::Debug, hash::Hash};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    peer::Measurement, NtpClock, NtpDuration, NtpPacket, NtpTimestamp, SystemConfig, TimeSnapshot,
+    peer::Measurement, ClockState, NtpClock, NtpDuration, NtpPacket, NtpTimestamp, PeerOptions,
+    PeerSelection, SystemConfig, TimeSnapshot,
 };
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -11,11 +12,29 @@ pub struct ObservablePeerTimedata {
     pub offset: NtpDuration,
     pub uncertainty: NtpDuration,
     pub delay: NtpDuration,
+    #[serde(default)]
+    pub jitter: NtpDuration,
 
     pub remote_delay: NtpDuration,
     pub remote_uncertainty: NtpDuration,
 
     pub last_update: NtpTimestamp,
+
+    /// Outcome of the most recent clock selection for this peer
+    #[serde(default)]
+    pub selection: PeerSelection,
+}
+
+/// A single measurement of a peer, as kept in the history of that peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MeasurementRecord {
+    pub offset: NtpDuration,
+    pub delay: NtpDuration,
+    pub dispersion: NtpDuration,
+    pub timestamp: NtpTimestamp,
+    /// Whether the clock filter selected this measurement to update the
+    /// statistics of the peer
+    pub accepted: bool,
 }
 
 pub trait TimeSyncController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
@@ -23,8 +42,9 @@ pub trait TimeSyncController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
     fn new(clock: C, config: SystemConfig) -> Self;
     /// Update used system config
     fn update_config(&mut self, config: SystemConfig);
-    /// Notify the controller that there is a new peer
-    fn peer_add(&mut self, id: PeerID);
+    /// Notify the controller that there is a new peer, which is used for
+    /// synchronization according to the given options
+    fn peer_add(&mut self, id: PeerID, options: PeerOptions);
     /// Notify the controller that a previous peer has gone
     fn peer_remove(&mut self, id: PeerID);
     /// Notify the controller that the status of a peer (whether
@@ -41,6 +61,10 @@ pub trait TimeSyncController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
     ) -> Option<(Vec<PeerID>, TimeSnapshot)>;
     /// Get a snapshot of the timekeeping state of a peer.
     fn peer_snapshot(&self, id: PeerID) -> Option<ObservablePeerTimedata>;
+    /// Get the most recent measurements of a peer, oldest first
+    fn peer_history(&self, id: PeerID) -> Vec<MeasurementRecord>;
+    /// Get the current state of the clock discipline.
+    fn clock_state(&self) -> ClockState;
 }
 
 mod standard;
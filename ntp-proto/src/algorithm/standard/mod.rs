@@ -1,15 +1,22 @@
-use std::{collections::HashMap, fmt::Debug, hash::Hash};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+};
 
 use tracing::{error, info};
 
 use crate::{
-    filter::LastMeasurements,
     peer::{Measurement, PeerTimeState},
-    ClockController, ClockUpdateResult, FilterAndCombine, NtpClock, NtpDuration, NtpInstant,
-    ObservablePeerTimedata, PeerTimeSnapshot, SystemConfig, TimeSnapshot,
+    ClockController, ClockState, ClockUpdateResult, FilterAndCombine, NtpClock, NtpDuration,
+    NtpInstant, ObservablePeerTimedata, PeerOptions, PeerSelection, PeerTimeSnapshot, SystemConfig,
+    TimeSnapshot,
 };
 
-use super::TimeSyncController;
+use super::{MeasurementRecord, TimeSyncController};
+
+/// Number of measurements kept in the history of each peer
+const HISTORY_SIZE: usize = 64;
 
 #[derive(Debug)]
 pub struct StandardClockController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> {
@@ -24,7 +31,10 @@ pub struct StandardClockController<C: NtpClock, PeerID: Hash + Eq + Copy + Debug
 #[derive(Debug, Clone)]
 struct ControllerPeerState {
     timestate: PeerTimeState,
+    options: PeerOptions,
     usable: bool,
+    selection: PeerSelection,
+    history: VecDeque<MeasurementRecord>,
 }
 
 impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> StandardClockController<C, PeerID> {
@@ -45,16 +55,34 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> StandardClockController<C, P
             self.timestate,
             &self.config,
         );
+
+        if current_peerstate.history.len() == HISTORY_SIZE {
+            current_peerstate.history.pop_front();
+        }
+        current_peerstate.history.push_back(MeasurementRecord {
+            offset: measurement.offset,
+            delay: measurement.delay,
+            dispersion: current_peerstate
+                .timestate
+                .last_measurements
+                .newest_dispersion(),
+            timestamp: measurement.localtime,
+            accepted: update_result.is_some(),
+        });
+
         update_result.is_none()
             || !current_peerstate.usable
-            || PeerTimeSnapshot::from_timestate(&current_peerstate.timestate)
-                .accept_synchronization(
-                    now,
-                    self.config.frequency_tolerance,
-                    self.config.distance_threshold,
-                    self.timestate.poll_interval,
-                )
-                .is_err()
+            || PeerTimeSnapshot::from_timestate(
+                &current_peerstate.timestate,
+                current_peerstate.options,
+            )
+            .accept_synchronization(
+                now,
+                self.config.frequency_tolerance,
+                self.config.distance_threshold,
+                self.timestate.poll_interval,
+            )
+            .is_err()
     }
 
     fn recalculate_clock(&mut self, now: NtpInstant) -> Option<(Vec<PeerID>, TimeSnapshot)> {
@@ -62,12 +90,27 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> StandardClockController<C, P
             .peerstate
             .iter()
             .filter_map(|(index, state)| match state.usable {
-                true => Some((*index, PeerTimeSnapshot::from_timestate(&state.timestate))),
+                true => Some((
+                    *index,
+                    PeerTimeSnapshot::from_timestate(&state.timestate, state.options),
+                )),
                 false => None,
             })
             .collect();
-        let result =
-            FilterAndCombine::run(&self.config, &snapshots, now, self.timestate.poll_interval);
+        let (result, peer_selection) = FilterAndCombine::run_with_selection(
+            &self.config,
+            &snapshots,
+            now,
+            self.timestate.poll_interval,
+        );
+        for state in self.peerstate.values_mut() {
+            state.selection = PeerSelection::Unusable;
+        }
+        for (id, selection) in peer_selection {
+            if let Some(state) = self.peerstate.get_mut(&id) {
+                state.selection = selection;
+            }
+        }
         let clock_select = match result {
             Some(clock_select) => clock_select,
             None => {
@@ -106,9 +149,23 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> StandardClockController<C, P
         if adjust_type != ClockUpdateResult::Ignore {
             self.timestate.poll_interval = self.controller.preferred_poll_interval();
             self.timestate.leap_indicator = clock_select.system_peer_snapshot.1.leap_indicator;
+            self.timestate.offset = clock_select.system_offset;
+            self.timestate.jitter = self.controller.jitter();
+            self.timestate.frequency = match self.clock.get_freq() {
+                Ok(frequency) => frequency,
+                Err(e) => {
+                    error!(error = %e, "Could not read clock frequency");
+                    self.timestate.frequency
+                }
+            };
             self.timestate.accumulated_steps = self.controller.accumulated_steps();
             self.timestate.root_delay = clock_select.system_root_delay;
             self.timestate.root_dispersion = clock_select.system_root_dispersion;
+            self.timestate.slew_remaining = self.controller.slew_remaining();
+            if let Some(remaining) = self.timestate.slew_remaining {
+                let remaining_s = remaining.to_seconds();
+                info!(remaining_s, "Estimated time until offset is slewed away");
+            }
 
             Some((vec![clock_select.system_peer_snapshot.0], self.timestate))
         } else {
@@ -136,18 +193,15 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
         self.config = config;
     }
 
-    fn peer_add(&mut self, id: PeerID) {
-        let time = NtpInstant::now();
+    fn peer_add(&mut self, id: PeerID, options: PeerOptions) {
         self.peerstate.insert(
             id,
             ControllerPeerState {
-                timestate: PeerTimeState {
-                    statistics: Default::default(),
-                    last_measurements: LastMeasurements::new(time),
-                    last_packet: Default::default(),
-                    time,
-                },
+                timestate: PeerTimeState::new(NtpInstant::now()),
+                options,
                 usable: false,
+                selection: PeerSelection::Unusable,
+                history: VecDeque::with_capacity(HISTORY_SIZE),
             },
         );
     }
@@ -188,16 +242,126 @@ impl<C: NtpClock, PeerID: Hash + Eq + Copy + Debug> TimeSyncController<C, PeerID
     fn peer_snapshot(&self, id: PeerID) -> Option<ObservablePeerTimedata> {
         self.peerstate
             .get(&id)
-            .map(|state| PeerTimeSnapshot::from_timestate(&state.timestate))
-            .map(|snapshot| ObservablePeerTimedata {
+            .map(|state| {
+                (
+                    PeerTimeSnapshot::from_timestate(&state.timestate, state.options),
+                    state.selection,
+                )
+            })
+            .map(|(snapshot, selection)| ObservablePeerTimedata {
                 offset: snapshot.statistics.offset,
                 uncertainty: snapshot.statistics.dispersion
                     + NtpDuration::from_seconds(snapshot.statistics.jitter),
                 delay: snapshot.statistics.delay,
+                jitter: NtpDuration::from_seconds(snapshot.statistics.jitter),
                 remote_delay: snapshot.root_delay,
                 remote_uncertainty: snapshot.root_dispersion,
                 last_update: self.clock.now().expect("Unable to get current time")
                     + NtpDuration::from_system_duration(snapshot.time.elapsed()),
+                selection,
             })
     }
+
+    fn peer_history(&self, id: PeerID) -> Vec<MeasurementRecord> {
+        self.peerstate
+            .get(&id)
+            .map(|state| state.history.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn clock_state(&self) -> ClockState {
+        self.controller.state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{NtpLeapIndicator, NtpPacket, NtpTimestamp, PollInterval};
+
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    struct TestClock;
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(NtpTimestamp::default())
+        }
+
+        fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            Ok(0.0)
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn update_clock(
+            &self,
+            _offset: NtpDuration,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+            _poll_interval: PollInterval,
+            _leap_status: NtpLeapIndicator,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn measurement(base: NtpInstant, seconds: u64, delay: f64) -> Measurement {
+        Measurement {
+            delay: NtpDuration::from_seconds(delay),
+            offset: NtpDuration::from_seconds(0.001),
+            localtime: NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds as u32, 0),
+            monotime: base + Duration::from_secs(seconds),
+        }
+    }
+
+    #[test]
+    fn test_peer_history() {
+        let mut controller: StandardClockController<TestClock, usize> =
+            StandardClockController::new(TestClock, SystemConfig::default());
+        // the filter only rejects measurements once synchronized
+        controller.timestate.leap_indicator = NtpLeapIndicator::NoWarning;
+        controller.peer_add(0, PeerOptions::default());
+        assert!(controller.peer_history(0).is_empty());
+
+        let base = NtpInstant::now();
+        controller.peer_measurement(0, measurement(base, 1, 0.010), NtpPacket::test());
+        // a larger delay than the previous measurement is not used by the filter
+        controller.peer_measurement(0, measurement(base, 2, 0.020), NtpPacket::test());
+
+        let history = controller.peer_history(0);
+        assert_eq!(history.len(), 2);
+        assert!(history[0].accepted);
+        assert!(!history[1].accepted);
+        assert_eq!(history[1].delay, NtpDuration::from_seconds(0.020));
+        assert_eq!(
+            history[1].timestamp,
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(2, 0)
+        );
+        assert!(history[1].dispersion > NtpDuration::ZERO);
+
+        // only the most recent measurements are kept
+        for seconds in 3..(HISTORY_SIZE as u64 + 10) {
+            controller.peer_measurement(0, measurement(base, seconds, 0.001), NtpPacket::test());
+        }
+        let history = controller.peer_history(0);
+        assert_eq!(history.len(), HISTORY_SIZE);
+        assert_eq!(
+            history.last().unwrap().timestamp,
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(HISTORY_SIZE as u32 + 9, 0)
+        );
+
+        controller.peer_remove(0);
+        assert!(controller.peer_history(0).is_empty());
+    }
 }
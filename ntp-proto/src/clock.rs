@@ -2,11 +2,16 @@ use crate::{
     packet::NtpLeapIndicator, time_types::PollInterval, NtpDuration, NtpInstant, NtpTimestamp,
     SystemConfig, TimeSnapshot,
 };
-use tracing::{debug, error, info, instrument, trace};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, instrument, trace, warn};
 
 /// Jitter averaging factor
 const JITTER_AVG: f64 = 4.;
 
+/// Largest frequency correction the kernel accepts, larger values are
+/// silently clamped to this
+const MAX_FREQUENCY: f64 = 500e-6;
+
 /// Interface for a clock settable by the ntp implementation.
 /// This needs to be a trait as a single system can have multiple clocks
 /// which need different implementation for steering and/or now.
@@ -16,6 +21,9 @@ pub trait NtpClock: Clone + Send + 'static {
     fn now(&self) -> Result<NtpTimestamp, Self::Error>;
 
     fn set_freq(&self, freq: f64) -> Result<(), Self::Error>;
+    /// Frequency correction currently applied to the clock, in the same
+    /// unit as for [`NtpClock::set_freq`]
+    fn get_freq(&self) -> Result<f64, Self::Error>;
     fn step_clock(&self, offset: NtpDuration) -> Result<(), Self::Error>;
     fn update_clock(
         &self,
@@ -27,15 +35,24 @@ pub trait NtpClock: Clone + Send + 'static {
     ) -> Result<(), Self::Error>;
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum ClockState {
+/// State of the clock discipline
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClockState {
+    /// No offset has been measured yet
+    #[default]
     StartupBlank,
     // Needed when implementing frequency backups
     #[allow(dead_code)]
     StartupFreq,
+    /// Measuring the frequency error of the clock after the initial offset was corrected
     MeasureFreq,
+    /// Ignoring a large offset, until it persists for long enough
     Spike,
+    /// Synchronized, steering the clock
     Sync,
+    /// Slewing away a large offset, as stepping is disabled
+    Slewing,
 }
 
 /// Controller responsible for actually
@@ -52,6 +69,10 @@ pub struct ClockController<C: NtpClock> {
     offset: NtpDuration,
     jitter: NtpDuration,
     accumulated_steps: NtpDuration,
+    /// Frequency of the clock before we started slewing away a large offset
+    slew_base_freq: Option<f64>,
+    /// Frequency of the clock while slewing, within the kernel limit
+    slew_freq: Option<f64>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -79,6 +100,8 @@ impl<C: NtpClock> ClockController<C> {
             offset: NtpDuration::ZERO,
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         }
     }
 
@@ -140,7 +163,11 @@ impl<C: NtpClock> ClockController<C> {
                     }
 
                     self.set_freq(offset, last_peer_update);
-                    return self.do_step(offset, last_peer_update, system.precision, config);
+                    if config.slew_only {
+                        self.start_slew(config, offset, last_peer_update);
+                    } else {
+                        return self.do_step(offset, last_peer_update, system.precision, config);
+                    }
                 }
                 ClockState::Spike => {
                     if NtpInstant::abs_diff(last_peer_update, self.last_update_time)
@@ -153,8 +180,17 @@ impl<C: NtpClock> ClockController<C> {
 
                     // Seems that the large difference reflects reality, since
                     // it persisted for a significant amount of time. So step
-                    // the clock
-                    return self.do_step(offset, last_peer_update, system.precision, config);
+                    // the clock, or slew if we are not allowed to step
+                    if config.slew_only {
+                        self.start_slew(config, offset, last_peer_update);
+                    } else {
+                        return self.do_step(offset, last_peer_update, system.precision, config);
+                    }
+                }
+                ClockState::Slewing => {
+                    // Still working on a previously detected large offset,
+                    // so no need for spike detection
+                    self.start_slew(config, offset, last_peer_update);
                 }
                 ClockState::StartupBlank | ClockState::StartupFreq => {
                     // In fully non-synchronized states, doing the jump
@@ -188,8 +224,12 @@ impl<C: NtpClock> ClockController<C> {
                     self.last_update_time = last_peer_update;
                     self.state = ClockState::Sync;
                 }
-                ClockState::StartupFreq | ClockState::Sync | ClockState::Spike => {
+                ClockState::StartupFreq
+                | ClockState::Sync
+                | ClockState::Spike
+                | ClockState::Slewing => {
                     // Just make the small adjustment needed, we are good
+                    self.stop_slew();
 
                     // Since we currently only support the kernel api interface,
                     // we do not need to calculate frequency changes here, the
@@ -211,10 +251,19 @@ impl<C: NtpClock> ClockController<C> {
             }
         }
 
+        // When slewing away a large offset, the frequency offset set by
+        // start_slew does the correcting. Handing the offset to the kernel
+        // as well would let its PLL correct at a rate we do not control.
+        let adjustment = if self.state == ClockState::Slewing {
+            NtpDuration::ZERO
+        } else {
+            self.offset
+        };
+
         // It is reasonable to panic here, as there is very little we can
         // be expected to do if the clock is not amenable to change
         let result = self.clock.update_clock(
-            self.offset,
+            adjustment,
             self.jitter,
             root_delay / 2 + root_dispersion,
             self.preferred_poll_interval,
@@ -273,6 +322,10 @@ impl<C: NtpClock> ClockController<C> {
         self.accumulated_steps
     }
 
+    pub fn state(&self) -> ClockState {
+        self.state
+    }
+
     pub fn offset(&self) -> NtpDuration {
         self.offset
     }
@@ -281,6 +334,20 @@ impl<C: NtpClock> ClockController<C> {
         self.jitter
     }
 
+    /// Estimate of the time needed to slew away the current offset, if
+    /// we are correcting a large offset without stepping.
+    pub fn slew_remaining(&self) -> Option<NtpDuration> {
+        if self.state != ClockState::Slewing {
+            return None;
+        }
+
+        // The kernel may have limited the rate below max_slew_rate
+        let rate = (self.slew_freq? - self.slew_base_freq?).abs();
+        Some(NtpDuration::from_seconds(
+            self.offset.abs().to_seconds() / rate,
+        ))
+    }
+
     fn offset_too_large(&self, config: &SystemConfig, offset: NtpDuration) -> bool {
         let threshold = match self.state {
             // The system might be wildly off on startup
@@ -289,19 +356,7 @@ impl<C: NtpClock> ClockController<C> {
             _ => config.panic_threshold,
         };
 
-        let forward_ok = if let Some(forward) = threshold.forward {
-            offset < forward
-        } else {
-            true
-        };
-
-        let backward_ok = if let Some(backward) = threshold.backward {
-            offset > -backward
-        } else {
-            true
-        };
-
-        !(forward_ok && backward_ok)
+        !threshold.is_within(offset)
     }
 
     fn combined_steps_too_large(&self, config: &SystemConfig, offset: NtpDuration) -> bool {
@@ -350,6 +405,69 @@ impl<C: NtpClock> ClockController<C> {
         ClockUpdateResult::Step
     }
 
+    /// Correct a large offset by running the clock fast or slow by the
+    /// maximum slew rate, on top of its normal frequency correction, as far
+    /// as the kernel allows
+    fn start_slew(
+        &mut self,
+        config: &SystemConfig,
+        offset: NtpDuration,
+        last_peer_update: NtpInstant,
+    ) {
+        let base_freq = match self.slew_base_freq {
+            Some(base_freq) => base_freq,
+            None => {
+                info!(
+                    offset = debug(offset),
+                    "Stepping disabled, slewing away large offset"
+                );
+                match self.clock.get_freq() {
+                    Ok(base_freq) => base_freq,
+                    Err(e) => {
+                        error!(error = %e, "Unable to read clock frequency, exiting");
+                        std::process::exit(exitcode::NOPERM);
+                    }
+                }
+            }
+        };
+
+        let rate = (NtpDuration::ONE * config.max_slew_rate).to_seconds();
+        let freq = if offset.to_seconds() < 0.0 {
+            base_freq - rate
+        } else {
+            base_freq + rate
+        }
+        .clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        if freq == base_freq {
+            warn!(
+                freq = display(base_freq),
+                "Clock frequency at the kernel limit, unable to slew away large offset"
+            );
+        }
+        if let Err(e) = self.clock.set_freq(freq) {
+            error!(error = %e, "Unable to adjust clock frequency, exiting");
+            std::process::exit(exitcode::NOPERM);
+        }
+
+        self.slew_base_freq = Some(base_freq);
+        self.slew_freq = Some(freq);
+        self.offset = offset;
+        self.last_update_time = last_peer_update;
+        self.state = ClockState::Slewing;
+    }
+
+    /// Return to the normal frequency correction once a slew has finished
+    fn stop_slew(&mut self) {
+        self.slew_freq = None;
+        if let Some(base_freq) = self.slew_base_freq.take() {
+            info!("Large offset slewed away");
+            if let Err(e) = self.clock.set_freq(base_freq) {
+                error!(error = %e, "Unable to adjust clock frequency, exiting");
+                std::process::exit(exitcode::NOPERM);
+            }
+        }
+    }
+
     fn set_freq(&mut self, offset: NtpDuration, last_peer_update: NtpInstant) {
         info!(
             freq = display(
@@ -371,7 +489,7 @@ impl<C: NtpClock> ClockController<C> {
 
 #[cfg(test)]
 mod tests {
-    use crate::time_types::PollIntervalLimits;
+    use crate::time_types::{FrequencyTolerance, PollIntervalLimits};
 
     use super::*;
     use core::cell::RefCell;
@@ -399,6 +517,10 @@ mod tests {
             Ok(())
         }
 
+        fn get_freq(&self) -> Result<f64, Self::Error> {
+            Ok(self.last_freq.borrow().unwrap_or_default())
+        }
+
         fn step_clock(&self, offset: NtpDuration) -> Result<(), Self::Error> {
             *self.last_offset.borrow_mut() = Some(offset);
             Ok(())
@@ -437,6 +559,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         let ref_interval = controller.preferred_poll_interval;
@@ -555,6 +679,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         controller.update(
@@ -589,6 +715,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         controller.update(
@@ -636,6 +764,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         controller.update(
@@ -668,6 +798,187 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_slew_only() {
+        let base = NtpInstant::now();
+        let config = SystemConfig {
+            slew_only: true,
+            max_slew_rate: FrequencyTolerance::ppm(400),
+            ..Default::default()
+        };
+        let system = TimeSnapshot::default();
+
+        let base_freq = 12e-6;
+        let clock = TestClock::default();
+        clock.set_freq(base_freq).unwrap();
+
+        let mut controller = ClockController {
+            clock,
+            state: ClockState::Sync,
+            last_update_time: base,
+            preferred_poll_interval: PollIntervalLimits::default().min,
+            poll_interval_counter: 0,
+            offset: NtpDuration::from_fixed_int(0),
+            jitter: system.precision,
+            accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
+        };
+
+        assert_eq!(
+            controller.update(
+                &config,
+                &system,
+                NtpDuration::from_seconds(10.),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(1),
+            ),
+            ClockUpdateResult::Ignore
+        );
+        assert_eq!(controller.state, ClockState::Spike);
+        assert_eq!(controller.slew_remaining(), None);
+
+        assert_eq!(
+            controller.update(
+                &config,
+                &system,
+                NtpDuration::from_seconds(10.),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(902),
+            ),
+            ClockUpdateResult::Slew
+        );
+        assert_eq!(controller.state, ClockState::Slewing);
+        assert_eq!(controller.accumulated_steps, NtpDuration::ZERO);
+
+        // The clock runs fast at 400 ppm, up to the resolution of NtpDuration,
+        // and the kernel PLL does not get to correct the offset at a rate
+        // of its own
+        let rate = controller.clock.last_freq.borrow().unwrap() - base_freq;
+        assert!((rate - 400e-6).abs() < 1e-9);
+        assert_eq!(
+            *controller.clock.last_offset.borrow(),
+            Some(NtpDuration::ZERO)
+        );
+
+        let remaining = controller.slew_remaining().unwrap().to_seconds();
+        assert!((remaining - 25000.).abs() < 1.);
+
+        // Continued large offsets are slewed without spike detection, the
+        // rate is relative to the frequency from before the slew
+        assert_eq!(
+            controller.update(
+                &config,
+                &system,
+                NtpDuration::from_seconds(-9.),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(903),
+            ),
+            ClockUpdateResult::Slew
+        );
+        assert_eq!(controller.state, ClockState::Slewing);
+        let rate = controller.clock.last_freq.borrow().unwrap() - base_freq;
+        assert!((rate + 400e-6).abs() < 1e-9);
+        assert_eq!(
+            *controller.clock.last_offset.borrow(),
+            Some(NtpDuration::ZERO)
+        );
+
+        // Once the offset is small, the frequency is restored and normal
+        // synchronization resumes
+        assert_eq!(
+            controller.update(
+                &config,
+                &system,
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(904),
+            ),
+            ClockUpdateResult::Slew
+        );
+        assert_eq!(controller.state, ClockState::Sync);
+        assert_eq!(*controller.clock.last_freq.borrow(), Some(base_freq));
+        assert_eq!(
+            *controller.clock.last_offset.borrow(),
+            Some(NtpDuration::from_seconds(0.01))
+        );
+        assert_eq!(controller.slew_remaining(), None);
+    }
+
+    #[test]
+    fn test_slew_kernel_limit() {
+        let base = NtpInstant::now();
+        let config = SystemConfig {
+            slew_only: true,
+            ..Default::default()
+        };
+        let system = TimeSnapshot::default();
+
+        let base_freq = 300e-6;
+        let clock = TestClock::default();
+        clock.set_freq(base_freq).unwrap();
+
+        let mut controller = ClockController {
+            clock,
+            state: ClockState::Spike,
+            last_update_time: base,
+            preferred_poll_interval: PollIntervalLimits::default().min,
+            poll_interval_counter: 0,
+            offset: NtpDuration::from_fixed_int(0),
+            jitter: system.precision,
+            accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
+        };
+
+        assert_eq!(
+            controller.update(
+                &config,
+                &system,
+                NtpDuration::from_seconds(10.),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(901),
+            ),
+            ClockUpdateResult::Slew
+        );
+        assert_eq!(controller.state, ClockState::Slewing);
+
+        // The frequency stays within what the kernel accepts, so the clock
+        // only runs 200 ppm fast and the estimate accounts for that
+        let freq = controller.clock.last_freq.borrow().unwrap();
+        assert!((freq - 500e-6).abs() < 1e-9);
+        let remaining = controller.slew_remaining().unwrap().to_seconds();
+        assert!((remaining - 50000.).abs() < 1.);
+
+        // Slewing the other way has the full rate available
+        assert_eq!(
+            controller.update(
+                &config,
+                &system,
+                NtpDuration::from_seconds(-10.),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(902),
+            ),
+            ClockUpdateResult::Slew
+        );
+        let freq = controller.clock.last_freq.borrow().unwrap();
+        assert!((freq + 200e-6).abs() < 1e-9);
+        let remaining = controller.slew_remaining().unwrap().to_seconds();
+        assert!((remaining - 20000.).abs() < 1.);
+    }
+
     #[test]
     fn test_accumulated_excess_detection() {
         let base = NtpInstant::now();
@@ -686,6 +997,8 @@ mod tests {
             offset: NtpDuration::ZERO,
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -741,6 +1054,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -827,6 +1142,8 @@ mod tests {
             offset: NtpDuration::from_seconds(2e-3),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -910,6 +1227,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -934,6 +1253,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -958,6 +1279,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -982,6 +1305,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -1006,6 +1331,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -1030,6 +1357,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -1054,6 +1383,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
@@ -1078,6 +1409,8 @@ mod tests {
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
             accumulated_steps: NtpDuration::ZERO,
+            slew_base_freq: None,
+            slew_freq: None,
         };
 
         assert_eq!(
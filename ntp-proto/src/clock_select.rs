@@ -4,8 +4,28 @@ use std::hash::Hash;
 use crate::peer::PeerTimeSnapshot;
 use crate::time_types::{FrequencyTolerance, NtpInstant};
 use crate::{NtpDuration, PollInterval, SystemConfig};
+use serde::{Deserialize, Serialize};
 use tracing::{debug, instrument, trace, warn};
 
+/// How a peer fared in the most recent run of the clock selection algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PeerSelection {
+    /// Not usable for synchronization, e.g. because it is unreachable, too far
+    /// away or marked `noselect`
+    #[default]
+    Unusable,
+    /// Outside of the interval the majority of peers agrees on, or there is no
+    /// such majority
+    Falseticker,
+    /// Discarded by the clustering algorithm
+    Outlier,
+    /// Combined into the system offset
+    Candidate,
+    /// The system peer
+    Selected,
+}
+
 #[derive(Debug, Clone)]
 pub struct FilterAndCombine<PeerID: Hash + Eq + Copy + Debug> {
     pub system_offset: NtpDuration,
@@ -23,11 +43,27 @@ impl<PeerID: Hash + Eq + Copy + Debug> FilterAndCombine<PeerID> {
         local_clock_time: NtpInstant,
         system_poll: PollInterval,
     ) -> Option<Self> {
-        let selection = clock_select(config, peers, local_clock_time, system_poll)?;
+        Self::run_with_selection(config, peers, local_clock_time, system_poll).0
+    }
+
+    /// Like [`FilterAndCombine::run`], but also reports how each of the peers
+    /// fared in clock selection
+    pub fn run_with_selection(
+        config: &SystemConfig,
+        peers: &[(PeerID, PeerTimeSnapshot)],
+        local_clock_time: NtpInstant,
+        system_poll: PollInterval,
+    ) -> (Option<Self>, Vec<(PeerID, PeerSelection)>) {
+        let (selection, peer_selection) =
+            clock_select(config, peers, local_clock_time, system_poll);
+        let selection = match selection {
+            Some(selection) => selection,
+            None => return (None, peer_selection),
+        };
 
-        // the clustering algorithm (part of `clock_select`) sorts the peers, best peer first.
-        // the first (and best) peer is chosen as the system peer, and its variables are used
-        // to update the system variables.
+        // the clustering algorithm (part of `clock_select`) sorts the peers, best peer first,
+        // with preferred peers ahead of all others. The first (and best) peer is chosen as the
+        // system peer, and its variables are used to update the system variables.
         //
         // NOTE: the code skeleton checks whether the current system peer is in the survivor list. If
         // so, it keeps that peer as the system peer rather selecting the now-best peer (something
@@ -54,13 +90,15 @@ impl<PeerID: Hash + Eq + Copy + Debug> FilterAndCombine<PeerID> {
                     + combined.system_offset.abs(),
             );
 
-        Some(FilterAndCombine {
+        let result = FilterAndCombine {
             system_offset: combined.system_offset,
             system_jitter: combined.system_jitter,
             system_root_delay: root_delay,
             system_root_dispersion: root_dispersion,
             system_peer_snapshot: *selection.survivors[0].peer,
-        })
+        };
+
+        (Some(result), peer_selection)
     }
 
     pub fn system_root_delay(&self) -> NtpDuration {
@@ -127,34 +165,82 @@ fn clock_select<'a, PeerID: Hash + Eq + Copy + Debug>(
     peers: &'a [(PeerID, PeerTimeSnapshot)],
     local_clock_time: NtpInstant,
     system_poll: PollInterval,
-) -> Option<ClockSelect<'a, PeerID>> {
+) -> (
+    Option<ClockSelect<'a, PeerID>>,
+    Vec<(PeerID, PeerSelection)>,
+) {
+    let mut peer_selection: Vec<_> = peers
+        .iter()
+        .map(|peer| (peer.0, PeerSelection::Unusable))
+        .collect();
+
+    // peers marked noselect are only monitored, never used for synchronization
     let valid_associations = peers.iter().filter(|p| {
-        p.1.accept_synchronization(
-            local_clock_time,
-            config.frequency_tolerance,
-            config.distance_threshold,
-            system_poll,
-        )
-        .is_ok()
+        !p.1.options.noselect
+            && p.1
+                .accept_synchronization(
+                    local_clock_time,
+                    config.frequency_tolerance,
+                    config.distance_threshold,
+                    system_poll,
+                )
+                .is_ok()
     });
 
     let candidates = construct_candidate_list(config, valid_associations, local_clock_time);
+    mark_selection(
+        &mut peer_selection,
+        candidates.iter().map(|candidate| candidate.peer.0),
+        PeerSelection::Falseticker,
+    );
 
     let mut survivors = construct_survivors(config, &candidates, local_clock_time);
 
     trace!(survivors = debug(&survivors));
     if survivors.len() < config.min_intersection_survivors {
         warn!("No clique of peers that agree on the current time.");
-        return None;
+        return (None, peer_selection);
     }
+    mark_selection(
+        &mut peer_selection,
+        survivors.iter().map(|survivor| survivor.peer.0),
+        PeerSelection::Outlier,
+    );
 
     let system_selection_jitter =
         NtpDuration::from_seconds(cluster_algorithm(config, &mut survivors));
+    mark_selection(
+        &mut peer_selection,
+        survivors.iter().map(|survivor| survivor.peer.0),
+        PeerSelection::Candidate,
+    );
+    mark_selection(
+        &mut peer_selection,
+        survivors.iter().take(1).map(|survivor| survivor.peer.0),
+        PeerSelection::Selected,
+    );
 
-    Some(ClockSelect {
-        survivors,
-        system_selection_jitter,
-    })
+    (
+        Some(ClockSelect {
+            survivors,
+            system_selection_jitter,
+        }),
+        peer_selection,
+    )
+}
+
+fn mark_selection<PeerID: Eq>(
+    peer_selection: &mut [(PeerID, PeerSelection)],
+    peers: impl IntoIterator<Item = PeerID>,
+    status: PeerSelection,
+) {
+    for peer in peers {
+        for (id, selection) in peer_selection.iter_mut() {
+            if *id == peer {
+                *selection = status;
+            }
+        }
+    }
 }
 
 /// Observation: Chrony (sources.c, SRC_SelectSource, line ~920) does not use the Middle tag
@@ -225,30 +311,33 @@ fn construct_survivors<'a, PeerID: Hash + Eq + Copy + Debug>(
     chime_list: &[CandidateTuple<'a, PeerID>],
     local_clock_time: NtpInstant,
 ) -> Vec<SurvivorTuple<'a, PeerID>> {
-    match find_interval(chime_list) {
-        Some((low, high)) => chime_list
-            .iter()
-            .filter_map(|candidate| filter_survivor(config, candidate, local_clock_time, low, high))
-            .collect(),
-        None => vec![],
-    }
+    let interval = find_interval(chime_list);
+
+    chime_list
+        .iter()
+        .filter_map(|candidate| filter_survivor(config, candidate, local_clock_time, interval))
+        .collect()
 }
 
 fn filter_survivor<'a, PeerID: Hash + Eq + Copy + Debug>(
     config: &SystemConfig,
     candidate: &CandidateTuple<'a, PeerID>,
     local_clock_time: NtpInstant,
-    low: NtpDuration,
-    high: NtpDuration,
+    interval: Option<(NtpDuration, NtpDuration)>,
 ) -> Option<SurvivorTuple<'a, PeerID>> {
     // To be a truechimer, a peers middle (actual offset)
     // needs to lie within the consistency interval.
     // Note: The standard is unclear on this, but this
     // is what gives sensible results in combination with
     // how interval selection works.
-    if candidate.edge < low
-        || candidate.edge > high
-        || candidate.endpoint_type != EndpointType::Middle
+    let within_interval = match interval {
+        Some((low, high)) => low <= candidate.edge && candidate.edge <= high,
+        None => false,
+    };
+
+    // Trusted peers are truechimers regardless of the interval
+    if candidate.endpoint_type != EndpointType::Middle
+        || !(within_interval || candidate.peer.1.options.trust)
     {
         None
     } else {
@@ -335,6 +424,7 @@ fn find_interval<PeerID: Hash + Eq + Copy + Debug>(
 }
 
 /// Discard the survivor with maximum selection jitter until a termination condition is met.
+/// Preferred peers are never discarded, and are sorted ahead of the other survivors.
 ///
 /// returns the (maximum) selection jitter
 #[instrument]
@@ -342,8 +432,12 @@ fn cluster_algorithm<PeerID: Hash + Eq + Copy + Debug>(
     config: &SystemConfig,
     candidates: &mut Vec<SurvivorTuple<PeerID>>,
 ) -> f64 {
-    // sort the candidates by increasing lambda_p (the merit factor)
-    candidates.sort_by(|a, b| a.metric.cmp(&b.metric));
+    // sort the candidates by increasing lambda_p (the merit factor), preferred peers first
+    candidates.sort_by(|a, b| {
+        let a_key = (!a.peer.1.options.prefer, a.metric);
+        let b_key = (!b.peer.1.options.prefer, b.metric);
+        a_key.cmp(&b_key)
+    });
 
     loop {
         // the lowest jitter of any candidate peer
@@ -353,7 +447,10 @@ fn cluster_algorithm<PeerID: Hash + Eq + Copy + Debug>(
         // the candidate with the max_selection_jitter is the worst candidate
         // we have seen so far, it's offset is most unlike the others.
         let mut max_selection_jitter = -2.0e9;
-        let mut max_selection_jitter_index = 0;
+
+        // the candidate to discard: the one with the highest selection jitter
+        // among those that are not preferred
+        let mut discard: Option<(usize, f64)> = None;
 
         for (index, candidate) in candidates.iter().enumerate() {
             let p = candidate.peer;
@@ -373,9 +470,14 @@ fn cluster_algorithm<PeerID: Hash + Eq + Copy + Debug>(
                 (selection_jitter_sum / ((candidates.len() - 1) as f64)).sqrt()
             };
 
-            if selection_jitter > max_selection_jitter {
-                max_selection_jitter_index = index;
-                max_selection_jitter = selection_jitter;
+            max_selection_jitter = f64::max(max_selection_jitter, selection_jitter);
+
+            let is_worse = match discard {
+                Some((_, worst)) => selection_jitter > worst,
+                None => true,
+            };
+            if !p.1.options.prefer && is_worse {
+                discard = Some((index, selection_jitter));
             }
         }
 
@@ -393,20 +495,23 @@ fn cluster_algorithm<PeerID: Hash + Eq + Copy + Debug>(
         // if the number of survivors is less than or equal to NMIN (3).
         let too_few_survivors = candidates.len() <= config.min_cluster_survivors;
 
-        if removed_bad_candidates || too_few_survivors {
-            // the final version of max_selection_jitter (psi_max in the spec) is
-            // stored under the name "system selection jitter" (PSI_s)
-
-            // Jitter is defined as the root-mean-square (RMS) average of the most recent offset differences
-            // RMS always produces a positive number, but our `max_selection_jitter` is negative.
-            // In the case of 0 candidates, bound max_selection_jitter from below
-            debug!(
-                selection_jitter = debug(max_selection_jitter),
-                survivors = debug(candidates.len()),
-                "Clustering completed"
-            );
-            return f64::max(0.0, max_selection_jitter);
-        }
+        let max_selection_jitter_index = match discard {
+            Some((index, _)) if !(removed_bad_candidates || too_few_survivors) => index,
+            _ => {
+                // the final version of max_selection_jitter (psi_max in the spec) is
+                // stored under the name "system selection jitter" (PSI_s)
+
+                // Jitter is defined as the root-mean-square (RMS) average of the most recent offset differences
+                // RMS always produces a positive number, but our `max_selection_jitter` is negative.
+                // In the case of 0 candidates, bound max_selection_jitter from below
+                debug!(
+                    selection_jitter = debug(max_selection_jitter),
+                    survivors = debug(candidates.len()),
+                    "Clustering completed"
+                );
+                return f64::max(0.0, max_selection_jitter);
+            }
+        };
 
         // delete the survivor qmax (the one with the highest jitter) and go around again
         trace!(
@@ -523,6 +628,8 @@ pub fn peer_snapshot() -> crate::PeerSnapshot {
         our_id: ReferenceId::from_int(1),
         reach,
         poll_interval: crate::time_types::PollIntervalLimits::default().min,
+        ignored: Default::default(),
+        options: Default::default(),
     }
 }
 
@@ -556,6 +663,7 @@ fn peer_time_snapshot(
         leap_indicator: crate::NtpLeapIndicator::NoWarning,
         root_delay,
         root_dispersion,
+        options: Default::default(),
     }
 }
 
@@ -1350,6 +1458,163 @@ mod test {
         }
     }
 
+    #[test]
+    fn cluster_algorithm_keeps_preferred_peer() {
+        let mut peer = test_peer_time_snapshot(NtpInstant::now());
+        peer.statistics.offset = NtpDuration::ONE * 3i64;
+
+        let peers = &mut vec![(0, peer); 4];
+
+        for (i, peer) in peers.iter_mut().enumerate() {
+            peer.0 = i + 1;
+            peer.1.statistics.jitter = 1.0 - (1.0 / (i + 1) as f64);
+            peer.1.statistics.offset = NtpDuration::ONE;
+        }
+
+        // the outlier would normally be discarded (see above), but is preferred
+        peers[2].1.statistics.offset = NtpDuration::ONE * 4;
+        peers[2].1.options.prefer = true;
+
+        let mut candidates = (0..peers.len())
+            .map(|i| SurvivorTuple {
+                peer: &peers[i],
+                metric: NtpDuration::ONE,
+            })
+            .collect::<Vec<_>>();
+
+        let config = SystemConfig::default();
+        let _answer = cluster_algorithm(&config, &mut candidates);
+
+        // the preferred peer survives, and is sorted first
+        assert_eq!(candidates.len(), 3);
+        assert_eq!(candidates[0].peer.0, 3);
+    }
+
+    #[test]
+    fn selection_respects_peer_options() {
+        let base = NtpInstant::now();
+
+        let config = SystemConfig {
+            min_intersection_survivors: 1,
+            ..Default::default()
+        };
+
+        let peer = |offset: f64| {
+            peer_time_snapshot(
+                PeerStatistics {
+                    offset: NtpDuration::from_seconds(offset),
+                    delay: NtpDuration::from_seconds(0.01),
+                    dispersion: NtpDuration::from_seconds(0.01),
+                    jitter: 0.001,
+                },
+                base,
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.01),
+            )
+        };
+
+        let mut peers = vec![(1, peer(0.0)), (2, peer(0.001)), (3, peer(0.5))];
+
+        // without options, the third peer is a falseticker
+        let result =
+            FilterAndCombine::run(&config, &peers, base, PollIntervalLimits::default().min)
+                .unwrap();
+        assert_ne!(result.system_peer_snapshot.0, 3);
+        assert!(result.system_offset < NtpDuration::from_seconds(0.01));
+
+        // a trusted peer is a truechimer regardless, and preferring it makes it the system peer
+        peers[2].1.options.trust = true;
+        peers[2].1.options.prefer = true;
+        let result =
+            FilterAndCombine::run(&config, &peers, base, PollIntervalLimits::default().min)
+                .unwrap();
+        assert_eq!(result.system_peer_snapshot.0, 3);
+
+        // noselect peers are never used
+        peers[2].1.options.noselect = true;
+        let result =
+            FilterAndCombine::run(&config, &peers, base, PollIntervalLimits::default().min)
+                .unwrap();
+        assert_ne!(result.system_peer_snapshot.0, 3);
+        assert!(result.system_offset < NtpDuration::from_seconds(0.01));
+
+        for peer in peers.iter_mut() {
+            peer.1.options.noselect = true;
+        }
+        assert!(
+            FilterAndCombine::run(&config, &peers, base, PollIntervalLimits::default().min)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn selection_reports_peer_status() {
+        let base = NtpInstant::now();
+
+        let mut config = SystemConfig {
+            min_intersection_survivors: 1,
+            ..Default::default()
+        };
+
+        let peer = |offset: f64| {
+            peer_time_snapshot(
+                PeerStatistics {
+                    offset: NtpDuration::from_seconds(offset),
+                    delay: NtpDuration::from_seconds(0.01),
+                    dispersion: NtpDuration::from_seconds(0.01),
+                    jitter: 0.001,
+                },
+                base,
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.01),
+            )
+        };
+
+        let mut peers = vec![
+            (1, peer(0.0)),
+            (2, peer(0.001)),
+            (3, peer(0.5)),
+            (4, peer(0.0)),
+        ];
+        peers[3].1.options.noselect = true;
+
+        let (result, selection) = FilterAndCombine::run_with_selection(
+            &config,
+            &peers,
+            base,
+            PollIntervalLimits::default().min,
+        );
+        let system_peer = result.unwrap().system_peer_snapshot.0;
+        for (id, status) in selection {
+            let expected = match id {
+                _ if id == system_peer => PeerSelection::Selected,
+                1 | 2 => PeerSelection::Candidate,
+                3 => PeerSelection::Falseticker,
+                _ => PeerSelection::Unusable,
+            };
+            assert_eq!(status, expected, "peer {}", id);
+        }
+
+        // without a majority, none of the usable peers can be trusted
+        config.min_intersection_survivors = 3;
+        let (result, selection) = FilterAndCombine::run_with_selection(
+            &config,
+            &peers,
+            base,
+            PollIntervalLimits::default().min,
+        );
+        assert!(result.is_none());
+        assert_eq!(
+            selection,
+            vec![
+                (1, PeerSelection::Falseticker),
+                (2, PeerSelection::Falseticker),
+                (3, PeerSelection::Falseticker),
+                (4, PeerSelection::Unusable),
+            ]
+        );
+    }
+
     #[test]
     fn system_variable_update() {
         let instant = NtpInstant::now();
@@ -22,12 +22,46 @@ where
     })
 }
 
+fn deserialize_max_slew_rate<'de, D>(deserializer: D) -> Result<FrequencyTolerance, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // The kernel does not accept frequency corrections beyond 500 ppm
+    let ppm: u32 = Deserialize::deserialize(deserializer)?;
+    if ppm == 0 || ppm > 500 {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(ppm as u64),
+            &"a rate between 1 and 500 parts-per-million",
+        ));
+    }
+    Ok(FrequencyTolerance::ppm(ppm))
+}
+
 #[derive(Debug, Default, Copy, Clone)]
 pub struct StepThreshold {
     pub forward: Option<NtpDuration>,
     pub backward: Option<NtpDuration>,
 }
 
+impl StepThreshold {
+    /// Whether a change of the clock by the given offset stays within the threshold
+    pub fn is_within(&self, offset: NtpDuration) -> bool {
+        let forward_ok = if let Some(forward) = self.forward {
+            offset < forward
+        } else {
+            true
+        };
+
+        let backward_ok = if let Some(backward) = self.backward {
+            offset > -backward
+        } else {
+            true
+        };
+
+        forward_ok && backward_ok
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 struct ThresholdPart(Option<NtpDuration>);
 
@@ -178,6 +212,34 @@ impl<'de> Deserialize<'de> for StepThreshold {
     }
 }
 
+/// Options that change how a single peer is polled and used for synchronization
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct PeerOptions {
+    /// Favour this peer over the others, both when clustering and when
+    /// choosing the system peer
+    pub prefer: bool,
+    /// Only monitor this peer, never use it for synchronization
+    pub noselect: bool,
+    /// Always consider this peer a truechimer, even when it does not agree
+    /// with the majority of the other peers
+    pub trust: bool,
+    /// Poll interval limits for this peer, overriding those of the system
+    pub poll_limits: Option<PollIntervalLimits>,
+    /// Send a quick train of requests at startup, and when the peer has
+    /// become unreachable, to speed up (re)synchronization
+    pub iburst: bool,
+    /// Send several requests per poll, only using the best response
+    pub burst: bool,
+}
+
+impl PeerOptions {
+    /// The poll interval limits that apply to this peer
+    pub fn poll_limits(&self, system_config: &SystemConfig) -> PollIntervalLimits {
+        self.poll_limits.unwrap_or(system_config.poll_limits)
+    }
+}
+
 #[derive(Deserialize, Debug, Clone, Copy)]
 #[serde(rename_all = "kebab-case")]
 pub struct SystemConfig {
@@ -258,6 +320,20 @@ pub struct SystemConfig {
     /// Initial poll interval of the system
     #[serde(default = "default_initial_poll")]
     pub initial_poll: PollInterval,
+
+    /// Never step the clock once startup has completed. Offsets that would
+    /// normally result in a step are instead slewed away, at a rate of at
+    /// most max_slew_rate.
+    #[serde(default)]
+    pub slew_only: bool,
+
+    /// Maximum rate at which large offsets are slewed away when slew_only
+    /// is enabled.
+    #[serde(
+        deserialize_with = "deserialize_max_slew_rate",
+        default = "default_max_slew_rate"
+    )]
+    pub max_slew_rate: FrequencyTolerance,
 }
 
 impl Default for SystemConfig {
@@ -278,6 +354,9 @@ impl Default for SystemConfig {
 
             poll_limits: Default::default(),
             initial_poll: default_initial_poll(),
+
+            slew_only: false,
+            max_slew_rate: default_max_slew_rate(),
         }
     }
 }
@@ -328,3 +407,7 @@ fn default_local_stratum() -> u8 {
 fn default_initial_poll() -> PollInterval {
     PollIntervalLimits::default().min
 }
+
+fn default_max_slew_rate() -> FrequencyTolerance {
+    FrequencyTolerance::ppm(500)
+}
@@ -78,6 +78,11 @@ impl LastMeasurements {
         }
     }
 
+    /// Dispersion of the most recently inserted tuple
+    pub(crate) fn newest_dispersion(&self) -> NtpDuration {
+        self.register[0].dispersion
+    }
+
     /// Insert the new tuple at index 0, move all other tuples one to the right.
     /// The final (oldest) tuple is discarded
     fn shift_and_insert(&mut self, mut current: FilterTuple, dispersion_correction: NtpDuration) {
@@ -22,6 +22,18 @@ impl ReferenceId {
         }
     }
 
+    /// Reference id for a reference clock, given as a code of up to four
+    /// ascii characters (e.g. `GPS` or `PPS`). Shorter codes are padded with zeros.
+    pub fn from_code(code: &str) -> Option<ReferenceId> {
+        if code.len() > 4 || !code.is_ascii() {
+            return None;
+        }
+
+        let mut bytes = [0; 4];
+        bytes[..code.len()].copy_from_slice(code.as_bytes());
+        Some(ReferenceId::from_bytes(bytes))
+    }
+
     pub(crate) const fn from_int(value: u32) -> ReferenceId {
         ReferenceId(value)
     }
@@ -38,7 +50,7 @@ impl ReferenceId {
         *self == Self::KISS_RSTR
     }
 
-    pub(crate) fn to_bytes(self) -> [u8; 4] {
+    pub fn to_bytes(self) -> [u8; 4] {
         self.0.to_be_bytes()
     }
 
@@ -76,6 +88,19 @@ mod tests {
         assert!(b.is_deny());
     }
 
+    #[test]
+    fn referenceid_codes() {
+        assert_eq!(
+            ReferenceId::from_code("GPS"),
+            Some(ReferenceId::from_bytes(*b"GPS\0"))
+        );
+        assert_eq!(
+            ReferenceId::from_code("PPS1"),
+            Some(ReferenceId::from_bytes(*b"PPS1"))
+        );
+        assert_eq!(ReferenceId::from_code("TOOLONG"), None);
+    }
+
     #[test]
     fn referenceid_from_ipv4() {
         let ip: IpAddr = "12.34.56.78".parse().unwrap();
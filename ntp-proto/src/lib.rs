@@ -9,25 +9,29 @@ mod identifiers;
 mod nts_record;
 mod packet;
 mod peer;
+mod refclock;
 mod time_types;
 
-pub use algorithm::{DefaultTimeSyncController, ObservablePeerTimedata, TimeSyncController};
-pub use clock::{ClockController, ClockUpdateResult, NtpClock};
+pub use algorithm::{
+    DefaultTimeSyncController, MeasurementRecord, ObservablePeerTimedata, TimeSyncController,
+};
+pub use clock::{ClockController, ClockState, ClockUpdateResult, NtpClock};
 #[cfg(feature = "fuzz")]
 pub use clock_select::fuzz_find_interval;
 #[cfg(feature = "ext-test")]
 pub use clock_select::peer_snapshot;
-pub use clock_select::FilterAndCombine;
-pub use config::{StepThreshold, SystemConfig};
+pub use clock_select::{FilterAndCombine, PeerSelection};
+pub use config::{PeerOptions, StepThreshold, SystemConfig};
 pub use identifiers::ReferenceId;
 
 pub use packet::{NtpAssociationMode, NtpLeapIndicator, NtpPacket};
 #[cfg(feature = "fuzz")]
 pub use peer::fuzz_measurement_from_packet;
 pub use peer::{
-    AcceptSynchronizationError, IgnoreReason, Measurement, Peer, PeerSnapshot, PeerStatistics,
-    PeerTimeSnapshot, Reach, SystemSnapshot, TimeSnapshot, Update,
+    AcceptSynchronizationError, IgnoreCounts, IgnoreReason, Measurement, Peer, PeerSnapshot,
+    PeerStatistics, PeerTimeSnapshot, PeerTimeState, Reach, SystemSnapshot, TimeSnapshot, Update,
 };
+pub use refclock::{RefClockSample, RefClockSource};
 #[cfg(feature = "fuzz")]
 pub use time_types::fuzz_duration_from_seconds;
 pub use time_types::{
@@ -401,6 +401,27 @@ impl NtpHeaderV3V4 {
         }
     }
 
+    fn refclock_sample(
+        leap: NtpLeapIndicator,
+        precision: i8,
+        reference_id: ReferenceId,
+        root_dispersion: NtpDuration,
+        timestamp: NtpTimestamp,
+    ) -> Self {
+        Self {
+            leap,
+            mode: NtpAssociationMode::Server,
+            stratum: 0,
+            precision,
+            root_dispersion,
+            reference_id,
+            reference_timestamp: timestamp,
+            receive_timestamp: timestamp,
+            transmit_timestamp: timestamp,
+            ..Self::new()
+        }
+    }
+
     fn rate_limit_response(packet_from_client: Self) -> Self {
         Self {
             mode: NtpAssociationMode::Server,
@@ -532,6 +553,28 @@ impl<'a> NtpPacket<'a> {
         }
     }
 
+    /// Packet describing a sample from a reference clock, as if it was
+    /// received from a stratum 0 server.
+    pub(crate) fn refclock_sample(
+        leap: NtpLeapIndicator,
+        precision: i8,
+        reference_id: ReferenceId,
+        root_dispersion: NtpDuration,
+        timestamp: NtpTimestamp,
+    ) -> Self {
+        NtpPacket {
+            header: NtpHeader::V4(NtpHeaderV3V4::refclock_sample(
+                leap,
+                precision,
+                reference_id,
+                root_dispersion,
+                timestamp,
+            )),
+            efdata: Default::default(),
+            mac: None,
+        }
+    }
+
     pub fn rate_limit_response(packet_from_client: Self) -> Self {
         match packet_from_client.header {
             NtpHeader::V3(header) => NtpPacket {
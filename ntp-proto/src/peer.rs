@@ -2,7 +2,8 @@ use crate::{
     filter::{FilterTuple, LastMeasurements},
     packet::{NtpAssociationMode, NtpLeapIndicator, RequestIdentifier},
     time_types::{FrequencyTolerance, NtpInstant},
-    NtpDuration, NtpPacket, NtpTimestamp, PollInterval, ReferenceId, SystemConfig,
+    ClockState, NtpDuration, NtpPacket, NtpTimestamp, PeerOptions, PollInterval, ReferenceId,
+    SystemConfig,
 };
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, instrument, trace, warn};
@@ -40,7 +41,9 @@ pub struct Peer {
     peer_id: ReferenceId,
     our_id: ReferenceId,
     reach: Reach,
+    ignored: IgnoreCounts,
 
+    options: PeerOptions,
     system_config: SystemConfig,
 }
 
@@ -73,8 +76,10 @@ impl Measurement {
     }
 }
 
+/// Time measurement state of a peer: the clock filter and the statistics
+/// derived from it.
 #[derive(Debug, Clone)]
-pub(crate) struct PeerTimeState {
+pub struct PeerTimeState {
     pub(crate) statistics: PeerStatistics,
     pub(crate) last_measurements: LastMeasurements,
     pub(crate) last_packet: NtpPacket<'static>,
@@ -82,7 +87,16 @@ pub(crate) struct PeerTimeState {
 }
 
 impl PeerTimeState {
-    pub(crate) fn update(
+    pub fn new(time: NtpInstant) -> Self {
+        PeerTimeState {
+            statistics: Default::default(),
+            last_measurements: LastMeasurements::new(time),
+            last_packet: Default::default(),
+            time,
+        }
+    }
+
+    pub fn update(
         &mut self,
         measurement: Measurement,
         packet: NtpPacket,
@@ -187,6 +201,11 @@ impl Reach {
         self.0 != 0
     }
 
+    /// The register itself, with the most recent poll in the least significant bit
+    pub fn as_bits(&self) -> u8 {
+        self.0
+    }
+
     /// We have just received a packet, so the peer is definitely reachable
     pub(crate) fn received_packet(&mut self) {
         self.0 |= 1;
@@ -194,7 +213,7 @@ impl Reach {
 
     /// A packet received some number of poll intervals ago is decreasingly relevant for
     /// determining that a peer is still reachable. We discount the packets received so far.
-    fn poll(&mut self) {
+    pub(crate) fn poll(&mut self) {
         self.0 <<= 1
     }
 
@@ -221,8 +240,21 @@ pub struct TimeSnapshot {
     pub root_dispersion: NtpDuration,
     /// Current leap indicator state
     pub leap_indicator: NtpLeapIndicator,
+    /// Offset of the local clock as measured at the last clock update
+    #[serde(default)]
+    pub offset: NtpDuration,
+    /// Estimated jitter of the offset of the local clock
+    #[serde(default)]
+    pub jitter: NtpDuration,
+    /// Frequency correction applied to the local clock, in seconds per second
+    #[serde(default)]
+    pub frequency: f64,
     /// Total amount that the clock has stepped
     pub accumulated_steps: NtpDuration,
+    /// Estimated time until a large offset has been slewed away, when
+    /// stepping is disabled
+    #[serde(default)]
+    pub slew_remaining: Option<NtpDuration>,
 }
 
 impl Default for TimeSnapshot {
@@ -233,7 +265,11 @@ impl Default for TimeSnapshot {
             root_delay: NtpDuration::ZERO,
             root_dispersion: NtpDuration::ZERO,
             leap_indicator: NtpLeapIndicator::Unknown,
+            offset: NtpDuration::ZERO,
+            jitter: NtpDuration::ZERO,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
+            slew_remaining: None,
         }
     }
 }
@@ -249,6 +285,9 @@ pub struct SystemSnapshot {
     /// Timekeeping data
     #[serde(flatten)]
     pub time_snapshot: TimeSnapshot,
+    /// State of the clock discipline
+    #[serde(default)]
+    pub clock_state: ClockState,
 }
 
 impl SystemSnapshot {
@@ -274,6 +313,7 @@ impl Default for SystemSnapshot {
             reference_id: ReferenceId::NONE,
             accumulated_steps_threshold: None,
             time_snapshot: TimeSnapshot::default(),
+            clock_state: ClockState::default(),
         }
     }
 }
@@ -296,6 +336,47 @@ pub enum IgnoreReason {
     TooOld,
 }
 
+/// The number of packets from a peer that were ignored, for each [`IgnoreReason`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IgnoreCounts {
+    pub invalid_mode: u64,
+    pub invalid_version: u64,
+    pub invalid_stratum: u64,
+    pub invalid_packet_time: u64,
+    pub kiss_ignore: u64,
+    pub kiss_demobilize: u64,
+    pub too_old: u64,
+}
+
+impl IgnoreCounts {
+    fn record(&mut self, reason: &IgnoreReason) {
+        let count = match reason {
+            IgnoreReason::InvalidMode => &mut self.invalid_mode,
+            IgnoreReason::InvalidVersion => &mut self.invalid_version,
+            IgnoreReason::InvalidStratum => &mut self.invalid_stratum,
+            IgnoreReason::InvalidPacketTime => &mut self.invalid_packet_time,
+            IgnoreReason::KissIgnore => &mut self.kiss_ignore,
+            IgnoreReason::KissDemobilize => &mut self.kiss_demobilize,
+            IgnoreReason::TooOld => &mut self.too_old,
+        };
+        *count += 1;
+    }
+
+    /// The counts, labeled with the snake case name of their reason
+    pub fn by_reason(&self) -> [(&'static str, u64); 7] {
+        [
+            ("invalid_mode", self.invalid_mode),
+            ("invalid_version", self.invalid_version),
+            ("invalid_stratum", self.invalid_stratum),
+            ("invalid_packet_time", self.invalid_packet_time),
+            ("kiss_ignore", self.kiss_ignore),
+            ("kiss_demobilize", self.kiss_demobilize),
+            ("too_old", self.too_old),
+        ]
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PeerSnapshot {
     pub peer_id: ReferenceId,
@@ -306,6 +387,9 @@ pub struct PeerSnapshot {
 
     pub stratum: u8,
     pub reference_id: ReferenceId,
+    pub ignored: IgnoreCounts,
+
+    pub options: PeerOptions,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -319,6 +403,8 @@ pub struct PeerTimeSnapshot {
     pub leap_indicator: NtpLeapIndicator,
     pub root_delay: NtpDuration,
     pub root_dispersion: NtpDuration,
+
+    pub options: PeerOptions,
 }
 
 impl PeerTimeSnapshot {
@@ -331,7 +417,7 @@ impl PeerTimeSnapshot {
             + (NtpInstant::abs_diff(local_clock_time, self.time) * frequency_tolerance)
     }
 
-    pub(crate) fn from_timestate(timestate: &PeerTimeState) -> Self {
+    pub fn from_timestate(timestate: &PeerTimeState, options: PeerOptions) -> Self {
         Self {
             root_distance_without_time: timestate.root_distance_without_time(),
             statistics: timestate.statistics,
@@ -340,6 +426,7 @@ impl PeerTimeSnapshot {
             leap_indicator: timestate.last_packet.leap(),
             root_delay: timestate.last_packet.root_delay(),
             root_dispersion: timestate.last_packet.root_dispersion(),
+            options,
         }
     }
 
@@ -418,6 +505,8 @@ impl PeerSnapshot {
             reference_id: peer.reference_id,
             reach: peer.reach,
             poll_interval: peer.last_poll_interval,
+            ignored: peer.ignored,
+            options: peer.options,
         }
     }
 }
@@ -444,20 +533,25 @@ impl Peer {
         peer_id: ReferenceId,
         local_clock_time: NtpInstant,
         system_config: SystemConfig,
+        options: PeerOptions,
     ) -> Self {
+        let poll_limits = options.poll_limits(&system_config);
+
         Self {
-            last_poll_interval: system_config.poll_limits.min,
-            backoff_interval: system_config.poll_limits.min,
-            remote_min_poll_interval: system_config.poll_limits.min,
+            last_poll_interval: poll_limits.min,
+            backoff_interval: poll_limits.min,
+            remote_min_poll_interval: poll_limits.min,
 
             current_request_identifier: None,
             our_id,
             peer_id,
             reach: Default::default(),
+            ignored: Default::default(),
 
             stratum: 16,
             reference_id: ReferenceId::NONE,
 
+            options,
             system_config,
         }
     }
@@ -467,13 +561,20 @@ impl Peer {
     }
 
     pub fn current_poll_interval(&self, system: SystemSnapshot) -> PollInterval {
+        let poll_limits = self.options.poll_limits(&self.system_config);
+
         system
             .time_snapshot
             .poll_interval
+            .clamp(poll_limits.min, poll_limits.max)
             .max(self.backoff_interval)
             .max(self.remote_min_poll_interval)
     }
 
+    pub fn options(&self) -> PeerOptions {
+        self.options
+    }
+
     pub fn generate_poll_message(
         &mut self,
         system: SystemSnapshot,
@@ -482,11 +583,21 @@ impl Peer {
         self.reach.poll();
 
         let poll_interval = self.current_poll_interval(system);
-        let (packet, identifier) = NtpPacket::poll_message(poll_interval);
-        self.current_request_identifier = Some((identifier, NtpInstant::now() + POLL_WINDOW));
+        let packet = self.generate_burst_message(system);
 
         // Ensure we don't spam the remote with polls if it is not reachable
-        self.backoff_interval = poll_interval.inc(system_config.poll_limits);
+        self.backoff_interval = poll_interval.inc(self.options.poll_limits(system_config));
+
+        packet
+    }
+
+    /// Generate an additional request within the current poll, as part of a
+    /// burst. Unlike a poll message, this does not affect reachability or the
+    /// poll interval. Only the response to the most recent request is accepted.
+    pub fn generate_burst_message(&mut self, system: SystemSnapshot) -> NtpPacket<'static> {
+        let poll_interval = self.current_poll_interval(system);
+        let (packet, identifier) = NtpPacket::poll_message(poll_interval);
+        self.current_request_identifier = Some((identifier, NtpInstant::now() + POLL_WINDOW));
 
         packet
     }
@@ -499,6 +610,21 @@ impl Peer {
         local_clock_time: NtpInstant,
         send_time: NtpTimestamp,
         recv_time: NtpTimestamp,
+    ) -> Result<Update, IgnoreReason> {
+        let result = self.check_incoming(system, message, local_clock_time, send_time, recv_time);
+        if let Err(reason) = &result {
+            self.ignored.record(reason);
+        }
+        result
+    }
+
+    fn check_incoming(
+        &mut self,
+        system: SystemSnapshot,
+        message: NtpPacket,
+        local_clock_time: NtpInstant,
+        send_time: NtpTimestamp,
+        recv_time: NtpTimestamp,
     ) -> Result<Update, IgnoreReason> {
         let request_identifier = match self.current_request_identifier {
             Some((next_expected_origin, validity)) if validity >= NtpInstant::now() => {
@@ -523,7 +649,7 @@ impl Peer {
             // KISS packets may not have correct timestamps at all, handle them anyway
             self.remote_min_poll_interval = Ord::max(
                 self.remote_min_poll_interval
-                    .inc(self.system_config.poll_limits),
+                    .inc(self.options.poll_limits(&self.system_config)),
                 self.last_poll_interval,
             );
             warn!(?self.remote_min_poll_interval, "Peer requested rate limit");
@@ -566,7 +692,7 @@ impl Peer {
         self.reach.received_packet();
 
         // Got a response, so no need for unreachability backoff
-        self.backoff_interval = self.system_config.poll_limits.min;
+        self.backoff_interval = self.options.poll_limits(&self.system_config).min;
 
         // we received this packet, and don't want to accept future ones with this next_expected_origin
         self.current_request_identifier = None;
@@ -611,10 +737,12 @@ impl Peer {
             peer_id: ReferenceId::from_int(0),
             our_id: ReferenceId::from_int(0),
             reach: Reach::default(),
+            ignored: IgnoreCounts::default(),
 
             stratum: 0,
             reference_id: ReferenceId::from_int(0),
 
+            options: PeerOptions::default(),
             system_config: SystemConfig::default(),
         }
     }
@@ -876,7 +1004,7 @@ mod test {
 
         macro_rules! accept {
             () => {{
-                let snapshot = PeerTimeSnapshot::from_timestate(&timestate);
+                let snapshot = PeerTimeSnapshot::from_timestate(&timestate, PeerOptions::default());
                 snapshot.accept_synchronization(local_clock_time, ft, dt, system_poll)
             }};
         }
@@ -951,6 +1079,51 @@ mod test {
         assert!(peer.remote_min_poll_interval > prev);
     }
 
+    #[test]
+    fn test_peer_poll_limits() {
+        let limits = PollIntervalLimits::default();
+        let mut system = SystemSnapshot::default();
+
+        let options = PeerOptions {
+            poll_limits: Some(PollIntervalLimits {
+                min: limits.max,
+                max: limits.max,
+            }),
+            ..Default::default()
+        };
+        let peer = Peer::new(
+            ReferenceId::from_int(0),
+            ReferenceId::from_int(0),
+            NtpInstant::now(),
+            SystemConfig::default(),
+            options,
+        );
+        system.time_snapshot.poll_interval = limits.min;
+        assert_eq!(peer.current_poll_interval(system), limits.max);
+        assert_eq!(PeerSnapshot::from_peer(&peer).options, options);
+
+        let options = PeerOptions {
+            poll_limits: Some(PollIntervalLimits {
+                min: limits.min,
+                max: limits.min,
+            }),
+            ..Default::default()
+        };
+        let mut peer = Peer::new(
+            ReferenceId::from_int(0),
+            ReferenceId::from_int(0),
+            NtpInstant::now(),
+            SystemConfig::default(),
+            options,
+        );
+        system.time_snapshot.poll_interval = limits.max;
+        assert_eq!(peer.current_poll_interval(system), limits.min);
+
+        // backoff stays within the limits of the peer
+        peer.generate_poll_message(system, &SystemConfig::default());
+        assert_eq!(peer.current_poll_interval(system), limits.min);
+    }
+
     #[test]
     fn test_handle_incoming() {
         let base = NtpInstant::now();
@@ -987,6 +1160,52 @@ mod test {
             .is_err());
     }
 
+    #[test]
+    fn test_burst_message() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer();
+        let system = SystemSnapshot::default();
+
+        let first = peer.generate_poll_message(system, &SystemConfig::default());
+        let reach = peer.reach;
+        let poll_interval = peer.current_poll_interval(system);
+
+        // a burst message affects neither reachability nor the poll interval
+        let second = peer.generate_burst_message(system);
+        assert_eq!(peer.reach.unanswered_polls(), reach.unanswered_polls());
+        assert_eq!(peer.current_poll_interval(system), poll_interval);
+
+        let response = |outgoing: &NtpPacket| {
+            let mut packet = NtpPacket::test();
+            packet.set_stratum(1);
+            packet.set_mode(NtpAssociationMode::Server);
+            packet.set_origin_timestamp(outgoing.transmit_timestamp());
+            packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+            packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+            packet
+        };
+
+        // only the response to the latest request is accepted
+        assert!(peer
+            .handle_incoming(
+                system,
+                response(&first),
+                base,
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400)
+            )
+            .is_err());
+        assert!(peer
+            .handle_incoming(
+                system,
+                response(&second),
+                base,
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400)
+            )
+            .is_ok());
+    }
+
     #[test]
     fn test_stratum_checks() {
         let base = NtpInstant::now();
@@ -1021,6 +1240,19 @@ mod test {
                 NtpTimestamp::from_fixed_int(500)
             )
             .is_err());
+
+        let ignored = PeerSnapshot::from_peer(&peer).ignored;
+        // stratum 0 marks a Kiss-o'-Death packet
+        assert_eq!(ignored.invalid_stratum, 1);
+        assert_eq!(ignored.kiss_ignore, 1);
+        assert_eq!(
+            ignored
+                .by_reason()
+                .iter()
+                .map(|(_, count)| count)
+                .sum::<u64>(),
+            2
+        );
     }
 
     #[test]
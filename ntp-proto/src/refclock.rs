@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+
+use crate::{
+    packet::NtpLeapIndicator,
+    peer::{Measurement, Reach},
+    NtpDuration, NtpInstant, NtpPacket, NtpTimestamp, PeerOptions, PeerSnapshot, PollInterval,
+    ReferenceId, Update,
+};
+
+/// A single reading of a reference clock.
+#[derive(Debug, Clone, Copy)]
+pub struct RefClockSample {
+    /// Offset of the reference clock relative to the local clock
+    /// (reference time minus local time)
+    pub offset: NtpDuration,
+    /// Estimate of the error in the offset, as reported by the driver
+    pub dispersion: NtpDuration,
+    /// Local time at which the sample was taken
+    pub localtime: NtpTimestamp,
+    /// Local monotonic time at which the sample was taken
+    pub monotime: NtpInstant,
+    /// Leap second warning given by the reference clock
+    pub leap: NtpLeapIndicator,
+}
+
+/// Maximum number of samples kept between polls. Drivers can produce
+/// samples much faster than we poll, so beyond this the oldest are dropped.
+const MAX_SAMPLES: usize = 64;
+
+/// Protocol state of a reference clock.
+///
+/// This plays the role [`Peer`](crate::Peer) plays for network sources:
+/// it collects the samples a driver produces and, once every poll interval,
+/// turns them into a measurement of a stratum 0 source for the
+/// synchronization algorithm.
+#[derive(Debug, Clone)]
+pub struct RefClockSource {
+    reference_id: ReferenceId,
+    precision: i8,
+    poll_interval: PollInterval,
+    reach: Reach,
+    samples: VecDeque<RefClockSample>,
+}
+
+impl RefClockSource {
+    pub fn new(reference_id: ReferenceId, precision: i8, poll_interval: PollInterval) -> Self {
+        Self {
+            reference_id,
+            precision,
+            poll_interval,
+            reach: Default::default(),
+            samples: VecDeque::with_capacity(MAX_SAMPLES),
+        }
+    }
+
+    pub fn poll_interval(&self) -> PollInterval {
+        self.poll_interval
+    }
+
+    /// Store a sample for processing at the next poll, dropping the oldest
+    /// sample when [`MAX_SAMPLES`] are already waiting
+    pub fn add_sample(&mut self, sample: RefClockSample) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Combine the samples received since the last poll into a single
+    /// measurement. The median sample is used, with the spread of the
+    /// samples around it taken into account in the dispersion.
+    pub fn poll(&mut self) -> Update {
+        self.reach.poll();
+
+        if self.samples.is_empty() {
+            return Update::BareUpdate(self.snapshot());
+        }
+
+        let samples = self.samples.make_contiguous();
+        samples.sort_by_key(|sample| sample.offset);
+        let median = samples[samples.len() / 2];
+        let spread = samples[samples.len() - 1].offset - samples[0].offset;
+        self.samples.clear();
+
+        self.reach.received_packet();
+
+        let measurement = Measurement {
+            delay: NtpDuration::ZERO,
+            offset: median.offset,
+            localtime: median.localtime,
+            monotime: median.monotime,
+        };
+
+        let packet = NtpPacket::refclock_sample(
+            median.leap,
+            self.precision,
+            self.reference_id,
+            median.dispersion.max(spread / 2i64),
+            median.localtime + median.offset,
+        );
+
+        Update::NewMeasurement(self.snapshot(), measurement, packet)
+    }
+
+    pub fn snapshot(&self) -> PeerSnapshot {
+        PeerSnapshot {
+            peer_id: self.reference_id,
+            our_id: ReferenceId::NONE,
+            poll_interval: self.poll_interval,
+            reach: self.reach,
+            stratum: 0,
+            reference_id: self.reference_id,
+            ignored: Default::default(),
+            options: PeerOptions::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(offset: f64) -> RefClockSample {
+        RefClockSample {
+            offset: NtpDuration::from_seconds(offset),
+            dispersion: NtpDuration::from_seconds(1e-6),
+            localtime: NtpTimestamp::from_fixed_int(0),
+            monotime: NtpInstant::now(),
+            leap: NtpLeapIndicator::NoWarning,
+        }
+    }
+
+    #[test]
+    fn poll_without_samples() {
+        let mut source = RefClockSource::new(
+            ReferenceId::from_code("GPS").unwrap(),
+            -20,
+            PollInterval::default(),
+        );
+
+        match source.poll() {
+            Update::BareUpdate(snapshot) => {
+                assert!(!snapshot.reach.is_reachable());
+                assert_eq!(snapshot.stratum, 0);
+            }
+            Update::NewMeasurement(..) => panic!("no samples, so no measurement expected"),
+        }
+    }
+
+    #[test]
+    fn poll_takes_median() {
+        let mut source = RefClockSource::new(
+            ReferenceId::from_code("GPS").unwrap(),
+            -20,
+            PollInterval::default(),
+        );
+
+        source.add_sample(sample(0.003));
+        source.add_sample(sample(0.001));
+        source.add_sample(sample(0.002));
+
+        match source.poll() {
+            Update::NewMeasurement(snapshot, measurement, packet) => {
+                assert!(snapshot.reach.is_reachable());
+                assert!(snapshot.accept_synchronization(16).is_ok());
+                assert_eq!(measurement.offset, NtpDuration::from_seconds(0.002));
+                assert_eq!(packet.stratum(), 0);
+                assert_eq!(packet.precision(), -20);
+                assert_eq!(
+                    packet.reference_id(),
+                    ReferenceId::from_code("GPS").unwrap()
+                );
+                assert!(
+                    (packet.root_dispersion().to_seconds() - 0.001).abs() < 1e-6,
+                    "{:?}",
+                    packet.root_dispersion()
+                );
+            }
+            Update::BareUpdate(_) => panic!("expected a measurement"),
+        }
+
+        // samples are consumed by a poll
+        assert!(matches!(source.poll(), Update::BareUpdate(_)));
+    }
+
+    #[test]
+    fn samples_are_bounded() {
+        let mut source = RefClockSource::new(
+            ReferenceId::from_code("GPS").unwrap(),
+            -20,
+            PollInterval::default(),
+        );
+
+        // A flood of old samples is pushed out by the most recent ones
+        for _ in 0..10 * MAX_SAMPLES {
+            source.add_sample(sample(1.0));
+        }
+        for _ in 0..MAX_SAMPLES {
+            source.add_sample(sample(0.002));
+        }
+        assert_eq!(source.samples.len(), MAX_SAMPLES);
+
+        match source.poll() {
+            Update::NewMeasurement(_, measurement, _) => {
+                assert_eq!(measurement.offset, NtpDuration::from_seconds(0.002));
+            }
+            Update::BareUpdate(_) => panic!("expected a measurement"),
+        }
+    }
+}
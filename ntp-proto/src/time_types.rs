@@ -198,7 +198,7 @@ impl NtpDuration {
     pub(crate) const ONE: Self = Self { duration: 1 << 32 };
 
     /// NtpDuration::from_seconds(0.125)
-    pub(crate) const STEP_THRESHOLD: Self = Self { duration: 1 << 29 };
+    pub const STEP_THRESHOLD: Self = Self { duration: 1 << 29 };
 
     /// NtpDuration::from_seconds(16.0)
     pub(crate) const MAX_DISPERSION: Self = Self {
@@ -84,7 +84,13 @@ impl UdpSocket {
             "server socket bound"
         );
 
-        let socket = socket.into_std()?;
+        Self::server_from_std(socket.into_std()?)
+    }
+
+    /// Use an already bound socket as server socket, e.g. one passed on by the service manager
+    #[instrument(level = "debug")]
+    pub fn server_from_std(socket: std::net::UdpSocket) -> io::Result<UdpSocket> {
+        socket.set_nonblocking(true)?;
 
         // our supported kernel versions always have receive timestamping. Send timestamping for a
         // server connection is not relevant, so we don't even bother with checking if it is supported
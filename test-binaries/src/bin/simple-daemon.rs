@@ -1,4 +1,7 @@
-use ntp_daemon::config::PeerConfig;
+use ntp_daemon::{
+    config::{PeerConfig, StatisticsConfig, SystemdConfig},
+    systemd::ListenFds,
+};
 use ntp_proto::SystemConfig;
 use std::error::Error;
 
@@ -8,7 +11,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let peer_configs = [PeerConfig::try_from("0.0.0.0:8080").unwrap()];
 
-    let (handle, _) = ntp_daemon::spawn(SystemConfig::default(), &peer_configs, &[]).await?;
+    let (handle, _) = ntp_daemon::spawn(
+        SystemConfig::default(),
+        &peer_configs,
+        &[],
+        &[],
+        &SystemdConfig::default(),
+        &StatisticsConfig::default(),
+        &mut ListenFds::default(),
+        Default::default(),
+    )
+    .await?;
 
     handle.await??;
 